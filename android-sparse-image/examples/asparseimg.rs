@@ -1,11 +1,15 @@
-use std::{
-    io::{copy, Read, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
-};
+use std::path::{Path, PathBuf};
 
 use android_sparse_image::{
-    split::split_image, ChunkHeader, ChunkHeaderBytes, FileHeader, FileHeaderBytes,
-    CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+    device::DeviceWriter,
+    diff::diff,
+    encode::{sparsify_file, Encoder},
+    reader::SparseReader,
+    split::SplitWriter,
+    stats::stats,
+    validate::validate,
+    verify::verify,
+    ChunkData,
 };
 use anyhow::Context;
 use clap::Parser;
@@ -15,21 +19,63 @@ enum Opts {
     /// Inspect the contents of a sparse image
     Inspect { img: PathBuf },
     /// Expand the content of <img> to <out>
-    Expand { img: PathBuf, out: PathBuf },
-    /// split content of <img> to fit maximum download size
+    Expand {
+        img: PathBuf,
+        out: PathBuf,
+        /// Verify the checksums of the image
+        #[clap(long)]
+        verify: bool,
+        /// Skip chunks of unknown types
+        #[clap(long)]
+        lenient: bool,
+    },
+    /// Write the content of <img> to the existing block device (or file) <device>
+    Apply {
+        img: PathBuf,
+        device: PathBuf,
+        /// Verify the checksums of the image
+        #[clap(long)]
+        verify: bool,
+        /// Discard the ranges of DontCare chunks
+        #[clap(long)]
+        discard: bool,
+    },
+    /// Encode the raw image <raw> (or stdin if "-") into the sparse image <out>
+    Encode {
+        raw: PathBuf,
+        out: PathBuf,
+        /// Append a Crc32 chunk
+        #[clap(long)]
+        crc32: bool,
+        /// Fill in the checksum in the file header
+        #[clap(long)]
+        checksum: bool,
+    },
+    /// split content of <img> to fit maximum download size into <out>.partNN.simg files
     Split {
         img: PathBuf,
         size: u32,
         out: PathBuf,
+        /// Append a Crc32 chunk to each split
+        #[clap(long)]
+        crc32: bool,
+        /// Fill in the checksum in the file header of each split
+        #[clap(long)]
+        checksum: bool,
+        /// Maximum number of chunks in each split
+        #[clap(long)]
+        max_chunks: Option<u32>,
     },
+    /// Compare the expanded content of the sparse images <a> and <b>
+    Diff { a: PathBuf, b: PathBuf },
+    /// Verify the checksums and structure of a sparse image
+    Verify { img: PathBuf },
 }
 
 fn inspect(img: &Path) -> anyhow::Result<()> {
-    let mut file = std::fs::File::open(img)?;
-    let mut header_bytes = FileHeaderBytes::default();
-    file.read_exact(&mut header_bytes)?;
-
-    let header = FileHeader::from_bytes(&header_bytes)?;
+    let file = std::fs::File::open(img)?;
+    let mut reader = SparseReader::new(std::io::BufReader::new(file))?;
+    let header = reader.header().clone();
     println!(
         "Chunks {}, Expanded size: {} ({} blocks, {} blocksize), checksum: {}:",
         header.chunks,
@@ -38,113 +84,156 @@ fn inspect(img: &Path) -> anyhow::Result<()> {
         header.block_size,
         header.checksum
     );
-    let mut offset: usize = 0;
-    for index in 0..header.chunks {
-        let mut chunk_bytes = ChunkHeaderBytes::default();
-        file.read_exact(&mut chunk_bytes)?;
-        let chunk = ChunkHeader::from_bytes(&chunk_bytes)?;
-
+    let mut offset: u64 = 0;
+    let mut index = 0;
+    let mut chunks = vec![];
+    while let Some(chunk) = reader.next_chunk() {
+        let (chunk, data) = chunk?;
         let out_size = chunk.out_size(&header);
-        match chunk.chunk_type {
-            android_sparse_image::ChunkType::Raw => {
+        chunks.push(chunk);
+        match data {
+            ChunkData::Raw(_) => {
                 println!("{index}: Offset: {offset} - Copying {out_size} bytes");
-                file.seek(std::io::SeekFrom::Current(chunk.data_size().try_into()?))?;
             }
-            android_sparse_image::ChunkType::Fill => {
-                let mut fill = [0u8; 4];
-                file.read_exact(&mut fill)?;
-                println!("{index}: Offset: {offset} - Filling {out_size} bytes with {fill:x?}");
+            ChunkData::Fill(fill) => {
+                println!(
+                    "{index}: Offset: {offset} - Filling {out_size} bytes with {:x?}",
+                    fill.pattern()
+                );
             }
-            android_sparse_image::ChunkType::DontCare => {
+            ChunkData::DontCare => {
                 println!("{index}: Offset: {offset} - Skipping {out_size} bytes");
             }
-            android_sparse_image::ChunkType::Crc32 => {
-                let mut crc = [0u8; 4];
-                file.read_exact(&mut crc)?;
-                println!("{index}: CRC value: {:x?}", crc);
+            ChunkData::Crc32(crc) => {
+                println!("{index}: CRC value: {crc:x}");
             }
         }
 
         offset += out_size;
+        index += 1;
+    }
+    if let Err(e) = validate(&header, &chunks) {
+        println!("Image is malformed: {e}");
+    }
+
+    let stats = stats(&header, &chunks);
+    for (name, s) in [
+        ("Raw", stats.raw),
+        ("Fill", stats.fill),
+        ("DontCare", stats.dont_care),
+        ("Crc32", stats.crc32),
+    ] {
+        println!(
+            "{name}: {} chunks, {} sparse bytes, {} expanded bytes",
+            s.count, s.sparse_size, s.expanded_size
+        );
     }
+    println!(
+        "Sparse size: {} ({:.1}% of expanded), largest raw run: {} bytes",
+        stats.sparse_size,
+        stats.ratio() * 100.0,
+        stats.largest_raw_run
+    );
     Ok(())
 }
 
-fn expand(img: &Path, out: &Path) -> anyhow::Result<()> {
-    let mut file = std::fs::File::open(img)?;
-    let output = std::fs::OpenOptions::new()
-        .create(true)
-        .truncate(false)
-        .write(true)
-        .open(out)?;
-    let mut header_bytes: FileHeaderBytes = [0; FILE_HEADER_BYTES_LEN];
-    file.read_exact(&mut header_bytes)?;
-
-    let mut output = std::io::BufWriter::new(output);
-    let header = FileHeader::from_bytes(&header_bytes)?;
-    for _ in 0..header.chunks {
-        let mut chunk_bytes: ChunkHeaderBytes = [0; CHUNK_HEADER_BYTES_LEN];
-        file.read_exact(&mut chunk_bytes)?;
-        let chunk = ChunkHeader::from_bytes(&chunk_bytes)?;
-
-        let out_size = chunk.out_size(&header);
-        match chunk.chunk_type {
-            android_sparse_image::ChunkType::Raw => {
-                let mut raw = (&mut file).take(out_size.try_into().unwrap());
-                copy(&mut raw, &mut output)?;
-            }
-            android_sparse_image::ChunkType::Fill => {
-                let mut fill = [0u8; 4];
-                file.read_exact(&mut fill)?;
-                for _ in 0..out_size / 4 {
-                    output.write_all(&fill)?;
-                }
-            }
-            android_sparse_image::ChunkType::DontCare => {
-                output.seek(SeekFrom::Current(out_size.try_into().unwrap()))?;
-            }
-            android_sparse_image::ChunkType::Crc32 => {
-                println!("Ignoring CRC");
-            }
-        }
+fn expand(img: &Path, out: &Path, verify: bool, lenient: bool) -> anyhow::Result<()> {
+    let file = std::fs::File::open(img)?;
+    let output = std::fs::File::create(out).with_context(|| format!("Failed to create {out:?}"))?;
+    let mut reader = SparseReader::new(std::io::BufReader::new(file))?.verify_checksums(verify);
+    if lenient {
+        reader = reader.skip_unknown_chunks(|chunk| {
+            eprintln!(
+                "Skipping chunk {} of unknown type {:#06x} at offset {}",
+                chunk.index, chunk.chunk_type, chunk.offset
+            )
+        });
     }
-    output.flush()?;
+    reader.expand_file(&output)?;
     Ok(())
 }
 
-fn split(img: &Path, size: u32, out: &Path) -> anyhow::Result<()> {
-    let mut file = std::fs::File::open(img)?;
-    let mut header_bytes: FileHeaderBytes = [0; FILE_HEADER_BYTES_LEN];
-    file.read_exact(&mut header_bytes)?;
+fn apply(img: &Path, device: &Path, verify: bool, discard: bool) -> anyhow::Result<()> {
+    let file = std::fs::File::open(img)?;
+    let output = std::fs::File::options()
+        .write(true)
+        .open(device)
+        .with_context(|| format!("Failed to open {device:?}"))?;
+    let reader = SparseReader::new(std::io::BufReader::new(file))?.verify_checksums(verify);
+    let size = DeviceWriter::new()
+        .discard(discard)
+        .write(reader, &output)?;
+    println!("Wrote {size} bytes");
+    Ok(())
+}
 
-    // Scan all chunks
-    let header = FileHeader::from_bytes(&header_bytes)?;
-    let mut chunks = vec![];
-    for _ in 0..header.chunks {
-        let mut chunk_bytes: ChunkHeaderBytes = [0; CHUNK_HEADER_BYTES_LEN];
-        file.read_exact(&mut chunk_bytes)?;
-        let chunk = ChunkHeader::from_bytes(&chunk_bytes)?;
+fn encode(raw: &Path, out: &Path, crc32: bool, checksum: bool) -> anyhow::Result<()> {
+    let encoder = Encoder::new().crc32_chunk(crc32).header_checksum(checksum);
+    let header = if raw == Path::new("-") {
+        let output =
+            std::fs::File::create(out).with_context(|| format!("Failed to create {out:?}"))?;
+        let mut writer = encoder.writer(std::io::BufWriter::new(output))?;
+        std::io::copy(&mut std::io::stdin().lock(), &mut writer)?;
+        writer.finish()?.0
+    } else {
+        sparsify_file(raw, out, &encoder)
+            .with_context(|| format!("Failed to encode {raw:?} into {out:?}"))?
+    };
+    println!(
+        "Encoded {} blocks into {} chunks",
+        header.blocks, header.chunks
+    );
+    Ok(())
+}
 
-        file.seek(SeekFrom::Current(chunk.data_size() as i64))?;
-        chunks.push(chunk);
+fn split(
+    img: &Path,
+    size: u32,
+    out: &Path,
+    crc32: bool,
+    checksum: bool,
+    max_chunks: Option<u32>,
+) -> anyhow::Result<()> {
+    let writer = SplitWriter::new()
+        .crc32_chunk(crc32)
+        .header_checksum(checksum);
+    let paths = match max_chunks {
+        Some(max_chunks) => writer.split_file_with_max_chunks(img, size, max_chunks, out)?,
+        None => writer.split_file(img, size, out)?,
+    };
+    for path in paths {
+        println!("Wrote {}", path.display());
     }
 
-    let splits = split_image(&header, &chunks, size)?;
-    for (i, split) in splits.iter().enumerate() {
-        let mut out = out.as_os_str().to_os_string();
-        out.push(format!(".{i}"));
-        let mut out =
-            std::fs::File::create(&out).with_context(|| format!("Failed to create {out:?}"))?;
-        out.write_all(&split.header.to_bytes())?;
-        for chunk in &split.chunks {
-            out.write_all(&chunk.header.to_bytes())?;
+    Ok(())
+}
 
-            file.seek(SeekFrom::Start(chunk.offset as u64))
-                .context("Failed to seek input file")?;
-            std::io::copy(&mut (&mut file).take(chunk.size as u64), &mut out)?;
-        }
+fn compare(a: &Path, b: &Path) -> anyhow::Result<()> {
+    let a = std::io::BufReader::new(std::fs::File::open(a)?);
+    let b = std::io::BufReader::new(std::fs::File::open(b)?);
+    let ranges = diff(a, b)?;
+    if ranges.is_empty() {
+        println!("Images have the same content");
     }
+    for range in ranges {
+        println!("Blocks {} - {} differ", range.start, range.end - 1);
+    }
+    Ok(())
+}
 
+fn check(img: &Path) -> anyhow::Result<()> {
+    let file = std::io::BufReader::new(std::fs::File::open(img)?);
+    let report = verify(file)?;
+    println!(
+        "Image is valid, checksum: {:#010x} (header checksum: {}, Crc32 chunks: {})",
+        report.checksum,
+        if report.header_checksum {
+            "verified"
+        } else {
+            "none"
+        },
+        report.crc32_chunks
+    );
     Ok(())
 }
 
@@ -152,8 +241,34 @@ fn main() -> anyhow::Result<()> {
     let opts = Opts::parse();
     match opts {
         Opts::Inspect { img } => inspect(&img)?,
-        Opts::Expand { img, out } => expand(&img, &out)?,
-        Opts::Split { img, size, out } => split(&img, size, &out)?,
+        Opts::Expand {
+            img,
+            out,
+            verify,
+            lenient,
+        } => expand(&img, &out, verify, lenient)?,
+        Opts::Apply {
+            img,
+            device,
+            verify,
+            discard,
+        } => apply(&img, &device, verify, discard)?,
+        Opts::Encode {
+            raw,
+            out,
+            crc32,
+            checksum,
+        } => encode(&raw, &out, crc32, checksum)?,
+        Opts::Split {
+            img,
+            size,
+            out,
+            crc32,
+            checksum,
+            max_chunks,
+        } => split(&img, size, &out, crc32, checksum, max_chunks)?,
+        Opts::Diff { a, b } => compare(&a, &b)?,
+        Opts::Verify { img } => check(&img)?,
     }
 
     Ok(())
@@ -0,0 +1,24 @@
+#![no_main]
+
+use android_sparse_image::{ChunkHeader, ChunkHeaderBytes, FileHeader, CHUNK_HEADER_BYTES_LEN};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(bytes) = data
+        .get(..CHUNK_HEADER_BYTES_LEN)
+        .and_then(|bytes| ChunkHeaderBytes::try_from(bytes).ok())
+    else {
+        return;
+    };
+    if let Ok(chunk) = ChunkHeader::from_bytes(&bytes) {
+        assert_eq!(ChunkHeader::from_bytes(&chunk.to_bytes()).unwrap(), chunk);
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: u32::MAX,
+            chunks: 1,
+            checksum: 0,
+        };
+        let _ = chunk.out_size(&header);
+        let _ = chunk.data_size();
+    }
+});
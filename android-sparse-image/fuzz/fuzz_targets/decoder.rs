@@ -0,0 +1,33 @@
+#![no_main]
+
+use android_sparse_image::decoder::{Decoder, Event};
+use libfuzzer_sys::fuzz_target;
+
+// Decode `image` in pieces of `size` bytes; Returns the amount of raw data and whether the image
+// was valid
+fn decode(image: &[u8], size: usize) -> (usize, bool) {
+    let mut decoder = Decoder::new();
+    let mut data = 0;
+    for piece in image.chunks(size) {
+        let r = decoder.feed(piece, |event| {
+            if let Event::Data(d) = event {
+                data += d.len()
+            }
+        });
+        if r.is_err() {
+            return (data, false);
+        }
+    }
+    (data, decoder.finish().is_ok())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&size, image)) = data.split_first() else {
+        return;
+    };
+    // Decoding in pieces gives the same result as decoding at once
+    assert_eq!(
+        decode(image, usize::from(size).max(1)),
+        decode(image, image.len().max(1))
+    );
+});
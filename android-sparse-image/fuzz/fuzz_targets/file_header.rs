@@ -0,0 +1,17 @@
+#![no_main]
+
+use android_sparse_image::{FileHeader, FileHeaderBytes, FILE_HEADER_BYTES_LEN};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(bytes) = data
+        .get(..FILE_HEADER_BYTES_LEN)
+        .and_then(|bytes| FileHeaderBytes::try_from(bytes).ok())
+    else {
+        return;
+    };
+    if let Ok(header) = FileHeader::from_bytes(&bytes) {
+        let _ = header.total_size();
+        assert_eq!(FileHeader::from_bytes(&header.to_bytes()).unwrap(), header);
+    }
+});
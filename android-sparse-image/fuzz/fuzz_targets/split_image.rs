@@ -0,0 +1,33 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use android_sparse_image::{
+    reader::read_chunk_table,
+    split::{split_image, split_image_with_max_chunks},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 5 {
+        return;
+    }
+    let (params, image) = data.split_at(5);
+    let size = u32::from_le_bytes([params[0], params[1], params[2], params[3]]);
+    let max_chunks = (params[4] != 0).then_some(u32::from(params[4]));
+    let Ok((header, chunks, _)) = read_chunk_table(Cursor::new(image)) else {
+        return;
+    };
+    let splits = match max_chunks {
+        Some(max) => split_image_with_max_chunks(&header, &chunks, size, max),
+        None => split_image(&header, &chunks, size),
+    };
+    if let Ok(splits) = splits {
+        for split in splits {
+            assert!(split.sparse_size() <= u64::from(size));
+            if let Some(max) = max_chunks {
+                assert!(split.header.chunks <= max);
+            }
+        }
+    }
+});
@@ -0,0 +1,168 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::{
+    encode::EncodeError, ChunkHeader, FileHeader, CHUNK_HEADER_BYTES_LEN, DEFAULT_BLOCKSIZE,
+    FILE_HEADER_BYTES_LEN,
+};
+
+/// Builder constructing a sparse image from explicit raw data, fill and skip runs
+///
+/// Each call adds one or more chunks to the image written to the output; The file header is
+/// written as a placeholder first and updated with the final counts by
+/// [SparseImageBuilder::finish]. Useful to create test fixtures or to generate images from a
+/// description of their layout.
+///
+/// ```
+/// # use android_sparse_image::builder::SparseImageBuilder;
+/// let (header, image) = SparseImageBuilder::new(std::io::Cursor::new(vec![]))?
+///     .raw(b"bootloader")?
+///     .skip(16)?
+///     .fill([0xff; 4], 8)?
+///     .finish()?;
+/// assert_eq!(header.blocks, 25);
+/// assert_eq!(header.chunks, 3);
+/// # Ok::<(), android_sparse_image::encode::EncodeError>(())
+/// ```
+#[derive(Debug)]
+pub struct SparseImageBuilder<W> {
+    output: W,
+    // Output position of the file header
+    start: u64,
+    header: FileHeader,
+}
+
+impl<W: Write + Seek> SparseImageBuilder<W> {
+    /// Start an image using the [DEFAULT_BLOCKSIZE]
+    pub fn new(output: W) -> Result<Self, EncodeError> {
+        Self::with_block_size(output, DEFAULT_BLOCKSIZE)
+    }
+
+    /// Start an image with the given block size; Should be a multiple of 4
+    pub fn with_block_size(mut output: W, block_size: u32) -> Result<Self, EncodeError> {
+        if block_size == 0 || block_size % 4 != 0 {
+            return Err(EncodeError::InvalidBlockSize(block_size));
+        }
+        let start = output.stream_position()?;
+        output.write_all(&[0; FILE_HEADER_BYTES_LEN])?;
+        Ok(Self {
+            output,
+            start,
+            header: FileHeader {
+                block_size,
+                blocks: 0,
+                chunks: 0,
+                checksum: 0,
+            },
+        })
+    }
+
+    fn push(&mut self, chunk: &ChunkHeader, data: &[u8]) -> Result<(), EncodeError> {
+        self.header.blocks = self
+            .header
+            .blocks
+            .checked_add(chunk.chunk_size)
+            .ok_or(EncodeError::TooLarge)?;
+        self.header.chunks += 1;
+        self.output.write_all(&chunk.to_bytes())?;
+        self.output.write_all(data)?;
+        Ok(())
+    }
+
+    /// Add raw data; The last block gets padded with zeroes
+    pub fn raw(mut self, data: &[u8]) -> Result<Self, EncodeError> {
+        let block_size = self.header.block_size as usize;
+        // Largest amount of data fitting a single chunk
+        let max = (u32::MAX as usize - CHUNK_HEADER_BYTES_LEN) / block_size * block_size;
+        for data in data.chunks(max) {
+            let blocks = data.len().div_ceil(block_size);
+            self.push(
+                &ChunkHeader::new_raw(blocks as u32, block_size as u32),
+                data,
+            )?;
+            self.output
+                .write_all(&vec![0; blocks * block_size - data.len()])?;
+        }
+        Ok(self)
+    }
+
+    /// Add `blocks` blocks filled with the 4 byte pattern
+    pub fn fill(mut self, pattern: [u8; 4], blocks: u32) -> Result<Self, EncodeError> {
+        if blocks > 0 {
+            self.push(&ChunkHeader::new_fill(blocks), &pattern)?;
+        }
+        Ok(self)
+    }
+
+    /// Skip `blocks` blocks, leaving their content unspecified
+    pub fn skip(mut self, blocks: u32) -> Result<Self, EncodeError> {
+        if blocks > 0 {
+            self.push(&ChunkHeader::new_dontcare(blocks), &[])?;
+        }
+        Ok(self)
+    }
+
+    /// Complete the image, returning its header and the output
+    pub fn finish(mut self) -> Result<(FileHeader, W), EncodeError> {
+        let end = self.output.stream_position()?;
+        self.output.seek(SeekFrom::Start(self.start))?;
+        self.output.write_all(&self.header.to_bytes())?;
+        self.output.seek(SeekFrom::Start(end))?;
+        self.output.flush()?;
+        Ok((self.header, self.output))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{expand::expand, validate::validate};
+
+    #[test]
+    fn build() {
+        let (header, image) = SparseImageBuilder::with_block_size(Cursor::new(vec![]), 16)
+            .unwrap()
+            .raw(&[0xaa; 20])
+            .unwrap()
+            .fill([1, 2, 3, 4], 2)
+            .unwrap()
+            .fill([0; 4], 0)
+            .unwrap()
+            .skip(1)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(
+            header,
+            FileHeader {
+                block_size: 16,
+                blocks: 5,
+                chunks: 3,
+                checksum: 0
+            }
+        );
+        validate(
+            &header,
+            &[
+                ChunkHeader::new_raw(2, 16),
+                ChunkHeader::new_fill(2),
+                ChunkHeader::new_dontcare(1),
+            ],
+        )
+        .unwrap();
+
+        let mut expanded = vec![];
+        expand(Cursor::new(image.into_inner()), &mut expanded).unwrap();
+        let mut expected = vec![0xaa; 20];
+        expected.extend([0; 12]);
+        expected.extend([1, 2, 3, 4].repeat(8));
+        expected.extend([0; 16]);
+        assert_eq!(expanded, expected);
+
+        assert!(matches!(
+            SparseImageBuilder::with_block_size(Cursor::new(vec![]), 6),
+            Err(EncodeError::InvalidBlockSize(6))
+        ));
+    }
+}
@@ -0,0 +1,69 @@
+/// Running CRC32 over the expanded content of a sparse image
+///
+/// This is the checksum AOSP's libsparse stores in [crate::FileHeader::checksum] and Crc32
+/// chunks: A standard CRC32 over all expanded data, where DontCare chunks count as zeroes.
+#[derive(Clone, Debug, Default)]
+pub struct Checksum(crc32fast::Hasher);
+
+impl Checksum {
+    /// Start a new checksum
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Continue a checksum from its value, e.g. one stored in an image
+    pub fn resume(value: u32) -> Self {
+        Self(crc32fast::Hasher::new_with_initial(value))
+    }
+
+    /// Add data to the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Add `size` bytes repeating the 4 byte pattern to the checksum
+    pub fn update_fill(&mut self, pattern: [u8; 4], size: u64) {
+        let buf = pattern.repeat(1024);
+        let mut left = size;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            self.0.update(&buf[..n]);
+            left -= n as u64;
+        }
+    }
+
+    /// Checksum of the data added so far
+    pub fn value(&self) -> u32 {
+        self.0.clone().finalize()
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fill() {
+        let mut filled = Checksum::new();
+        filled.update_fill([1, 2, 3, 4], 10000);
+        let mut data = Checksum::new();
+        data.update(&[1, 2, 3, 4].repeat(2500));
+        assert_eq!(filled.value(), data.value());
+        // CRC32 of "123456789"
+        let mut check = Checksum::new();
+        check.update(b"123456789");
+        assert_eq!(check.value(), 0xcbf43926);
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{
+    encode::{Encoder, Run, RunKind},
+    expand::copy_raw,
+    reader::{ReadError, SparseReader},
+    ChunkData, ChunkHeader, FileHeader, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+
+// Output of the coalesced image, merging chunks into the current run where possible
+struct Runs<W> {
+    output: W,
+    encoder: Encoder,
+    // Kind and number of blocks of the current run
+    run: Option<(RunKind, u32)>,
+    // Output position of the header of the current raw run
+    raw_header: u64,
+    chunks: u32,
+}
+
+impl<W: Write + Seek> Runs<W> {
+    // Add a chunk of the given kind; The data of raw chunks has to be written after this
+    fn add(&mut self, kind: RunKind, blocks: u32) -> std::io::Result<()> {
+        match self.run {
+            Some((k, ref mut b)) if k == kind && blocks <= self.encoder.max_blocks(kind) - *b => {
+                *b += blocks
+            }
+            _ => {
+                self.finish_run()?;
+                if kind == RunKind::Raw {
+                    self.raw_header = self.output.stream_position()?;
+                    self.output.write_all(&[0; CHUNK_HEADER_BYTES_LEN])?;
+                }
+                self.run = Some((kind, blocks));
+            }
+        }
+        Ok(())
+    }
+
+    // Write the chunk header of the current run
+    fn finish_run(&mut self) -> std::io::Result<()> {
+        let Some((kind, blocks)) = self.run.take() else {
+            return Ok(());
+        };
+        let run = Run {
+            kind,
+            start: 0,
+            blocks,
+        };
+        let header = run.header(self.encoder.block_size).to_bytes();
+        match kind {
+            RunKind::Raw => {
+                let end = self.output.stream_position()?;
+                self.output.seek(SeekFrom::Start(self.raw_header))?;
+                self.output.write_all(&header)?;
+                self.output.seek(SeekFrom::Start(end))?;
+            }
+            RunKind::Fill(value) => {
+                self.output.write_all(&header)?;
+                self.output.write_all(&value)?;
+            }
+            RunKind::DontCare => self.output.write_all(&header)?,
+        }
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+/// Rewrite the sparse image read from `reader` to `output`, merging adjacent chunks of the same
+/// kind; Returns the header of the rewritten image and the output
+///
+/// Consecutive Raw chunks, Fill chunks with the same pattern and DontCare chunks each become a
+/// single chunk (as far as the chunk size allows), while chunks without any blocks are dropped.
+/// The expanded content of the image doesn't change, so Crc32 chunks and the header checksum are
+/// kept as is. Useful for images from tools fragmenting them into many tiny chunks, before
+/// splitting or flashing them.
+pub fn coalesce<R, W>(reader: R, mut output: W) -> Result<(FileHeader, W), ReadError>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    let mut reader = SparseReader::new(reader)?;
+    let header = reader.header().clone();
+    let start = output.stream_position()?;
+    output.write_all(&[0; FILE_HEADER_BYTES_LEN])?;
+
+    let mut runs = Runs {
+        output,
+        encoder: Encoder::new().block_size(header.block_size),
+        run: None,
+        raw_header: 0,
+        chunks: 0,
+    };
+    while let Some(chunk) = reader.next_chunk() {
+        let (chunk, data) = chunk?;
+        if chunk.chunk_size == 0 && !matches!(data, ChunkData::Crc32(_)) {
+            continue;
+        }
+        match data {
+            ChunkData::Raw(data) => {
+                runs.add(RunKind::Raw, chunk.chunk_size)?;
+                copy_raw(data, &mut runs.output, chunk.data_size() as u64, |_| ())?;
+            }
+            ChunkData::Fill(value) => runs.add(RunKind::Fill(value.0), chunk.chunk_size)?,
+            ChunkData::DontCare => runs.add(RunKind::DontCare, chunk.chunk_size)?,
+            ChunkData::Crc32(crc) => {
+                runs.finish_run()?;
+                runs.output
+                    .write_all(&ChunkHeader::new_crc32().to_bytes())?;
+                runs.output.write_all(&crc.to_le_bytes())?;
+                runs.chunks += 1;
+            }
+        }
+    }
+    runs.finish_run()?;
+
+    let header = FileHeader {
+        chunks: runs.chunks,
+        ..header
+    };
+    let mut output = runs.output;
+    let end = output.stream_position()?;
+    output.seek(SeekFrom::Start(start))?;
+    output.write_all(&header.to_bytes())?;
+    output.seek(SeekFrom::Start(end))?;
+    output.flush()?;
+    Ok((header, output))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{builder::SparseImageBuilder, diff::content_eq, DEFAULT_BLOCKSIZE};
+
+    #[test]
+    fn coalesce_chunks() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let data: Vec<u8> = (0..4 * block).map(|i| (i % 251) as u8).collect();
+        let mut builder = SparseImageBuilder::new(Cursor::new(vec![])).unwrap();
+        for data in data.chunks(block) {
+            builder = builder.raw(data).unwrap();
+        }
+        let (header, image) = builder
+            .fill([0x11; 4], 1)
+            .unwrap()
+            .fill([0x11; 4], 2)
+            .unwrap()
+            .fill([0x22; 4], 1)
+            .unwrap()
+            .skip(1)
+            .unwrap()
+            .skip(3)
+            .unwrap()
+            .raw(&data[..block])
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(header.chunks, 10);
+        let image = image.into_inner();
+
+        let (coalesced_header, coalesced) =
+            coalesce(Cursor::new(&image), Cursor::new(vec![])).unwrap();
+        let coalesced = coalesced.into_inner();
+        assert_eq!(
+            coalesced_header,
+            FileHeader {
+                chunks: 5,
+                ..header
+            }
+        );
+        let mut reader = SparseReader::new(Cursor::new(&coalesced)).unwrap();
+        assert_eq!(reader.header(), &coalesced_header);
+        let mut chunks = vec![];
+        while let Some(chunk) = reader.next_chunk() {
+            chunks.push(chunk.unwrap().0);
+        }
+        assert_eq!(
+            chunks,
+            [
+                ChunkHeader::new_raw(4, DEFAULT_BLOCKSIZE),
+                ChunkHeader::new_fill(3),
+                ChunkHeader::new_fill(1),
+                ChunkHeader::new_dontcare(4),
+                ChunkHeader::new_raw(1, DEFAULT_BLOCKSIZE),
+            ]
+        );
+        assert!(content_eq(Cursor::new(&image), Cursor::new(&coalesced)).unwrap());
+
+        // Truncated data of the final Raw chunk is an error rather than a short image
+        assert!(matches!(
+            coalesce(
+                Cursor::new(&image[..image.len() - 100]),
+                Cursor::new(vec![])
+            ),
+            Err(ReadError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{
+    limits::Limits, ChunkData, ChunkHeader, ChunkType, FileHeader, ParseError,
+    CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+
+/// Event emitted by a [Decoder]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// The file header of the image
+    Header(FileHeader),
+    /// Start of a chunk; Raw chunks are followed by [Event::Data] events with their data
+    Chunk(ChunkHeader, ChunkData<()>),
+    /// Part of the data of the current raw chunk
+    Data(&'a [u8]),
+    /// All chunks of the image were decoded
+    End,
+}
+
+// What the decoder expects next
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Header,
+    ChunkHeader,
+    // Fill pattern or checksum of the chunk
+    Value(ChunkHeader),
+    // Bytes of raw data left in the chunk
+    Data(u64),
+    End,
+    Done,
+}
+
+/// Push-based decoder for sparse images which are received in pieces of arbitrary size
+///
+/// The image is passed to the decoder as it arrives (e.g. from a socket or a pipe), which turns
+/// it into [Event]s. Only headers get buffered, while the data of raw chunks is passed on as
+/// slices of the input.
+///
+/// ```
+/// # use android_sparse_image::decoder::{Decoder, Event};
+/// # fn handle(_: &[u8]) {}
+/// # let pieces: [&[u8]; 0] = [];
+/// let mut decoder = Decoder::new();
+/// for piece in pieces {
+///     decoder.feed(piece, |event| {
+///         if let Event::Data(data) = event {
+///             handle(data)
+///         }
+///     })?;
+/// }
+/// # Ok::<(), android_sparse_image::ParseError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    state: State,
+    header: Option<FileHeader>,
+    buf: [u8; FILE_HEADER_BYTES_LEN],
+    // Bytes buffered for the header being decoded
+    filled: usize,
+    // Index and sparse image offset of the next chunk
+    index: u32,
+    position: u64,
+    // Offset in the expanded image after the current chunk
+    offset: u64,
+    limits: Limits,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    /// Create a decoder for a new image
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    /// Create a decoder for a new image, checking its file header is within `limits`
+    pub fn with_limits(limits: Limits) -> Self {
+        Self {
+            state: State::Header,
+            header: None,
+            buf: [0; FILE_HEADER_BYTES_LEN],
+            filled: 0,
+            index: 0,
+            position: FILE_HEADER_BYTES_LEN as u64,
+            offset: 0,
+            limits,
+        }
+    }
+
+    /// The file header of the image, once decoded
+    pub fn header(&self) -> Option<&FileHeader> {
+        self.header.as_ref()
+    }
+
+    /// Whether all chunks of the image were decoded
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Bytes of data of the current raw chunk which weren't decoded yet
+    pub fn data_left(&self) -> u64 {
+        match self.state {
+            State::Data(left) => left,
+            _ => 0,
+        }
+    }
+
+    /// Decode the next event from `input`; Returns the number of bytes consumed and the event
+    ///
+    /// No event is returned when all of the input was consumed without completing one, or once
+    /// decoding is done; Data after the last chunk is not consumed. After an error the decoder
+    /// should not be used anymore.
+    pub fn decode<'a>(
+        &mut self,
+        mut input: &'a [u8],
+    ) -> Result<(usize, Option<Event<'a>>), ParseError> {
+        let len = input.len();
+        loop {
+            match self.state {
+                State::Header => {
+                    if !self.fill(&mut input, FILE_HEADER_BYTES_LEN) {
+                        return Ok((len, None));
+                    }
+                    let header = FileHeader::from_bytes(&self.buf)?;
+                    self.limits.check(&header)?;
+                    self.header = Some(header.clone());
+                    self.next_chunk();
+                    return Ok((len - input.len(), Some(Event::Header(header))));
+                }
+                State::ChunkHeader => {
+                    if !self.fill(&mut input, CHUNK_HEADER_BYTES_LEN) {
+                        return Ok((len, None));
+                    }
+                    let chunk = self.chunk_header()?;
+                    let data = match chunk.chunk_type {
+                        ChunkType::Raw => {
+                            self.state = State::Data(chunk.data_size() as u64);
+                            ChunkData::Raw(())
+                        }
+                        ChunkType::DontCare => {
+                            self.next_chunk();
+                            ChunkData::DontCare
+                        }
+                        ChunkType::Fill | ChunkType::Crc32 => {
+                            self.state = State::Value(chunk);
+                            continue;
+                        }
+                    };
+                    return Ok((len - input.len(), Some(Event::Chunk(chunk, data))));
+                }
+                State::Value(ref chunk) => {
+                    let chunk = chunk.clone();
+                    if !self.fill(&mut input, 4) {
+                        return Ok((len, None));
+                    }
+                    let value = [self.buf[0], self.buf[1], self.buf[2], self.buf[3]];
+                    let data = ChunkData::from_value(chunk.chunk_type, value).unwrap();
+                    self.next_chunk();
+                    return Ok((len - input.len(), Some(Event::Chunk(chunk, data))));
+                }
+                State::Data(0) => self.next_chunk(),
+                State::Data(left) => {
+                    if input.is_empty() {
+                        return Ok((len, None));
+                    }
+                    let n = input.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+                    let (data, rest) = input.split_at(n);
+                    self.state = State::Data(left - n as u64);
+                    return Ok((len - rest.len(), Some(Event::Data(data))));
+                }
+                State::End => {
+                    self.state = State::Done;
+                    return Ok((len - input.len(), Some(Event::End)));
+                }
+                State::Done => return Ok((len - input.len(), None)),
+            }
+        }
+    }
+
+    /// Decode all of `input`, passing the events to `f`; Data after the last chunk is ignored
+    pub fn feed<F>(&mut self, mut input: &[u8], mut f: F) -> Result<(), ParseError>
+    where
+        F: FnMut(Event<'_>),
+    {
+        loop {
+            let (consumed, event) = self.decode(input)?;
+            input = &input[consumed..];
+            match event {
+                Some(event) => f(event),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Check the complete image was decoded, at the end of the input
+    pub fn finish(&self) -> Result<(), ParseError> {
+        match self.state {
+            State::Done => Ok(()),
+            _ => Err(ParseError::Truncated),
+        }
+    }
+
+    // Buffer input up to `len` bytes; Returns whether that many bytes are available
+    fn fill(&mut self, input: &mut &[u8], len: usize) -> bool {
+        let n = (len - self.filled).min(input.len());
+        self.buf[self.filled..self.filled + n].copy_from_slice(&input[..n]);
+        *input = &input[n..];
+        self.filled += n;
+        if self.filled < len {
+            return false;
+        }
+        self.filled = 0;
+        true
+    }
+
+    // Parse the buffered chunk header
+    fn chunk_header(&mut self) -> Result<ChunkHeader, ParseError> {
+        let header = self.header.as_ref().ok_or(ParseError::Truncated)?;
+        let mut bytes = [0; CHUNK_HEADER_BYTES_LEN];
+        bytes.copy_from_slice(&self.buf[..CHUNK_HEADER_BYTES_LEN]);
+        let (chunk, end) = ChunkHeader::from_bytes(&bytes)
+            .and_then(|chunk| chunk.check(header, self.offset).map(|end| (chunk, end)))
+            .map_err(|e| e.in_chunk(self.index, self.position))?;
+        self.index += 1;
+        self.position += chunk.total_size as u64;
+        self.offset = end;
+        Ok(chunk)
+    }
+
+    fn next_chunk(&mut self) {
+        let chunks = self.header.as_ref().map_or(0, |header| header.chunks);
+        self.state = if self.index < chunks {
+            State::ChunkHeader
+        } else {
+            State::End
+        };
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use proptest::{collection::vec, prelude::*, sample::Index};
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{encode::Encoder, reader::SparseReader, DEFAULT_BLOCKSIZE};
+
+    #[test]
+    fn decode_pieces() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([0x5a; 4].repeat(block / 4));
+        raw.extend(vec![0; 2 * block]);
+        raw.extend((0..block).map(|i| (i % 13) as u8));
+        let mut image = vec![];
+        Encoder::new()
+            .crc32_chunk(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        for size in [1, 7, 100, 5000, image.len()] {
+            let mut decoder = Decoder::new();
+            let mut expanded = vec![];
+            let mut events = vec![];
+            for piece in image.chunks(size) {
+                decoder
+                    .feed(piece, |event| match event {
+                        Event::Data(data) => expanded.extend_from_slice(data),
+                        Event::Chunk(chunk, data) => {
+                            if let ChunkData::Fill(value) = data {
+                                expanded
+                                    .extend(value.0.repeat(chunk.chunk_size as usize * block / 4));
+                            }
+                            events.push(Event::Chunk(chunk, data));
+                        }
+                        Event::Header(header) => events.push(Event::Header(header)),
+                        Event::End => events.push(Event::End),
+                    })
+                    .unwrap();
+            }
+            decoder.finish().unwrap();
+            assert_eq!(expanded, raw);
+            assert!(matches!(events[0], Event::Header(_)));
+            assert!(matches!(
+                events[events.len() - 2],
+                Event::Chunk(_, ChunkData::Crc32(_))
+            ));
+            assert_eq!(events.last(), Some(&Event::End));
+        }
+
+        // Truncated image and trailing data
+        let mut decoder = Decoder::new();
+        decoder.feed(&image[..image.len() - 2], |_| ()).unwrap();
+        assert!(matches!(decoder.finish(), Err(ParseError::Truncated)));
+        let mut decoder = Decoder::new();
+        let mut padded = image.clone();
+        padded.extend([0; 16]);
+        let (consumed, _) = decoder.decode(&padded).unwrap();
+        assert_eq!(consumed, FILE_HEADER_BYTES_LEN);
+        decoder.feed(&padded[consumed..], |_| ()).unwrap();
+        assert!(decoder.is_done());
+    }
+
+    // Decode `image` in pieces of `size` bytes; Returns the events without data, the concatenated
+    // raw data and the result of decoding
+    fn decode_in_pieces(
+        image: &[u8],
+        size: usize,
+    ) -> (Vec<Event<'static>>, Vec<u8>, Result<(), ParseError>) {
+        let mut decoder = Decoder::new();
+        let mut events = vec![];
+        let mut data = vec![];
+        for piece in image.chunks(size) {
+            let r = decoder.feed(piece, |event| match event {
+                Event::Data(d) => data.extend_from_slice(d),
+                Event::Header(header) => events.push(Event::Header(header)),
+                Event::Chunk(chunk, d) => events.push(Event::Chunk(chunk, d)),
+                Event::End => events.push(Event::End),
+            });
+            if r.is_err() {
+                return (events, data, r);
+            }
+        }
+        let r = decoder.finish();
+        (events, data, r)
+    }
+
+    proptest! {
+        #[test]
+        fn decode_corrupted(
+            mut raw in vec(any::<u8>(), 0..8192),
+            zeroes in 0..4096usize,
+            corruptions in vec((any::<Index>(), any::<u8>()), 0..4),
+            size in 1..1000usize,
+        ) {
+            raw.extend(vec![0; zeroes]);
+            let mut image = vec![];
+            Encoder::new()
+                .block_size(1024)
+                .encode(Cursor::new(&raw), &mut image)
+                .unwrap();
+            for (index, value) in corruptions {
+                let index = index.index(image.len());
+                image[index] = value;
+            }
+
+            // Decoding in pieces gives the same result as decoding at once
+            let (events, data, r) = decode_in_pieces(&image, image.len());
+            let (piece_events, piece_data, piece_r) = decode_in_pieces(&image, size);
+            prop_assert_eq!(events, piece_events);
+            prop_assert_eq!(data, piece_data);
+            prop_assert_eq!(r.is_ok(), piece_r.is_ok());
+
+            // The reader accepts whatever the decoder accepts
+            if r.is_ok() {
+                let reader = SparseReader::new(Cursor::new(&image)).unwrap();
+                prop_assert!(reader.expand(std::io::sink()).is_ok());
+            }
+        }
+    }
+}
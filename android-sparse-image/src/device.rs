@@ -0,0 +1,176 @@
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "unix"))]
+use std::os::fd::AsRawFd;
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use thiserror::Error;
+
+use crate::{
+    expand::{copy_raw, write_fill},
+    reader::{ReadError, SparseReader},
+    ChunkData,
+};
+
+/// Errors while writing a sparse image to a device
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("Device is too small for the image: {device} bytes, need {image}")]
+    TooSmall { device: u64, image: u64 },
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Writer applying sparse images to block devices (or existing files) in place
+///
+/// Like simg2img followed by a copy to the device, but without an intermediate raw image; Raw and
+/// Fill chunks are written at their offsets, while the ranges of DontCare chunks are skipped and
+/// keep their previous content. Unlike [crate::expand::expand_file] the device isn't truncated
+/// nor resized, as that's not possible for block devices.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceWriter {
+    discard: bool,
+}
+
+impl DeviceWriter {
+    /// Create a new writer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discard the ranges of DontCare chunks (using `BLKDISCARD`) rather than leaving their
+    /// previous content; Disabled by default
+    ///
+    /// Only supported on Linux with the `unix` feature enabled; Discarding is skipped on devices
+    /// which don't support it. Note that discarded ranges aren't guaranteed to read back as
+    /// zeroes.
+    pub fn discard(mut self, discard: bool) -> Self {
+        self.discard = discard;
+        self
+    }
+
+    /// Write the remaining chunks of the image read by `reader` to `device`; Returns the size of
+    /// the expanded image
+    ///
+    /// The device has to be at least as large as the expanded image. All data is synced to the
+    /// device before returning.
+    pub fn write<R: Read>(
+        &self,
+        mut reader: SparseReader<R>,
+        mut device: &File,
+    ) -> Result<u64, DeviceError> {
+        let header = reader.header().clone();
+        let available = device.seek(SeekFrom::End(0))?;
+        if available < header.total_size() {
+            return Err(DeviceError::TooSmall {
+                device: available,
+                image: header.total_size(),
+            });
+        }
+
+        let mut progress = reader.take_progress();
+        let mut writer = BufWriter::new(device);
+        let mut offset = 0;
+        while let Some(chunk) = reader.next_chunk() {
+            let (chunk, data) = chunk?;
+            let size = chunk.out_size(&header);
+            match data {
+                ChunkData::Raw(data) => {
+                    writer.seek(SeekFrom::Start(offset))?;
+                    copy_raw(data, &mut writer, size, |copied| progress(offset + copied))?;
+                }
+                ChunkData::Fill(value) => {
+                    writer.seek(SeekFrom::Start(offset))?;
+                    write_fill(&mut writer, value.0, size)?;
+                }
+                ChunkData::DontCare if self.discard => discard(device, offset, size)?,
+                ChunkData::DontCare => (),
+                ChunkData::Crc32(_) => continue,
+            }
+            offset += size;
+            progress(offset);
+        }
+        writer.flush()?;
+        device.sync_data()?;
+        Ok(offset)
+    }
+}
+
+// Discard a range of a block device; Devices (or files) not supporting it are left as is
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "unix"))]
+fn discard(device: &File, offset: u64, size: u64) -> std::io::Result<()> {
+    // _IO(0x12, 119) from linux/fs.h
+    const BLKDISCARD: libc::Ioctl = 0x1277;
+    let range = [offset, size];
+    // SAFETY: BLKDISCARD only reads the range from the passed array
+    let r = unsafe { libc::ioctl(device.as_raw_fd(), BLKDISCARD, range.as_ptr()) };
+    if r < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => Ok(()),
+            _ => Err(e),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(not(all(any(target_os = "linux", target_os = "android"), feature = "unix")))]
+fn discard(_device: &File, _offset: u64, _size: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        encode::{Encoder, ZeroBlocks},
+        DEFAULT_BLOCKSIZE,
+    };
+
+    #[test]
+    fn write_device() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw = vec![0; 2 * block];
+        raw.extend((0..block).map(|i| (i % 251) as u8));
+        raw.extend([0x11; 4].repeat(block / 4));
+        raw.extend(vec![0; 2 * block]);
+        raw.extend((0..block).map(|i| (i % 13) as u8));
+
+        let mut image = vec![];
+        Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("device-{}", std::process::id()));
+        let write = |size: usize| {
+            std::fs::write(&path, vec![0xaa; size]).unwrap();
+            let device = File::options().write(true).open(&path).unwrap();
+            let reader = SparseReader::new(Cursor::new(&image)).unwrap();
+            DeviceWriter::new().discard(true).write(reader, &device)
+        };
+
+        // Too small
+        assert!(matches!(
+            write(4 * block),
+            Err(DeviceError::TooSmall { device, image })
+                if device == 4 * block as u64 && image == raw.len() as u64
+        ));
+
+        // DontCare ranges and content beyond the image keep their content, as files can't be
+        // discarded
+        let size = write(8 * block).unwrap();
+        assert_eq!(size, raw.len() as u64);
+        let mut expected = raw.clone();
+        expected[..2 * block].fill(0xaa);
+        expected[4 * block..6 * block].fill(0xaa);
+        expected.extend(vec![0xaa; block]);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+use std::{io::Read, ops::Range};
+
+use thiserror::Error;
+
+use crate::{
+    reader::{ReadError, SparseReader},
+    ChunkData,
+};
+
+/// Errors while comparing sparse images
+#[derive(Debug, Error)]
+pub enum DiffError {
+    #[error("Images have different block sizes: {0} and {1}")]
+    BlockSize(u32, u32),
+    #[error(transparent)]
+    Read(#[from] ReadError),
+}
+
+// Content of the current chunk of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Content {
+    Raw,
+    Fill([u8; 4]),
+    DontCare,
+}
+
+impl Content {
+    // Content with DontCare expanded to zeroes
+    fn expanded(self) -> Self {
+        match self {
+            Content::DontCare => Content::Fill([0; 4]),
+            content => content,
+        }
+    }
+}
+
+// Image being compared, read block by block
+struct Side<R> {
+    reader: SparseReader<R>,
+    content: Content,
+    // Blocks of the current chunk left to be compared
+    left: u64,
+}
+
+impl<R: Read> Side<R> {
+    fn new(reader: R) -> Result<Self, ReadError> {
+        Ok(Self {
+            reader: SparseReader::new(reader)?,
+            content: Content::DontCare,
+            left: 0,
+        })
+    }
+
+    // Content and blocks left of the current chunk; None after the last chunk
+    fn current(&mut self) -> Result<Option<(Content, u64)>, ReadError> {
+        while self.left == 0 {
+            let Some(chunk) = self.reader.next_chunk() else {
+                return Ok(None);
+            };
+            let (chunk, data) = chunk?;
+            self.left = u64::from(chunk.chunk_size);
+            self.content = match data {
+                ChunkData::Raw(_) => Content::Raw,
+                ChunkData::Fill(value) => Content::Fill(value.0),
+                ChunkData::DontCare | ChunkData::Crc32(_) => Content::DontCare,
+            };
+        }
+        Ok(Some((self.content, self.left)))
+    }
+
+    // Read the next block of the current chunk
+    fn read_block(&mut self, buf: &mut [u8]) -> Result<(), ReadError> {
+        match self.content {
+            Content::Raw => self.reader.raw_data().read_exact(buf)?,
+            Content::Fill(pattern) => buf
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b = pattern[i % 4]),
+            Content::DontCare => buf.fill(0),
+        }
+        self.left -= 1;
+        Ok(())
+    }
+
+    // Skip blocks of the current chunk
+    fn skip(&mut self, blocks: u64) -> Result<(), ReadError> {
+        if self.content == Content::Raw {
+            let size = blocks * u64::from(self.reader.header().block_size);
+            let skipped =
+                std::io::copy(&mut self.reader.raw_data().take(size), &mut std::io::sink())?;
+            if skipped != size {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        self.left -= blocks;
+        Ok(())
+    }
+
+    // Consume the rest of the current chunk; Returns whether it expands to zeroes
+    fn zeroes(&mut self, buf: &mut [u8]) -> Result<bool, ReadError> {
+        match self.content {
+            Content::Raw => {
+                while self.left > 0 {
+                    self.read_block(buf)?;
+                    if buf.iter().any(|&b| b != 0) {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            content => {
+                self.skip(self.left)?;
+                Ok(matches!(
+                    content,
+                    Content::Fill([0, 0, 0, 0]) | Content::DontCare
+                ))
+            }
+        }
+    }
+}
+
+// Open both images, which need to have the same block size
+fn sides<A: Read, B: Read>(a: A, b: B) -> Result<(Side<A>, Side<B>), DiffError> {
+    let a = Side::new(a)?;
+    let b = Side::new(b)?;
+    let block_size = a.reader.header().block_size;
+    if block_size != b.reader.header().block_size {
+        return Err(DiffError::BlockSize(
+            block_size,
+            b.reader.header().block_size,
+        ));
+    }
+    Ok((a, b))
+}
+
+// Add a range of differing blocks, merging it with the previous one if adjacent
+fn push(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+/// Compare the expanded content of two sparse images, returning the ranges of blocks which differ
+///
+/// DontCare chunks match any content and Fill chunks are compared by the data they expand to, so
+/// images encoded differently but with the same content have no differences. Blocks beyond the
+/// end of the shorter image differ, unless they are DontCare in the longer one. Both images need
+/// to have the same block size.
+pub fn diff<A: Read, B: Read>(a: A, b: B) -> Result<Vec<Range<u64>>, DiffError> {
+    let (mut a, mut b) = sides(a, b)?;
+    let block_size = a.reader.header().block_size;
+    let mut ranges = vec![];
+    let mut block = 0;
+    let mut buf_a = vec![0; block_size as usize];
+    let mut buf_b = vec![0; block_size as usize];
+    loop {
+        let blocks = match (a.current()?, b.current()?) {
+            (None, None) => break,
+            (Some((Content::DontCare, n)), None) | (None, Some((Content::DontCare, n))) => n,
+            (Some((_, n)), None) | (None, Some((_, n))) => {
+                push(&mut ranges, block..block + n);
+                n
+            }
+            (Some((content_a, n_a)), Some((content_b, n_b))) => {
+                let n = n_a.min(n_b);
+                match (content_a, content_b) {
+                    (Content::DontCare, _) | (_, Content::DontCare) => (),
+                    (Content::Fill(pattern_a), Content::Fill(pattern_b)) => {
+                        if pattern_a != pattern_b {
+                            push(&mut ranges, block..block + n);
+                        }
+                    }
+                    _ => {
+                        for i in 0..n {
+                            a.read_block(&mut buf_a)?;
+                            b.read_block(&mut buf_b)?;
+                            if buf_a != buf_b {
+                                push(&mut ranges, block + i..block + i + 1);
+                            }
+                        }
+                        block += n;
+                        continue;
+                    }
+                }
+                n
+            }
+        };
+        if a.left > 0 {
+            a.skip(blocks.min(a.left))?;
+        }
+        if b.left > 0 {
+            b.skip(blocks.min(b.left))?;
+        }
+        block += blocks;
+    }
+    Ok(ranges)
+}
+
+/// Check whether two sparse images expand to the same bytes
+///
+/// Unlike [diff], DontCare chunks and blocks not covered by any chunk are taken to expand to
+/// zeroes, as when written out by simg2img, so the images match regardless of how they are
+/// chunked; e.g. a Fill chunk of zeroes equals Raw zeroes or a DontCare chunk. Images with a
+/// different expanded size are never equal. Both images need to have the same block size.
+pub fn content_eq<A: Read, B: Read>(a: A, b: B) -> Result<bool, DiffError> {
+    let (mut a, mut b) = sides(a, b)?;
+    if a.reader.header().total_size() != b.reader.header().total_size() {
+        return Ok(false);
+    }
+
+    let block_size = a.reader.header().block_size as usize;
+    let mut buf_a = vec![0; block_size];
+    let mut buf_b = vec![0; block_size];
+    loop {
+        let equal = match (a.current()?, b.current()?) {
+            (None, None) => return Ok(true),
+            (Some(_), None) => a.zeroes(&mut buf_a)?,
+            (None, Some(_)) => b.zeroes(&mut buf_b)?,
+            (Some((content_a, n_a)), Some((content_b, n_b))) => {
+                let n = n_a.min(n_b);
+                match (content_a.expanded(), content_b.expanded()) {
+                    (Content::Fill(pattern_a), Content::Fill(pattern_b)) => {
+                        a.skip(n)?;
+                        b.skip(n)?;
+                        pattern_a == pattern_b
+                    }
+                    _ => {
+                        for _ in 0..n {
+                            a.read_block(&mut buf_a)?;
+                            b.read_block(&mut buf_b)?;
+                            if buf_a != buf_b {
+                                return Ok(false);
+                            }
+                        }
+                        true
+                    }
+                }
+            }
+        };
+        if !equal {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{builder::SparseImageBuilder, encode::EncodeError, DEFAULT_BLOCKSIZE};
+
+    #[test]
+    fn diff_images() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let data: Vec<u8> = (0..4 * block).map(|i| (i % 251) as u8).collect();
+        let (_, a) = SparseImageBuilder::new(Cursor::new(vec![]))
+            .unwrap()
+            .raw(&data)
+            .unwrap()
+            .fill([0x11; 4], 2)
+            .unwrap()
+            .skip(2)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        // Same content, encoded differently
+        let mut expanded = data.clone();
+        expanded.extend([0x11; 4].repeat(block / 4));
+        let (_, same) = SparseImageBuilder::new(Cursor::new(vec![]))
+            .unwrap()
+            .raw(&expanded)
+            .unwrap()
+            .fill([0x11; 4], 1)
+            .unwrap()
+            .raw(&[0x42; 8192])
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(
+            diff(Cursor::new(a.get_ref()), Cursor::new(same.get_ref())).unwrap(),
+            []
+        );
+
+        // Changed blocks, a different fill and additional blocks
+        let mut changed = data.clone();
+        changed[block + 7] ^= 0xff;
+        changed[2 * block] ^= 0xff;
+        let (_, changed) = SparseImageBuilder::new(Cursor::new(vec![]))
+            .unwrap()
+            .raw(&changed)
+            .unwrap()
+            .fill([0x11; 4], 1)
+            .unwrap()
+            .fill([0x22; 4], 1)
+            .unwrap()
+            .skip(2)
+            .unwrap()
+            .raw(&[0x42; 4096])
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(
+            diff(Cursor::new(a.get_ref()), Cursor::new(changed.get_ref())).unwrap(),
+            [1..3, 5..6, 8..9]
+        );
+    }
+
+    fn build(
+        steps: impl FnOnce(
+            SparseImageBuilder<Cursor<Vec<u8>>>,
+        ) -> Result<SparseImageBuilder<Cursor<Vec<u8>>>, EncodeError>,
+    ) -> Vec<u8> {
+        let builder = SparseImageBuilder::new(Cursor::new(vec![])).unwrap();
+        let (_, image) = steps(builder).unwrap().finish().unwrap();
+        image.into_inner()
+    }
+
+    #[test]
+    fn content_equal() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let data: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+        let a = build(|b| b.raw(&data)?.fill([0; 4], 2)?.fill([0x11; 4], 2));
+
+        // Zeroes as Raw, DontCare and Fill chunks, with different chunk boundaries
+        let mut expanded = data[..2 * block].to_vec();
+        let b = build(|b| {
+            b.raw(&expanded)?
+                .raw(&data[2 * block..])?
+                .raw(&vec![0; block])?
+                .skip(1)?
+                .fill([0x11; 4], 1)?
+                .raw(&[0x11; 4096])
+        });
+        assert!(content_eq(Cursor::new(&a), Cursor::new(&b)).unwrap());
+
+        // Different content or size
+        expanded[block] ^= 0xff;
+        let changed = build(|b| {
+            b.raw(&expanded)?
+                .raw(&data[2 * block..])?
+                .skip(2)?
+                .fill([0x11; 4], 2)
+        });
+        assert!(!content_eq(Cursor::new(&a), Cursor::new(&changed)).unwrap());
+        let longer = build(|b| b.raw(&data)?.skip(2)?.fill([0x11; 4], 2)?.skip(1));
+        assert!(!content_eq(Cursor::new(&a), Cursor::new(&longer)).unwrap());
+    }
+}
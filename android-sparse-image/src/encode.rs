@@ -0,0 +1,964 @@
+#[cfg(all(unix, feature = "unix"))]
+use std::os::fd::{AsFd, AsRawFd};
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::{
+    checksum::Checksum, ChunkHeader, FileHeader, CHUNK_HEADER_BYTES_LEN, DEFAULT_BLOCKSIZE,
+    FILE_HEADER_BYTES_LEN,
+};
+
+/// Errors while encoding a sparse image
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("Block size {0} is not a non-zero multiple of 4")]
+    InvalidBlockSize(u32),
+    #[error("Image has too many blocks for a sparse image")]
+    TooLarge,
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// Content of a run of blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RunKind {
+    Raw,
+    Fill([u8; 4]),
+    DontCare,
+}
+
+// Run of consecutive blocks which end up in a single chunk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Run {
+    pub(crate) kind: RunKind,
+    // First block of the run in the input
+    pub(crate) start: u32,
+    pub(crate) blocks: u32,
+}
+
+impl Run {
+    pub(crate) fn header(&self, block_size: u32) -> ChunkHeader {
+        match self.kind {
+            RunKind::Raw => ChunkHeader::new_raw(self.blocks, block_size),
+            RunKind::Fill(_) => ChunkHeader::new_fill(self.blocks),
+            RunKind::DontCare => ChunkHeader::new_dontcare(self.blocks),
+        }
+    }
+}
+
+// Classify the content of a single block; Blocks repeating a single 4 byte pattern can be filled,
+// all-zero blocks become the given kind
+fn classify(block: &[u8], zero: RunKind) -> RunKind {
+    let (pattern, rest) = block.split_at(4);
+    if rest.chunks_exact(4).all(|c| c == pattern) {
+        if pattern == [0; 4] {
+            zero
+        } else {
+            RunKind::Fill(pattern.try_into().unwrap())
+        }
+    } else {
+        RunKind::Raw
+    }
+}
+
+// Fill the buffer from the reader, padding with zeroes at the end of the input; Returns the number
+// of bytes read
+pub(crate) fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf[read..].fill(0);
+    Ok(read)
+}
+
+/// How blocks containing only zeroes are encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ZeroBlocks {
+    /// Fill chunks with zeroes, explicitly zeroing the blocks on the device
+    #[default]
+    Fill,
+    /// DontCare chunks, leaving whatever was on the device before in place; Only correct if the
+    /// partition gets erased before flashing, but faster to flash
+    DontCare,
+}
+
+/// Encoder converting raw images into android sparse images, like AOSP's img2simg
+///
+/// The raw image is scanned block by block; Consecutive blocks consisting of the same repeated 4
+/// byte pattern (e.g. zeroes) are gathered into Fill chunks, other blocks into Raw chunks. If the
+/// image size isn't a multiple of the block size the last block gets padded with zeroes.
+#[derive(Clone, Debug)]
+pub struct Encoder {
+    pub(crate) block_size: u32,
+    zero_blocks: ZeroBlocks,
+    crc32_chunk: bool,
+    header_checksum: bool,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCKSIZE,
+            zero_blocks: ZeroBlocks::default(),
+            crc32_chunk: false,
+            header_checksum: false,
+        }
+    }
+}
+
+impl Encoder {
+    /// Create an encoder using the [DEFAULT_BLOCKSIZE]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the block size of the generated image; Should be a multiple of 4
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set how blocks containing only zeroes are encoded; Zeroes are filled by default
+    pub fn zero_blocks(mut self, zero_blocks: ZeroBlocks) -> Self {
+        self.zero_blocks = zero_blocks;
+        self
+    }
+
+    /// Append a Crc32 chunk with the checksum of the image data; Disabled by default
+    ///
+    /// Lets stock tooling and bootloaders validating the checksum verify the image.
+    pub fn crc32_chunk(mut self, crc32_chunk: bool) -> Self {
+        self.crc32_chunk = crc32_chunk;
+        self
+    }
+
+    /// Fill in [FileHeader::checksum] with the checksum of the image data; Disabled by default
+    pub fn header_checksum(mut self, header_checksum: bool) -> Self {
+        self.header_checksum = header_checksum;
+        self
+    }
+
+    fn check_block_size(&self) -> Result<(), EncodeError> {
+        if self.block_size == 0 || self.block_size % 4 != 0 {
+            return Err(EncodeError::InvalidBlockSize(self.block_size));
+        }
+        Ok(())
+    }
+
+    // Kind of runs of zero blocks
+    fn zero_kind(&self) -> RunKind {
+        match self.zero_blocks {
+            ZeroBlocks::Fill => RunKind::Fill([0; 4]),
+            ZeroBlocks::DontCare => RunKind::DontCare,
+        }
+    }
+
+    // Largest number of blocks in a single chunk of the given kind
+    pub(crate) fn max_blocks(&self, kind: RunKind) -> u32 {
+        match kind {
+            RunKind::Raw => (u32::MAX - CHUNK_HEADER_BYTES_LEN as u32) / self.block_size,
+            RunKind::Fill(_) | RunKind::DontCare => u32::MAX,
+        }
+    }
+
+    // Add `blocks` blocks of the given kind starting at block `start` to the runs
+    fn push(&self, runs: &mut Vec<Run>, kind: RunKind, mut start: u32, mut blocks: u32) {
+        while blocks > 0 {
+            let added = match runs.last_mut() {
+                Some(run) if run.kind == kind && run.blocks < self.max_blocks(kind) => {
+                    let added = blocks.min(self.max_blocks(kind) - run.blocks);
+                    run.blocks += added;
+                    added
+                }
+                _ => {
+                    let added = blocks.min(self.max_blocks(kind));
+                    runs.push(Run {
+                        kind,
+                        start,
+                        blocks: added,
+                    });
+                    added
+                }
+            };
+            start += added;
+            blocks -= added;
+        }
+    }
+
+    // Start scanning an image, see [Scanner]
+    pub(crate) fn scanner(&self) -> Result<Scanner<'_>, EncodeError> {
+        self.check_block_size()?;
+        Ok(Scanner {
+            encoder: self,
+            runs: vec![],
+            index: 0,
+            checksum: self.header_checksum.then(Checksum::new),
+        })
+    }
+
+    // Scan the input until its end or block `end`, gathering runs of blocks with the same content
+    fn scan<R: Read>(
+        &self,
+        input: &mut R,
+        scanner: &mut Scanner,
+        end: Option<u32>,
+    ) -> Result<(), EncodeError> {
+        let mut block = vec![0; self.block_size as usize];
+        while end.is_none_or(|end| scanner.index < end) && read_block(input, &mut block)? > 0 {
+            scanner.block(&block)?;
+        }
+        Ok(())
+    }
+
+    // Scan a file, turning holes into zero runs without reading them
+    #[cfg(all(unix, feature = "unix"))]
+    fn scan_holes<R>(&self, input: &mut R, scanner: &mut Scanner) -> Result<(), EncodeError>
+    where
+        R: Read + Seek + AsFd,
+    {
+        let block_size = self.block_size as u64;
+        let size = input.seek(SeekFrom::End(0))?;
+        let blocks: u32 = size
+            .div_ceil(block_size)
+            .try_into()
+            .map_err(|_| EncodeError::TooLarge)?;
+
+        let mut index = 0;
+        while index < blocks {
+            let offset = index as u64 * block_size;
+            let (data, hole) = match data_range(input, offset)? {
+                Some(range) => range,
+                None => (size, size),
+            };
+            // Blocks partially covered by data are read
+            let first = (data / block_size) as u32;
+            let last = hole.div_ceil(block_size).min(blocks as u64) as u32;
+            if first > index {
+                scanner.zeroes(first - index);
+            }
+            if last > first {
+                input.seek(SeekFrom::Start(first as u64 * block_size))?;
+                self.scan(input, scanner, Some(last))?;
+            }
+            index = last.max(first);
+        }
+        Ok(())
+    }
+
+    /// Encode the raw image read from `input` into a sparse image written to `output`, returning
+    /// the header of the sparse image
+    ///
+    /// The input is read twice; Once to determine the chunks and once more to copy the raw data.
+    pub fn encode<R, W>(&self, mut input: R, output: W) -> Result<FileHeader, EncodeError>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        let mut scanner = self.scanner()?;
+        input.seek(SeekFrom::Start(0))?;
+        self.scan(&mut input, &mut scanner, None)?;
+        write(scanner.finish(), input, output)
+    }
+
+    /// Encode the raw image file `input` into a sparse image written to `output`, returning the
+    /// header of the sparse image
+    ///
+    /// Unlike [Encoder::encode] holes in the file (unallocated ranges on filesystems supporting
+    /// sparse files) are found with `SEEK_HOLE`/`SEEK_DATA` and encoded as zero blocks (see
+    /// [Encoder::zero_blocks]) without being read, making encoding of mostly empty images nearly
+    /// instant. Falls back to reading everything if the filesystem doesn't support finding holes.
+    #[cfg(all(unix, feature = "unix"))]
+    pub fn encode_file<R, W>(&self, mut input: R, output: W) -> Result<FileHeader, EncodeError>
+    where
+        R: Read + Seek + AsFd,
+        W: Write,
+    {
+        let mut scanner = self.scanner()?;
+        match self.scan_holes(&mut input, &mut scanner) {
+            Err(EncodeError::Io(e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+                scanner = self.scanner()?;
+                input.seek(SeekFrom::Start(0))?;
+                self.scan(&mut input, &mut scanner, None)?;
+            }
+            r => r?,
+        }
+        write(scanner.finish(), input, output)
+    }
+
+    /// Create a [SparseWriter] encoding the raw data written to it into a sparse image written to
+    /// `output`
+    pub fn writer<W>(&self, mut output: W) -> Result<SparseWriter<W>, EncodeError>
+    where
+        W: Write + Seek,
+    {
+        self.check_block_size()?;
+        let start = output.stream_position()?;
+        // Placeholder, written once the number of blocks and chunks is known
+        output.write_all(&[0; FILE_HEADER_BYTES_LEN])?;
+        Ok(SparseWriter {
+            output,
+            encoder: self.clone(),
+            block: vec![0; self.block_size as usize],
+            filled: 0,
+            run: None,
+            start,
+            raw_header: 0,
+            blocks: 0,
+            chunks: 0,
+            checksum: (self.crc32_chunk || self.header_checksum).then(Checksum::new),
+        })
+    }
+}
+
+// Runs of blocks with the same content gathered while scanning the input block by block, along
+// with the checksum of the data for the file header if requested; Shared by the sync and async
+// encoders, which only differ in how they do IO
+pub(crate) struct Scanner<'a> {
+    encoder: &'a Encoder,
+    runs: Vec<Run>,
+    // Index of the next block
+    index: u32,
+    checksum: Option<Checksum>,
+}
+
+impl<'a> Scanner<'a> {
+    // Add the next block of the input
+    pub(crate) fn block(&mut self, block: &[u8]) -> Result<(), EncodeError> {
+        let kind = classify(block, self.encoder.zero_kind());
+        self.encoder.push(&mut self.runs, kind, self.index, 1);
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(block);
+        }
+        self.index = self.index.checked_add(1).ok_or(EncodeError::TooLarge)?;
+        Ok(())
+    }
+
+    // Add `blocks` zero blocks without reading them
+    #[cfg(all(unix, feature = "unix"))]
+    fn zeroes(&mut self, blocks: u32) {
+        let kind = self.encoder.zero_kind();
+        self.encoder.push(&mut self.runs, kind, self.index, blocks);
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update_fill([0; 4], blocks as u64 * self.encoder.block_size as u64);
+        }
+        self.index += blocks;
+    }
+
+    // Finish scanning, turning the runs into the chunks of the image
+    pub(crate) fn finish(self) -> Chunks<'a> {
+        let encoder = self.encoder;
+        let header = FileHeader {
+            block_size: encoder.block_size,
+            blocks: self.runs.iter().map(|r| r.blocks).sum(),
+            chunks: self.runs.len() as u32 + u32::from(encoder.crc32_chunk),
+            checksum: self.checksum.as_ref().map_or(0, Checksum::value),
+        };
+        Chunks {
+            encoder,
+            header,
+            runs: self.runs.into_iter(),
+            checksum: encoder.crc32_chunk.then(Checksum::new),
+        }
+    }
+}
+
+// Input offset and number of blocks of the raw data of a chunk
+type RawBlocks = (u64, u32);
+
+// Chunks of an encoded image, produced from the runs of a [Scanner]
+//
+// The raw data of each chunk has to be passed to [Chunks::raw_block] as it's written, for the
+// checksum of the Crc32 chunk.
+pub(crate) struct Chunks<'a> {
+    encoder: &'a Encoder,
+    header: FileHeader,
+    runs: std::vec::IntoIter<Run>,
+    checksum: Option<Checksum>,
+}
+
+impl Chunks<'_> {
+    // The file header of the image
+    pub(crate) fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    // The bytes of the next chunk, along with the input offset and number of blocks of the raw
+    // data following them
+    pub(crate) fn next_chunk(&mut self) -> Option<(Vec<u8>, Option<RawBlocks>)> {
+        let run = self.runs.next()?;
+        let block_size = self.encoder.block_size as u64;
+        let mut bytes = run.header(self.encoder.block_size).to_bytes().to_vec();
+        let size = run.blocks as u64 * block_size;
+        let pattern = match run.kind {
+            RunKind::Raw => {
+                return Some((bytes, Some((run.start as u64 * block_size, run.blocks))))
+            }
+            RunKind::Fill(value) => {
+                bytes.extend_from_slice(&value);
+                value
+            }
+            RunKind::DontCare => [0; 4],
+        };
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update_fill(pattern, size);
+        }
+        Some((bytes, None))
+    }
+
+    // Account for a (padded) block of raw data written
+    pub(crate) fn raw_block(&mut self, block: &[u8]) {
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(block);
+        }
+    }
+
+    // The bytes of the final Crc32 chunk, if requested
+    pub(crate) fn finish(self) -> Option<Vec<u8>> {
+        self.checksum.as_ref().map(crc32_chunk)
+    }
+}
+
+// Write the image consisting of the given chunks, taking raw data from the input
+fn write<R, W>(mut chunks: Chunks, mut input: R, mut output: W) -> Result<FileHeader, EncodeError>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let header = chunks.header().clone();
+    output.write_all(&header.to_bytes())?;
+    let mut block = vec![0; header.block_size as usize];
+    while let Some((bytes, raw)) = chunks.next_chunk() {
+        output.write_all(&bytes)?;
+        if let Some((offset, blocks)) = raw {
+            input.seek(SeekFrom::Start(offset))?;
+            // The last block gets padded
+            for _ in 0..blocks {
+                read_block(&mut input, &mut block)?;
+                output.write_all(&block)?;
+                chunks.raw_block(&block);
+            }
+        }
+    }
+    if let Some(bytes) = chunks.finish() {
+        output.write_all(&bytes)?;
+    }
+    output.flush()?;
+    Ok(header)
+}
+
+/// Writer encoding the raw data written to it into a sparse image on the fly
+///
+/// Data is classified a block at a time as it's written, so raw images can be converted without
+/// storing them first (e.g. when they're piped in). As the file and raw chunk headers are only
+/// known once their data has been written, the output has to be seekable; The headers are written
+/// as placeholders and updated later. Created by [Encoder::writer].
+///
+/// [SparseWriter::finish] has to be called after writing all data to complete the image.
+#[derive(Debug)]
+pub struct SparseWriter<W> {
+    output: W,
+    encoder: Encoder,
+    // Partially written block
+    block: Vec<u8>,
+    filled: usize,
+    // Kind and number of blocks of the current run
+    run: Option<(RunKind, u32)>,
+    // Output position of the file header
+    start: u64,
+    // Output position of the header of the current raw run
+    raw_header: u64,
+    blocks: u32,
+    chunks: u32,
+    // Checksum of the data, if a Crc32 chunk or header checksum gets written
+    checksum: Option<Checksum>,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    // Add the buffered block to the image
+    fn push_block(&mut self) -> Result<(), EncodeError> {
+        let kind = classify(&self.block, self.encoder.zero_kind());
+        self.blocks = self.blocks.checked_add(1).ok_or(EncodeError::TooLarge)?;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(&self.block);
+        }
+        match self.run {
+            Some((k, ref mut blocks)) if k == kind && *blocks < self.encoder.max_blocks(kind) => {
+                *blocks += 1
+            }
+            _ => {
+                self.finish_run()?;
+                if kind == RunKind::Raw {
+                    self.raw_header = self.output.stream_position()?;
+                    self.output.write_all(&[0; CHUNK_HEADER_BYTES_LEN])?;
+                }
+                self.run = Some((kind, 1));
+            }
+        }
+        if kind == RunKind::Raw {
+            self.output.write_all(&self.block)?;
+        }
+        Ok(())
+    }
+
+    // Write the chunk header of the current run
+    fn finish_run(&mut self) -> Result<(), EncodeError> {
+        let Some((kind, blocks)) = self.run.take() else {
+            return Ok(());
+        };
+        let run = Run {
+            kind,
+            start: 0,
+            blocks,
+        };
+        let header = run.header(self.encoder.block_size).to_bytes();
+        match kind {
+            RunKind::Raw => {
+                let end = self.output.stream_position()?;
+                self.output.seek(SeekFrom::Start(self.raw_header))?;
+                self.output.write_all(&header)?;
+                self.output.seek(SeekFrom::Start(end))?;
+            }
+            RunKind::Fill(value) => {
+                self.output.write_all(&header)?;
+                self.output.write_all(&value)?;
+            }
+            RunKind::DontCare => self.output.write_all(&header)?,
+        }
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// Complete the image, returning its header and the output
+    ///
+    /// A partially written last block is padded with zeroes.
+    pub fn finish(mut self) -> Result<(FileHeader, W), EncodeError> {
+        if self.filled > 0 {
+            self.block[self.filled..].fill(0);
+            self.push_block()?;
+        }
+        self.finish_run()?;
+        if let (Some(checksum), true) = (&self.checksum, self.encoder.crc32_chunk) {
+            write_crc32_chunk(&mut self.output, checksum)?;
+            self.chunks += 1;
+        }
+        let header = FileHeader {
+            block_size: self.encoder.block_size,
+            blocks: self.blocks,
+            chunks: self.chunks,
+            checksum: self
+                .checksum
+                .as_ref()
+                .filter(|_| self.encoder.header_checksum)
+                .map_or(0, Checksum::value),
+        };
+        let end = self.output.stream_position()?;
+        self.output.seek(SeekFrom::Start(self.start))?;
+        self.output.write_all(&header.to_bytes())?;
+        self.output.seek(SeekFrom::Start(end))?;
+        self.output.flush()?;
+        Ok((header, self.output))
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.block.len() - self.filled);
+        self.block[self.filled..][..n].copy_from_slice(&buf[..n]);
+        self.filled += n;
+        if self.filled == self.block.len() {
+            self.filled = 0;
+            self.push_block().map_err(|e| match e {
+                EncodeError::Io(e) => e,
+                e => std::io::Error::other(e),
+            })?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Encode the raw image file at `input` into a sparse image file at `output` with `encoder`,
+/// returning the header of the sparse image
+///
+/// The input is read once and encoded on the fly by a [SparseWriter], so memory usage stays at a
+/// single block however large or fragmented the image is; Unlike [Encoder::encode], which keeps
+/// all runs of blocks in memory. The headers are written as placeholders and filled in once the
+/// number of blocks and chunks is known. The output file is created or truncated.
+pub fn sparsify_file<P, Q>(
+    input: P,
+    output: Q,
+    encoder: &Encoder,
+) -> Result<FileHeader, EncodeError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut input = File::open(input)?;
+    let mut writer = encoder.writer(BufWriter::new(File::create(output)?))?;
+    std::io::copy(&mut input, &mut writer)?;
+    let (header, output) = writer.finish()?;
+    output
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    Ok(header)
+}
+
+// Bytes of a Crc32 chunk with the checksum of the data so far
+fn crc32_chunk(checksum: &Checksum) -> Vec<u8> {
+    let mut bytes = ChunkHeader::new_crc32().to_bytes().to_vec();
+    bytes.extend_from_slice(&checksum.value().to_le_bytes());
+    bytes
+}
+
+// Write a Crc32 chunk with the checksum of the data so far
+pub(crate) fn write_crc32_chunk<W: Write>(
+    output: &mut W,
+    checksum: &Checksum,
+) -> std::io::Result<()> {
+    output.write_all(&crc32_chunk(checksum))
+}
+
+// Range of data in a file starting at or after `offset` up to the next hole; None if there is no
+// more data
+#[cfg(all(unix, feature = "unix"))]
+fn data_range<F: AsFd>(file: &F, offset: u64) -> std::io::Result<Option<(u64, u64)>> {
+    let fd = file.as_fd().as_raw_fd();
+    // SAFETY: lseek only repositions the file offset of the descriptor
+    let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if data < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    // SAFETY: As above
+    let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+    if hole < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(Some((data as u64, hole as u64)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    use super::*;
+    use crate::{reader::SparseReader, ChunkHeaderBytes, ChunkType, FileHeaderBytes};
+
+    // Parse an encoded image into its header and chunks with their data
+    fn parse(image: &[u8]) -> (FileHeader, Vec<(ChunkHeader, Vec<u8>)>) {
+        let header = FileHeader::from_bytes(
+            &FileHeaderBytes::try_from(&image[..FILE_HEADER_BYTES_LEN]).unwrap(),
+        )
+        .unwrap();
+        let mut offset = FILE_HEADER_BYTES_LEN;
+        let mut chunks = vec![];
+        for _ in 0..header.chunks {
+            let chunk = ChunkHeader::from_bytes(
+                &ChunkHeaderBytes::try_from(&image[offset..offset + CHUNK_HEADER_BYTES_LEN])
+                    .unwrap(),
+            )
+            .unwrap();
+            offset += CHUNK_HEADER_BYTES_LEN;
+            let data = image[offset..offset + chunk.data_size()].to_vec();
+            offset += chunk.data_size();
+            chunks.push((chunk, data));
+        }
+        assert_eq!(offset, image.len());
+        (header, chunks)
+    }
+
+    #[test]
+    fn encode_raw_and_zeroes() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw = vec![0u8; 5 * block + 100];
+        for (i, b) in raw[..2 * block].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        raw[5 * block..].fill(0x55);
+
+        let mut image = vec![];
+        let header = Encoder::new()
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        assert_eq!(
+            header,
+            FileHeader {
+                block_size: DEFAULT_BLOCKSIZE,
+                blocks: 6,
+                chunks: 3,
+                checksum: 0,
+            }
+        );
+
+        let (parsed, chunks) = parse(&image);
+        assert_eq!(parsed, header);
+        assert_eq!(chunks[0].0, ChunkHeader::new_raw(2, DEFAULT_BLOCKSIZE));
+        assert_eq!(chunks[0].1, raw[..2 * block]);
+        assert_eq!(chunks[1].0, ChunkHeader::new_fill(3));
+        assert_eq!(chunks[1].1, [0; 4]);
+        // The last partial block is padded with zeroes
+        assert_eq!(chunks[2].0.chunk_type, ChunkType::Raw);
+        assert_eq!(chunks[2].1[..100], raw[5 * block..]);
+        assert!(chunks[2].1[100..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn encode_zero_blocks() {
+        let mut raw = vec![0u8; 4 * DEFAULT_BLOCKSIZE as usize];
+        raw[DEFAULT_BLOCKSIZE as usize] = 1;
+
+        let mut image = vec![];
+        Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        let (_, chunks) = parse(&image);
+        let chunks: Vec<_> = chunks.into_iter().map(|(h, _)| h).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                ChunkHeader::new_dontcare(1),
+                ChunkHeader::new_raw(1, DEFAULT_BLOCKSIZE),
+                ChunkHeader::new_dontcare(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_invalid_block_size() {
+        let r = Encoder::new()
+            .block_size(1022)
+            .encode(Cursor::new(vec![0; 4096]), std::io::sink());
+        assert!(matches!(r, Err(EncodeError::InvalidBlockSize(1022))));
+    }
+
+    #[test]
+    fn encode_fill_patterns() {
+        let block = 1024;
+        let mut raw: Vec<u8> = [0xde, 0xad, 0xbe, 0xef].repeat(3 * block / 4);
+        raw.extend([0xff; 1024]);
+        // Repeated two byte patterns are four byte patterns as well
+        raw.extend([0x12, 0x34].repeat(block / 2));
+        // Pattern broken by the last byte
+        let mut almost = [0x11; 1024];
+        almost[1023] = 0x12;
+        raw.extend(almost);
+
+        let mut image = vec![];
+        let header = Encoder::new()
+            .block_size(block as u32)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        assert_eq!(header.blocks, 6);
+
+        let (_, chunks) = parse(&image);
+        let chunks: Vec<_> = chunks
+            .into_iter()
+            .map(|(h, d)| (h.chunk_type, h.chunk_size, d))
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (ChunkType::Fill, 3, vec![0xde, 0xad, 0xbe, 0xef]),
+                (ChunkType::Fill, 1, vec![0xff; 4]),
+                (ChunkType::Fill, 1, vec![0x12, 0x34, 0x12, 0x34]),
+                (ChunkType::Raw, 1, almost.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sparse_writer() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend(vec![0; 2 * block]);
+        raw.extend([9, 8, 7, 6].repeat(block));
+        raw.extend((0..block + 10).map(|i| (i % 7) as u8));
+
+        for (zero_blocks, crc32, header_checksum) in [
+            (ZeroBlocks::Fill, false, false),
+            (ZeroBlocks::DontCare, false, false),
+            (ZeroBlocks::DontCare, true, false),
+            (ZeroBlocks::Fill, false, true),
+        ] {
+            let encoder = Encoder::new()
+                .zero_blocks(zero_blocks)
+                .crc32_chunk(crc32)
+                .header_checksum(header_checksum);
+            let mut expected = vec![];
+            let header = encoder.encode(Cursor::new(&raw), &mut expected).unwrap();
+
+            let mut writer = encoder.writer(Cursor::new(vec![])).unwrap();
+            // Written in pieces not aligned to blocks
+            for piece in raw.chunks(1000) {
+                writer.write_all(piece).unwrap();
+            }
+            let (written, output) = writer.finish().unwrap();
+            assert_eq!(written, header);
+            assert_eq!(output.into_inner(), expected);
+        }
+    }
+
+    #[test]
+    fn encode_crc32_chunk() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..block).map(|i| (i % 251) as u8).collect();
+        raw.extend(vec![0; 2 * block]);
+        raw.extend([0x42; 10]);
+
+        let mut image = vec![];
+        let header = Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .crc32_chunk(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        assert_eq!(header.chunks, 4);
+
+        let (_, chunks) = parse(&image);
+        let (crc, value) = chunks.last().unwrap();
+        assert_eq!(crc, &ChunkHeader::new_crc32());
+        let mut checksum = Checksum::new();
+        checksum.update(&raw);
+        checksum.update_fill([0; 4], (4 * block - raw.len()) as u64);
+        assert_eq!(value[..], checksum.value().to_le_bytes());
+
+        let mut image = vec![];
+        let header = Encoder::new()
+            .header_checksum(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        assert_eq!(header.checksum, checksum.value());
+        assert_eq!(parse(&image).0, header);
+    }
+
+    // Raw content made up of random data, repeated patterns and zeroes
+    fn content() -> impl Strategy<Value = Vec<u8>> {
+        let segment = prop_oneof![
+            vec(any::<u8>(), 1..5000),
+            (any::<[u8; 4]>(), 1..2000usize).prop_map(|(pattern, n)| pattern.repeat(n)),
+            (1..20000usize).prop_map(|n| vec![0; n]),
+        ];
+        vec(segment, 0..8).prop_map(|segments| segments.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn encode_round_trip(
+            raw in content(),
+            block_size in select(&[512u32, 1024, 4096, 65536][..]),
+            dontcare in any::<bool>(),
+            crc32 in any::<bool>(),
+            header_checksum in any::<bool>(),
+        ) {
+            let zero_blocks = if dontcare { ZeroBlocks::DontCare } else { ZeroBlocks::Fill };
+            let mut image = vec![];
+            let header = Encoder::new()
+                .block_size(block_size)
+                .zero_blocks(zero_blocks)
+                .crc32_chunk(crc32)
+                .header_checksum(header_checksum)
+                .encode(Cursor::new(&raw), &mut image)
+                .unwrap();
+            prop_assert_eq!(header.block_size, block_size);
+            prop_assert_eq!(header.total_size(), raw.len().next_multiple_of(block_size as usize) as u64);
+
+            let reader = SparseReader::new(Cursor::new(&image))
+                .unwrap()
+                .verify_checksums(true);
+            prop_assert_eq!(reader.header(), &header);
+            let mut expanded = vec![];
+            prop_assert_eq!(reader.expand(&mut expanded).unwrap(), header.total_size());
+            prop_assert_eq!(&expanded[..raw.len()], &raw[..]);
+            prop_assert!(expanded[raw.len()..].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn sparsify() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend(vec![0; 5 * block]);
+        raw.extend([1, 2, 3, 4].repeat(block));
+        raw.extend([0x42; 100]);
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("sparsify-raw-{}", std::process::id()));
+        let output = dir.join(format!("sparsify-sparse-{}", std::process::id()));
+        std::fs::write(&input, &raw).unwrap();
+
+        let encoder = Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .crc32_chunk(true);
+        let header = sparsify_file(&input, &output, &encoder).unwrap();
+        let mut expected = vec![];
+        assert_eq!(
+            encoder.encode(Cursor::new(&raw), &mut expected).unwrap(),
+            header
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), expected);
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "unix"))]
+    #[test]
+    fn encode_holes() {
+        use std::io::Write;
+
+        let block = DEFAULT_BLOCKSIZE as u64;
+        let path = std::env::temp_dir().join(format!("encode-holes-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        // Data in the middle of a 64 MiB file, with holes around it
+        let size = 16384 * block;
+        file.set_len(size).unwrap();
+        file.seek(SeekFrom::Start(8192 * block + 10)).unwrap();
+        file.write_all(&[0xaa; 100]).unwrap();
+        drop(file);
+
+        let mut image = vec![];
+        let file = std::fs::File::open(&path).unwrap();
+        let header = Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode_file(&file, &mut image)
+            .unwrap();
+        assert_eq!(header.blocks, 16384);
+        assert_eq!(header.chunks, 3);
+
+        let (_, chunks) = parse(&image);
+        let raw: Vec<_> = chunks
+            .iter()
+            .filter(|(h, _)| h.chunk_type == ChunkType::Raw)
+            .collect();
+        assert_eq!(raw.len(), 1);
+        assert_eq!(raw[0].0.chunk_size, 1);
+        assert_eq!(raw[0].1[10..110], [0xaa; 100]);
+        assert_eq!(chunks[0].0.chunk_type, ChunkType::DontCare);
+        assert_eq!(chunks.last().unwrap().0.chunk_type, ChunkType::DontCare);
+
+        // Holes are zeroes, so get filled by default
+        let mut filled = vec![];
+        Encoder::new().encode_file(&file, &mut filled).unwrap();
+        let (_, chunks) = parse(&filled);
+        assert_eq!(chunks[0].0, ChunkHeader::new_fill(8192));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,534 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    reader::{ReadError, SparseReader},
+    ChunkData, ChunkHeader, ChunkHeaderBytes, ChunkType, FileHeader, FileHeaderBytes, ParseError,
+    FILE_HEADER_BYTES_LEN,
+};
+
+// Reader producing `size` bytes repeating a 4 byte pattern, for the output of Fill and DontCare
+// chunks; Shared by the sync and async expansion
+pub(crate) struct Fill {
+    // The pattern repeated, with room to start at any offset within it
+    buf: Vec<u8>,
+    // Bytes produced so far and in total
+    pos: u64,
+    size: u64,
+}
+
+impl Fill {
+    pub(crate) fn new(pattern: [u8; 4], size: u64) -> Self {
+        Self {
+            buf: pattern.repeat(1025),
+            pos: 0,
+            size,
+        }
+    }
+}
+
+impl Read for Fill {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = (self.pos % 4) as usize;
+        let n = buf
+            .len()
+            .min(self.buf.len() - 4)
+            .min(usize::try_from(self.size - self.pos).unwrap_or(usize::MAX));
+        buf[..n].copy_from_slice(&self.buf[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// Write `size` bytes repeating the 4 byte pattern
+pub(crate) fn write_fill<W: Write>(
+    writer: &mut W,
+    pattern: [u8; 4],
+    size: u64,
+) -> std::io::Result<()> {
+    std::io::copy(&mut Fill::new(pattern, size), writer)?;
+    Ok(())
+}
+
+// Copy `size` bytes of raw chunk data to `writer`, passing the number of bytes copied so far to
+// `progress`
+pub(crate) fn copy_raw<R, W, F>(
+    data: R,
+    writer: &mut W,
+    size: u64,
+    mut progress: F,
+) -> Result<(), ReadError>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(u64),
+{
+    let mut data = data.take(size);
+    let mut buf = [0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+        progress(copied);
+    }
+    if copied != size {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Expand the sparse image read from `reader` into the raw image it describes, written to
+/// `writer`; Returns the size of the expanded image
+///
+/// DontCare chunks are written out as zeroes, so the expanded image always has the full size
+/// given by the file header. Crc32 chunks are skipped; Use [SparseReader::expand] to verify
+/// checksums while expanding, and [SparseReader::progress] to follow the progress.
+pub fn expand<R, W>(reader: R, writer: W) -> Result<u64, ReadError>
+where
+    R: Read,
+    W: Write,
+{
+    SparseReader::new(reader)?.expand(writer)
+}
+
+/// Expand the sparse image read from `reader` into `file`; Returns the size of the expanded
+/// image
+///
+/// The file is truncated first, so DontCare chunks (and chunks filled with zeroes) are skipped
+/// over and end up as holes in the file on filesystems supporting sparse files. This keeps the
+/// disk usage of expanding mostly empty images close to the size of their actual data.
+pub fn expand_file<R: Read>(reader: R, file: &File) -> Result<u64, ReadError> {
+    SparseReader::new(reader)?.expand_file(file)
+}
+
+impl<R: Read> SparseReader<R> {
+    /// Expand the remaining chunks into `writer`, see [expand]
+    pub fn expand<W: Write>(mut self, mut writer: W) -> Result<u64, ReadError> {
+        let header = self.header().clone();
+        let mut progress = self.take_progress();
+        let mut written = 0;
+        while let Some(chunk) = self.next_chunk() {
+            let (chunk, data) = chunk?;
+            let size = chunk.out_size(&header);
+            match data {
+                ChunkData::Raw(data) => {
+                    copy_raw(data, &mut writer, size, |copied| progress(written + copied))?
+                }
+                ChunkData::Fill(value) => write_fill(&mut writer, value.0, size)?,
+                ChunkData::DontCare => write_fill(&mut writer, [0; 4], size)?,
+                ChunkData::Crc32(_) => continue,
+            }
+            written += size;
+            progress(written);
+        }
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Expand the remaining chunks into `file`, see [expand_file]
+    pub fn expand_file(mut self, file: &File) -> Result<u64, ReadError> {
+        let header = self.header().clone();
+        let mut progress = self.take_progress();
+        file.set_len(0)?;
+        let mut writer = BufWriter::new(file);
+        let mut offset = 0;
+        while let Some(chunk) = self.next_chunk() {
+            let (chunk, data) = chunk?;
+            let size = chunk.out_size(&header);
+            match data {
+                ChunkData::Raw(data) => {
+                    writer.seek(SeekFrom::Start(offset))?;
+                    copy_raw(data, &mut writer, size, |copied| progress(offset + copied))?;
+                }
+                ChunkData::Fill(value) if !value.is_zero() => {
+                    writer.seek(SeekFrom::Start(offset))?;
+                    write_fill(&mut writer, value.0, size)?;
+                }
+                ChunkData::Fill(_) | ChunkData::DontCare => (),
+                ChunkData::Crc32(_) => continue,
+            }
+            offset += size;
+            progress(offset);
+        }
+        writer.flush()?;
+        // Trailing holes aren't written, so extend the file to its full size
+        file.set_len(offset)?;
+        Ok(offset)
+    }
+}
+
+// Content of the chunk currently being read by an ExpandedReader
+#[derive(Clone, Copy, Debug)]
+enum Content {
+    Raw,
+    Fill([u8; 4]),
+    Zero,
+}
+
+fn invalid_data(e: ParseError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// Reader presenting the expanded content of a sparse image
+///
+/// The image is expanded lazily while reading, so it can be processed by ordinary IO code (e.g.
+/// hashing) without expanding it first. When the underlying reader supports seeking the expanded
+/// image can be seeked as well; The offsets of chunks seen so far are remembered, so only chunk
+/// headers which weren't seen yet get parsed.
+#[derive(Debug)]
+pub struct ExpandedReader<R> {
+    reader: R,
+    header: FileHeader,
+    // Index of the next chunk
+    index: u32,
+    content: Content,
+    // Bytes of the current chunk left to be read
+    left: u64,
+    // Position in the expanded image
+    position: u64,
+    // Position in the sparse image
+    offset: u64,
+    // Expanded and sparse image offsets of the chunks seen so far
+    chunks: Vec<(u64, u64)>,
+}
+
+impl<R: Read> ExpandedReader<R> {
+    /// Parse the file header of the sparse image read from `reader`
+    pub fn new(mut reader: R) -> Result<Self, ReadError> {
+        let mut header_bytes = FileHeaderBytes::default();
+        reader.read_exact(&mut header_bytes)?;
+        let header = FileHeader::from_bytes(&header_bytes)?;
+        let offset = FILE_HEADER_BYTES_LEN as u64;
+        Ok(Self {
+            reader,
+            header,
+            index: 0,
+            content: Content::Zero,
+            left: 0,
+            position: 0,
+            offset,
+            chunks: vec![(0, offset)],
+        })
+    }
+
+    /// The file header of the image
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Unwrap the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    // Parse the header of the next chunk
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        let mut chunk_bytes = ChunkHeaderBytes::default();
+        self.reader.read_exact(&mut chunk_bytes)?;
+        let (chunk, end) = ChunkHeader::from_bytes(&chunk_bytes)
+            .and_then(|chunk| {
+                chunk
+                    .check(&self.header, self.position)
+                    .map(|end| (chunk, end))
+            })
+            .map_err(|e| invalid_data(e.in_chunk(self.index, self.offset)))?;
+        let size = end - self.position;
+        self.index += 1;
+        self.offset += chunk.total_size as u64;
+        if self.chunks.len() == self.index as usize {
+            self.chunks.push((self.position + size, self.offset));
+        }
+
+        (self.content, self.left) = match chunk.chunk_type {
+            ChunkType::Raw => {
+                self.offset -= size;
+                (Content::Raw, size)
+            }
+            ChunkType::Fill | ChunkType::Crc32 => {
+                let mut value = [0; 4];
+                self.reader.read_exact(&mut value)?;
+                if chunk.chunk_type == ChunkType::Fill {
+                    (Content::Fill(value), size)
+                } else {
+                    (Content::Zero, 0)
+                }
+            }
+            ChunkType::DontCare => (Content::Zero, size),
+        };
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ExpandedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.left == 0 {
+            if self.index >= self.header.chunks {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let max = buf
+            .len()
+            .min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        let buf = &mut buf[..max];
+        let n = match self.content {
+            Content::Raw => {
+                let n = self.reader.read(buf)?;
+                if n == 0 && max > 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                self.offset += n as u64;
+                n
+            }
+            Content::Fill(pattern) => {
+                // Chunks start block aligned, so the pattern phase follows from the position
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = pattern[(self.position as usize + i) % 4];
+                }
+                max
+            }
+            Content::Zero => {
+                buf.fill(0);
+                max
+            }
+        };
+        self.position += n as u64;
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for ExpandedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(target) => Some(target),
+            SeekFrom::End(delta) => self.header.total_size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        }
+        .ok_or(std::io::ErrorKind::InvalidInput)?;
+
+        // Within the current chunk only the position needs to move
+        if target >= self.position && target - self.position < self.left {
+            let skip = target - self.position;
+            if let Content::Raw = self.content {
+                self.reader.seek(SeekFrom::Current(skip as i64))?;
+                self.offset += skip;
+            }
+            self.position = target;
+            self.left -= skip;
+            return Ok(target);
+        }
+
+        // Restart from the last known chunk starting before the target
+        let base = self.reader.stream_position()? - self.offset;
+        let i = self.chunks.partition_point(|&(start, _)| start <= target) - 1;
+        (self.position, self.offset) = self.chunks[i];
+        self.index = i as u32;
+        self.left = 0;
+        self.reader.seek(SeekFrom::Start(base + self.offset))?;
+        while self.index < self.header.chunks {
+            self.next_chunk()?;
+            if target - self.position < self.left {
+                return self.seek(SeekFrom::Start(target));
+            }
+            if let Content::Raw = self.content {
+                self.reader.seek(SeekFrom::Current(self.left as i64))?;
+                self.offset += self.left;
+            }
+            self.position += self.left;
+            self.left = 0;
+        }
+        // Past the end of the image
+        self.position = target;
+        Ok(target)
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        encode::{Encoder, ZeroBlocks},
+        ChunkHeader, FileHeader, DEFAULT_BLOCKSIZE,
+    };
+
+    #[test]
+    fn expand_roundtrip() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..3 * block).map(|i| (i % 241) as u8).collect();
+        raw.extend([1, 2, 3, 4].repeat(block));
+        // Trailing zeroes become a trailing DontCare chunk
+        raw.extend(vec![0; 2 * block]);
+
+        let mut image = vec![];
+        Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let mut expanded = vec![];
+        let size = expand(Cursor::new(&image), &mut expanded).unwrap();
+        assert_eq!(size, raw.len() as u64);
+        assert_eq!(expanded, raw);
+    }
+
+    #[test]
+    fn expand_crc_and_truncated() {
+        let header = FileHeader {
+            block_size: 1024,
+            blocks: 2,
+            chunks: 3,
+            checksum: 0,
+        };
+        let mut image = header.to_bytes().to_vec();
+        image.extend(ChunkHeader::new_fill(1).to_bytes());
+        image.extend([0xab; 4]);
+        image.extend(ChunkHeader::new_crc32().to_bytes());
+        image.extend([0; 4]);
+        image.extend(ChunkHeader::new_raw(1, 1024).to_bytes());
+        image.extend([0xcd; 1024]);
+
+        let mut expanded = vec![];
+        assert_eq!(expand(Cursor::new(&image), &mut expanded).unwrap(), 2048);
+        assert_eq!(expanded[..1024], [0xab; 1024]);
+        assert_eq!(expanded[1024..], [0xcd; 1024]);
+
+        // Raw data missing at the end of the image
+        image.truncate(image.len() - 100);
+        assert!(matches!(
+            expand(Cursor::new(&image), std::io::sink()),
+            Err(ReadError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn expand_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..40 * block).map(|i| (i % 241) as u8).collect();
+        raw.extend(vec![0; 8 * block]);
+        let mut image = vec![];
+        Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("expand-progress-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        for to_file in [false, true] {
+            let reports = Arc::new(Mutex::new(vec![]));
+            let r = reports.clone();
+            let reader = SparseReader::new(Cursor::new(&image))
+                .unwrap()
+                .progress(move |done, total| r.lock().unwrap().push((done, total)));
+            if to_file {
+                reader.expand_file(&file).unwrap();
+            } else {
+                reader.expand(std::io::sink()).unwrap();
+            }
+
+            let reports = reports.lock().unwrap();
+            let total = raw.len() as u64;
+            // The raw chunk is reported while being copied
+            assert!(reports.len() > 2);
+            assert!(reports.windows(2).all(|w| w[0].0 <= w[1].0));
+            assert!(reports.iter().all(|&(_, t)| t == total));
+            assert_eq!(reports.last(), Some(&(total, total)));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn expanded_reader() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..2 * block).map(|i| (i % 241) as u8).collect();
+        raw.extend([1, 2, 3, 4].repeat(3 * block));
+        raw.extend(vec![0; 2 * block]);
+        raw.extend((0..block).map(|i| (i % 13) as u8));
+
+        let mut image = vec![];
+        Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let mut expanded = vec![];
+        ExpandedReader::new(Cursor::new(&image))
+            .unwrap()
+            .read_to_end(&mut expanded)
+            .unwrap();
+        assert_eq!(expanded, raw);
+
+        // Image preceded by other data
+        let mut data = vec![0x55; 100];
+        data.extend(&image);
+        let mut cursor = Cursor::new(data);
+        cursor.seek(SeekFrom::Start(100)).unwrap();
+        let mut reader = ExpandedReader::new(cursor).unwrap();
+        let mut buf = [0; 10];
+        for pos in [
+            SeekFrom::Start(3 * block as u64 + 3),
+            SeekFrom::Start(5),
+            SeekFrom::End(-7),
+            SeekFrom::Current(-(4 * block as i64)),
+            SeekFrom::Start(block as u64 - 5),
+            SeekFrom::Current(2 * block as i64),
+        ] {
+            let offset = reader.seek(pos).unwrap() as usize;
+            let n = reader.read(&mut buf).unwrap();
+            assert_eq!(buf[..n], raw[offset..][..n], "{pos:?}");
+        }
+        reader.seek(SeekFrom::Start(block as u64 - 5)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], raw[block - 5..][..10]);
+
+        assert_eq!(
+            reader.seek(SeekFrom::End(10)).unwrap(),
+            raw.len() as u64 + 10
+        );
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader
+            .seek(SeekFrom::Current(-(raw.len() as i64) - 11))
+            .is_err());
+    }
+
+    #[test]
+    fn expand_into_file() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw = vec![0; 4 * block];
+        raw.extend((0..block).map(|i| (i % 251) as u8));
+        raw.extend([0xff; 4].repeat(block));
+        raw.extend(vec![0; 8 * block]);
+
+        let mut image = vec![];
+        Encoder::new()
+            .zero_blocks(ZeroBlocks::DontCare)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("expand-file-{}", std::process::id()));
+        // Existing content gets replaced
+        std::fs::write(&path, vec![0xaa; 20 * block]).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        let size = expand_file(Cursor::new(&image), &file).unwrap();
+        drop(file);
+        assert_eq!(size, raw.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), raw);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use bytes::Bytes;
+
+use crate::{
+    expand::write_fill,
+    limits::Limits,
+    reader::{read_chunk_table, read_chunk_table_with_limits, ReadError},
+    split::{split_image, split_image_with_max_chunks, Split, SplitError},
+    stats::{stats, Stats},
+    ChunkData, ChunkHeader, ChunkType, FileHeader,
+};
+
+/// Data of a raw chunk of a [SparseImage]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload<'a> {
+    /// Data at the given offset of the source the image was parsed from
+    Offset(u64),
+    /// Data borrowed from the buffer the image was parsed from
+    Borrowed(&'a [u8]),
+    /// Data owned by the image
+    Owned(Vec<u8>),
+    /// Data shared with other images or buffers, e.g. when constructing or transforming images in
+    /// memory
+    Bytes(Bytes),
+}
+
+/// Chunk of a [SparseImage] along with its data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageChunk<'a> {
+    /// Header of the chunk
+    pub header: ChunkHeader,
+    /// Data of the chunk
+    pub data: ChunkData<Payload<'a>>,
+}
+
+/// Complete sparse image; The file header along with all chunks and their data
+///
+/// The data of raw chunks is either kept as offsets into the source the image was parsed from
+/// (see [SparseImage::parse]), borrowed from a buffer (see [SparseImage::from_bytes]), owned or
+/// shared as [Bytes] (see [SparseImage::from_shared]).
+/// Methods writing or expanding the image take the source to read data kept as offsets from; For
+/// images without such chunks any seekable reader (e.g. an empty [Cursor]) will do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseImage<'a> {
+    header: FileHeader,
+    chunks: Vec<ImageChunk<'a>>,
+}
+
+impl<'a> SparseImage<'a> {
+    /// Create an image from its file header and chunks
+    ///
+    /// The file header is expected to be consistent with the chunks, see
+    /// [crate::validate::validate].
+    pub fn new(header: FileHeader, chunks: Vec<ImageChunk<'a>>) -> Self {
+        Self { header, chunks }
+    }
+
+    /// Parse the sparse image read from `reader`, keeping the data of raw chunks as offsets
+    ///
+    /// The image is expected to start at the current position of the reader; The offsets are
+    /// positions in the reader.
+    pub fn parse<R: Read + Seek>(mut reader: R) -> Result<SparseImage<'static>, ReadError> {
+        let (header, chunks, offsets) = read_chunk_table(&mut reader)?;
+        let end = reader.stream_position()?;
+        if reader.seek(SeekFrom::End(0))? < end {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let chunks = chunks
+            .into_iter()
+            .zip(offsets)
+            .map(|(header, offset)| {
+                let data = match header.chunk_type {
+                    ChunkType::Raw => ChunkData::Raw(Payload::Offset(offset)),
+                    ChunkType::DontCare => ChunkData::DontCare,
+                    ChunkType::Fill | ChunkType::Crc32 => {
+                        let mut value = [0; 4];
+                        reader.seek(SeekFrom::Start(offset))?;
+                        reader.read_exact(&mut value)?;
+                        ChunkData::from_value(header.chunk_type, value).unwrap()
+                    }
+                };
+                Ok(ImageChunk { header, data })
+            })
+            .collect::<Result<_, ReadError>>()?;
+        Ok(SparseImage { header, chunks })
+    }
+
+    /// Parse the sparse image in `bytes`, borrowing the data of raw chunks
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, ReadError> {
+        Self::from_bytes_with_limits(bytes, &Limits::default())
+    }
+
+    /// Parse the sparse image in `bytes`, borrowing the data of raw chunks and checking the file
+    /// header is within `limits`
+    pub fn from_bytes_with_limits(bytes: &'a [u8], limits: &Limits) -> Result<Self, ReadError> {
+        Self::from_buffer(bytes, limits, Payload::Borrowed)
+    }
+
+    /// Parse the sparse image in `bytes`, sharing the data of raw chunks as slices of `bytes`
+    /// rather than copying it
+    pub fn from_shared(bytes: Bytes) -> Result<SparseImage<'static>, ReadError> {
+        Self::from_shared_with_limits(bytes, &Limits::default())
+    }
+
+    /// Parse the sparse image in `bytes`, sharing the data of raw chunks and checking the file
+    /// header is within `limits`
+    pub fn from_shared_with_limits(
+        bytes: Bytes,
+        limits: &Limits,
+    ) -> Result<SparseImage<'static>, ReadError> {
+        SparseImage::from_buffer(&bytes, limits, |data| Payload::Bytes(bytes.slice_ref(data)))
+    }
+
+    // Parse the sparse image in `bytes`, creating the payload of raw chunks from their data
+    fn from_buffer<'b>(
+        bytes: &'b [u8],
+        limits: &Limits,
+        payload: impl Fn(&'b [u8]) -> Payload<'a>,
+    ) -> Result<Self, ReadError> {
+        let (header, chunks, offsets) = read_chunk_table_with_limits(Cursor::new(bytes), limits)?;
+        let chunks = chunks
+            .into_iter()
+            .zip(offsets)
+            .map(|(header, offset)| {
+                let data = usize::try_from(offset)
+                    .ok()
+                    .and_then(|offset| bytes.get(offset..)?.get(..header.data_size()))
+                    .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                let data = match header.chunk_type {
+                    ChunkType::Raw => ChunkData::Raw(payload(data)),
+                    ChunkType::DontCare => ChunkData::DontCare,
+                    ChunkType::Fill | ChunkType::Crc32 => ChunkData::from_value(
+                        header.chunk_type,
+                        [data[0], data[1], data[2], data[3]],
+                    )
+                    .unwrap(),
+                };
+                Ok(ImageChunk { header, data })
+            })
+            .collect::<Result<_, ReadError>>()?;
+        Ok(SparseImage { header, chunks })
+    }
+
+    /// The file header of the image
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// The chunks of the image
+    pub fn chunks(&self) -> &[ImageChunk<'a>] {
+        &self.chunks
+    }
+
+    /// The headers of the chunks of the image
+    pub fn chunk_headers(&self) -> Vec<ChunkHeader> {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.header.clone())
+            .collect()
+    }
+
+    /// Write the image to `output`, reading data kept as offsets from `source`
+    pub fn write<R, W>(&self, source: &mut R, mut output: W) -> Result<(), ReadError>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        output.write_all(&self.header.to_bytes())?;
+        for chunk in &self.chunks {
+            output.write_all(&chunk.header.to_bytes())?;
+            match &chunk.data {
+                ChunkData::Raw(payload) => copy(
+                    payload,
+                    chunk.header.data_size() as u64,
+                    source,
+                    &mut output,
+                )?,
+                ChunkData::Fill(value) => output.write_all(&value.0)?,
+                ChunkData::DontCare => (),
+                ChunkData::Crc32(crc) => output.write_all(&crc.to_le_bytes())?,
+            }
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Expand the image into `writer` like [crate::expand::expand], reading data kept as offsets
+    /// from `source`; Returns the size of the expanded image
+    pub fn expand<R, W>(&self, source: &mut R, mut writer: W) -> Result<u64, ReadError>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        let mut written = 0;
+        for chunk in &self.chunks {
+            let size = chunk.header.out_size(&self.header);
+            match &chunk.data {
+                ChunkData::Raw(payload) => copy(payload, size, source, &mut writer)?,
+                ChunkData::Fill(value) => write_fill(&mut writer, value.0, size)?,
+                ChunkData::DontCare => write_fill(&mut writer, [0; 4], size)?,
+                ChunkData::Crc32(_) => (),
+            }
+            written += size;
+        }
+        // Blocks not covered by any chunk
+        let total = self.header.total_size();
+        if written < total {
+            write_fill(&mut writer, [0; 4], total - written)?;
+        }
+        writer.flush()?;
+        Ok(total.max(written))
+    }
+
+    /// Split the image like [split_image]; The offsets of the split chunks refer to the image
+    /// as written by [SparseImage::write]
+    pub fn split(&self, size: u32) -> Result<Vec<Split>, SplitError> {
+        split_image(&self.header, &self.chunk_headers(), size)
+    }
+
+    /// Split the image like [split_image_with_max_chunks], see [SparseImage::split]
+    pub fn split_with_max_chunks(
+        &self,
+        size: u32,
+        max_chunks: u32,
+    ) -> Result<Vec<Split>, SplitError> {
+        split_image_with_max_chunks(&self.header, &self.chunk_headers(), size, max_chunks)
+    }
+
+    /// Gather statistics of the image, see [stats]
+    pub fn stats(&self) -> Stats {
+        stats(&self.header, &self.chunk_headers())
+    }
+}
+
+// Copy `size` bytes of raw chunk data to `output`
+fn copy<R, W>(payload: &Payload, size: u64, source: &mut R, output: &mut W) -> Result<(), ReadError>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let copied = match payload {
+        Payload::Offset(offset) => {
+            source.seek(SeekFrom::Start(*offset))?;
+            std::io::copy(&mut source.take(size), output)?
+        }
+        Payload::Borrowed(data) => std::io::copy(&mut data.take(size), output)?,
+        Payload::Owned(data) => std::io::copy(&mut data.as_slice().take(size), output)?,
+        Payload::Bytes(data) => std::io::copy(&mut data.as_ref().take(size), output)?,
+    };
+    if copied != size {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{encode::Encoder, FillValue, ParseError, DEFAULT_BLOCKSIZE};
+
+    #[test]
+    fn image_model() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..2 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([0x5a; 4].repeat(block / 4));
+        raw.extend((0..block).map(|i| (i % 13) as u8));
+        let mut image = vec![];
+        Encoder::new()
+            .crc32_chunk(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let mut source = Cursor::new(&image);
+        let parsed = SparseImage::parse(&mut source).unwrap();
+        let borrowed = SparseImage::from_bytes(&image).unwrap();
+        assert_eq!(parsed.header(), borrowed.header());
+        assert_eq!(parsed.chunks().len(), 4);
+        assert_eq!(parsed.chunks()[0].data, ChunkData::Raw(Payload::Offset(40)));
+        assert_eq!(
+            borrowed.chunks()[0].data,
+            ChunkData::Raw(Payload::Borrowed(&raw[..2 * block]))
+        );
+        assert_eq!(
+            borrowed.chunks()[1].data,
+            ChunkData::Fill(FillValue([0x5a; 4]))
+        );
+
+        for model in [&parsed, &borrowed] {
+            let mut written = vec![];
+            model.write(&mut source, &mut written).unwrap();
+            assert_eq!(written, image);
+            let mut expanded = vec![];
+            let size = model.expand(&mut source, &mut expanded).unwrap();
+            assert_eq!(size, raw.len() as u64);
+            assert_eq!(expanded, raw);
+        }
+
+        let header = parsed.header().clone();
+        assert_eq!(
+            parsed.split(8192).unwrap(),
+            split_image(&header, &parsed.chunk_headers(), 8192).unwrap()
+        );
+        assert_eq!(parsed.stats(), stats(&header, &parsed.chunk_headers()));
+
+        // Owned and shared data, and blocks not covered by chunks
+        let shared = Bytes::from(raw[block..2 * block].to_vec());
+        let owned = SparseImage::new(
+            FileHeader {
+                blocks: 4,
+                chunks: 2,
+                ..header
+            },
+            vec![
+                ImageChunk {
+                    header: ChunkHeader::new_raw(1, DEFAULT_BLOCKSIZE),
+                    data: ChunkData::Raw(Payload::Owned(raw[..block].to_vec())),
+                },
+                ImageChunk {
+                    header: ChunkHeader::new_raw(1, DEFAULT_BLOCKSIZE),
+                    data: ChunkData::Raw(Payload::Bytes(shared)),
+                },
+            ],
+        );
+        let mut expanded = vec![];
+        owned.expand(&mut Cursor::new([]), &mut expanded).unwrap();
+        assert_eq!(expanded[..2 * block], raw[..2 * block]);
+        assert_eq!(expanded[2 * block..], vec![0; 2 * block]);
+
+        // Shared data is sliced from the buffer without copying
+        let buffer = Bytes::from(image.clone());
+        let shared = SparseImage::from_shared(buffer.clone()).unwrap();
+        assert_eq!(shared.header(), parsed.header());
+        match &shared.chunks()[0].data {
+            ChunkData::Raw(Payload::Bytes(data)) => {
+                assert_eq!(data.as_ptr(), buffer[40..].as_ptr());
+                assert_eq!(data[..], raw[..2 * block]);
+            }
+            data => panic!("Unexpected chunk data: {data:?}"),
+        }
+        let limits = Limits {
+            max_chunks: 3,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            SparseImage::from_shared_with_limits(buffer, &limits),
+            Err(ReadError::Parse(ParseError::Limit(_)))
+        ));
+
+        assert!(matches!(
+            SparseImage::from_bytes(&image[..image.len() - 100]),
+            Err(ReadError::Io(_))
+        ));
+        assert!(matches!(
+            SparseImage::parse(Cursor::new(&image[..image.len() - 100])),
+            Err(ReadError::Io(_))
+        ));
+    }
+}
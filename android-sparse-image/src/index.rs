@@ -0,0 +1,253 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{
+    reader::{read_chunk_table, ReadError},
+    ChunkHeader, ChunkType, FileHeader, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+
+/// Chunk of an [Index]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Header of the chunk
+    pub header: ChunkHeader,
+    /// Offset of the chunk in the expanded image
+    pub start: u64,
+    /// Size of the chunk in the expanded image
+    pub size: u64,
+    /// Offset of the chunk data in the sparse image
+    pub data_offset: u64,
+}
+
+/// Location of an offset of the expanded image within a sparse image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// Index of the chunk covering the offset
+    pub chunk: usize,
+    /// Offset within the expanded chunk
+    pub chunk_offset: u64,
+    /// Offset in the sparse image where the byte is stored; Only raw chunks store their data
+    pub file_offset: Option<u64>,
+}
+
+/// Index of the chunks of a sparse image, mapping offsets in the expanded image to chunks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Index {
+    header: FileHeader,
+    entries: Vec<IndexEntry>,
+    // Position of the image in the reader it was read from
+    base: u64,
+}
+
+impl Index {
+    /// Create an index from the file header and chunk headers of an image
+    ///
+    /// The chunks are expected to follow the file header in the given order, as in
+    /// [crate::split::split_image].
+    pub fn new(header: &FileHeader, chunks: &[ChunkHeader]) -> Self {
+        let mut start = 0;
+        let mut data_offset = FILE_HEADER_BYTES_LEN as u64;
+        let entries = chunks
+            .iter()
+            .map(|chunk| {
+                let size = chunk.out_size(header);
+                let entry = IndexEntry {
+                    header: chunk.clone(),
+                    start,
+                    size,
+                    data_offset: data_offset + CHUNK_HEADER_BYTES_LEN as u64,
+                };
+                start += size;
+                data_offset += chunk.total_size as u64;
+                entry
+            })
+            .collect();
+        Self {
+            header: header.clone(),
+            entries,
+            base: 0,
+        }
+    }
+
+    /// Create an index of the sparse image read from `reader`, seeking over the chunk data
+    ///
+    /// The image is expected to start at the current position of the reader, the offsets in the
+    /// index are relative to that.
+    pub fn read<R: Read + Seek>(mut reader: R) -> Result<Self, ReadError> {
+        let base = reader.stream_position()?;
+        let (header, chunks, _) = read_chunk_table(reader)?;
+        Ok(Self {
+            base,
+            ..Self::new(&header, &chunks)
+        })
+    }
+
+    /// The file header of the image
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// The chunks of the image
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Size of the expanded image as covered by the chunks
+    pub fn expanded_size(&self) -> u64 {
+        self.entries.last().map_or(0, |e| e.start + e.size)
+    }
+
+    /// Find the chunk covering `offset` in the expanded image; None if it's past the end
+    pub fn locate(&self, offset: u64) -> Option<Location> {
+        let chunk = self
+            .entries
+            .partition_point(|e| e.start <= offset)
+            .checked_sub(1)?;
+        let entry = &self.entries[chunk];
+        let chunk_offset = offset - entry.start;
+        if chunk_offset >= entry.size {
+            return None;
+        }
+        let file_offset =
+            (entry.header.chunk_type == ChunkType::Raw).then(|| entry.data_offset + chunk_offset);
+        Some(Location {
+            chunk,
+            chunk_offset,
+            file_offset,
+        })
+    }
+
+    /// Read the expanded image from `offset` onwards into `buf`, reading only the chunk data
+    /// needed from `reader`
+    ///
+    /// The image is expected at the same position in `reader` as when the index was read, or at
+    /// its start for indexes created by [Index::new].
+    pub fn read_at<R>(&self, reader: &mut R, offset: u64, buf: &mut [u8]) -> Result<(), ReadError>
+    where
+        R: Read + Seek,
+    {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let location = self
+                .locate(position)
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            let entry = &self.entries[location.chunk];
+            let n = (entry.size - location.chunk_offset).min((buf.len() - done) as u64) as usize;
+            let out = &mut buf[done..done + n];
+            match entry.header.chunk_type {
+                ChunkType::Raw => {
+                    let file_offset = entry.data_offset + location.chunk_offset;
+                    reader.seek(SeekFrom::Start(self.base + file_offset))?;
+                    reader.read_exact(out)?;
+                }
+                ChunkType::Fill => {
+                    let mut pattern = [0; 4];
+                    reader.seek(SeekFrom::Start(self.base + entry.data_offset))?;
+                    reader.read_exact(&mut pattern)?;
+                    // Chunks start block aligned, so the pattern phase follows from the offset
+                    for (i, b) in out.iter_mut().enumerate() {
+                        *b = pattern[(location.chunk_offset as usize + i) % 4];
+                    }
+                }
+                ChunkType::DontCare | ChunkType::Crc32 => out.fill(0),
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// Read `len` bytes of the expanded image starting at `start` from the sparse image read from
+/// `reader`
+///
+/// Only the chunk headers and the data of the chunks covering the range are read, so e.g. a
+/// filesystem superblock can be inspected without expanding the whole image. DontCare regions
+/// read as zeroes. To read multiple ranges create an [Index] once and use [Index::read_at].
+pub fn read_range<R>(mut reader: R, start: u64, len: usize) -> Result<Vec<u8>, ReadError>
+where
+    R: Read + Seek,
+{
+    let index = Index::read(&mut reader)?;
+    let mut buf = vec![0; len];
+    index.read_at(&mut reader, start, &mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::builder::SparseImageBuilder;
+
+    #[test]
+    fn locate() {
+        let (_, image) = SparseImageBuilder::with_block_size(Cursor::new(vec![]), 16)
+            .unwrap()
+            .skip(2)
+            .unwrap()
+            .raw(&[0xaa; 32])
+            .unwrap()
+            .fill([1; 4], 1)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let index = Index::read(Cursor::new(image.into_inner())).unwrap();
+        assert_eq!(index.expanded_size(), 80);
+        assert_eq!(index.entries().len(), 3);
+
+        assert_eq!(
+            index.locate(5),
+            Some(Location {
+                chunk: 0,
+                chunk_offset: 5,
+                file_offset: None
+            })
+        );
+        // File header, DontCare chunk header and raw chunk header precede the raw data
+        assert_eq!(
+            index.locate(40),
+            Some(Location {
+                chunk: 1,
+                chunk_offset: 8,
+                file_offset: Some(28 + 12 + 12 + 8)
+            })
+        );
+        assert_eq!(index.locate(64).unwrap().chunk, 2);
+        assert_eq!(index.locate(80), None);
+    }
+
+    #[test]
+    fn ranges() {
+        let raw: Vec<u8> = (0..32).collect();
+        let (_, image) = SparseImageBuilder::with_block_size(Cursor::new(vec![]), 16)
+            .unwrap()
+            .skip(1)
+            .unwrap()
+            .raw(&raw)
+            .unwrap()
+            .fill([1, 2, 3, 4], 2)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let mut expanded = vec![0; 16];
+        expanded.extend(&raw);
+        expanded.extend([1, 2, 3, 4].repeat(8));
+
+        // Image preceded by other data
+        let mut data = vec![0x55; 7];
+        data.extend(image.into_inner());
+        let mut reader = Cursor::new(data);
+        reader.set_position(7);
+        let index = Index::read(&mut reader).unwrap();
+        for (start, len) in [(0, 80), (10, 10), (45, 7), (79, 1), (3, 0)] {
+            let mut buf = vec![0xff; len];
+            index.read_at(&mut reader, start, &mut buf).unwrap();
+            assert_eq!(buf, expanded[start as usize..][..len], "{start} {len}");
+        }
+        assert!(index.read_at(&mut reader, 70, &mut [0; 11]).is_err());
+
+        reader.set_position(7);
+        assert_eq!(read_range(reader, 30, 20).unwrap(), expanded[30..50]);
+    }
+}
@@ -1,8 +1,61 @@
 #![doc = include_str!("../README.md")]
-
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+/// Programmatic construction of sparse images
+#[cfg(feature = "std")]
+pub mod builder;
+/// CRC32 checksums of sparse images
+pub mod checksum;
+/// Merging of adjacent chunks of the same kind
+#[cfg(feature = "std")]
+pub mod coalesce;
+/// Push-based decoding of sparse images received in pieces
+pub mod decoder;
+/// Writing of sparse images directly to block devices
+#[cfg(feature = "std")]
+pub mod device;
+/// Comparison of the content of sparse images
+#[cfg(feature = "std")]
+pub mod diff;
+/// Conversion of raw images into sparse images
+#[cfg(feature = "std")]
+pub mod encode;
+/// Expansion of sparse images into raw images
+#[cfg(feature = "std")]
+pub mod expand;
+/// Model of complete sparse images, owning all chunks and their data
+#[cfg(feature = "std")]
+pub mod image;
+/// Random access to the chunks of sparse images by expanded offset
+#[cfg(feature = "std")]
+pub mod index;
+/// Sanity limits guarding against crafted file headers
+pub mod limits;
+/// Zero-copy access to memory-mapped sparse images
+#[cfg(feature = "mmap")]
+pub mod mmap;
+/// Chunk by chunk reading of sparse images
+#[cfg(feature = "std")]
+pub mod reader;
 /// Helpers to split an image into multiple smaller ones
 pub mod split;
-
+/// Statistics of the chunks of sparse images
+pub mod stats;
+/// Async reading, expansion and encoding of sparse images using tokio
+#[cfg(feature = "tokio")]
+pub mod tokio;
+/// Trimming and extending the end of sparse images
+#[cfg(feature = "std")]
+pub mod trailing;
+/// Checks whether images are well-formed
+pub mod validate;
+/// Verification of the checksums and structure of complete sparse images
+#[cfg(feature = "std")]
+pub mod verify;
+
+use alloc::boxed::Box;
 use bytes::{Buf, BufMut};
 use log::trace;
 use strum::FromRepr;
@@ -25,14 +78,43 @@ pub enum ParseError {
     UnknownVersion,
     #[error("Header has an unexpected header or chunk size")]
     UnexpectedSize,
+    #[error("Block size {0} is not a non-zero multiple of 4")]
+    InvalidBlockSize(u32),
     #[error("Header has an unknown chunk type")]
     UnknownChunkType,
+    #[error("Chunks exceed the expanded size of the image")]
+    ExceedsImage,
+    #[error("Image is truncated")]
+    Truncated,
+    #[error(transparent)]
+    Limit(#[from] limits::LimitError),
+    #[error("Chunk {index} at offset {offset}: {error}")]
+    Chunk {
+        /// Index of the chunk
+        index: u32,
+        /// Offset of the chunk header in the sparse image
+        offset: u64,
+        #[source]
+        error: Box<ParseError>,
+    },
+}
+
+impl ParseError {
+    /// Add the position of the chunk the error occurred in
+    pub fn in_chunk(self, index: u32, offset: u64) -> Self {
+        ParseError::Chunk {
+            index,
+            offset,
+            error: Box::new(self),
+        }
+    }
 }
 
 /// Byte array which fits a file header
 pub type FileHeaderBytes = [u8; FILE_HEADER_BYTES_LEN];
 /// Global file header
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileHeader {
     /// Block size in bytes (should be multiple of 4)
     pub block_size: u32,
@@ -68,18 +150,22 @@ impl FileHeader {
         }
 
         let header_len = bytes.get_u16_le();
-        if FILE_HEADER_BYTES_LEN != header_len.into() {
+        if FILE_HEADER_BYTES_LEN != usize::from(header_len) {
             trace!("Unexpected header size: {}", header_len);
             return Err(ParseError::UnexpectedSize);
         }
 
         let chunk_header_len = bytes.get_u16_le();
-        if CHUNK_HEADER_BYTES_LEN != chunk_header_len.into() {
+        if CHUNK_HEADER_BYTES_LEN != usize::from(chunk_header_len) {
             trace!("Unexpected chunk header size: {}", chunk_header_len);
             return Err(ParseError::UnexpectedSize);
         }
 
         let block_size = bytes.get_u32_le();
+        if block_size == 0 || block_size % 4 != 0 {
+            trace!("Invalid block size: {}", block_size);
+            return Err(ParseError::InvalidBlockSize(block_size));
+        }
         let blocks = bytes.get_u32_le();
         let chunks = bytes.get_u32_le();
         let checksum = bytes.get_u32_le();
@@ -110,13 +196,15 @@ impl FileHeader {
         bytes
     }
 
-    pub fn total_size(&self) -> usize {
-        self.blocks as usize * self.block_size as usize
+    /// Size of the expanded image in bytes
+    pub fn total_size(&self) -> u64 {
+        u64::from(self.blocks) * u64::from(self.block_size)
     }
 }
 
 /// Type of a chunk
 #[derive(Copy, Clone, Debug, FromRepr, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ChunkType {
     /// Chunk header is followed by raw content for [ChunkHeader::out_size] bytes; Should be copied
     /// to the output
@@ -130,11 +218,75 @@ pub enum ChunkType {
     Crc32 = 0xcac4,
 }
 
+/// Value of a Fill chunk; The 4 byte pattern repeated over the expanded output of the chunk
+///
+/// The pattern is stored as-is in the image; When treated as a number (as libsparse does), it's
+/// little endian.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FillValue(pub [u8; 4]);
+
+impl FillValue {
+    /// Create a fill value from its numeric (little endian) representation
+    pub fn from_u32(value: u32) -> Self {
+        Self(value.to_le_bytes())
+    }
+
+    /// Numeric (little endian) representation of the fill value
+    pub fn as_u32(self) -> u32 {
+        u32::from_le_bytes(self.0)
+    }
+
+    /// The 4 byte pattern as stored in the image
+    pub fn pattern(self) -> [u8; 4] {
+        self.0
+    }
+
+    /// Whether the chunk is filled with zeroes
+    pub fn is_zero(self) -> bool {
+        self.0 == [0; 4]
+    }
+}
+
+impl From<[u8; 4]> for FillValue {
+    fn from(pattern: [u8; 4]) -> Self {
+        Self(pattern)
+    }
+}
+
+/// Data of a chunk, interpreted according to its [ChunkType]
+///
+/// The data of raw chunks is of type `T`, e.g. a reader for the data (see
+/// [reader::SparseReader]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkData<T> {
+    /// Data to be copied to the output
+    Raw(T),
+    /// Value to fill the output with
+    Fill(FillValue),
+    /// No data; The output can have any content
+    DontCare,
+    /// Crc32 checksum of the output up to this chunk
+    Crc32(u32),
+}
+
+impl<T> ChunkData<T> {
+    /// Parse the 4 bytes of data of a Fill or Crc32 chunk; None for other chunk types
+    pub fn from_value(chunk_type: ChunkType, bytes: [u8; 4]) -> Option<Self> {
+        match chunk_type {
+            ChunkType::Fill => Some(ChunkData::Fill(FillValue(bytes))),
+            ChunkType::Crc32 => Some(ChunkData::Crc32(u32::from_le_bytes(bytes))),
+            ChunkType::Raw | ChunkType::DontCare => None,
+        }
+    }
+}
+
 /// Byte array which fits a chunk header
 pub type ChunkHeaderBytes = [u8; CHUNK_HEADER_BYTES_LEN];
 
 /// Header of a chunk
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChunkHeader {
     /// The type of the chunk
     pub chunk_type: ChunkType,
@@ -177,6 +329,18 @@ impl ChunkHeader {
         }
     }
 
+    /// Create a new CRC32 header
+    ///
+    /// The header should be followed by the 4 byte (little-endian) checksum of the image data up
+    /// to this chunk
+    pub fn new_crc32() -> Self {
+        ChunkHeader {
+            chunk_type: ChunkType::Crc32,
+            chunk_size: 0,
+            total_size: CHUNK_HEADER_BYTES_LEN as u32 + 4,
+        }
+    }
+
     /// Create new ChunkHeader from a raw header
     pub fn from_bytes(bytes: &ChunkHeaderBytes) -> Result<ChunkHeader, ParseError> {
         let mut bytes = &bytes[..];
@@ -209,14 +373,33 @@ impl ChunkHeader {
     }
 
     /// Resulting size of this chunk in the output
-    pub fn out_size(&self, header: &FileHeader) -> usize {
-        self.chunk_size as usize * header.block_size as usize
+    pub fn out_size(&self, header: &FileHeader) -> u64 {
+        u64::from(self.chunk_size) * u64::from(header.block_size)
     }
 
     /// Data bytes after the header
     pub fn data_size(&self) -> usize {
         (self.total_size as usize).saturating_sub(CHUNK_HEADER_BYTES_LEN)
     }
+
+    // Check the size of the chunk data is consistent with the chunk type and the chunk fits in
+    // the image when starting at expanded offset `start`; Returns the expanded end offset
+    pub(crate) fn check(&self, header: &FileHeader, start: u64) -> Result<u64, ParseError> {
+        let expected = match self.chunk_type {
+            ChunkType::Raw => self.out_size(header),
+            ChunkType::Fill | ChunkType::Crc32 => 4,
+            ChunkType::DontCare => 0,
+        };
+        if (self.total_size as usize) < CHUNK_HEADER_BYTES_LEN
+            || self.data_size() as u64 != expected
+        {
+            return Err(ParseError::UnexpectedSize);
+        }
+        start
+            .checked_add(self.out_size(header))
+            .filter(|&end| end <= header.total_size())
+            .ok_or(ParseError::ExceedsImage)
+    }
 }
 
 #[cfg(test)]
@@ -242,6 +425,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn file_header_block_size() {
+        for block_size in [0, 4097] {
+            let header = FileHeader {
+                block_size,
+                blocks: 1,
+                chunks: 1,
+                checksum: 0,
+            };
+            assert!(matches!(
+                FileHeader::from_bytes(&header.to_bytes()),
+                Err(ParseError::InvalidBlockSize(b)) if b == block_size
+            ));
+        }
+    }
+
     #[test]
     fn file_header_roundtrip() {
         let orig = FileHeader {
@@ -287,4 +486,20 @@ mod test {
 
         assert_eq!(orig, echo);
     }
+
+    #[test]
+    fn chunk_values() {
+        let bytes = [0x78, 0x56, 0x34, 0x12];
+        let fill = ChunkData::<()>::from_value(ChunkType::Fill, bytes);
+        assert_eq!(fill, Some(ChunkData::Fill(FillValue(bytes))));
+        assert_eq!(FillValue(bytes).as_u32(), 0x12345678);
+        assert_eq!(FillValue::from_u32(0x12345678).pattern(), bytes);
+        assert!(FillValue::default().is_zero());
+
+        assert_eq!(
+            ChunkData::<()>::from_value(ChunkType::Crc32, bytes),
+            Some(ChunkData::Crc32(0x12345678))
+        );
+        assert_eq!(ChunkData::<()>::from_value(ChunkType::Raw, bytes), None);
+    }
 }
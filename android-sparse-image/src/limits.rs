@@ -0,0 +1,106 @@
+use thiserror::Error;
+
+use crate::FileHeader;
+
+/// Limit exceeded by the header of a sparse image, see [Limits]
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("Header announces {chunks} chunks, more than the limit of {max}")]
+    TooManyChunks { chunks: u32, max: u32 },
+    #[error("Expanded image of {size} bytes exceeds the limit of {max} bytes")]
+    TooLarge { size: u64, max: u64 },
+}
+
+/// Sanity limits for the file header of a sparse image
+///
+/// A crafted header can announce billions of chunks or an expanded image of many terabytes,
+/// making consumers loop or allocate based on it for a long time before the image turns out to be
+/// bogus. The default limits allow anything the format can describe, so they need to be lowered
+/// to what's reasonable for the use case, e.g. the size of the partition being flashed:
+///
+/// ```
+/// # use android_sparse_image::limits::Limits;
+/// let limits = Limits {
+///     max_expanded_size: 64 << 30,
+///     ..Limits::default()
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Limits {
+    /// Maximum number of chunks
+    pub max_chunks: u32,
+    /// Maximum size of the expanded image in bytes
+    pub max_expanded_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_chunks: u32::MAX,
+            max_expanded_size: u64::MAX,
+        }
+    }
+}
+
+impl Limits {
+    /// Check the file header is within the limits
+    pub fn check(&self, header: &FileHeader) -> Result<(), LimitError> {
+        if header.chunks > self.max_chunks {
+            return Err(LimitError::TooManyChunks {
+                chunks: header.chunks,
+                max: self.max_chunks,
+            });
+        }
+        if header.total_size() > self.max_expanded_size {
+            return Err(LimitError::TooLarge {
+                size: header.total_size(),
+                max: self.max_expanded_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_limits() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 1 << 20,
+            chunks: 1000,
+            checksum: 0,
+        };
+        assert_eq!(Limits::default().check(&header), Ok(()));
+        let limits = Limits {
+            max_chunks: 1000,
+            max_expanded_size: 4 << 30,
+        };
+        assert_eq!(limits.check(&header), Ok(()));
+        assert_eq!(
+            Limits {
+                max_chunks: 999,
+                ..limits
+            }
+            .check(&header),
+            Err(LimitError::TooManyChunks {
+                chunks: 1000,
+                max: 999
+            })
+        );
+        assert_eq!(
+            Limits {
+                max_expanded_size: 1 << 30,
+                ..limits
+            }
+            .check(&header),
+            Err(LimitError::TooLarge {
+                size: 4 << 30,
+                max: 1 << 30
+            })
+        );
+    }
+}
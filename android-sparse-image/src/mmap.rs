@@ -0,0 +1,177 @@
+use std::{fs::File, path::Path};
+
+use memmap2::Mmap;
+
+use crate::{
+    reader::ReadError, ChunkData, ChunkHeader, ChunkHeaderBytes, ChunkType, FileHeader,
+    FileHeaderBytes, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+
+/// Sparse image mapped into memory
+///
+/// The chunk headers are parsed up front, after which the data of the chunks is available as
+/// slices of the mapped file without reading or copying it. Useful to split or hash very large
+/// images.
+///
+/// The file must not be modified while it is mapped, which is why creating the map is unsafe.
+#[derive(Debug)]
+pub struct SparseImageMap {
+    map: Mmap,
+    header: FileHeader,
+    chunks: Vec<ChunkHeader>,
+    // Offsets of the data of each chunk in the map
+    offsets: Vec<usize>,
+}
+
+impl SparseImageMap {
+    /// Map and parse the sparse image at `path`
+    ///
+    /// # Safety
+    ///
+    /// See [SparseImageMap::new]
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, ReadError> {
+        // SAFETY: Upheld by the caller
+        unsafe { Self::new(&File::open(path)?) }
+    }
+
+    /// Map and parse the sparse image in `file`
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified, truncated in particular, by this or any other process for
+    /// as long as the returned map exists. Modifications change the data behind slices handed
+    /// out by the map, which is undefined behaviour, while accessing a truncated part of the file
+    /// terminates the process with SIGBUS.
+    pub unsafe fn new(file: &File) -> Result<Self, ReadError> {
+        // SAFETY: Upheld by the caller
+        let map = unsafe { Mmap::map(file)? };
+        let header_bytes: &FileHeaderBytes = map
+            .get(..FILE_HEADER_BYTES_LEN)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        let header = FileHeader::from_bytes(header_bytes)?;
+
+        let mut chunks = Vec::new();
+        let mut offsets = Vec::new();
+        let mut position = FILE_HEADER_BYTES_LEN;
+        let mut start = 0;
+        for index in 0..header.chunks {
+            let chunk_bytes: &ChunkHeaderBytes = map
+                .get(position..position + CHUNK_HEADER_BYTES_LEN)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            let (chunk, end) = ChunkHeader::from_bytes(chunk_bytes)
+                .and_then(|chunk| chunk.check(&header, start).map(|end| (chunk, end)))
+                .map_err(|e| e.in_chunk(index, position as u64))?;
+            start = end;
+            let offset = position + CHUNK_HEADER_BYTES_LEN;
+            position = offset + chunk.data_size();
+            if position > map.len() {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            chunks.push(chunk);
+            offsets.push(offset);
+        }
+
+        Ok(Self {
+            map,
+            header,
+            chunks,
+            offsets,
+        })
+    }
+
+    /// The file header of the image
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// The headers of the chunks of the image, e.g. to split it with
+    /// [crate::split::split_image]
+    pub fn chunk_headers(&self) -> &[ChunkHeader] {
+        &self.chunks
+    }
+
+    /// The complete mapped image
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// The data of the chunk with the given index; None if there is no such chunk
+    pub fn chunk(&self, index: usize) -> Option<ChunkData<&[u8]>> {
+        self.chunks
+            .get(index)
+            .map(|chunk| self.data(chunk, self.offsets[index]))
+    }
+
+    /// Iterate over the chunks of the image along with their data
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkHeader, ChunkData<&[u8]>)> {
+        self.chunks
+            .iter()
+            .zip(&self.offsets)
+            .map(|(chunk, &offset)| (chunk, self.data(chunk, offset)))
+    }
+
+    // Data of a chunk at the given offset, which was checked to be in the map while parsing
+    fn data(&self, chunk: &ChunkHeader, offset: usize) -> ChunkData<&[u8]> {
+        let data = &self.map[offset..][..chunk.data_size()];
+        match chunk.chunk_type {
+            ChunkType::Raw => ChunkData::Raw(data),
+            ChunkType::DontCare => ChunkData::DontCare,
+            ChunkType::Fill | ChunkType::Crc32 => {
+                ChunkData::from_value(chunk.chunk_type, [data[0], data[1], data[2], data[3]])
+                    .unwrap()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{encode::Encoder, split::split_image, FillValue, DEFAULT_BLOCKSIZE};
+
+    #[test]
+    fn map_image() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..2 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([0x5a; 4].repeat(block / 4));
+        let mut image = vec![];
+        Encoder::new()
+            .crc32_chunk(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("mmap-{}", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+        // SAFETY: The file isn't modified while mapped
+        let map = unsafe { SparseImageMap::open(&path) }.unwrap();
+        assert_eq!(map.as_bytes(), image);
+        assert_eq!(map.header().blocks, 3);
+
+        let chunks: Vec<_> = map.chunks().collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].1, ChunkData::Raw(&raw[..2 * block]));
+        assert_eq!(chunks[1].1, ChunkData::Fill(FillValue([0x5a; 4])));
+        assert!(matches!(chunks[2].1, ChunkData::Crc32(_)));
+        assert!(map.chunk(3).is_none());
+        assert_eq!(
+            split_image(map.header(), map.chunk_headers(), 1024 * 1024)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Truncated image
+        drop(map);
+        std::fs::write(&path, &image[..image.len() - 20]).unwrap();
+        assert!(matches!(
+            // SAFETY: The file isn't modified while mapped
+            unsafe { SparseImageMap::open(&path) },
+            Err(ReadError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
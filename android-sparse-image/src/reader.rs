@@ -0,0 +1,669 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use thiserror::Error;
+
+use crate::{
+    checksum::Checksum, limits::Limits, ChunkData, ChunkHeader, ChunkHeaderBytes, ChunkType,
+    FileHeader, FileHeaderBytes, ParseError, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+
+/// Errors while reading a sparse image
+#[derive(Debug, Error)]
+pub enum ReadError {
+    #[error("Failed to parse sparse image: {0}")]
+    Parse(#[from] ParseError),
+    #[error("Checksum mismatch at offset {offset}: expected {expected:#010x}, got {actual:#010x}")]
+    ChecksumMismatch {
+        /// Offset in the expanded image the checksum covers data up to
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Reader for the data of a raw chunk
+///
+/// Data which isn't read gets skipped when reading the next chunk
+#[derive(Debug)]
+pub struct RawData<'a, R> {
+    reader: &'a mut R,
+    left: &'a mut u64,
+    checksum: Option<&'a mut Checksum>,
+}
+
+impl<R> RawData<'_, R> {
+    /// Bytes of the chunk which weren't read yet
+    pub fn left(&self) -> u64 {
+        *self.left
+    }
+}
+
+impl<R: Read> Read for RawData<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = buf
+            .len()
+            .min(usize::try_from(*self.left).unwrap_or(usize::MAX));
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.reader.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        *self.left -= n as u64;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Chunk of an unknown type skipped by a [SparseReader], see
+/// [SparseReader::skip_unknown_chunks]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownChunk {
+    /// Type of the chunk as found in its header
+    pub chunk_type: u16,
+    /// Output size of the chunk in blocks
+    pub chunk_size: u32,
+    /// Size of the chunk in the sparse image
+    pub total_size: u32,
+    /// Index of the chunk
+    pub index: u32,
+    /// Offset of the chunk in the sparse image
+    pub offset: u64,
+}
+
+// Callback for skipped unknown chunks
+struct Warn(Box<dyn FnMut(&UnknownChunk) + Send>);
+
+impl std::fmt::Debug for Warn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Warn")
+    }
+}
+
+// Callback for the progress of expanding the image
+struct Progress(Box<dyn FnMut(u64, u64) + Send>);
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Chunk read by a [SparseReader], along with its data
+pub type Chunk<'a, R> = (ChunkHeader, ChunkData<RawData<'a, R>>);
+
+/// Reader parsing a sparse image chunk by chunk
+///
+/// Only the headers and the data of the current chunk are read, so images can be processed
+/// without loading them into memory or seeking in them.
+#[derive(Debug)]
+pub struct SparseReader<R> {
+    reader: R,
+    header: FileHeader,
+    // Index and sparse image offset of the next chunk
+    index: u32,
+    position: u64,
+    // Data of the current chunk which is left to be read
+    left: u64,
+    // Whether the data left is raw image data
+    left_raw: bool,
+    // Offset in the expanded image after the current chunk
+    offset: u64,
+    // Running checksum, if checksums are verified
+    checksum: Option<Checksum>,
+    // Whether the image checksum was verified
+    verified: bool,
+    // Callback for unknown chunks, which get skipped if set
+    warn: Option<Warn>,
+    progress: Option<Progress>,
+}
+
+impl<R: Read> SparseReader<R> {
+    /// Parse the file header of the sparse image read from `reader`
+    pub fn new(reader: R) -> Result<Self, ReadError> {
+        Self::with_limits(reader, &Limits::default())
+    }
+
+    /// Parse the file header of the sparse image read from `reader`, checking it's within
+    /// `limits`
+    pub fn with_limits(mut reader: R, limits: &Limits) -> Result<Self, ReadError> {
+        let mut header_bytes = FileHeaderBytes::default();
+        reader.read_exact(&mut header_bytes)?;
+        let header = FileHeader::from_bytes(&header_bytes)?;
+        limits.check(&header).map_err(ParseError::from)?;
+        Ok(Self {
+            reader,
+            header,
+            index: 0,
+            position: FILE_HEADER_BYTES_LEN as u64,
+            left: 0,
+            left_raw: false,
+            offset: 0,
+            checksum: None,
+            verified: false,
+            warn: None,
+            progress: None,
+        })
+    }
+
+    /// Verify the checksums of the image while reading it; Disabled by default
+    ///
+    /// The data of all chunks gets checksummed, including raw data which isn't read by the
+    /// caller. The running checksum is compared with each Crc32 chunk and, after the last chunk,
+    /// with [FileHeader::checksum] if that is set. A mismatch is reported as
+    /// [ReadError::ChecksumMismatch].
+    pub fn verify_checksums(mut self, verify: bool) -> Self {
+        self.checksum = verify.then(Checksum::new);
+        self
+    }
+
+    /// Skip chunks of unknown types (e.g. written by vendor tools) rather than failing with
+    /// [ParseError::UnknownChunkType]; Disabled by default
+    ///
+    /// `warn` is called for each skipped chunk. The data of an unknown chunk is skipped based on
+    /// its total size and its blocks are returned as a DontCare chunk, so the following chunks
+    /// still end up at the right offsets.
+    pub fn skip_unknown_chunks<F>(mut self, warn: F) -> Self
+    where
+        F: FnMut(&UnknownChunk) + Send + 'static,
+    {
+        self.warn = Some(Warn(Box::new(warn)));
+        self
+    }
+
+    /// Report the progress of expanding the image (e.g. by [SparseReader::expand] or
+    /// [crate::device::DeviceWriter]) to `progress`
+    ///
+    /// `progress` is called with the number of bytes of the expanded image done so far and the
+    /// total size of the expanded image given by the file header, after each chunk and while
+    /// copying the data of raw chunks.
+    pub fn progress<F>(mut self, progress: F) -> Self
+    where
+        F: FnMut(u64, u64) + Send + 'static,
+    {
+        self.progress = Some(Progress(Box::new(progress)));
+        self
+    }
+
+    /// The file header of the image
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    // Take the progress callback, as a function reporting the bytes of the expanded image done
+    pub(crate) fn take_progress(&mut self) -> impl FnMut(u64) {
+        let total = self.header.total_size();
+        let mut progress = self.progress.take();
+        move |done| {
+            if let Some(progress) = &mut progress {
+                (progress.0)(done, total)
+            }
+        }
+    }
+
+    /// Read the next chunk; None after the last chunk
+    pub fn next_chunk(&mut self) -> Option<Result<Chunk<'_, R>, ReadError>> {
+        if self.index >= self.header.chunks {
+            return self.verify_image().err().map(Err);
+        }
+        Some(self.read_chunk())
+    }
+
+    // Skip what's left of the current chunk
+    fn skip(&mut self) -> Result<(), ReadError> {
+        let mut data = (&mut self.reader).take(self.left);
+        let skipped = match &mut self.checksum {
+            Some(checksum) if self.left_raw => std::io::copy(&mut data, checksum)?,
+            _ => std::io::copy(&mut data, &mut std::io::sink())?,
+        };
+        if skipped != self.left {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        self.left = 0;
+        Ok(())
+    }
+
+    // Verify the image checksum once all chunks are read
+    fn verify_image(&mut self) -> Result<(), ReadError> {
+        if self.verified || self.checksum.is_none() {
+            return Ok(());
+        }
+        self.verified = true;
+        self.skip()?;
+        // Zero means the image has no checksum
+        if self.header.checksum == 0 {
+            return Ok(());
+        }
+        self.check(self.header.checksum)
+    }
+
+    // Compare the running checksum with an expected one
+    fn check(&self, expected: u32) -> Result<(), ReadError> {
+        match &self.checksum {
+            Some(checksum) if checksum.value() != expected => Err(ReadError::ChecksumMismatch {
+                offset: self.offset,
+                expected,
+                actual: checksum.value(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<Chunk<'_, R>, ReadError> {
+        self.skip()?;
+
+        let mut chunk_bytes = ChunkHeaderBytes::default();
+        self.reader.read_exact(&mut chunk_bytes)?;
+        let (chunk, end) = match (ChunkHeader::from_bytes(&chunk_bytes), &mut self.warn) {
+            (Err(ParseError::UnknownChunkType), Some(warn)) => {
+                let unknown = UnknownChunk {
+                    chunk_type: u16::from_le_bytes([chunk_bytes[0], chunk_bytes[1]]),
+                    chunk_size: u32::from_le_bytes(chunk_bytes[4..8].try_into().unwrap()),
+                    total_size: u32::from_le_bytes(chunk_bytes[8..12].try_into().unwrap()),
+                    index: self.index,
+                    offset: self.position,
+                };
+                // Skipped like a DontCare chunk with data
+                let chunk = ChunkHeader {
+                    chunk_type: ChunkType::DontCare,
+                    chunk_size: unknown.chunk_size,
+                    total_size: unknown.total_size,
+                };
+                if (chunk.total_size as usize) < CHUNK_HEADER_BYTES_LEN {
+                    Err(ParseError::UnexpectedSize)
+                } else {
+                    (warn.0)(&unknown);
+                    ChunkHeader::new_dontcare(chunk.chunk_size)
+                        .check(&self.header, self.offset)
+                        .map(|end| (chunk, end))
+                }
+            }
+            (chunk, _) => chunk.and_then(|chunk| {
+                chunk
+                    .check(&self.header, self.offset)
+                    .map(|end| (chunk, end))
+            }),
+        }
+        .map_err(|e| e.in_chunk(self.index, self.position))?;
+        self.index += 1;
+        self.position += chunk.total_size as u64;
+        let size = end - self.offset;
+        self.offset = end;
+        self.left_raw = chunk.chunk_type == ChunkType::Raw;
+
+        let data = match chunk.chunk_type {
+            ChunkType::Raw => {
+                self.left = chunk.data_size() as u64;
+                ChunkData::Raw(RawData {
+                    reader: &mut self.reader,
+                    left: &mut self.left,
+                    checksum: self.checksum.as_mut(),
+                })
+            }
+            ChunkType::Fill | ChunkType::Crc32 => {
+                let mut value = [0; 4];
+                self.reader.read_exact(&mut value)?;
+                let data = ChunkData::from_value(chunk.chunk_type, value).unwrap();
+                match (&data, &mut self.checksum) {
+                    (ChunkData::Fill(value), Some(checksum)) => checksum.update_fill(value.0, size),
+                    (ChunkData::Crc32(value), _) => self.check(*value)?,
+                    _ => (),
+                }
+                data
+            }
+            ChunkType::DontCare => {
+                self.left = chunk.data_size() as u64;
+                if let Some(checksum) = &mut self.checksum {
+                    checksum.update_fill([0; 4], size);
+                }
+                ChunkData::DontCare
+            }
+        };
+        Ok((chunk, data))
+    }
+
+    // Reader for what's left of the data of the current raw chunk
+    pub(crate) fn raw_data(&mut self) -> RawData<'_, R> {
+        RawData {
+            reader: &mut self.reader,
+            left: &mut self.left,
+            checksum: self.checksum.as_mut(),
+        }
+    }
+
+    // Running checksum of the chunks read so far, if checksums are verified
+    pub(crate) fn checksum(&self) -> Option<u32> {
+        self.checksum.as_ref().map(Checksum::value)
+    }
+
+    /// Unwrap the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Read the file header and all chunk headers of the sparse image read from `reader`, seeking
+/// over the chunk data
+///
+/// The image is expected to start at the current position of the reader. Besides the headers the
+/// offsets of the data of each chunk in the reader are returned, such that the chunks can be
+/// passed on to [crate::split::split_image] and their data read back later.
+pub fn read_chunk_table<R: Read + Seek>(
+    reader: R,
+) -> Result<(FileHeader, Vec<ChunkHeader>, Vec<u64>), ReadError> {
+    read_chunk_table_with_limits(reader, &Limits::default())
+}
+
+/// Read the file header and all chunk headers of the sparse image read from `reader`, checking the
+/// file header is within `limits`, see [read_chunk_table]
+pub fn read_chunk_table_with_limits<R: Read + Seek>(
+    mut reader: R,
+    limits: &Limits,
+) -> Result<(FileHeader, Vec<ChunkHeader>, Vec<u64>), ReadError> {
+    let base = reader.stream_position()?;
+    let mut header_bytes = FileHeaderBytes::default();
+    reader.read_exact(&mut header_bytes)?;
+    let header = FileHeader::from_bytes(&header_bytes)?;
+    limits.check(&header).map_err(ParseError::from)?;
+    let mut chunks = vec![];
+    let mut offsets = vec![];
+    let mut position = FILE_HEADER_BYTES_LEN as u64;
+    let mut start = 0;
+    for index in 0..header.chunks {
+        let mut chunk_bytes = ChunkHeaderBytes::default();
+        reader.read_exact(&mut chunk_bytes)?;
+        let (chunk, end) = ChunkHeader::from_bytes(&chunk_bytes)
+            .and_then(|chunk| chunk.check(&header, start).map(|end| (chunk, end)))
+            .map_err(|e| e.in_chunk(index, position))?;
+        start = end;
+        reader.seek(SeekFrom::Current(chunk.data_size() as i64))?;
+        offsets.push(base + position + CHUNK_HEADER_BYTES_LEN as u64);
+        position += chunk.total_size as u64;
+        chunks.push(chunk);
+    }
+    Ok((header, chunks, offsets))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        encode::Encoder, limits::LimitError, split::split_image, FillValue, DEFAULT_BLOCKSIZE,
+    };
+
+    #[test]
+    fn read_chunks() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..2 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([0x5a; 4].repeat(block));
+        raw.extend((0..block).map(|i| (i % 13) as u8));
+        let mut image = vec![];
+        Encoder::new()
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let mut reader = SparseReader::new(Cursor::new(image)).unwrap();
+        assert_eq!(reader.header().chunks, 3);
+
+        let (header, data) = reader.next_chunk().unwrap().unwrap();
+        assert_eq!(header, ChunkHeader::new_raw(2, DEFAULT_BLOCKSIZE));
+        let ChunkData::Raw(mut data) = data else {
+            panic!("Not a raw chunk");
+        };
+        // Only read part of the chunk, the rest gets skipped
+        let mut start = [0; 100];
+        data.read_exact(&mut start).unwrap();
+        assert_eq!(start, raw[..100]);
+        assert_eq!(data.left(), 2 * block as u64 - 100);
+
+        let (header, data) = reader.next_chunk().unwrap().unwrap();
+        assert_eq!(header, ChunkHeader::new_fill(4));
+        assert!(matches!(
+            data,
+            ChunkData::Fill(FillValue([0x5a, 0x5a, 0x5a, 0x5a]))
+        ));
+
+        let (_, data) = reader.next_chunk().unwrap().unwrap();
+        let ChunkData::Raw(mut data) = data else {
+            panic!("Not a raw chunk");
+        };
+        let mut last = vec![];
+        data.read_to_end(&mut last).unwrap();
+        assert_eq!(last, raw[6 * block..]);
+
+        assert!(reader.next_chunk().is_none());
+    }
+
+    #[test]
+    fn header_limits() {
+        // Crafted header announcing billions of chunks without any data
+        let header = FileHeader {
+            block_size: DEFAULT_BLOCKSIZE,
+            blocks: u32::MAX,
+            chunks: u32::MAX,
+            checksum: 0,
+        };
+        let image = header.to_bytes();
+        let limits = Limits {
+            max_chunks: 1 << 20,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            SparseReader::with_limits(Cursor::new(image), &limits),
+            Err(ReadError::Parse(ParseError::Limit(
+                LimitError::TooManyChunks { .. }
+            )))
+        ));
+        let limits = Limits {
+            max_expanded_size: 1 << 30,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            SparseReader::with_limits(Cursor::new(image), &limits),
+            Err(ReadError::Parse(ParseError::Limit(
+                LimitError::TooLarge { .. }
+            )))
+        ));
+        assert!(SparseReader::new(Cursor::new(image)).is_ok());
+    }
+
+    #[test]
+    fn chunk_errors() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..block).map(|i| (i % 251) as u8).collect();
+        raw.extend([0x5a; 4].repeat(block / 4));
+        let mut image = vec![];
+        Encoder::new()
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        let second = FILE_HEADER_BYTES_LEN + CHUNK_HEADER_BYTES_LEN + block;
+
+        let error = |image: Vec<u8>| {
+            let mut reader = SparseReader::new(Cursor::new(image)).unwrap();
+            reader.next_chunk().unwrap().unwrap();
+            match reader.next_chunk().unwrap() {
+                Err(ReadError::Parse(ParseError::Chunk {
+                    index,
+                    offset,
+                    error,
+                })) => (index, offset, *error),
+                r => panic!("Unexpected result: {r:?}"),
+            }
+        };
+
+        let mut unknown = image.clone();
+        unknown[second] = 0x42;
+        assert!(matches!(
+            error(unknown),
+            (1, offset, ParseError::UnknownChunkType) if offset == second as u64
+        ));
+
+        // Fill chunk with more than 4 bytes of data
+        let mut size = image.clone();
+        size[second + 8] += 4;
+        size.extend([0; 4]);
+        assert!(matches!(
+            error(size),
+            (1, offset, ParseError::UnexpectedSize) if offset == second as u64
+        ));
+
+        // Fill chunk beyond the expanded size of the image
+        let mut blocks = image.clone();
+        blocks[second + 4..second + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            error(blocks),
+            (1, offset, ParseError::ExceedsImage) if offset == second as u64
+        ));
+    }
+
+    #[test]
+    fn skip_unknown_chunks() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let raw: Vec<u8> = (0..2 * block).map(|i| (i % 251) as u8).collect();
+        let (_, image) = crate::builder::SparseImageBuilder::new(Cursor::new(vec![]))
+            .unwrap()
+            .raw(&raw[..block])
+            .unwrap()
+            .fill([0x11; 4], 2)
+            .unwrap()
+            .raw(&raw[block..])
+            .unwrap()
+            .finish()
+            .unwrap();
+        let mut image = image.into_inner();
+        let second = FILE_HEADER_BYTES_LEN + CHUNK_HEADER_BYTES_LEN + block;
+        image[second..second + 2].copy_from_slice(&0xcaffu16.to_le_bytes());
+        assert!(SparseReader::new(Cursor::new(&image))
+            .unwrap()
+            .expand(std::io::sink())
+            .is_err());
+
+        let skipped = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let s = skipped.clone();
+        let mut expanded = vec![];
+        SparseReader::new(Cursor::new(&image))
+            .unwrap()
+            .skip_unknown_chunks(move |chunk| s.lock().unwrap().push(chunk.clone()))
+            .expand(&mut expanded)
+            .unwrap();
+        assert_eq!(
+            *skipped.lock().unwrap(),
+            [UnknownChunk {
+                chunk_type: 0xcaff,
+                chunk_size: 2,
+                total_size: CHUNK_HEADER_BYTES_LEN as u32 + 4,
+                index: 1,
+                offset: second as u64,
+            }]
+        );
+        assert_eq!(expanded[..block], raw[..block]);
+        assert_eq!(expanded[block..3 * block], vec![0; 2 * block]);
+        assert_eq!(expanded[3 * block..], raw[block..]);
+    }
+
+    #[test]
+    fn verify_checksums() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let raw: Vec<u8> = (0..block).map(|i| (i % 251) as u8).collect();
+        let mut checksum = Checksum::new();
+        checksum.update(&raw);
+        let crc = checksum.value();
+        checksum.update_fill([0x11; 4], block as u64);
+        checksum.update_fill([0; 4], block as u64);
+
+        let image = |raw: &[u8], checksum: u32| {
+            let mut image = FileHeader {
+                block_size: DEFAULT_BLOCKSIZE,
+                blocks: 3,
+                chunks: 4,
+                checksum,
+            }
+            .to_bytes()
+            .to_vec();
+            image.extend(ChunkHeader::new_raw(1, DEFAULT_BLOCKSIZE).to_bytes());
+            image.extend(raw);
+            image.extend(ChunkHeader::new_crc32().to_bytes());
+            image.extend(crc.to_le_bytes());
+            image.extend(ChunkHeader::new_fill(1).to_bytes());
+            image.extend([0x11; 4]);
+            image.extend(ChunkHeader::new_dontcare(1).to_bytes());
+            image
+        };
+        let verify = |image: Vec<u8>| {
+            SparseReader::new(Cursor::new(image))
+                .unwrap()
+                .verify_checksums(true)
+                .expand(std::io::sink())
+        };
+
+        assert_eq!(
+            verify(image(&raw, checksum.value())).unwrap(),
+            3 * block as u64
+        );
+
+        let mut corrupt = raw.clone();
+        corrupt[100] ^= 1;
+        assert!(matches!(
+            verify(image(&corrupt, checksum.value())),
+            Err(ReadError::ChecksumMismatch { offset, expected, .. })
+                if offset == block as u64 && expected == crc
+        ));
+        // Unread raw data is checksummed as well
+        let mut reader = SparseReader::new(Cursor::new(image(&corrupt, 0)))
+            .unwrap()
+            .verify_checksums(true);
+        reader.next_chunk().unwrap().unwrap();
+        assert!(reader.next_chunk().unwrap().is_err());
+
+        assert!(matches!(
+            verify(image(&raw, 0x1234)),
+            Err(ReadError::ChecksumMismatch { offset, expected: 0x1234, .. })
+                if offset == 3 * block as u64
+        ));
+        // Not verified unless requested
+        SparseReader::new(Cursor::new(image(&raw, 0x1234)))
+            .unwrap()
+            .expand(std::io::sink())
+            .unwrap();
+    }
+
+    #[test]
+    fn chunk_table() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..2 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([0x5a; 4].repeat(block / 4));
+        raw.extend((0..block).map(|i| (i % 13) as u8));
+        // Image preceded by other data
+        let mut image = vec![0xff; 10];
+        Encoder::new()
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let mut reader = Cursor::new(&image);
+        reader.set_position(10);
+        let (header, chunks, offsets) = read_chunk_table(&mut reader).unwrap();
+        assert_eq!(reader.position(), image.len() as u64);
+        assert_eq!(header.chunks, 3);
+        assert_eq!(
+            chunks,
+            [
+                ChunkHeader::new_raw(2, DEFAULT_BLOCKSIZE),
+                ChunkHeader::new_fill(1),
+                ChunkHeader::new_raw(1, DEFAULT_BLOCKSIZE),
+            ]
+        );
+        let first = 10 + FILE_HEADER_BYTES_LEN + CHUNK_HEADER_BYTES_LEN;
+        assert_eq!(offsets[0], first as u64);
+        assert_eq!(&image[first..][..8], &raw[..8]);
+        assert_eq!(image[offsets[1] as usize..][..4], [0x5a; 4]);
+        assert_eq!(&image[offsets[2] as usize..], &raw[3 * block..]);
+        assert_eq!(split_image(&header, &chunks, 8192).unwrap().len(), 3);
+    }
+}
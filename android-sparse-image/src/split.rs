@@ -1,3 +1,16 @@
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::{
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "std")]
+use crate::{
+    checksum::Checksum,
+    encode::{read_block, write_crc32_chunk},
+    reader::{read_chunk_table, ReadError},
+};
 use crate::{
     ChunkHeader, ChunkType, FileHeader, CHUNK_HEADER_BYTES_LEN, DEFAULT_BLOCKSIZE,
     FILE_HEADER_BYTES_LEN,
@@ -8,6 +21,7 @@ use thiserror::Error;
 /// (chunk) header should be written out first followed by size bytes from the original file from
 /// offset (in bytes) onwards
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SplitChunk {
     /// Chunk header
     pub header: ChunkHeader,
@@ -20,6 +34,7 @@ pub struct SplitChunk {
 /// A definition of a split sparse image; When writing out or downloading to a device the  (file)
 /// header should be written first followed by each chunk
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Split {
     /// Global file header
     pub header: FileHeader,
@@ -42,33 +57,262 @@ impl Split {
         Split { header, chunks }
     }
 
-    /// Total size of the sparse image that would be generated when writing out the split
-    pub fn sparse_size(&self) -> usize {
-        FILE_HEADER_BYTES_LEN
-            + self
-                .chunks
-                .iter()
-                .map(|c| c.header.total_size as usize)
-                .sum::<usize>()
+    /// Total size of the sparse image that would be generated when writing out the split;
+    /// Saturates at `u64::MAX`
+    pub fn sparse_size(&self) -> u64 {
+        self.chunks
+            .iter()
+            .try_fold(FILE_HEADER_BYTES_LEN as u64, |size, c| {
+                size.checked_add(c.header.total_size.into())
+            })
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Size of the split as a single download, checking it fits in `max_download` bytes (e.g. the
+    /// max-download-size of a device)
+    pub fn download_size(&self, max_download: u32) -> Result<u32, SplitError> {
+        let size = self.sparse_size();
+        u32::try_from(size)
+            .ok()
+            .filter(|&size| size <= max_download)
+            .ok_or(SplitError::TooLarge {
+                size,
+                max: max_download,
+            })
+    }
+}
+
+/// Writer of [Split]s as standalone sparse images
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct SplitWriter {
+    crc32_chunk: bool,
+    header_checksum: bool,
+}
+
+#[cfg(feature = "std")]
+impl SplitWriter {
+    /// Create a writer writing splits as-is
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a Crc32 chunk with the checksum of the data of each split; Disabled by default
+    ///
+    /// The Crc32 chunk is added on top of the chunks of the split, so splits written with it need
+    /// to be planned with room for one more chunk of 16 bytes (the size of
+    /// [ChunkHeader::new_crc32]); I.e. with a size and chunk limit one chunk below the actual
+    /// limits. [SplitWriter::sparse_size] includes the Crc32 chunk, and [SplitWriter::split_file]
+    /// leaves room for it.
+    pub fn crc32_chunk(mut self, crc32_chunk: bool) -> Self {
+        self.crc32_chunk = crc32_chunk;
+        self
+    }
+
+    /// Fill in [FileHeader::checksum] of each split with the checksum of its data; Disabled by
+    /// default, in which case the checksum of the split header is written as-is
+    pub fn header_checksum(mut self, header_checksum: bool) -> Self {
+        self.header_checksum = header_checksum;
+        self
+    }
+
+    /// Size of the sparse image written for a split
+    pub fn sparse_size(&self, split: &Split) -> u64 {
+        let crc32 = if self.crc32_chunk {
+            ChunkHeader::new_crc32().total_size.into()
+        } else {
+            0
+        };
+        split.sparse_size().saturating_add(crc32)
+    }
+
+    /// Write a split to `output`, reading the chunk data from `source` at the offsets recorded in
+    /// the split
+    ///
+    /// For raw sources which don't end at a block boundary the last chunk is padded with zeroes.
+    /// If a checksum is written the data gets read twice; Once to compute the checksum and once
+    /// to write it.
+    pub fn write<R, W>(&self, split: &Split, source: &mut R, output: &mut W) -> std::io::Result<()>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        let checksum = if self.crc32_chunk || self.header_checksum {
+            Some(split_checksum(split, source)?)
+        } else {
+            None
+        };
+        let mut header = split.header.clone();
+        header.chunks += u32::from(self.crc32_chunk);
+        if let (Some(checksum), true) = (&checksum, self.header_checksum) {
+            header.checksum = checksum.value();
+        }
+        output.write_all(&header.to_bytes())?;
+
+        let mut buf = vec![0; 64 * 1024];
+        for chunk in &split.chunks {
+            output.write_all(&chunk.header.to_bytes())?;
+            source.seek(SeekFrom::Start(chunk.offset as u64))?;
+            let mut left = chunk.size;
+            while left > 0 {
+                let n = left.min(buf.len());
+                read_block(source, &mut buf[..n])?;
+                output.write_all(&buf[..n])?;
+                left -= n;
+            }
+        }
+        if let (Some(checksum), true) = (&checksum, self.crc32_chunk) {
+            write_crc32_chunk(output, checksum)?;
+        }
+        output.flush()
+    }
+
+    /// Write each split to its own file, named after `base` with `.partNN.simg` appended (e.g.
+    /// `image.part00.simg`, `image.part01.simg`, ...); Returns the paths of the written files
+    ///
+    /// Existing files are overwritten. See [SplitWriter::write] for how the data is read from
+    /// `source`.
+    pub fn write_files<R, P>(
+        &self,
+        splits: &[Split],
+        source: &mut R,
+        base: P,
+    ) -> std::io::Result<Vec<PathBuf>>
+    where
+        R: Read + Seek,
+        P: AsRef<Path>,
+    {
+        splits
+            .iter()
+            .enumerate()
+            .map(|(i, split)| {
+                let mut path = base.as_ref().as_os_str().to_os_string();
+                path.push(format!(".part{i:02}.simg"));
+                let path = PathBuf::from(path);
+                let mut output = BufWriter::new(std::fs::File::create(&path)?);
+                self.write(split, source, &mut output)?;
+                Ok(path)
+            })
+            .collect()
+    }
+
+    /// Split the sparse image at `path` into files fitting into `size` bytes, like simg2simg;
+    /// Returns the paths of the written files
+    ///
+    /// The files are named as by [SplitWriter::write_files]. The size includes the Crc32 chunk if
+    /// enabled (see [SplitWriter::crc32_chunk]).
+    pub fn split_file<P, Q>(
+        &self,
+        path: P,
+        size: u32,
+        base: Q,
+    ) -> Result<Vec<PathBuf>, SplitFileError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.split_file_limited(path.as_ref(), size, None, base.as_ref())
+    }
+
+    /// Split the sparse image at `path` into files like [SplitWriter::split_file], each having at
+    /// most `max_chunks` chunks including the Crc32 chunk if enabled
+    pub fn split_file_with_max_chunks<P, Q>(
+        &self,
+        path: P,
+        size: u32,
+        max_chunks: u32,
+        base: Q,
+    ) -> Result<Vec<PathBuf>, SplitFileError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.split_file_limited(path.as_ref(), size, Some(max_chunks), base.as_ref())
+    }
+
+    fn split_file_limited(
+        &self,
+        path: &Path,
+        size: u32,
+        max_chunks: Option<u32>,
+        base: &Path,
+    ) -> Result<Vec<PathBuf>, SplitFileError> {
+        // Leave room for the Crc32 chunk appended to each split
+        let (size, max_chunks) = if self.crc32_chunk {
+            let size = size
+                .checked_sub(ChunkHeader::new_crc32().total_size)
+                .ok_or(SplitError::TooSmall)?;
+            let max_chunks = max_chunks
+                .map(|max| max.checked_sub(1).ok_or(SplitError::TooFewChunks))
+                .transpose()?;
+            (size, max_chunks)
+        } else {
+            (size, max_chunks)
+        };
+        let mut file = std::fs::File::open(path)?;
+        let (header, chunks, _) = read_chunk_table(&mut file)?;
+        let splits = split_image_limited(&header, &chunks, size, max_chunks)?;
+        Ok(self.write_files(&splits, &mut file, base)?)
     }
 }
 
+// Checksum of the expanded data of a split
+#[cfg(feature = "std")]
+fn split_checksum<R>(split: &Split, source: &mut R) -> std::io::Result<Checksum>
+where
+    R: Read + Seek,
+{
+    let mut checksum = Checksum::new();
+    let mut buf = vec![0; 64 * 1024];
+    for chunk in &split.chunks {
+        let size = chunk.header.out_size(&split.header);
+        match chunk.header.chunk_type {
+            ChunkType::Raw => {
+                source.seek(SeekFrom::Start(chunk.offset as u64))?;
+                let mut left = chunk.size;
+                while left > 0 {
+                    let n = left.min(buf.len());
+                    read_block(source, &mut buf[..n])?;
+                    checksum.update(&buf[..n]);
+                    left -= n;
+                }
+            }
+            ChunkType::Fill => {
+                let mut value = [0; 4];
+                source.seek(SeekFrom::Start(chunk.offset as u64))?;
+                source.read_exact(&mut value)?;
+                checksum.update_fill(value, size);
+            }
+            ChunkType::DontCare => checksum.update_fill([0; 4], size),
+            ChunkType::Crc32 => (),
+        }
+    }
+    Ok(checksum)
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 struct SplitBuilder {
+    size: u32,
     space: u32,
+    // Number of chunks which can still be added
+    chunks_left: u32,
     block_size: u32,
+    // Whether the chunks are kept, rather than only accounting for their size
+    record: bool,
     chunks: Vec<SplitChunk>,
 }
 
 impl SplitBuilder {
-    fn new(block_size: u32, mut space: u32, blocks_offset: u32) -> Self {
-        space -= FILE_HEADER_BYTES_LEN as u32;
+    fn new(block_size: u32, size: u32, max_chunks: Option<u32>, blocks_offset: u32) -> Self {
+        let mut space = size - FILE_HEADER_BYTES_LEN as u32;
+        let mut chunks_left = max_chunks.unwrap_or(u32::MAX);
         let chunks = if blocks_offset == 0 {
             vec![]
         } else {
             // Seek to the offset first
             let header = ChunkHeader::new_dontcare(blocks_offset);
             space -= header.total_size;
+            chunks_left -= 1;
             vec![SplitChunk {
                 header,
                 offset: 0,
@@ -76,21 +320,35 @@ impl SplitBuilder {
             }]
         };
         Self {
+            size,
             space,
+            chunks_left,
             block_size,
+            record: true,
             chunks,
         }
     }
 
+    fn push(&mut self, chunk: SplitChunk) {
+        if self.record {
+            self.chunks.push(chunk);
+        }
+    }
+
+    // Sparse size of the split so far
+    fn sparse_size(&self) -> u64 {
+        u64::from(self.size - self.space)
+    }
+
     fn try_add_chunk(&mut self, chunk: &ChunkHeader, image_offset: usize) -> bool {
-        if self.space > chunk.total_size {
-            let split = SplitChunk {
+        if self.space > chunk.total_size && self.chunks_left > 0 {
+            self.push(SplitChunk {
                 header: chunk.clone(),
                 offset: image_offset,
                 size: chunk.data_size(),
-            };
-            self.chunks.push(split);
+            });
             self.space -= chunk.total_size;
+            self.chunks_left -= 1;
             true
         } else {
             false
@@ -102,12 +360,13 @@ impl SplitBuilder {
         let left = self.space.saturating_sub(CHUNK_HEADER_BYTES_LEN as u32);
         let blocks_left = left / self.block_size;
 
-        if blocks_left > 0 {
+        if blocks_left > 0 && self.chunks_left > 0 {
             let blocks = blocks.min(blocks_left);
             let header = ChunkHeader::new_raw(blocks, self.block_size);
             self.space -= header.total_size;
+            self.chunks_left -= 1;
 
-            self.chunks.push(SplitChunk {
+            self.push(SplitChunk {
                 size: header.data_size(),
                 offset: image_offset,
                 header,
@@ -128,90 +387,324 @@ impl SplitBuilder {
 pub enum SplitError {
     #[error("Size is too small to fit chunks")]
     TooSmall,
+    #[error("Block size {0} is not a non-zero multiple of 4")]
+    InvalidBlockSize(u32),
+    #[error("Chunk limit is too small to fit chunks")]
+    TooFewChunks,
+    #[error("Split of {size} bytes doesn't fit in a download of {max} bytes")]
+    TooLarge { size: u64, max: u32 },
+}
+
+/// Errors while splitting a sparse image file
+#[cfg(feature = "std")]
+#[derive(Debug, Error)]
+pub enum SplitFileError {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error(transparent)]
+    Split(#[from] SplitError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
-fn check_minimal_size(size: u32, block_size: u32) -> Result<(), SplitError> {
+fn check_minimal_size(
+    size: u32,
+    block_size: u32,
+    max_chunks: Option<u32>,
+) -> Result<(), SplitError> {
+    if block_size == 0 || block_size % 4 != 0 {
+        return Err(SplitError::InvalidBlockSize(block_size));
+    }
     // At the very list the size we split into should be enough to have:
     // * A file header
     // * A Chunk header for an initial don't care block
     // * A Chunk header for a raw block and a single block
-    if size < FILE_HEADER_BYTES_LEN as u32 + 2 * CHUNK_HEADER_BYTES_LEN as u32 + block_size {
+    let minimal = FILE_HEADER_BYTES_LEN as u64 + 2 * CHUNK_HEADER_BYTES_LEN as u64;
+    if u64::from(size) < minimal + u64::from(block_size) {
         return Err(SplitError::TooSmall);
     }
+    // Similarly there should be room for both of those chunks
+    if max_chunks.is_some_and(|max| max < 2) {
+        return Err(SplitError::TooFewChunks);
+    }
     Ok(())
 }
 
 /// Split an existing sparse image based on its file header and chunks into multiple splits fitting
 /// into the given `size`
+///
+/// Crc32 chunks of the image are left out, as their checksums don't apply to the splits; Use
+/// [SplitWriter::crc32_chunk] to add a checksum to each split when writing them out. See
+/// [SplitIter] to produce the splits one at a time instead.
 pub fn split_image(
     header: &FileHeader,
     chunks: &[ChunkHeader],
     size: u32,
 ) -> Result<Vec<Split>, SplitError> {
-    check_minimal_size(size, header.block_size)?;
-    let (_, _, builder, mut splits) = chunks.iter().try_fold(
-        (
-            // output offset in blocks
-            0,
-            // Start of the first data area (after initial file and chunk header
-            FILE_HEADER_BYTES_LEN + CHUNK_HEADER_BYTES_LEN,
-            SplitBuilder::new(header.block_size, size, 0),
-            // Splits collector
-            vec![],
-        ),
-        |(block_offset, image_offset, mut builder, mut splits), chunk| {
-            if !builder.try_add_chunk(chunk, image_offset) {
-                if chunk.chunk_type == ChunkType::Raw {
-                    // Try packing in partial chunks
-                    let mut blocks = 0;
-                    loop {
-                        blocks += builder.add_raw(
-                            image_offset + (blocks * header.block_size) as usize,
-                            chunk.chunk_size - blocks,
-                        );
-
-                        if blocks >= chunk.chunk_size {
-                            break;
-                        } else {
-                            splits.push(builder.finish());
-                            builder =
-                                SplitBuilder::new(header.block_size, size, block_offset + blocks);
-                        }
-                    }
-                } else {
-                    splits.push(builder.finish());
-                    builder = SplitBuilder::new(header.block_size, size, block_offset);
-                    if !builder.try_add_chunk(chunk, image_offset) {
-                        return Err(SplitError::TooSmall);
-                    }
+    split_image_limited(header, chunks, size, None)
+}
+
+/// Split an existing sparse image like [split_image], with each split having at most
+/// `max_chunks` chunks; For bootloaders which limit the number of chunks in a sparse image
+pub fn split_image_with_max_chunks(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    size: u32,
+    max_chunks: u32,
+) -> Result<Vec<Split>, SplitError> {
+    split_image_limited(header, chunks, size, Some(max_chunks))
+}
+
+fn split_image_limited(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    size: u32,
+    max_chunks: Option<u32>,
+) -> Result<Vec<Split>, SplitError> {
+    SplitIter::limited(header, chunks.iter().cloned(), size, max_chunks)?.collect()
+}
+
+/// Iterator producing the splits of an existing sparse image one at a time, see [split_image]
+///
+/// Only the split being produced is kept in memory, and the chunks are consumed as needed; So the
+/// first split is available without going over all chunks of large and heavily fragmented images
+/// first.
+#[derive(Clone, Debug)]
+pub struct SplitIter<I> {
+    chunks: I,
+    block_size: u32,
+    checksum: u32,
+    size: u32,
+    max_chunks: Option<u32>,
+    // Chunk being split, with the blocks of it which are already in a split
+    current: Option<(ChunkHeader, u32)>,
+    // Output offset in blocks and image offset of the data of the current chunk
+    block_offset: u32,
+    image_offset: usize,
+    splits: usize,
+    done: bool,
+    // Whether the chunks of the splits are kept, see [SplitBuilder]
+    record: bool,
+}
+
+impl<I: Iterator<Item = ChunkHeader>> SplitIter<I> {
+    /// Create an iterator over the splits of the image with the given file header and chunks
+    pub fn new(header: &FileHeader, chunks: I, size: u32) -> Result<Self, SplitError> {
+        Self::limited(header, chunks, size, None)
+    }
+
+    /// Create an iterator over the splits of the image, with each split having at most
+    /// `max_chunks` chunks
+    pub fn with_max_chunks(
+        header: &FileHeader,
+        chunks: I,
+        size: u32,
+        max_chunks: u32,
+    ) -> Result<Self, SplitError> {
+        Self::limited(header, chunks, size, Some(max_chunks))
+    }
+
+    fn limited(
+        header: &FileHeader,
+        chunks: I,
+        size: u32,
+        max_chunks: Option<u32>,
+    ) -> Result<Self, SplitError> {
+        check_minimal_size(size, header.block_size, max_chunks)?;
+        Ok(Self {
+            chunks,
+            block_size: header.block_size,
+            checksum: header.checksum,
+            size,
+            max_chunks,
+            current: None,
+            block_offset: 0,
+            // Start of the first data area (after initial file and chunk header)
+            image_offset: FILE_HEADER_BYTES_LEN + CHUNK_HEADER_BYTES_LEN,
+            splits: 0,
+            done: false,
+            record: true,
+        })
+    }
+
+    // Move on to the next chunk, returning false when there are no more chunks
+    fn advance(&mut self) -> bool {
+        if let Some((chunk, _)) = self.current.take() {
+            self.block_offset += chunk.chunk_size;
+            self.image_offset += chunk.total_size as usize;
+        }
+        self.current = self.chunks.next().map(|chunk| (chunk, 0));
+        self.current.is_some()
+    }
+
+    fn next_split(&mut self) -> Result<SplitBuilder, SplitError> {
+        let done = self.current.as_ref().map_or(0, |(_, done)| *done);
+        let mut builder = SplitBuilder::new(
+            self.block_size,
+            self.size,
+            self.max_chunks,
+            self.block_offset + done,
+        );
+        builder.record = self.record;
+        let mut empty = true;
+        loop {
+            let Some((chunk, done)) = &mut self.current else {
+                if self.advance() {
+                    continue;
+                }
+                break;
+            };
+            if chunk.chunk_type == ChunkType::Crc32 {
+                // Checksums of the whole image don't apply to the splits, so skip over them
+                self.advance();
+            } else if *done == 0 && builder.try_add_chunk(chunk, self.image_offset) {
+                empty = false;
+                self.advance();
+            } else if chunk.chunk_type == ChunkType::Raw {
+                // Try packing in partial chunks
+                let added = builder.add_raw(
+                    self.image_offset + *done as usize * self.block_size as usize,
+                    chunk.chunk_size - *done,
+                );
+                *done += added;
+                if *done < chunk.chunk_size {
+                    return match added {
+                        0 if empty => Err(SplitError::TooSmall),
+                        _ => Ok(builder),
+                    };
                 }
+                empty = false;
+                self.advance();
+            } else if empty {
+                return Err(SplitError::TooSmall);
+            } else {
+                return Ok(builder);
             }
-            Ok((
-                block_offset + chunk.chunk_size,
-                image_offset + chunk.total_size as usize,
-                builder,
-                splits,
-            ))
-        },
-    )?;
-    splits.push(builder.finish());
-    Ok(splits)
+        }
+        self.done = true;
+        Ok(builder)
+    }
+
+    // Produce the next split with the given function
+    fn next_with<T, F>(&mut self, f: F) -> Option<Result<T, SplitError>>
+    where
+        F: FnOnce(SplitBuilder) -> T,
+    {
+        if self.done {
+            return None;
+        }
+        let split = self.next_split();
+        self.splits += 1;
+        if split.is_err() {
+            self.done = true;
+        }
+        Some(split.map(f))
+    }
+}
+
+impl<I: Iterator<Item = ChunkHeader>> Iterator for SplitIter<I> {
+    type Item = Result<Split, SplitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.splits == 0;
+        let checksum = self.checksum;
+        let split = self.next_with(SplitBuilder::finish)?;
+        Some(split.map(|mut split| {
+            // A single split is the whole image, so the image checksum still applies
+            if first && self.done {
+                split.header.checksum = checksum;
+            }
+            split
+        }))
+    }
+}
+
+/// Number of splits of an image and their sizes, see [plan_splits]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SplitPlan {
+    /// Sparse size of each split, as given by [Split::sparse_size]
+    pub sizes: Vec<u64>,
+}
+
+impl SplitPlan {
+    /// Number of splits
+    pub fn count(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// Total size of all splits
+    pub fn total_size(&self) -> u64 {
+        self.sizes.iter().sum()
+    }
+}
+
+/// Determine the splits [split_image] would produce for the same arguments, without building
+/// them; Useful to show the number of splits and the total amount of data to be sent up front
+///
+/// Only the size of each split is computed, so no chunk lists get allocated. Note that the sizes
+/// don't include Crc32 chunks added by [SplitWriter::crc32_chunk].
+pub fn plan_splits(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    size: u32,
+) -> Result<SplitPlan, SplitError> {
+    plan_splits_limited(header, chunks, size, None)
+}
+
+/// Determine the splits [split_image_with_max_chunks] would produce for the same arguments, see
+/// [plan_splits]
+pub fn plan_splits_with_max_chunks(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    size: u32,
+    max_chunks: u32,
+) -> Result<SplitPlan, SplitError> {
+    plan_splits_limited(header, chunks, size, Some(max_chunks))
+}
+
+fn plan_splits_limited(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    size: u32,
+    max_chunks: Option<u32>,
+) -> Result<SplitPlan, SplitError> {
+    let mut iter = SplitIter::limited(header, chunks.iter().cloned(), size, max_chunks)?;
+    iter.record = false;
+    let mut sizes = vec![];
+    while let Some(size) = iter.next_with(|builder| builder.sparse_size()) {
+        sizes.push(size?);
+    }
+    Ok(SplitPlan { sizes })
 }
 
 /// Generate a set of splits for a raw image of a given `raw_size` each fitting within `size`; The
 /// raw size is rounded up to multiple of [DEFAULT_BLOCKSIZE] as that's the minimal granularity.
 /// When writing out the android sparse image the data should just be padded as needed as well!
+///
+/// Each split consists of at most two chunks, so unlike [split_image] there is no need to limit
+/// the number of chunks.
 pub fn split_raw(raw_size: usize, size: u32) -> Result<Vec<Split>, SplitError> {
-    check_minimal_size(size, DEFAULT_BLOCKSIZE)?;
-    let raw_blocks = raw_size.div_ceil(DEFAULT_BLOCKSIZE as usize) as u32;
+    split_raw_with_block_size(raw_size, DEFAULT_BLOCKSIZE, size)
+}
+
+/// Generate a set of splits for a raw image like [split_raw], using the given block size rather
+/// than the [DEFAULT_BLOCKSIZE]; Has to be a non-zero multiple of 4
+pub fn split_raw_with_block_size(
+    raw_size: usize,
+    block_size: u32,
+    size: u32,
+) -> Result<Vec<Split>, SplitError> {
+    check_minimal_size(size, block_size, None)?;
+    let raw_blocks = raw_size.div_ceil(block_size as usize) as u32;
 
     let mut block_offset = 0;
     let mut splits = vec![];
 
     while raw_blocks > block_offset {
-        let mut builder = SplitBuilder::new(DEFAULT_BLOCKSIZE, size, block_offset);
+        let mut builder = SplitBuilder::new(block_size, size, None, block_offset);
         block_offset += builder.add_raw(
-            (block_offset * DEFAULT_BLOCKSIZE) as usize,
+            block_offset as usize * block_size as usize,
             raw_blocks - block_offset,
         );
         splits.push(builder.finish());
@@ -223,6 +716,178 @@ pub fn split_raw(raw_size: usize, size: u32) -> Result<Vec<Split>, SplitError> {
 mod test {
     use super::*;
 
+    #[test]
+    fn split_crc32() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 16,
+            chunks: 4,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_raw(8, 4096),
+            ChunkHeader::new_crc32(),
+            ChunkHeader::new_fill(8),
+            ChunkHeader::new_crc32(),
+        ];
+
+        let splits = split_image(&header, &chunks, 16 * 4096).unwrap();
+        assert_eq!(splits.len(), 1);
+        assert_eq!(splits[0].header.chunks, 2);
+        assert_eq!(splits[0].header.blocks, 16);
+        assert_eq!(
+            splits[0].chunks[1],
+            SplitChunk {
+                header: ChunkHeader::new_fill(8),
+                offset: FILE_HEADER_BYTES_LEN + 3 * CHUNK_HEADER_BYTES_LEN + 8 * 4096 + 4,
+                size: 4,
+            }
+        );
+
+        let splits = split_image(&header, &chunks, 6 * 4096).unwrap();
+        assert!(splits
+            .iter()
+            .flat_map(|s| &s.chunks)
+            .all(|c| c.header.chunk_type != ChunkType::Crc32));
+        let blocks: u32 = splits
+            .iter()
+            .flat_map(|s| &s.chunks)
+            .map(|c| c.header.chunk_size)
+            .sum();
+        assert!(blocks >= 16);
+    }
+
+    #[test]
+    fn split_max_chunks() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 16,
+            chunks: 8,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_fill(2),
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_dontcare(2),
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_fill(2),
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_fill(2),
+        ];
+        assert_eq!(split_image(&header, &chunks, 1024 * 4096).unwrap().len(), 1);
+
+        let splits = split_image_with_max_chunks(&header, &chunks, 1024 * 4096, 3).unwrap();
+        assert_eq!(splits.len(), 4);
+        for split in &splits {
+            assert!(split.chunks.len() <= 3);
+            assert_eq!(split.header.chunks as usize, split.chunks.len());
+        }
+        // Each split after the first one starts with a DontCare chunk skipping what's written by
+        // the previous ones
+        assert_eq!(splits[1].chunks[0].header, ChunkHeader::new_dontcare(6));
+        assert_eq!(splits[3].header.blocks, 16);
+
+        assert!(matches!(
+            split_image_with_max_chunks(&header, &chunks, 1024 * 4096, 1),
+            Err(SplitError::TooFewChunks)
+        ));
+    }
+
+    #[test]
+    fn split_iter() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 3000,
+            chunks: 6,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_raw(600, 4096),
+            ChunkHeader::new_fill(400),
+            ChunkHeader::new_raw(1000, 4096),
+            ChunkHeader::new_crc32(),
+            ChunkHeader::new_dontcare(500),
+            ChunkHeader::new_raw(500, 4096),
+        ];
+        let expected = split_image(&header, &chunks, 512 * 4096).unwrap();
+        assert!(expected.len() > 3);
+
+        // Chunks are only consumed as far as needed for the first split
+        let consumed = std::cell::Cell::new(0);
+        let mut iter = SplitIter::new(
+            &header,
+            chunks
+                .iter()
+                .cloned()
+                .inspect(|_| consumed.set(consumed.get() + 1)),
+            512 * 4096,
+        )
+        .unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), expected[0]);
+        assert_eq!(consumed.get(), 1);
+        let splits: Vec<_> = iter.map(Result::unwrap).collect();
+        assert_eq!(consumed.get(), chunks.len());
+        assert_eq!(splits, expected[1..]);
+    }
+
+    #[test]
+    fn plan() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 3000,
+            chunks: 6,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_raw(600, 4096),
+            ChunkHeader::new_fill(400),
+            ChunkHeader::new_raw(1000, 4096),
+            ChunkHeader::new_crc32(),
+            ChunkHeader::new_dontcare(500),
+            ChunkHeader::new_raw(500, 4096),
+        ];
+        for (size, max_chunks) in [
+            (512 * 4096, None),
+            (100 * 4096 + 10, Some(2)),
+            (4096 * 4096, None),
+            (4096 * 4096, Some(3)),
+        ] {
+            let splits = split_image_limited(&header, &chunks, size, max_chunks).unwrap();
+            let plan = plan_splits_limited(&header, &chunks, size, max_chunks).unwrap();
+            assert_eq!(plan.count(), splits.len());
+            assert_eq!(
+                plan.sizes,
+                splits.iter().map(Split::sparse_size).collect::<Vec<_>>()
+            );
+            assert_eq!(
+                plan.total_size(),
+                splits.iter().map(Split::sparse_size).sum::<u64>()
+            );
+        }
+        assert!(matches!(
+            plan_splits(&header, &chunks, 4096),
+            Err(SplitError::TooSmall)
+        ));
+    }
+
+    #[test]
+    fn download_size() {
+        let splits = split_raw(8 * DEFAULT_BLOCKSIZE as usize, 3 * DEFAULT_BLOCKSIZE).unwrap();
+        for split in &splits {
+            let size = split.sparse_size();
+            assert!(size <= 3 * DEFAULT_BLOCKSIZE as u64);
+            assert_eq!(
+                split.download_size(3 * DEFAULT_BLOCKSIZE).unwrap() as u64,
+                size
+            );
+            assert!(matches!(
+                split.download_size(size as u32 - 1),
+                Err(SplitError::TooLarge { size: s, max }) if s == size && max as u64 == size - 1
+            ));
+        }
+    }
+
     #[test]
     fn split_simple() {
         let header = FileHeader {
@@ -439,4 +1104,299 @@ mod test {
             );
         }
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_splits() {
+        use crate::{encode::Encoder, reader::SparseReader, ChunkData};
+        use std::io::Cursor;
+
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..6 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([7; 4].repeat(block / 2));
+        let mut image = vec![];
+        let header = Encoder::new()
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        let chunks = [
+            ChunkHeader::new_raw(6, DEFAULT_BLOCKSIZE),
+            ChunkHeader::new_fill(2),
+        ];
+        let splits = split_image(&header, &chunks, 4 * DEFAULT_BLOCKSIZE).unwrap();
+        assert!(splits.len() > 1);
+
+        let writer = SplitWriter::new().crc32_chunk(true).header_checksum(true);
+        let mut expanded = vec![0; raw.len()];
+        for split in &splits {
+            let mut out = vec![];
+            writer
+                .write(split, &mut Cursor::new(&image), &mut out)
+                .unwrap();
+            assert_eq!(out.len() as u64, writer.sparse_size(split));
+            assert_ne!(
+                out[FILE_HEADER_BYTES_LEN - 4..FILE_HEADER_BYTES_LEN],
+                [0; 4]
+            );
+
+            // Each split is a valid image with a matching checksum
+            let mut reader = SparseReader::new(Cursor::new(out))
+                .unwrap()
+                .verify_checksums(true);
+            let header = reader.header().clone();
+            let mut offset = 0;
+            while let Some(chunk) = reader.next_chunk() {
+                let (chunk, data) = chunk.unwrap();
+                let size = chunk.out_size(&header) as usize;
+                match data {
+                    ChunkData::Raw(mut data) => {
+                        data.read_exact(&mut expanded[offset..][..size]).unwrap()
+                    }
+                    ChunkData::Fill(value) => expanded[offset..][..size]
+                        .iter_mut()
+                        .enumerate()
+                        .for_each(|(i, b)| *b = value.0[i % 4]),
+                    _ => (),
+                }
+                offset += size;
+            }
+        }
+        assert_eq!(expanded, raw);
+
+        // An image fitting in a single split keeps its checksum
+        let header = FileHeader {
+            checksum: 0x1234,
+            ..header
+        };
+        let splits = split_image(&header, &chunks, 16 * DEFAULT_BLOCKSIZE).unwrap();
+        assert_eq!(splits.len(), 1);
+        assert_eq!(splits[0].header, header);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn split_block_sizes() {
+        use crate::{device::DeviceWriter, encode::Encoder, reader::SparseReader};
+        use std::io::Cursor;
+
+        let path = std::env::temp_dir().join(format!("split-block-sizes-{}", std::process::id()));
+        for block_size in [1024, 64 * 1024] {
+            let block = block_size as usize;
+            let mut raw: Vec<u8> = (0..5 * block).map(|i| (i % 251) as u8).collect();
+            raw.extend([7; 4].repeat(block / 4));
+            raw.extend(vec![0; block]);
+            raw.extend((0..block).map(|i| (i % 13) as u8));
+            let mut image = vec![];
+            let header = Encoder::new()
+                .block_size(block_size)
+                .encode(Cursor::new(&raw), &mut image)
+                .unwrap();
+            let mut chunks = vec![];
+            let mut reader = SparseReader::new(Cursor::new(&image)).unwrap();
+            while let Some(chunk) = reader.next_chunk() {
+                chunks.push(chunk.unwrap().0);
+            }
+
+            // Apply each split in turn, which should result in the raw image
+            let size = 2 * block_size + 100;
+            let splits = split_image(&header, &chunks, size).unwrap();
+            assert!(splits.len() > 2);
+            let raw_splits = split_raw_with_block_size(raw.len(), block_size, size).unwrap();
+            assert!(raw_splits.len() > 2);
+            for (splits, source) in [(splits, &image), (raw_splits, &raw)] {
+                std::fs::write(&path, vec![0xaa; raw.len()]).unwrap();
+                let device = std::fs::File::options().write(true).open(&path).unwrap();
+                for split in &splits {
+                    let mut written = vec![];
+                    SplitWriter::new()
+                        .write(split, &mut Cursor::new(source), &mut written)
+                        .unwrap();
+                    assert!(written.len() <= size as usize);
+                    let reader = SparseReader::new(Cursor::new(written)).unwrap();
+                    DeviceWriter::new().write(reader, &device).unwrap();
+                }
+                assert_eq!(std::fs::read(&path).unwrap(), raw);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            split_raw_with_block_size(4096, 1023, 64 * 1024),
+            Err(SplitError::InvalidBlockSize(1023))
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    proptest::proptest! {
+        #[test]
+        fn split_round_trip(
+            blocks in proptest::collection::vec(0..4u8, 1..48),
+            block_size in proptest::sample::select(&[512u32, 4096][..]),
+            dontcare in proptest::bool::ANY,
+            size_blocks in 1..6u32,
+            extra in 0..512u32,
+            max_chunks in proptest::option::of(2..6u32),
+        ) {
+            use crate::{
+                device::DeviceWriter,
+                encode::{Encoder, ZeroBlocks},
+                reader::SparseReader,
+            };
+            use std::io::Cursor;
+
+            // Blocks of random data, zeroes and fill patterns
+            let block = block_size as usize;
+            let mut raw = vec![];
+            for (i, kind) in blocks.iter().enumerate() {
+                match kind {
+                    0 => raw.extend((0..block).map(|b| (b * 7 + i) as u8)),
+                    1 => raw.extend(vec![0; block]),
+                    _ => raw.extend(vec![*kind; block]),
+                }
+            }
+            let zero_blocks = if dontcare { ZeroBlocks::DontCare } else { ZeroBlocks::Fill };
+            let mut image = vec![];
+            let header = Encoder::new()
+                .block_size(block_size)
+                .zero_blocks(zero_blocks)
+                .encode(Cursor::new(&raw), &mut image)
+                .unwrap();
+            let mut chunks = vec![];
+            let mut reader = SparseReader::new(Cursor::new(&image)).unwrap();
+            while let Some(chunk) = reader.next_chunk() {
+                chunks.push(chunk.unwrap().0);
+            }
+
+            let size = (FILE_HEADER_BYTES_LEN + 2 * CHUNK_HEADER_BYTES_LEN) as u32
+                + size_blocks * block_size
+                + extra;
+            let splits = split_image_limited(&header, &chunks, size, max_chunks).unwrap();
+            let path = std::env::temp_dir().join(format!("split-round-trip-{}", std::process::id()));
+            std::fs::write(&path, vec![0; raw.len()]).unwrap();
+            let device = std::fs::File::options().write(true).open(&path).unwrap();
+            for split in &splits {
+                proptest::prop_assert!(split.sparse_size() <= size.into());
+                if let Some(max) = max_chunks {
+                    proptest::prop_assert!(split.header.chunks <= max);
+                }
+                let mut written = vec![];
+                SplitWriter::new()
+                    .write(split, &mut Cursor::new(&image), &mut written)
+                    .unwrap();
+                let reader = SparseReader::new(Cursor::new(written)).unwrap();
+                DeviceWriter::new().write(reader, &device).unwrap();
+            }
+            let applied = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            proptest::prop_assert_eq!(applied, raw);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_split_files() {
+        use std::io::Cursor;
+
+        // Raw source not ending at a block boundary
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let raw: Vec<u8> = (0..5 * block + 100).map(|i| (i % 251) as u8).collect();
+        let splits = split_raw(raw.len(), 4 * DEFAULT_BLOCKSIZE).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("split-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let writer = SplitWriter::new();
+        let paths = writer
+            .write_files(&splits, &mut Cursor::new(&raw), dir.join("image"))
+            .unwrap();
+        assert_eq!(paths.len(), splits.len());
+        assert_eq!(paths[1], dir.join("image.part01.simg"));
+        for (path, split) in paths.iter().zip(&splits) {
+            let mut expected = vec![];
+            writer
+                .write(split, &mut Cursor::new(&raw), &mut expected)
+                .unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), expected);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn split_sparse_file() {
+        use crate::encode::Encoder;
+        use std::io::Cursor;
+
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..6 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend([7; 4].repeat(block / 2));
+        let mut image = vec![];
+        let header = Encoder::new()
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("split-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("image.simg"), &image).unwrap();
+        // Exactly fits a split with a Don't care and a Raw chunk of 3 blocks, but no Crc32 chunk
+        let size = (FILE_HEADER_BYTES_LEN + 2 * CHUNK_HEADER_BYTES_LEN + 3 * block) as u32;
+        let crc32_size = ChunkHeader::new_crc32().total_size;
+        let writer = SplitWriter::new().crc32_chunk(true);
+        let paths = writer
+            .split_file(dir.join("image.simg"), size, dir.join("image"))
+            .unwrap();
+
+        let (_, chunks, _) = read_chunk_table(Cursor::new(&image)).unwrap();
+        let splits = split_image(&header, &chunks, size - crc32_size).unwrap();
+        assert_eq!(paths.len(), splits.len());
+        for (path, split) in paths.iter().zip(&splits) {
+            let mut expected = vec![];
+            writer
+                .write(split, &mut Cursor::new(&image), &mut expected)
+                .unwrap();
+            let written = std::fs::read(path).unwrap();
+            assert!(written.len() <= size as usize);
+            assert_eq!(written, expected);
+        }
+
+        let paths = writer
+            .split_file_with_max_chunks(dir.join("image.simg"), size, 3, dir.join("image"))
+            .unwrap();
+        for path in &paths {
+            let written = std::fs::read(path).unwrap();
+            assert!(written.len() <= size as usize);
+            let (header, chunks, _) = read_chunk_table(Cursor::new(&written)).unwrap();
+            assert!(header.chunks <= 3);
+            assert_eq!(chunks.last().unwrap().chunk_type, ChunkType::Crc32);
+        }
+        assert!(matches!(
+            writer.split_file_with_max_chunks(dir.join("image.simg"), size, 2, dir.join("image")),
+            Err(SplitFileError::Split(SplitError::TooFewChunks))
+        ));
+
+        assert!(matches!(
+            writer.split_file(dir.join("missing"), size, dir.join("image")),
+            Err(SplitFileError::Io(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 1024,
+            chunks: 3,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_fill(8),
+            ChunkHeader::new_dontcare(16),
+            ChunkHeader::new_raw(1024 - 24, 4096),
+        ];
+        let splits = split_image(&header, &chunks, 512 * 4096).unwrap();
+
+        let json = serde_json::to_string(&splits).unwrap();
+        let parsed: Vec<Split> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, splits);
+    }
 }
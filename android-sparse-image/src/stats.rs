@@ -0,0 +1,140 @@
+use crate::{ChunkHeader, ChunkType, FileHeader, FILE_HEADER_BYTES_LEN};
+
+/// Totals of the chunks of one [ChunkType]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkStats {
+    /// Number of chunks
+    pub count: u32,
+    /// Size of the chunks in the sparse image, including their headers
+    pub sparse_size: u64,
+    /// Size of the chunks in the expanded image
+    pub expanded_size: u64,
+}
+
+impl ChunkStats {
+    fn add(&mut self, chunk: &ChunkHeader, header: &FileHeader) {
+        self.count += 1;
+        self.sparse_size += u64::from(chunk.total_size);
+        self.expanded_size += chunk.out_size(header);
+    }
+}
+
+/// Statistics of a sparse image as returned by [stats]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Raw chunks
+    pub raw: ChunkStats,
+    /// Fill chunks
+    pub fill: ChunkStats,
+    /// DontCare chunks
+    pub dont_care: ChunkStats,
+    /// Crc32 chunks
+    pub crc32: ChunkStats,
+    /// Size of the sparse image in bytes, including the file header
+    pub sparse_size: u64,
+    /// Size of the expanded image in bytes, according to the file header
+    pub expanded_size: u64,
+    /// Size in bytes of the largest run of consecutive Raw chunks in the expanded image
+    pub largest_raw_run: u64,
+}
+
+impl Stats {
+    /// Totals of the chunks of the given type
+    pub fn chunk_type(&self, chunk_type: ChunkType) -> &ChunkStats {
+        match chunk_type {
+            ChunkType::Raw => &self.raw,
+            ChunkType::Fill => &self.fill,
+            ChunkType::DontCare => &self.dont_care,
+            ChunkType::Crc32 => &self.crc32,
+        }
+    }
+
+    /// Ratio of the sparse size to the expanded size; Zero for an empty image
+    pub fn ratio(&self) -> f64 {
+        if self.expanded_size == 0 {
+            return 0.0;
+        }
+        self.sparse_size as f64 / self.expanded_size as f64
+    }
+}
+
+/// Gather statistics of an image consisting of the given file header and chunk headers
+pub fn stats(header: &FileHeader, chunks: &[ChunkHeader]) -> Stats {
+    let mut stats = Stats {
+        sparse_size: FILE_HEADER_BYTES_LEN as u64,
+        expanded_size: header.total_size(),
+        ..Default::default()
+    };
+    let mut run = 0;
+    for chunk in chunks {
+        match chunk.chunk_type {
+            ChunkType::Raw => {
+                stats.raw.add(chunk, header);
+                run += chunk.out_size(header);
+                stats.largest_raw_run = stats.largest_raw_run.max(run);
+            }
+            // Crc32 chunks don't take up space in the expanded image, so don't end a run
+            ChunkType::Crc32 => stats.crc32.add(chunk, header),
+            ChunkType::Fill => {
+                stats.fill.add(chunk, header);
+                run = 0;
+            }
+            ChunkType::DontCare => {
+                stats.dont_care.add(chunk, header);
+                run = 0;
+            }
+        }
+        stats.sparse_size += u64::from(chunk.total_size);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_stats() {
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 16,
+            chunks: 6,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_raw(3, 4096),
+            ChunkHeader::new_fill(4),
+            ChunkHeader::new_raw(4, 4096),
+            ChunkHeader::new_dontcare(3),
+            ChunkHeader::new_crc32(),
+        ];
+        let stats = stats(&header, &chunks);
+
+        assert_eq!(
+            stats.raw,
+            ChunkStats {
+                count: 3,
+                sparse_size: 3 * 12 + 9 * 4096,
+                expanded_size: 9 * 4096,
+            }
+        );
+        assert_eq!(
+            *stats.chunk_type(ChunkType::Fill),
+            ChunkStats {
+                count: 1,
+                sparse_size: 16,
+                expanded_size: 4 * 4096,
+            }
+        );
+        assert_eq!(stats.dont_care.expanded_size, 3 * 4096);
+        assert_eq!(stats.crc32.count, 1);
+        assert_eq!(
+            stats.sparse_size,
+            FILE_HEADER_BYTES_LEN as u64 + 4 * 12 + 2 * 16 + 9 * 4096
+        );
+        assert_eq!(stats.expanded_size, 16 * 4096);
+        assert_eq!(stats.largest_raw_run, 5 * 4096);
+        assert!(stats.ratio() > 0.5 && stats.ratio() < 0.6);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    io::SeekFrom,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use ::tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+};
+
+use crate::{
+    decoder::{Decoder, Event},
+    encode::{EncodeError, Encoder},
+    expand::Fill,
+    limits::Limits,
+    reader::ReadError,
+    ChunkData, ChunkHeader, ChunkHeaderBytes, FileHeader, FileHeaderBytes, ParseError,
+    CHUNK_HEADER_BYTES_LEN,
+};
+
+/// Reader for the data of a raw chunk
+///
+/// Data which isn't read gets skipped when reading the next chunk
+#[derive(Debug)]
+pub struct RawData<'a, R> {
+    reader: &'a mut R,
+    decoder: &'a mut Decoder,
+}
+
+impl<R> RawData<'_, R> {
+    /// Bytes of the chunk which weren't read yet
+    pub fn left(&self) -> u64 {
+        self.decoder.data_left()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RawData<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let max = buf
+            .remaining()
+            .min(usize::try_from(this.decoder.data_left()).unwrap_or(usize::MAX));
+        if max == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut *this.reader).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        if n == 0 {
+            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        // Only ever fed data of the current raw chunk, which can't fail to decode
+        this.decoder
+            .decode(limited.filled())
+            .map_err(std::io::Error::other)?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Fill {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let n = std::io::Read::read(self.get_mut(), buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Chunk read by a [SparseReader], along with its data
+pub type Chunk<'a, R> = (ChunkHeader, ChunkData<RawData<'a, R>>);
+
+/// Async variant of [crate::reader::SparseReader]
+///
+/// The headers are parsed and checked by a [Decoder], which is fed the data as it's read.
+#[derive(Debug)]
+pub struct SparseReader<R> {
+    reader: R,
+    header: FileHeader,
+    decoder: Decoder,
+}
+
+impl<R: AsyncRead + Unpin> SparseReader<R> {
+    /// Parse the file header of the sparse image read from `reader`
+    pub async fn new(reader: R) -> Result<Self, ReadError> {
+        Self::with_limits(reader, &Limits::default()).await
+    }
+
+    /// Parse the file header of the sparse image read from `reader`, checking it's within
+    /// `limits`
+    pub async fn with_limits(mut reader: R, limits: &Limits) -> Result<Self, ReadError> {
+        let mut decoder = Decoder::with_limits(*limits);
+        let mut header_bytes = FileHeaderBytes::default();
+        reader.read_exact(&mut header_bytes).await?;
+        decoder.decode(&header_bytes)?;
+        let header = decoder.header().cloned().ok_or(ParseError::Truncated)?;
+        Ok(Self {
+            reader,
+            header,
+            decoder,
+        })
+    }
+
+    /// The file header of the image
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Read the next chunk; None after the last chunk
+    pub async fn next_chunk(&mut self) -> Option<Result<Chunk<'_, R>, ReadError>> {
+        if let Err(e) = self.skip_data().await {
+            return Some(Err(e));
+        }
+        if self.decoder.is_done() {
+            return None;
+        }
+        Some(self.read_chunk().await)
+    }
+
+    // Skip what's left of the data of the previous chunk
+    async fn skip_data(&mut self) -> Result<(), ReadError> {
+        let mut data = RawData {
+            reader: &mut self.reader,
+            decoder: &mut self.decoder,
+        };
+        ::tokio::io::copy(&mut data, &mut ::tokio::io::sink()).await?;
+        // Moves the decoder on to the end after the last chunk
+        self.decoder.decode(&[])?;
+        Ok(())
+    }
+
+    async fn read_chunk(&mut self) -> Result<Chunk<'_, R>, ReadError> {
+        let (chunk, data) = match self.decode_chunk(CHUNK_HEADER_BYTES_LEN).await? {
+            Some(chunk) => chunk,
+            // Fill and Crc32 chunks are only complete with their value
+            None => self.decode_chunk(4).await?.ok_or(ParseError::Truncated)?,
+        };
+        let data = match data {
+            ChunkData::Raw(()) => ChunkData::Raw(RawData {
+                reader: &mut self.reader,
+                decoder: &mut self.decoder,
+            }),
+            ChunkData::Fill(value) => ChunkData::Fill(value),
+            ChunkData::DontCare => ChunkData::DontCare,
+            ChunkData::Crc32(value) => ChunkData::Crc32(value),
+        };
+        Ok((chunk, data))
+    }
+
+    // Feed `len` bytes read to the decoder, returning the chunk completed by them if any
+    async fn decode_chunk(
+        &mut self,
+        len: usize,
+    ) -> Result<Option<(ChunkHeader, ChunkData<()>)>, ReadError> {
+        let mut bytes = ChunkHeaderBytes::default();
+        self.reader.read_exact(&mut bytes[..len]).await?;
+        match self.decoder.decode(&bytes[..len])? {
+            (_, Some(Event::Chunk(chunk, data))) => Ok(Some((chunk, data))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Unwrap the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Async variant of [crate::expand::expand]
+pub async fn expand<R, W>(reader: R, mut writer: W) -> Result<u64, ReadError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = SparseReader::new(reader).await?;
+    let header = reader.header().clone();
+    let mut written = 0;
+    while let Some(chunk) = reader.next_chunk().await {
+        let (chunk, data) = chunk?;
+        let size = chunk.out_size(&header);
+        let pattern = match data {
+            ChunkData::Raw(data) => {
+                ::tokio::io::copy(&mut data.take(size), &mut writer).await?;
+                written += size;
+                continue;
+            }
+            ChunkData::Fill(value) => value.0,
+            ChunkData::DontCare => [0; 4],
+            ChunkData::Crc32(_) => continue,
+        };
+        ::tokio::io::copy(&mut Fill::new(pattern, size), &mut writer).await?;
+        written += size;
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+// Fill the buffer from the reader, padding with zeroes at the end of the input; Returns the number
+// of bytes read
+async fn read_block<R>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]).await {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf[read..].fill(0);
+    Ok(read)
+}
+
+/// Async variant of [Encoder::encode]
+pub async fn encode<R, W>(
+    encoder: &Encoder,
+    mut input: R,
+    mut output: W,
+) -> Result<FileHeader, EncodeError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut scanner = encoder.scanner()?;
+    input.seek(SeekFrom::Start(0)).await?;
+    let mut block = vec![0; encoder.block_size as usize];
+    while read_block(&mut input, &mut block).await? > 0 {
+        scanner.block(&block)?;
+    }
+
+    let mut chunks = scanner.finish();
+    let header = chunks.header().clone();
+    output.write_all(&header.to_bytes()).await?;
+    while let Some((bytes, raw)) = chunks.next_chunk() {
+        output.write_all(&bytes).await?;
+        if let Some((offset, blocks)) = raw {
+            input.seek(SeekFrom::Start(offset)).await?;
+            // The last block gets padded
+            for _ in 0..blocks {
+                read_block(&mut input, &mut block).await?;
+                output.write_all(&block).await?;
+                chunks.raw_block(&block);
+            }
+        }
+    }
+    if let Some(bytes) = chunks.finish() {
+        output.write_all(&bytes).await?;
+    }
+    output.flush().await?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{encode::ZeroBlocks, ChunkType, DEFAULT_BLOCKSIZE};
+
+    #[::tokio::test]
+    async fn roundtrip() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..2 * block).map(|i| (i % 241) as u8).collect();
+        raw.extend([5, 6, 7, 8].repeat(block / 4));
+        raw.extend(vec![0; 3 * block]);
+        raw.extend([0xee; 100]);
+
+        let encoder = Encoder::new().zero_blocks(ZeroBlocks::DontCare);
+        let mut expected = vec![];
+        let header = encoder.encode(Cursor::new(&raw), &mut expected).unwrap();
+        let mut image = vec![];
+        assert_eq!(
+            encode(&encoder, Cursor::new(&raw), &mut image)
+                .await
+                .unwrap(),
+            header
+        );
+        assert_eq!(image, expected);
+
+        let mut reader = SparseReader::new(Cursor::new(&image)).await.unwrap();
+        assert_eq!(reader.header(), &header);
+        let mut types = vec![];
+        while let Some(chunk) = reader.next_chunk().await {
+            let (chunk, _) = chunk.unwrap();
+            types.push(chunk.chunk_type);
+        }
+        assert_eq!(
+            types,
+            [
+                ChunkType::Raw,
+                ChunkType::Fill,
+                ChunkType::DontCare,
+                ChunkType::Raw
+            ]
+        );
+
+        let mut expanded = vec![];
+        let size = expand(Cursor::new(&image), &mut expanded).await.unwrap();
+        assert_eq!(size, 7 * block as u64);
+        assert_eq!(expanded[..raw.len()], raw);
+        assert!(expanded[raw.len()..].iter().all(|&b| b == 0));
+    }
+}
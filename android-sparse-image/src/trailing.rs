@@ -0,0 +1,202 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+};
+
+use thiserror::Error;
+
+use crate::{
+    checksum::Checksum,
+    reader::{read_chunk_table, ReadError},
+    ChunkHeader, ChunkType, FileHeader, CHUNK_HEADER_BYTES_LEN,
+};
+
+/// Errors while trimming or extending a sparse image
+#[derive(Debug, Error)]
+pub enum TrailingError {
+    #[error("Size {size} is not a multiple of the block size {block_size}")]
+    Unaligned { size: u64, block_size: u32 },
+    #[error("Size {size} is smaller than the expanded image of {image} bytes")]
+    TooSmall { size: u64, image: u64 },
+    #[error("Image has too many blocks for a sparse image")]
+    TooLarge,
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// Number of blocks covered by the chunks
+fn covered(chunks: &[ChunkHeader]) -> u32 {
+    chunks.iter().map(|chunk| chunk.chunk_size).sum()
+}
+
+/// Trim the trailing DontCare chunks of the sparse image in `file`, shrinking the image in place;
+/// Returns the updated file header
+///
+/// Blocks at the end of the image which aren't covered by any chunk are dropped as well. As the
+/// header checksum covers the trimmed blocks, it gets cleared if it was set.
+pub fn trim(mut file: &File) -> Result<FileHeader, TrailingError> {
+    file.rewind()?;
+    let (header, chunks, offsets) = read_chunk_table(file)?;
+    let keep = chunks
+        .iter()
+        .rposition(|chunk| chunk.chunk_type != ChunkType::DontCare)
+        .map_or(0, |last| last + 1);
+    let blocks = covered(&chunks[..keep]);
+    if blocks == header.blocks {
+        return Ok(header);
+    }
+
+    let end = match offsets.get(keep) {
+        Some(offset) => offset - CHUNK_HEADER_BYTES_LEN as u64,
+        None => file.stream_position()?,
+    };
+    let header = FileHeader {
+        blocks,
+        chunks: keep as u32,
+        checksum: 0,
+        ..header
+    };
+    file.set_len(end)?;
+    file.rewind()?;
+    file.write_all(&header.to_bytes())?;
+    Ok(header)
+}
+
+/// Extend the sparse image in `file` with DontCare blocks to `size` bytes (e.g. the size of the
+/// partition it's flashed to) in place; Returns the updated file header
+///
+/// A trailing DontCare chunk is grown, otherwise a DontCare chunk gets appended. The header
+/// checksum is updated to include the added blocks if it was set.
+pub fn extend(mut file: &File, size: u64) -> Result<FileHeader, TrailingError> {
+    file.rewind()?;
+    let (header, chunks, offsets) = read_chunk_table(file)?;
+    if size % u64::from(header.block_size) != 0 {
+        return Err(TrailingError::Unaligned {
+            size,
+            block_size: header.block_size,
+        });
+    }
+    if size < header.total_size() {
+        return Err(TrailingError::TooSmall {
+            size,
+            image: header.total_size(),
+        });
+    }
+    let blocks =
+        u32::try_from(size / u64::from(header.block_size)).map_err(|_| TrailingError::TooLarge)?;
+    if blocks == header.blocks {
+        return Ok(header);
+    }
+
+    // Blocks not covered by any chunk yet end up in the DontCare chunk as well
+    let added = blocks - covered(&chunks);
+    let mut header = FileHeader {
+        blocks,
+        checksum: match header.checksum {
+            0 => 0,
+            checksum => {
+                let mut checksum = Checksum::resume(checksum);
+                checksum.update_fill([0; 4], size - header.total_size());
+                checksum.value()
+            }
+        },
+        ..header
+    };
+    match chunks.last() {
+        Some(last) if last.chunk_type == ChunkType::DontCare => {
+            let offset = offsets[chunks.len() - 1] - CHUNK_HEADER_BYTES_LEN as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&ChunkHeader::new_dontcare(last.chunk_size + added).to_bytes())?;
+        }
+        _ => {
+            let end = file.stream_position()?;
+            file.set_len(end)?;
+            file.write_all(&ChunkHeader::new_dontcare(added).to_bytes())?;
+            header.chunks += 1;
+        }
+    }
+    file.rewind()?;
+    file.write_all(&header.to_bytes())?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        builder::SparseImageBuilder, encode::Encoder, reader::SparseReader, FILE_HEADER_BYTES_LEN,
+    };
+
+    #[test]
+    fn trim_extend() {
+        let block = crate::DEFAULT_BLOCKSIZE as usize;
+        let path = std::env::temp_dir().join(format!("trailing-{}", std::process::id()));
+        let (_, image) = SparseImageBuilder::new(Cursor::new(vec![]))
+            .unwrap()
+            .skip(1)
+            .unwrap()
+            .raw(&[0x42; 100])
+            .unwrap()
+            .skip(2)
+            .unwrap()
+            .skip(1)
+            .unwrap()
+            .finish()
+            .unwrap();
+        std::fs::write(&path, image.get_ref()).unwrap();
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+
+        let header = trim(&file).unwrap();
+        assert_eq!((header.blocks, header.chunks), (2, 2));
+        let len = FILE_HEADER_BYTES_LEN + 2 * CHUNK_HEADER_BYTES_LEN + block;
+        let trimmed = std::fs::read(&path).unwrap();
+        assert_eq!(trimmed[..FILE_HEADER_BYTES_LEN], header.to_bytes());
+        assert_eq!(
+            trimmed[FILE_HEADER_BYTES_LEN..],
+            image.get_ref()[FILE_HEADER_BYTES_LEN..len]
+        );
+        assert_eq!(trim(&file).unwrap(), header);
+
+        assert!(matches!(
+            extend(&file, 100),
+            Err(TrailingError::Unaligned { size: 100, .. })
+        ));
+        assert!(matches!(
+            extend(&file, block as u64),
+            Err(TrailingError::TooSmall { .. })
+        ));
+        let header = extend(&file, 4 * block as u64).unwrap();
+        assert_eq!((header.blocks, header.chunks), (4, 3));
+        // The DontCare chunk gets grown
+        let header = extend(&file, 8 * block as u64).unwrap();
+        assert_eq!((header.blocks, header.chunks), (8, 3));
+        let mut reader = SparseReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.header(), &header);
+        let mut chunks = vec![];
+        while let Some(chunk) = reader.next_chunk() {
+            chunks.push(chunk.unwrap().0);
+        }
+        assert_eq!(chunks[2], ChunkHeader::new_dontcare(6));
+
+        // Header checksum is updated
+        let raw: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+        let mut image = vec![];
+        Encoder::new()
+            .header_checksum(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+        std::fs::write(&path, &image).unwrap();
+        let header = extend(&file, 5 * block as u64).unwrap();
+        assert_ne!(header.checksum, 0);
+        SparseReader::new(File::open(&path).unwrap())
+            .unwrap()
+            .verify_checksums(true)
+            .expand(std::io::sink())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
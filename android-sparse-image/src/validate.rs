@@ -0,0 +1,191 @@
+use thiserror::Error;
+
+use crate::{
+    limits::{LimitError, Limits},
+    ChunkHeader, ChunkType, FileHeader, CHUNK_HEADER_BYTES_LEN,
+};
+
+/// Violations of the sparse image format found by [validate]
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("Block size {0} is not a non-zero multiple of 4")]
+    InvalidBlockSize(u32),
+    #[error("Header announces {expected} chunks, but the image has {actual}")]
+    ChunkCount { expected: u32, actual: usize },
+    #[error("Header announces {expected} blocks, but the chunks cover {actual}")]
+    BlockCount { expected: u32, actual: u64 },
+    #[error(
+        "Chunk {index} ({chunk_type:?}) has a total size of {actual} bytes, expected {expected}"
+    )]
+    ChunkSize {
+        index: usize,
+        chunk_type: ChunkType,
+        expected: u64,
+        actual: u32,
+    },
+    #[error("Crc32 chunk {index} covers {blocks} blocks")]
+    Crc32Blocks { index: usize, blocks: u32 },
+    #[error(transparent)]
+    Limit(#[from] LimitError),
+}
+
+/// Check whether an image consisting of the given file header and chunk headers is well-formed
+///
+/// Checks that the block size is valid, the number of chunks and the blocks they cover match the
+/// header, and that the total size of each chunk is consistent with its type; Raw chunks carry
+/// exactly their blocks worth of data, Fill and Crc32 chunks exactly 4 bytes and DontCare chunks
+/// none. Sizes are computed without overflowing, so a Raw chunk whose data size doesn't fit in
+/// its total size is reported as a size mismatch.
+pub fn validate(header: &FileHeader, chunks: &[ChunkHeader]) -> Result<(), ValidationError> {
+    validate_with_limits(header, chunks, &Limits::default())
+}
+
+/// Check whether an image is well-formed like [validate], and its file header is within `limits`
+pub fn validate_with_limits(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    limits: &Limits,
+) -> Result<(), ValidationError> {
+    limits.check(header)?;
+    if header.block_size == 0 || header.block_size % 4 != 0 {
+        return Err(ValidationError::InvalidBlockSize(header.block_size));
+    }
+    if chunks.len() != header.chunks as usize {
+        return Err(ValidationError::ChunkCount {
+            expected: header.chunks,
+            actual: chunks.len(),
+        });
+    }
+
+    let mut blocks = 0u64;
+    for (index, chunk) in chunks.iter().enumerate() {
+        let data_size = match chunk.chunk_type {
+            ChunkType::Raw => chunk.chunk_size as u64 * header.block_size as u64,
+            ChunkType::Fill | ChunkType::Crc32 => 4,
+            ChunkType::DontCare => 0,
+        };
+        let expected = CHUNK_HEADER_BYTES_LEN as u64 + data_size;
+        if chunk.total_size as u64 != expected {
+            return Err(ValidationError::ChunkSize {
+                index,
+                chunk_type: chunk.chunk_type,
+                expected,
+                actual: chunk.total_size,
+            });
+        }
+        if chunk.chunk_type == ChunkType::Crc32 && chunk.chunk_size != 0 {
+            return Err(ValidationError::Crc32Blocks {
+                index,
+                blocks: chunk.chunk_size,
+            });
+        }
+        blocks += chunk.chunk_size as u64;
+    }
+
+    if blocks != header.blocks as u64 {
+        return Err(ValidationError::BlockCount {
+            expected: header.blocks,
+            actual: blocks,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(blocks: u32, chunks: u32) -> FileHeader {
+        FileHeader {
+            block_size: 4096,
+            blocks,
+            chunks,
+            checksum: 0,
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let chunks = [
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_fill(3),
+            ChunkHeader::new_dontcare(5),
+            ChunkHeader::new_crc32(),
+        ];
+        assert_eq!(validate(&header(10, 4), &chunks), Ok(()));
+    }
+
+    #[test]
+    fn invalid() {
+        let chunks = [ChunkHeader::new_raw(2, 4096), ChunkHeader::new_fill(3)];
+        assert_eq!(
+            validate(&header(5, 3), &chunks),
+            Err(ValidationError::ChunkCount {
+                expected: 3,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            validate(&header(6, 2), &chunks),
+            Err(ValidationError::BlockCount {
+                expected: 6,
+                actual: 5
+            })
+        );
+        assert_eq!(
+            validate(
+                &FileHeader {
+                    block_size: 4097,
+                    ..header(5, 2)
+                },
+                &chunks
+            ),
+            Err(ValidationError::InvalidBlockSize(4097))
+        );
+
+        let fill = ChunkHeader {
+            total_size: 20,
+            ..ChunkHeader::new_fill(1)
+        };
+        assert_eq!(
+            validate(&header(1, 1), &[fill]),
+            Err(ValidationError::ChunkSize {
+                index: 0,
+                chunk_type: ChunkType::Fill,
+                expected: 16,
+                actual: 20
+            })
+        );
+
+        // Data size of the raw chunk overflows its total size
+        let raw = ChunkHeader::new_raw(1 << 20, 4096);
+        assert!(matches!(
+            validate(&header(1 << 20, 1), &[raw]),
+            Err(ValidationError::ChunkSize { index: 0, .. })
+        ));
+
+        let crc = ChunkHeader {
+            chunk_size: 1,
+            ..ChunkHeader::new_crc32()
+        };
+        assert_eq!(
+            validate(&header(1, 1), &[crc]),
+            Err(ValidationError::Crc32Blocks {
+                index: 0,
+                blocks: 1
+            })
+        );
+
+        let limits = Limits {
+            max_chunks: 1,
+            ..Limits::default()
+        };
+        assert_eq!(
+            validate_with_limits(&header(10, 4), &[], &limits),
+            Err(ValidationError::Limit(LimitError::TooManyChunks {
+                chunks: 4,
+                max: 1
+            }))
+        );
+    }
+}
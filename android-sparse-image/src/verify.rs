@@ -0,0 +1,115 @@
+use std::io::Read;
+
+use thiserror::Error;
+
+use crate::{
+    reader::{ReadError, SparseReader},
+    validate::{validate, ValidationError},
+    ChunkData, FileHeader,
+};
+
+/// Errors found while verifying a sparse image
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error("Image is malformed: {0}")]
+    Invalid(#[from] ValidationError),
+}
+
+/// Result of successfully verifying a sparse image, see [verify]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrcReport {
+    /// File header of the image
+    pub header: FileHeader,
+    /// Checksum of the expanded image
+    pub checksum: u32,
+    /// Whether the image has a checksum in its file header, which was verified
+    pub header_checksum: bool,
+    /// Number of Crc32 chunks which were verified
+    pub crc32_chunks: u32,
+}
+
+/// Verify the sparse image read from `reader`, reading it completely
+///
+/// The checksum of the expanded image is computed while reading and compared with each Crc32
+/// chunk and with [FileHeader::checksum] if that is set; A mismatch is reported as
+/// [ReadError::ChecksumMismatch]. Besides that the image has to be well-formed as checked by
+/// [validate], and its data must not be truncated.
+pub fn verify<R: Read>(reader: R) -> Result<CrcReport, VerifyError> {
+    let mut reader = SparseReader::new(reader)?.verify_checksums(true);
+    let header = reader.header().clone();
+    let mut chunks = vec![];
+    let mut crc32_chunks = 0;
+    while let Some(chunk) = reader.next_chunk() {
+        let (chunk, data) = chunk?;
+        if let ChunkData::Crc32(_) = data {
+            crc32_chunks += 1;
+        }
+        chunks.push(chunk);
+    }
+    validate(&header, &chunks)?;
+    Ok(CrcReport {
+        checksum: reader.checksum().unwrap_or_default(),
+        header_checksum: header.checksum != 0,
+        header,
+        crc32_chunks,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{encode::Encoder, DEFAULT_BLOCKSIZE, FILE_HEADER_BYTES_LEN};
+
+    #[test]
+    fn verify_image() {
+        let block = DEFAULT_BLOCKSIZE as usize;
+        let mut raw: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+        raw.extend(vec![0; 2 * block]);
+        let mut image = vec![];
+        let header = Encoder::new()
+            .crc32_chunk(true)
+            .header_checksum(true)
+            .encode(Cursor::new(&raw), &mut image)
+            .unwrap();
+
+        let report = verify(Cursor::new(&image)).unwrap();
+        assert_eq!(
+            report,
+            CrcReport {
+                checksum: header.checksum,
+                header,
+                header_checksum: true,
+                crc32_chunks: 1,
+            }
+        );
+
+        // Corrupted raw data
+        let mut corrupt = image.clone();
+        corrupt[FILE_HEADER_BYTES_LEN + 100] ^= 0xff;
+        assert!(matches!(
+            verify(Cursor::new(&corrupt)),
+            Err(VerifyError::Read(ReadError::ChecksumMismatch { .. }))
+        ));
+
+        // Blocks not covered by any chunk
+        let mut uncovered = image.clone();
+        let mut header = report.header.clone();
+        header.blocks += 1;
+        header.checksum = 0;
+        uncovered[..FILE_HEADER_BYTES_LEN].copy_from_slice(&header.to_bytes());
+        assert!(matches!(
+            verify(Cursor::new(&uncovered)),
+            Err(VerifyError::Invalid(ValidationError::BlockCount { .. }))
+        ));
+
+        image.truncate(image.len() - 20);
+        assert!(matches!(
+            verify(Cursor::new(&image)),
+            Err(VerifyError::Read(ReadError::Io(_)))
+        ));
+    }
+}
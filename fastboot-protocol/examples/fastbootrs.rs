@@ -1,159 +1,142 @@
-use std::{
-    io::SeekFrom,
-    path::{Path, PathBuf},
-};
-
-use android_sparse_image::{
-    split::split_image, ChunkHeader, FileHeader, FileHeaderBytes, CHUNK_HEADER_BYTES_LEN,
-};
-use anyhow::{bail, Context};
 use clap::Parser;
+use fastboot_protocol::bootimg::boot_image;
+use fastboot_protocol::flash::{flash_length_prefixed, flash_source, ImageSource};
 use fastboot_protocol::nusb::NusbFastBoot;
-use fastboot_protocol::protocol::parse_u32;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use fastboot_protocol::progress::ProgressEvent;
+use indicatif::{ProgressBar, ProgressStyle};
 
 #[derive(Parser)]
-enum Opts {
-    GetVar { var: String },
-    GetAllVars {},
-    Flash { target: String, file: PathBuf },
-    Reboot,
+struct Opts {
+    /// Serial number of the device to use, rather than the first one found
+    #[clap(short, long, global = true)]
+    serial: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
 }
 
-async fn flash_raw<R>(
-    fb: &mut NusbFastBoot,
-    target: &str,
-    mut file: R,
-    file_size: u32,
-) -> anyhow::Result<()>
-where
-    R: AsyncRead + AsyncSeek + Unpin,
-{
-    println!("Uploading raw image directly");
-    let mut sender = fb.download(file_size).await?;
-    loop {
-        let left = sender.left();
-        if left == 0 {
-            break;
-        }
-        let buf = sender.get_mut_data(left as usize).await?;
-        file.read_exact(buf)
-            .await
-            .context("Failed to read from file")?;
-    }
-
-    sender.finish().await?;
-    println!("Flashing data");
-    fb.flash(target).await?;
-
-    Ok(())
+#[derive(clap::Subcommand)]
+enum Command {
+    /// List connected fastboot devices
+    Devices,
+    #[clap(flatten)]
+    Device(DeviceCommand),
 }
 
-// Exactly fill the buffer; If EOF is reached before the buffer is full fill the remainder with 0.
-// This is useful in particular when flashing a big file that's not aligned to the android sparse
-// image block size
-// size (4096 bytes)
-async fn read_exact_padded<R: AsyncRead + Unpin>(
-    input: &mut R,
-    buf: &mut [u8],
-) -> std::io::Result<usize> {
-    let total = buf.len();
-    let mut offset = 0;
-    while offset < total {
-        match input.read(&mut buf[offset..]).await {
-            Ok(0) => {
-                /* EOF, fill the remainder with 0 */
-                buf[offset..].fill(0);
-                break;
-            }
-            Ok(read) => offset += read,
-            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
-            Err(err) => return Err(err),
-        }
-    }
-
-    Ok(total)
+// Commands run against a single device
+#[derive(clap::Subcommand)]
+enum DeviceCommand {
+    GetVar {
+        var: String,
+    },
+    GetAllVars {},
+    Flash {
+        target: String,
+        /// Image file, block device or "-" to read a length-prefixed image from stdin
+        file: String,
+    },
+    /// Erase a partition
+    Erase {
+        partition: String,
+    },
+    /// Run an OEM specific command
+    Oem {
+        #[clap(required = true)]
+        args: Vec<String>,
+    },
+    /// Download a boot image and boot it without flashing
+    Boot {
+        image: String,
+    },
+    /// Flash all images in an update package
+    #[cfg(feature = "zip")]
+    Update {
+        zip: String,
+        /// Erase user data after flashing
+        #[clap(short, long)]
+        wipe: bool,
+        /// Don't reboot the device after flashing
+        #[clap(long)]
+        skip_reboot: bool,
+    },
+    Reboot,
 }
 
-async fn flash(fb: &mut NusbFastBoot, target: &str, file: &Path) -> anyhow::Result<()> {
-    let max_download = fb.get_var("max-download-size").await?;
-    let max_download = parse_u32(&max_download)
-        .with_context(|| anyhow::anyhow!("Failed to parse max download size: {max_download}"))?;
-    println!("Max download size: {max_download}");
-
-    let mut f = tokio::fs::File::open(file).await?;
-    let mut header_bytes = FileHeaderBytes::default();
-    f.read_exact(&mut header_bytes).await?;
-    let splits = match FileHeader::from_bytes(&header_bytes) {
-        Ok(header) => {
-            println!("Preparing to flash android sparse image");
-            let mut chunks = vec![];
-            for _ in 0..header.chunks {
-                let mut chunk_bytes = [0; CHUNK_HEADER_BYTES_LEN];
-                f.read_exact(&mut chunk_bytes).await?;
-                let chunk = ChunkHeader::from_bytes(&chunk_bytes)?;
-
-                f.seek(SeekFrom::Current(chunk.data_size() as i64)).await?;
-                chunks.push(chunk);
+// Progress callback showing a progress bar for each downloaded part of an image and printing
+// INFO messages from the device
+fn progress_bar() -> impl FnMut(&ProgressEvent) + Send + 'static {
+    let style = ProgressStyle::with_template(
+        "{msg:>10} [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+    )
+    .unwrap()
+    .progress_chars("=> ");
+    let mut current: Option<(usize, ProgressBar)> = None;
+    move |event| {
+        let (index, total, downloaded, size) = match *event {
+            ProgressEvent::Part {
+                index,
+                total,
+                downloaded,
+                size,
+            } => (index, total, downloaded, size),
+            ProgressEvent::Heartbeat { ref message } => {
+                match &current {
+                    Some((_, bar)) if !bar.is_finished() => {
+                        bar.println(format!("(bootloader) {message}"))
+                    }
+                    _ => println!("(bootloader) {message}"),
+                }
+                return;
             }
-            split_image(&header, &chunks, max_download)?
-        }
-        Err(android_sparse_image::ParseError::UnknownMagic) => {
-            f.seek(SeekFrom::Start(0))
-                .await
-                .context("Seeking back to the start")?;
-            let file_size = f
-                .seek(SeekFrom::End(0))
-                .await
-                .context("Seek for determining file size")?;
-            if file_size < max_download.into() {
-                f.seek(SeekFrom::Start(0))
-                    .await
-                    .context("Seeking back to the start")?;
-                return flash_raw(fb, target, f, file_size as u32).await;
-            }
-            android_sparse_image::split::split_raw(file_size as usize, max_download)?
-        }
-        Err(e) => bail!("Failed to parse sparse image: {e}"),
-    };
-
-    println!("Flashing in {} parts", splits.len());
-    for (i, split) in splits.iter().enumerate() {
-        println!("Downloading part {i}");
-        let mut sender = fb.download(split.sparse_size() as u32).await?;
-
-        sender.extend_from_slice(&split.header.to_bytes()).await?;
-        for chunk in &split.chunks {
-            sender.extend_from_slice(&chunk.header.to_bytes()).await?;
-            f.seek(SeekFrom::Start(chunk.offset as u64))
-                .await
-                .context("Failed to seek input file")?;
-            let mut left = chunk.size;
-            while left > 0 {
-                let buf = sender.get_mut_data(left).await?;
-
-                left -= read_exact_padded(&mut f, buf)
-                    .await
-                    .context("Failed to read from file")?;
+            _ => return,
+        };
+        let bar = match &current {
+            // A part restarting at 0 (e.g. on a retry) gets a new bar
+            Some((i, bar)) if *i == index && bar.position() <= downloaded.into() => bar,
+            _ => {
+                if let Some((_, bar)) = current.take() {
+                    bar.abandon();
+                }
+                let bar = ProgressBar::new(size.into()).with_style(style.clone());
+                match total {
+                    Some(1) => bar.set_message("download"),
+                    Some(total) => bar.set_message(format!("{}/{total}", index + 1)),
+                    None => bar.set_message(format!("{}", index + 1)),
+                }
+                &current.insert((index, bar)).1
             }
+        };
+        bar.set_position(downloaded.into());
+        if downloaded == size {
+            bar.finish();
         }
-        sender.finish().await?;
-        println!("Flashing Part {i}");
-        fb.flash(target).await?;
     }
+}
 
+// Print the connected fastboot devices, one per line
+async fn list_devices() -> anyhow::Result<()> {
+    for info in fastboot_protocol::nusb::devices().await? {
+        println!(
+            "{}\tfastboot\t{}:{}\t{}",
+            info.serial_number().unwrap_or("????????????"),
+            info.bus_id(),
+            info.device_address(),
+            info.product_string().unwrap_or_default()
+        );
+    }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    let opts = Opts::parse();
-
-    let mut devices = fastboot_protocol::nusb::devices().await?;
-    let info = devices
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No Device found"))?;
+// Open the device with the given serial, or the first one found
+async fn open_device(serial: Option<&str>) -> anyhow::Result<NusbFastBoot> {
+    let info = match serial {
+        Some(serial) => fastboot_protocol::nusb::find_device(serial)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No Device found with serial {serial}"))?,
+        None => fastboot_protocol::nusb::devices()
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No Device found"))?,
+    };
 
     println!(
         "Using Fastboot device: {}:{} M: {} P: {}",
@@ -164,21 +147,74 @@ async fn main() -> anyhow::Result<()> {
     );
 
     let mut fb = NusbFastBoot::from_info(&info).await?;
+    fb.set_progress(progress_bar());
+    Ok(fb)
+}
 
-    match opts {
-        Opts::GetVar { var } => {
+// Run a command against an opened device
+async fn run(mut fb: NusbFastBoot, command: DeviceCommand) -> anyhow::Result<()> {
+    match command {
+        DeviceCommand::GetVar { var } => {
             let r = fb.get_var(&var).await?;
             println!("{var}: {r:?}");
         }
-        Opts::GetAllVars {} => {
+        DeviceCommand::GetAllVars {} => {
             let r = fb.get_all_vars().await?;
             for (k, v) in r {
                 println!("{k}: {v}");
             }
         }
-        Opts::Flash { target, file } => flash(&mut fb, &target, &file).await?,
-        Opts::Reboot => fb.reboot().await?,
+        DeviceCommand::Flash { target, file } if file == "-" => {
+            flash_length_prefixed(&mut fb, &target, tokio::io::stdin(), "stdin").await?
+        }
+        DeviceCommand::Flash { target, file } => {
+            flash_source(&mut fb, &target, &ImageSource::from_location(&file)).await?
+        }
+        DeviceCommand::Erase { partition } => fb.erase(&partition).await?,
+        DeviceCommand::Oem { args } => {
+            let r = fb.oem(&args.join(" ")).await?;
+            if !r.is_empty() {
+                println!("{r}");
+            }
+        }
+        DeviceCommand::Boot { image } => {
+            let file = tokio::fs::File::open(&image).await?;
+            let len = u32::try_from(file.metadata().await?.len())
+                .map_err(|_| anyhow::anyhow!("Boot image {image} is too large"))?;
+            boot_image(&mut fb, file, len).await?
+        }
+        #[cfg(feature = "zip")]
+        DeviceCommand::Update {
+            zip,
+            wipe,
+            skip_reboot,
+        } => {
+            fb = fastboot_protocol::update::update(fb, &zip).await?;
+            if wipe {
+                for partition in fastboot_protocol::wipe::erase_userdata(&mut fb).await? {
+                    println!("Erased {partition}");
+                }
+            }
+            if !skip_reboot {
+                fb.reboot().await?;
+            }
+        }
+        DeviceCommand::Reboot => fb.reboot().await?,
     }
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let opts = Opts::parse();
+
+    match opts.command {
+        Command::Devices => list_devices().await,
+        Command::Device(command) => {
+            let fb = open_device(opts.serial.as_deref()).await?;
+            run(fb, command).await
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::info;
+
+use crate::{flash::FlashError, nusb::NusbFastBoot};
+
+const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+// Fixed page size of version 3 and 4 boot images
+const V3_PAGE_SIZE: u32 = 4096;
+// Header sizes per version
+const V2_HEADER_SIZE: u32 = 1660;
+const V3_HEADER_SIZE: u32 = 1580;
+const V4_HEADER_SIZE: u32 = 1584;
+// Maximum command line length, including the terminating NUL
+const V2_CMDLINE_SIZE: usize = 512;
+const V2_EXTRA_CMDLINE_SIZE: usize = 1024;
+const V3_CMDLINE_SIZE: usize = 1536;
+
+// Default load offsets relative to the base address, matching mkbootimg
+const KERNEL_OFFSET: u32 = 0x0000_8000;
+const RAMDISK_OFFSET: u32 = 0x0100_0000;
+const SECOND_OFFSET: u32 = 0x00f0_0000;
+const TAGS_OFFSET: u32 = 0x0000_0100;
+const DTB_OFFSET: u32 = 0x01f0_0000;
+
+/// Default base address for version 2 boot images
+pub const DEFAULT_BASE: u32 = 0x1000_0000;
+/// Default page size for version 2 boot images
+pub const DEFAULT_PAGE_SIZE: u32 = 2048;
+
+/// Errors building a boot image
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BootImageError {
+    #[error("Kernel command line too long: {len} bytes, at most {max} supported")]
+    CmdlineTooLong { len: usize, max: usize },
+    #[error("Boot image version {0:?} doesn't support a dtb")]
+    DtbUnsupported(BootImageVersion),
+    #[error("Invalid page size {0}")]
+    InvalidPageSize(u32),
+    #[error("Boot image component too large")]
+    TooLarge,
+}
+
+/// Android boot image header version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootImageVersion {
+    V2,
+    V3,
+    V4,
+}
+
+/// Builder composing a kernel, ramdisk and command line into an android boot image
+///
+/// ```no_run
+/// # async fn example(fb: &mut fastboot_protocol::nusb::NusbFastBoot) -> anyhow::Result<()> {
+/// use fastboot_protocol::bootimg::{boot_image, BootImageBuilder, BootImageVersion};
+///
+/// let image = BootImageBuilder::new(BootImageVersion::V2, std::fs::read("zImage")?)
+///     .ramdisk(std::fs::read("ramdisk.img")?)
+///     .cmdline("console=ttyS0,115200")
+///     .build()?;
+/// boot_image(fb, image.as_slice(), image.len() as u32).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct BootImageBuilder {
+    version: BootImageVersion,
+    kernel: Bytes,
+    ramdisk: Bytes,
+    dtb: Option<Bytes>,
+    cmdline: String,
+    page_size: u32,
+    base: u32,
+}
+
+fn size_u32(data: &[u8]) -> Result<u32, BootImageError> {
+    u32::try_from(data.len()).map_err(|_| BootImageError::TooLarge)
+}
+
+// Append data padded to a multiple of the page size
+fn push_padded(image: &mut Vec<u8>, data: &[u8], page_size: u32) {
+    image.extend_from_slice(data);
+    image.resize(image.len().next_multiple_of(page_size as usize), 0);
+}
+
+// Append a string as a NUL padded fixed size field
+fn push_str(header: &mut Vec<u8>, s: &[u8], size: usize) {
+    header.extend_from_slice(s);
+    header.resize(header.len() + size - s.len(), 0);
+}
+
+impl BootImageBuilder {
+    /// Create a builder for a boot image of the given version with the given kernel
+    pub fn new<K: Into<Bytes>>(version: BootImageVersion, kernel: K) -> Self {
+        Self {
+            version,
+            kernel: kernel.into(),
+            ramdisk: Bytes::new(),
+            dtb: None,
+            cmdline: String::new(),
+            page_size: DEFAULT_PAGE_SIZE,
+            base: DEFAULT_BASE,
+        }
+    }
+
+    /// Set the ramdisk
+    pub fn ramdisk<R: Into<Bytes>>(mut self, ramdisk: R) -> Self {
+        self.ramdisk = ramdisk.into();
+        self
+    }
+
+    /// Set the device tree blob; Only supported by version 2 boot images
+    pub fn dtb<D: Into<Bytes>>(mut self, dtb: D) -> Self {
+        self.dtb = Some(dtb.into());
+        self
+    }
+
+    /// Set the kernel command line
+    pub fn cmdline<S: Into<String>>(mut self, cmdline: S) -> Self {
+        self.cmdline = cmdline.into();
+        self
+    }
+
+    /// Set the page size; Only used by version 2 boot images, later versions always use 4096
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Set the base load address; Only used by version 2 boot images
+    pub fn base(mut self, base: u32) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Build the boot image
+    pub fn build(&self) -> Result<Vec<u8>, BootImageError> {
+        match self.version {
+            BootImageVersion::V2 => self.build_v2(),
+            BootImageVersion::V3 | BootImageVersion::V4 => self.build_v3(),
+        }
+    }
+
+    fn check_cmdline(&self, max: usize) -> Result<(), BootImageError> {
+        let len = self.cmdline.len();
+        // Leave room for the terminating NUL
+        if len >= max {
+            return Err(BootImageError::CmdlineTooLong { len, max: max - 1 });
+        }
+        Ok(())
+    }
+
+    fn build_v2(&self) -> Result<Vec<u8>, BootImageError> {
+        let page_size = self.page_size;
+        if !page_size.is_power_of_two() || page_size < V2_HEADER_SIZE {
+            return Err(BootImageError::InvalidPageSize(page_size));
+        }
+        // Both the cmdline and extra cmdline fields are NUL terminated, like mkbootimg does
+        self.check_cmdline(V2_CMDLINE_SIZE - 1 + V2_EXTRA_CMDLINE_SIZE)?;
+        let dtb = self.dtb.as_deref().unwrap_or_default();
+        let cmdline = self.cmdline.as_bytes();
+        let (cmdline, extra_cmdline) = cmdline.split_at(cmdline.len().min(V2_CMDLINE_SIZE - 1));
+
+        let mut image = Vec::new();
+        image.extend_from_slice(BOOT_MAGIC);
+        for v in [
+            size_u32(&self.kernel)?,
+            self.base.wrapping_add(KERNEL_OFFSET),
+            size_u32(&self.ramdisk)?,
+            self.base.wrapping_add(RAMDISK_OFFSET),
+            // No second stage bootloader
+            0,
+            self.base.wrapping_add(SECOND_OFFSET),
+            self.base.wrapping_add(TAGS_OFFSET),
+            page_size,
+            2,
+            // OS version
+            0,
+        ] {
+            image.extend_from_slice(&v.to_le_bytes());
+        }
+        // Name
+        push_str(&mut image, b"", 16);
+        push_str(&mut image, cmdline, V2_CMDLINE_SIZE);
+        // Image id; Unused for booting, left empty
+        push_str(&mut image, b"", 32);
+        push_str(&mut image, extra_cmdline, V2_EXTRA_CMDLINE_SIZE);
+        // No recovery dtbo
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.extend_from_slice(&0u64.to_le_bytes());
+        image.extend_from_slice(&V2_HEADER_SIZE.to_le_bytes());
+        image.extend_from_slice(&size_u32(dtb)?.to_le_bytes());
+        let dtb_addr = u64::from(self.base) + u64::from(DTB_OFFSET);
+        image.extend_from_slice(&dtb_addr.to_le_bytes());
+        debug_assert_eq!(image.len(), V2_HEADER_SIZE as usize);
+
+        push_padded(&mut image, &[], page_size);
+        push_padded(&mut image, &self.kernel, page_size);
+        push_padded(&mut image, &self.ramdisk, page_size);
+        push_padded(&mut image, dtb, page_size);
+        Ok(image)
+    }
+
+    fn build_v3(&self) -> Result<Vec<u8>, BootImageError> {
+        if self.dtb.is_some() {
+            return Err(BootImageError::DtbUnsupported(self.version));
+        }
+        self.check_cmdline(V3_CMDLINE_SIZE)?;
+        let (version, header_size) = match self.version {
+            BootImageVersion::V4 => (4u32, V4_HEADER_SIZE),
+            _ => (3, V3_HEADER_SIZE),
+        };
+
+        let mut image = Vec::new();
+        image.extend_from_slice(BOOT_MAGIC);
+        for v in [
+            size_u32(&self.kernel)?,
+            size_u32(&self.ramdisk)?,
+            // OS version
+            0,
+            header_size,
+            // Reserved
+            0,
+            0,
+            0,
+            0,
+            version,
+        ] {
+            image.extend_from_slice(&v.to_le_bytes());
+        }
+        push_str(&mut image, self.cmdline.as_bytes(), V3_CMDLINE_SIZE);
+        if version == 4 {
+            // No boot signature
+            image.extend_from_slice(&0u32.to_le_bytes());
+        }
+        debug_assert_eq!(image.len(), header_size as usize);
+
+        push_padded(&mut image, &[], V3_PAGE_SIZE);
+        push_padded(&mut image, &self.kernel, V3_PAGE_SIZE);
+        push_padded(&mut image, &self.ramdisk, V3_PAGE_SIZE);
+        Ok(image)
+    }
+}
+
+/// Download a boot image of `len` bytes from `reader` and boot it, equivalent to `fastboot boot`
+///
+/// The image is booted directly without being flashed; See [BootImageBuilder] to compose a boot
+/// image from a kernel and ramdisk on the host
+pub async fn boot_image<R>(fb: &mut NusbFastBoot, mut reader: R, len: u32) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    info!("Downloading boot image");
+    let mut sender = fb.download(len).await?;
+    loop {
+        let left = sender.left();
+        if left == 0 {
+            break;
+        }
+        let buf = sender.get_mut_data(left as usize).await?;
+        reader.read_exact(buf).await?;
+    }
+    sender.finish().await?;
+    info!("Booting");
+    fb.boot().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn u32_at(image: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn build_v2() {
+        let image = BootImageBuilder::new(BootImageVersion::V2, vec![1; 3000])
+            .ramdisk(vec![2; 100])
+            .dtb(vec![3; 10])
+            .cmdline("console=ttyS0")
+            .build()
+            .unwrap();
+        assert_eq!(&image[0..8], BOOT_MAGIC);
+        assert_eq!(u32_at(&image, 8), 3000);
+        assert_eq!(u32_at(&image, 12), DEFAULT_BASE + KERNEL_OFFSET);
+        assert_eq!(u32_at(&image, 16), 100);
+        assert_eq!(u32_at(&image, 36), DEFAULT_PAGE_SIZE);
+        assert_eq!(u32_at(&image, 40), 2);
+        assert_eq!(&image[64..77], b"console=ttyS0");
+        assert_eq!(image[77], 0);
+        // header_size and dtb_size
+        assert_eq!(u32_at(&image, 1644), V2_HEADER_SIZE);
+        assert_eq!(u32_at(&image, 1648), 10);
+        // Header, 2 kernel pages, 1 ramdisk page and 1 dtb page
+        assert_eq!(image.len(), 5 * DEFAULT_PAGE_SIZE as usize);
+        assert_eq!(image[2048], 1);
+        assert_eq!(image[3 * 2048], 2);
+        assert_eq!(image[4 * 2048], 3);
+    }
+
+    #[test]
+    fn build_v2_extra_cmdline() {
+        let cmdline = format!("{}{}", "a".repeat(511), "b".repeat(100));
+        let image = BootImageBuilder::new(BootImageVersion::V2, vec![1; 10])
+            .cmdline(cmdline)
+            .build()
+            .unwrap();
+        assert_eq!(&image[64..575], "a".repeat(511).as_bytes());
+        assert_eq!(image[575], 0);
+        assert_eq!(&image[608..708], "b".repeat(100).as_bytes());
+        assert_eq!(image[708], 0);
+
+        let max = V2_CMDLINE_SIZE + V2_EXTRA_CMDLINE_SIZE - 2;
+        let r = BootImageBuilder::new(BootImageVersion::V2, vec![])
+            .cmdline("x".repeat(max))
+            .build();
+        assert!(r.is_ok());
+        let r = BootImageBuilder::new(BootImageVersion::V2, vec![])
+            .cmdline("x".repeat(max + 1))
+            .build();
+        assert_eq!(r, Err(BootImageError::CmdlineTooLong { len: max + 1, max }));
+    }
+
+    #[test]
+    fn build_v4() {
+        let image = BootImageBuilder::new(BootImageVersion::V4, vec![1; 10])
+            .cmdline("quiet")
+            .build()
+            .unwrap();
+        assert_eq!(u32_at(&image, 8), 10);
+        assert_eq!(u32_at(&image, 12), 0);
+        assert_eq!(u32_at(&image, 20), V4_HEADER_SIZE);
+        assert_eq!(u32_at(&image, 40), 4);
+        assert_eq!(&image[44..49], b"quiet");
+        assert_eq!(image.len(), 2 * V3_PAGE_SIZE as usize);
+        assert_eq!(image[4096], 1);
+    }
+
+    #[test]
+    fn build_errors() {
+        let r = BootImageBuilder::new(BootImageVersion::V3, vec![])
+            .dtb(vec![])
+            .build();
+        assert_eq!(r, Err(BootImageError::DtbUnsupported(BootImageVersion::V3)));
+
+        let r = BootImageBuilder::new(BootImageVersion::V3, vec![])
+            .cmdline("x".repeat(V3_CMDLINE_SIZE))
+            .build();
+        assert_eq!(
+            r,
+            Err(BootImageError::CmdlineTooLong {
+                len: V3_CMDLINE_SIZE,
+                max: V3_CMDLINE_SIZE - 1
+            })
+        );
+
+        let r = BootImageBuilder::new(BootImageVersion::V2, vec![])
+            .page_size(1000)
+            .build();
+        assert_eq!(r, Err(BootImageError::InvalidPageSize(1000)));
+    }
+}
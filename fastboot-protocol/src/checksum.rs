@@ -0,0 +1,427 @@
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::info;
+
+use crate::{
+    flash::{flash_raw_stream, flash_stream, open_block_device, FlashError, ImageSource},
+    nusb::NusbFastBoot,
+};
+
+/// Errors while parsing a checksum manifest
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ManifestError {
+    #[error("Invalid manifest line {0}")]
+    InvalidLine(usize),
+    #[error("Invalid sha256 digest {0:?}")]
+    InvalidDigest(String),
+}
+
+/// Expected checksum of an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checksum {
+    /// Sha256 digest as lowercase hex
+    pub sha256: String,
+    /// Size in bytes, if known
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub size: Option<u64>,
+}
+
+impl Checksum {
+    /// Checksum with the given sha256 digest in hex
+    pub fn new(sha256: &str, size: Option<u64>) -> Result<Self, ManifestError> {
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ManifestError::InvalidDigest(sha256.to_string()));
+        }
+        Ok(Self {
+            sha256: sha256.to_ascii_lowercase(),
+            size,
+        })
+    }
+}
+
+/// Expected checksums of images by file name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Manifest {
+    checksums: BTreeMap<String, Checksum>,
+}
+
+impl Manifest {
+    /// Create an empty manifest
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a manifest in the format of `sha256sum` (e.g. `<digest>  boot.img`)
+    pub fn parse(manifest: &str) -> Result<Self, ManifestError> {
+        let mut parsed = Self::new();
+        for (i, line) in manifest.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (digest, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or(ManifestError::InvalidLine(i + 1))?;
+            // Binary mode marker of sha256sum
+            let name = name.trim_start();
+            let name = name.strip_prefix('*').unwrap_or(name);
+            if name.is_empty() {
+                return Err(ManifestError::InvalidLine(i + 1));
+            }
+            parsed.insert(name, Checksum::new(digest, None)?);
+        }
+        Ok(parsed)
+    }
+
+    /// Set the expected checksum for the given file name
+    pub fn insert<S: Into<String>>(&mut self, name: S, checksum: Checksum) {
+        self.checksums.insert(name.into(), checksum);
+    }
+
+    /// Expected checksum for the given file name
+    pub fn get(&self, name: &str) -> Option<&Checksum> {
+        self.checksums.get(name)
+    }
+
+    /// Expected checksum for an image source, looked up by its file name
+    ///
+    /// In-memory sources have no name, so never have a checksum
+    pub fn get_source(&self, source: &ImageSource) -> Option<&Checksum> {
+        let name = match source {
+            ImageSource::File(path)
+            | ImageSource::BlockDevice(path)
+            | ImageSource::Compressed { path, .. } => path.file_name()?.to_str()?,
+            ImageSource::Bytes(_) => return None,
+            #[cfg(feature = "http")]
+            ImageSource::Url(url) => url.rsplit('/').next()?,
+        };
+        self.get(name)
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Reader verifying the data read through it against an expected checksum
+///
+/// The check happens once `size` bytes were read, or at the end of the data if the size is
+/// unknown; On a mismatch the read completing the data fails with an IO error wrapping a
+/// [FlashError::ChecksumMismatch] or [FlashError::SizeMismatch]. As the data is verified while
+/// it's passed on, e.g. to the device, the end of a corrupted image never gets flashed.
+pub struct ChecksumReader<R> {
+    reader: R,
+    name: String,
+    expected: Checksum,
+    size: Option<u64>,
+    hasher: Sha256,
+    read: u64,
+    checked: bool,
+}
+
+impl<R> ChecksumReader<R> {
+    /// Verify the `size` bytes (if known) read from `reader`; `name` is only used for error
+    /// reporting
+    pub fn new(reader: R, name: &str, expected: &Checksum, size: Option<u64>) -> Self {
+        Self {
+            reader,
+            name: name.to_string(),
+            expected: expected.clone(),
+            size,
+            hasher: Sha256::new(),
+            read: 0,
+            checked: false,
+        }
+    }
+
+    /// Whether all data was read and verified
+    pub fn is_checked(&self) -> bool {
+        self.checked
+    }
+
+    fn check(&mut self) -> Result<(), FlashError> {
+        self.checked = true;
+        if let Some(expected) = self.expected.size.filter(|&s| s != self.read) {
+            return Err(FlashError::SizeMismatch {
+                name: self.name.clone(),
+                expected,
+                actual: self.read,
+            });
+        }
+        let actual = hex(&std::mem::take(&mut self.hasher).finalize());
+        if actual != self.expected.sha256 {
+            return Err(FlashError::ChecksumMismatch {
+                name: self.name.clone(),
+                expected: self.expected.sha256.clone(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ChecksumReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.checked {
+            return Poll::Ready(Ok(()));
+        }
+        if let (Some(expected), Some(size)) = (self.expected.size, self.size) {
+            if expected != size {
+                // Fail before any data is passed on
+                self.checked = true;
+                let e = FlashError::SizeMismatch {
+                    name: self.name.clone(),
+                    expected,
+                    actual: size,
+                };
+                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)));
+            }
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+        let data = &buf.filled()[filled..];
+        self.hasher.update(data);
+        self.read += data.len() as u64;
+        if data.is_empty() || self.size == Some(self.read) {
+            self.check()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Recover the verification failure of a [ChecksumReader] from the IO error it was reported as
+fn checksum_error(e: FlashError) -> FlashError {
+    match e {
+        FlashError::Io(e) if e.get_ref().is_some_and(|e| e.is::<FlashError>()) => {
+            match e.into_inner().map(|e| e.downcast::<FlashError>()) {
+                Some(Ok(e)) => *e,
+                _ => unreachable!("Checked to wrap a FlashError"),
+            }
+        }
+        e => e,
+    }
+}
+
+/// Verify data read from `reader` against the expected checksum
+///
+/// The data is streamed through the digest without being kept in memory; `name` is only used
+/// for error reporting.
+pub async fn verify_checksum<R>(
+    reader: R,
+    name: &str,
+    expected: &Checksum,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = ChecksumReader::new(reader, name, expected, None);
+    tokio::io::copy(&mut reader, &mut tokio::io::sink())
+        .await
+        .map_err(|e| checksum_error(e.into()))?;
+    Ok(())
+}
+
+/// Verify an image source against the expected checksum in a separate pass, without flashing it
+///
+/// Used for dry runs; Streamed sources (e.g. URLs) are retrieved for verification. See
+/// [flash_source_checksum] to verify an image while it gets flashed.
+pub async fn verify_source_checksum(
+    source: &ImageSource,
+    expected: &Checksum,
+) -> Result<(), FlashError> {
+    info!("Verifying checksum of {source}");
+    let name = source.to_string();
+    match source {
+        // Compressed files are verified as stored, as listed in the manifests shipped with them
+        ImageSource::File(path)
+        | ImageSource::BlockDevice(path)
+        | ImageSource::Compressed { path, .. } => {
+            let file = tokio::fs::File::open(path).await?;
+            verify_checksum(file, &name, expected).await
+        }
+        ImageSource::Bytes(bytes) => {
+            verify_checksum(Cursor::new(bytes.clone()), &name, expected).await
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => {
+            let (reader, _) = crate::http::body(crate::http::get(url).await?);
+            verify_checksum(reader, &name, expected).await
+        }
+    }
+}
+
+/// Flash an image from the given source to the given target, verifying it against the expected
+/// checksum while it's streamed to the device
+///
+/// The source is read exactly once, sequentially, so the data hashed is the data sent to the
+/// device; See [flash_stream] for details on how the image is flashed. On a mismatch the last
+/// part of the image isn't flashed and the error is returned, but earlier parts of images which
+/// don't fit in a single download will have been written already. Compressed files are verified
+/// as stored.
+pub async fn flash_source_checksum(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    source: &ImageSource,
+    expected: &Checksum,
+) -> Result<(), FlashError> {
+    info!("Flashing {source} to {target}, verifying its checksum");
+    let name = source.to_string();
+    let r = match source {
+        ImageSource::File(path) => {
+            let file = tokio::fs::File::open(path).await?;
+            let size = file.metadata().await?.len();
+            let mut reader = ChecksumReader::new(file, &name, expected, Some(size));
+            match flash_stream(fb, target, &mut reader, Some(size), &name).await {
+                Ok(()) => drain(reader).await,
+                Err(e) => Err(e),
+            }
+        }
+        ImageSource::BlockDevice(path) => {
+            let (file, size) = open_block_device(path).await?;
+            let mut reader = ChecksumReader::new(file, &name, expected, Some(size));
+            match flash_raw_stream(fb, target, &mut reader, size).await {
+                Ok(()) => drain(reader).await,
+                Err(e) => Err(e),
+            }
+        }
+        ImageSource::Bytes(bytes) => {
+            let size = bytes.len() as u64;
+            let mut reader =
+                ChecksumReader::new(Cursor::new(bytes.clone()), &name, expected, Some(size));
+            match flash_stream(fb, target, &mut reader, Some(size), &name).await {
+                Ok(()) => drain(reader).await,
+                Err(e) => Err(e),
+            }
+        }
+        ImageSource::Compressed { path, compression } => {
+            let file = tokio::fs::File::open(path).await?;
+            let size = file.metadata().await?.len();
+            let mut reader = ChecksumReader::new(file, &name, expected, Some(size));
+            let decoder = compression.decoder(&mut reader)?;
+            match flash_stream(fb, target, decoder, None, &name).await {
+                Ok(()) => drain(reader).await,
+                Err(e) => Err(e),
+            }
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => {
+            let (reader, size) = crate::http::body(crate::http::get(url).await?);
+            let mut reader = ChecksumReader::new(reader, &name, expected, size);
+            match flash_stream(fb, target, &mut reader, size, &name).await {
+                Ok(()) => drain(reader).await,
+                Err(e) => Err(e),
+            }
+        }
+    };
+    r.map_err(checksum_error)
+}
+
+// Read any data left after the flashed image to complete its verification
+async fn drain<R: AsyncRead + Unpin>(mut reader: ChecksumReader<R>) -> Result<(), FlashError> {
+    if !reader.is_checked() {
+        tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = Manifest::parse(&format!(
+            "# images\n{EMPTY}  boot.img\n{}  *system image.img\n",
+            EMPTY.to_uppercase()
+        ))
+        .unwrap();
+        assert_eq!(manifest.get("boot.img").unwrap().sha256, EMPTY);
+        assert_eq!(manifest.get("system image.img").unwrap().sha256, EMPTY);
+        assert_eq!(
+            manifest.get_source(&ImageSource::File("/images/boot.img".into())),
+            manifest.get("boot.img")
+        );
+        assert_eq!(
+            manifest.get_source(&ImageSource::Bytes(vec![].into())),
+            None
+        );
+
+        assert_eq!(
+            Manifest::parse("boot.img"),
+            Err(ManifestError::InvalidLine(1))
+        );
+        assert_eq!(
+            Manifest::parse("1234  boot.img"),
+            Err(ManifestError::InvalidDigest("1234".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let checksum = Checksum::new(EMPTY, Some(0)).unwrap();
+        verify_checksum(&b""[..], "empty", &checksum).await.unwrap();
+        assert!(matches!(
+            verify_checksum(&b"a"[..], "a", &checksum).await,
+            Err(FlashError::SizeMismatch { actual: 1, .. })
+        ));
+        let checksum = Checksum::new(EMPTY, None).unwrap();
+        assert!(matches!(
+            verify_checksum(&b"a"[..], "a", &checksum).await,
+            Err(FlashError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn checksum_reader() {
+        use tokio::io::AsyncReadExt;
+
+        let data = vec![0x5a; 10000];
+        let sha256 = hex(&Sha256::digest(&data));
+        let checksum = Checksum::new(&sha256, None).unwrap();
+        let mut reader = ChecksumReader::new(&data[..], "data", &checksum, Some(10000));
+        let mut buf = vec![0; 10000];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert!(reader.is_checked());
+
+        // The read completing the data fails on a mismatch
+        let mut corrupted = data.clone();
+        corrupted[9999] = 0;
+        let mut reader = ChecksumReader::new(&corrupted[..], "data", &checksum, Some(10000));
+        reader.read_exact(&mut buf[..5000]).await.unwrap();
+        let e = reader.read_exact(&mut buf[5000..]).await.unwrap_err();
+        assert!(matches!(
+            checksum_error(e.into()),
+            FlashError::ChecksumMismatch { .. }
+        ));
+
+        // A known size mismatch fails before any data is read
+        let checksum = Checksum::new(&sha256, Some(20000)).unwrap();
+        let mut reader = ChecksumReader::new(&data[..], "data", &checksum, Some(10000));
+        let e = reader.read(&mut buf).await.unwrap_err();
+        assert!(matches!(
+            checksum_error(e.into()),
+            FlashError::SizeMismatch { actual: 10000, .. }
+        ));
+    }
+}
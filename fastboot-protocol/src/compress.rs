@@ -0,0 +1,191 @@
+use std::{fmt::Display, path::Path, pin::Pin};
+
+use tokio::io::{AsyncRead, BufReader};
+use tracing::info;
+
+use crate::{
+    flash::{flash_stream, plan_stream, FlashError, ImagePlan},
+    nusb::NusbFastBoot,
+};
+
+/// Compression format of an image
+///
+/// Decompression support for each format is enabled by the feature of the same name (`gzip`,
+/// `zstd` or `xz`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip compressed (.gz)
+    Gzip,
+    /// Zstandard compressed (.zst)
+    Zstd,
+    /// xz compressed (.xz)
+    Xz,
+}
+
+impl Compression {
+    /// Compression format indicated by the extension of a path (e.g. "super.img.gz")
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            "xz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    /// Whether decompression of this format is enabled
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Xz => cfg!(feature = "xz"),
+        }
+    }
+
+    /// Wrap a reader to decompress the data read from it
+    pub fn decoder<'a, R>(
+        self,
+        reader: R,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send + 'a>>, FlashError>
+    where
+        R: AsyncRead + Send + 'a,
+    {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut decoder =
+                    async_compression::tokio::bufread::GzipDecoder::new(BufReader::new(reader));
+                // Parallel compressors (e.g. pigz) write multiple gzip members
+                decoder.multiple_members(true);
+                Ok(Box::pin(decoder))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut decoder =
+                    async_compression::tokio::bufread::ZstdDecoder::new(BufReader::new(reader));
+                decoder.multiple_members(true);
+                Ok(Box::pin(decoder))
+            }
+            #[cfg(feature = "xz")]
+            Compression::Xz => {
+                let mut decoder =
+                    async_compression::tokio::bufread::XzDecoder::new(BufReader::new(reader));
+                decoder.multiple_members(true);
+                Ok(Box::pin(decoder))
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                drop(BufReader::new(reader));
+                Err(FlashError::UnsupportedCompression(self))
+            }
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Xz => write!(f, "xz"),
+        }
+    }
+}
+
+/// Open a compressed image file, reading its decompressed data
+pub async fn open_compressed(
+    path: &Path,
+    compression: Compression,
+) -> Result<Pin<Box<dyn AsyncRead + Send>>, FlashError> {
+    let file = tokio::fs::File::open(path).await?;
+    compression.decoder(file)
+}
+
+// Size of the decompressed data of a compressed image file
+async fn decompressed_size(path: &Path, compression: Compression) -> Result<u64, FlashError> {
+    info!("Determining decompressed size of {}", path.display());
+    let mut reader = open_compressed(path, compression).await?;
+    Ok(tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?)
+}
+
+/// Flash a compressed image file to the given target, decompressing it on the fly
+///
+/// The image is streamed to the device without storing the decompressed image; As the size of
+/// raw images isn't known upfront, they are wrapped into sparse images while being decompressed.
+/// See [flash_stream] for details.
+pub async fn flash_compressed(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    path: &Path,
+    compression: Compression,
+) -> Result<(), FlashError> {
+    let name = path.display().to_string();
+    let reader = open_compressed(path, compression).await?;
+    flash_stream(fb, target, reader, None, &name).await
+}
+
+/// Determine how a compressed image file would be flashed
+///
+/// The whole image gets decompressed (but not stored) to determine the chunk layout or size of
+/// the image; See [crate::flash::plan_image] for details.
+pub async fn plan_compressed(
+    fb: &mut NusbFastBoot,
+    path: &Path,
+    compression: Compression,
+) -> Result<ImagePlan, FlashError> {
+    let name = path.display().to_string();
+    let reader = open_compressed(path, compression).await?;
+    match plan_stream(fb, reader, None, &name).await {
+        Err(FlashError::UnknownSize(_)) => {
+            let size = decompressed_size(path, compression).await?;
+            let reader = open_compressed(path, compression).await?;
+            plan_stream(fb, reader, Some(size), &name).await
+        }
+        r => r,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_path() {
+        assert_eq!(
+            Compression::from_path("super.img.gz"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::from_path("/images/system.img.zst"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::from_path("vendor.img.xz"),
+            Some(Compression::Xz)
+        );
+        assert_eq!(Compression::from_path("boot.img"), None);
+        assert_eq!(Compression::from_path("gz"), None);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn gzip_members() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut compressed = vec![];
+        for part in [&b"first member "[..], b"second member"] {
+            let mut encoder = GzipEncoder::new(vec![]);
+            encoder.write_all(part).await.unwrap();
+            encoder.shutdown().await.unwrap();
+            compressed.extend(encoder.into_inner());
+        }
+
+        let mut decoder = Compression::Gzip
+            .decoder(std::io::Cursor::new(compressed))
+            .unwrap();
+        let mut data = vec![];
+        decoder.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"first member second member");
+    }
+}
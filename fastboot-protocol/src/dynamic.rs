@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    flash::{download_source, flash_source, plan_source, FlashError, ImageSource},
+    flashall::super_partition_name,
+    mode::{ensure_mode, FastbootMode, ModeError, DEFAULT_MODE_TIMEOUT},
+    nusb::{NusbFastBoot, NusbFastBootError},
+    slot::{slot_partition, SlotError, SlotSelection},
+};
+
+/// Default time to wait for the device to come back after rebooting into fastbootd
+pub const DEFAULT_REBOOT_TIMEOUT: Duration = DEFAULT_MODE_TIMEOUT;
+
+/// Errors during the dynamic partition flashing workflow
+#[derive(Debug, Error)]
+pub enum DynamicError {
+    #[error(transparent)]
+    Mode(#[from] ModeError),
+    #[error(transparent)]
+    Slot(#[from] SlotError),
+    #[error(transparent)]
+    Flash(#[from] FlashError),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+}
+
+/// Reboot the device into userspace fastboot (fastbootd) and reconnect to it
+///
+/// See [ensure_mode] for details
+pub async fn reboot_to_fastbootd(
+    fb: NusbFastBoot,
+    timeout: Duration,
+) -> Result<NusbFastBoot, DynamicError> {
+    Ok(ensure_mode(fb, FastbootMode::Fastbootd, timeout).await?)
+}
+
+// Whether the given partition is a logical partition in super
+async fn is_logical(fb: &mut NusbFastBoot, partition: &str) -> Result<bool, NusbFastBootError> {
+    match fb.get_var(&format!("is-logical:{partition}")).await {
+        Ok(v) => Ok(v == "yes"),
+        Err(NusbFastBootError::FastbootFailed(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Create the logical partition `target` or resize it if it exists, to fit `size` bytes
+pub(crate) async fn resize_logical(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    size: u64,
+) -> Result<(), NusbFastBootError> {
+    if is_logical(fb, target).await? {
+        info!("Resizing {target} to {size} bytes");
+        fb.resize_logical_partition(target, size).await
+    } else {
+        info!("Creating {target} with {size} bytes");
+        fb.create_logical_partition(target, size).await
+    }
+}
+
+/// Flash dynamic partitions, as required by devices using a super partition
+///
+/// The device is rebooted into fastbootd if needed (see [reboot_to_fastbootd]); The super
+/// partition metadata is then updated from `super_empty` (removing all existing logical
+/// partitions if `wipe` is set), each logical partition is created or resized to fit its image
+/// and finally flashed. Partitions with slots are flashed to the current slot. As the device may
+/// get rebooted the client is consumed; The client connected to fastbootd is returned.
+pub async fn flash_dynamic(
+    fb: NusbFastBoot,
+    super_empty: &ImageSource,
+    images: &[(String, ImageSource)],
+    wipe: bool,
+) -> Result<NusbFastBoot, DynamicError> {
+    let mut fb = reboot_to_fastbootd(fb, DEFAULT_REBOOT_TIMEOUT).await?;
+
+    let super_name = super_partition_name(&mut fb).await;
+    info!("Updating super partition {super_name} from {super_empty}");
+    download_source(&mut fb, super_empty).await?;
+    fb.update_super(&super_name, wipe).await?;
+    // Partition layout changed
+    fb.clear_var_cache();
+
+    for (partition, source) in images {
+        let target = slot_partition(&mut fb, partition, &SlotSelection::Current).await?;
+        let size = plan_source(&mut fb, source).await?.expanded_size;
+        resize_logical(&mut fb, &target, size).await?;
+        info!("Flashing {source} to {target}");
+        flash_source(&mut fb, &target, source).await?;
+    }
+
+    Ok(fb)
+}
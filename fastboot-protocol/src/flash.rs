@@ -0,0 +1,1468 @@
+use std::{
+    fmt::Display,
+    io::{Cursor, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use android_sparse_image::{
+    split::{split_image, split_image_with_max_chunks, split_raw, Split, SplitError},
+    ChunkHeader, ChunkHeaderBytes, ChunkType, FileHeader, FileHeaderBytes, ParseError,
+    CHUNK_HEADER_BYTES_LEN, DEFAULT_BLOCKSIZE, FILE_HEADER_BYTES_LEN,
+};
+use bytes::Bytes;
+use futures::Stream;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::{info, warn};
+
+use crate::{
+    compress::Compression,
+    flashall::{BOOT_PARTITIONS, OS_PARTITIONS},
+    nusb::{DataDownload, DownloadError, NusbFastBoot, NusbFastBootError, UploadError},
+    progress::ProgressEvent,
+    protocol::{parse_u32, parse_u64},
+    verify::VerifyReport,
+};
+
+/// Errors while flashing an image
+#[derive(Debug, Error)]
+pub enum FlashError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse max download size: {0}")]
+    MaxDownloadSize(String),
+    #[error("Failed to parse sparse image: {0}")]
+    SparseImage(#[from] ParseError),
+    #[error("Failed to split image: {0}")]
+    Split(#[from] SplitError),
+    #[error(transparent)]
+    Download(#[from] DownloadError),
+    #[error(transparent)]
+    Upload(#[from] UploadError),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+    #[error("Device returned no data for fetch")]
+    EmptyFetch,
+    #[error("Verification failed: {} mismatching ranges", .0.mismatches.len())]
+    Verification(VerifyReport),
+    #[error("Resume token doesn't match the image")]
+    ResumeMismatch,
+    #[error("Partition {0} not found on the device")]
+    PartitionNotFound(String),
+    #[error("Image of {image} bytes doesn't fit in partition {target} of {partition} bytes")]
+    ImageTooLarge {
+        target: String,
+        image: u64,
+        partition: u64,
+    },
+    #[error("Checksum of {name} doesn't match: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Size of {name} doesn't match: expected {expected}, got {actual}")]
+    SizeMismatch {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Step hook failed: {0}")]
+    Hook(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Size of raw image from {0} is unknown")]
+    UnknownSize(String),
+    #[error("Image source {0} can't be read back")]
+    NotSeekable(String),
+    #[error("Support for {0} compressed images is not enabled")]
+    UnsupportedCompression(Compression),
+    #[cfg(feature = "http")]
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+/// Retrieve the maximum download size of the device
+pub async fn max_download_size(fb: &mut NusbFastBoot) -> Result<u32, FlashError> {
+    let max_download = fb.get_var_cached("max-download-size").await?;
+    parse_u32(&max_download).map_err(|_| FlashError::MaxDownloadSize(max_download))
+}
+
+/// Check that the target partition exists and is big enough for an image of `size` bytes
+///
+/// Skipped when the device has the `no_partition_vars` quirk set. If the device reports a type
+/// but no size for the target only its existence is checked.
+pub async fn validate_target(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    size: u64,
+) -> Result<(), FlashError> {
+    if fb.quirks().no_partition_vars {
+        return Ok(());
+    }
+    // Not cached, as partition sizes can change (e.g. resized logical partitions)
+    let partition = match fb.get_var(&format!("partition-size:{target}")).await {
+        Ok(partition) => partition,
+        Err(NusbFastBootError::FastbootFailed(_)) => {
+            return match fb.get_var(&format!("partition-type:{target}")).await {
+                Ok(_) => Ok(()),
+                Err(NusbFastBootError::FastbootFailed(_)) => {
+                    Err(FlashError::PartitionNotFound(target.to_string()))
+                }
+                Err(e) => Err(e.into()),
+            };
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match parse_u64(&partition) {
+        Ok(partition) if size > partition => Err(FlashError::ImageTooLarge {
+            target: target.to_string(),
+            image: size,
+            partition,
+        }),
+        Ok(_) => Ok(()),
+        Err(_) => {
+            warn!("Failed to parse size of {target}: {partition}");
+            Ok(())
+        }
+    }
+}
+
+/// Whether the target partition has raw flash semantics according to its `partition-type`
+///
+/// Partitions without a reported type are not considered raw
+pub async fn is_raw_partition(
+    fb: &mut NusbFastBoot,
+    target: &str,
+) -> Result<bool, NusbFastBootError> {
+    match fb.get_var_cached(&format!("partition-type:{target}")).await {
+        Ok(t) => Ok(t.trim() == "raw"),
+        Err(NusbFastBootError::FastbootFailed(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Flash a raw image of `size` bytes from `reader` directly in one download
+///
+/// The size should not exceed the max download size of the device
+pub async fn flash_raw<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut reader: R,
+    size: u32,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    info!("Uploading raw image directly");
+    let mut sender = fb.download(size).await?;
+    part_progress(&mut sender, 0, Some(1));
+    loop {
+        let left = sender.left();
+        if left == 0 {
+            break;
+        }
+        let buf = sender.get_mut_data(left as usize).await?;
+        reader.read_exact(buf).await?;
+        part_progress(&mut sender, 0, Some(1));
+    }
+
+    sender.finish().await?;
+    info!("Flashing data");
+    fb.flash(target).await?;
+
+    Ok(())
+}
+
+// Report the download progress of a part
+fn part_progress(sender: &mut DataDownload<'_>, index: usize, total: Option<usize>) {
+    let size = sender.size();
+    let downloaded = size - sender.left();
+    sender.progress(ProgressEvent::Part {
+        index,
+        total,
+        downloaded,
+        size,
+    });
+}
+
+// Exactly fill the buffer; If EOF is reached before the buffer is full fill the remainder with 0.
+// This is useful in particular when flashing a big file that's not aligned to the android sparse
+// image block size
+// size (4096 bytes)
+async fn read_exact_padded<R: AsyncRead + Unpin>(
+    input: &mut R,
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    let read = read_full(input, buf).await?;
+    /* EOF, fill the remainder with 0 */
+    buf[read..].fill(0);
+    Ok(buf.len())
+}
+
+// Fill the buffer until EOF is reached; Returns the number of bytes read
+async fn read_full<R: AsyncRead + Unpin>(input: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        match input.read(&mut buf[offset..]).await {
+            Ok(0) => break,
+            Ok(read) => offset += read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(offset)
+}
+
+/// Flash a raw image of `size` bytes read sequentially from `reader` to the given target
+///
+/// If the image exceeds the max download size of the device it is transparently wrapped into
+/// multiple generated sparse images, each flashed one after the other. The end of the image is
+/// padded with zeroes to a multiple of the sparse image block size as needed.
+pub async fn flash_raw_stream<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut reader: R,
+    size: u64,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    validate_target(fb, target, size).await?;
+    let max_download = max_download_size(fb).await?;
+    info!("Max download size: {max_download}");
+    if size < max_download.into() {
+        return flash_raw(fb, target, reader, size as u32).await;
+    }
+
+    let splits = split_raw(size as usize, max_download)?;
+    let total = splits.len();
+    info!("Flashing in {total} parts");
+    for (i, split) in splits.iter().enumerate() {
+        info!("Downloading part {i}");
+        let mut sender = fb.download(split.download_size(max_download)?).await?;
+        part_progress(&mut sender, i, Some(total));
+        sender.extend_from_slice(&split.header.to_bytes()).await?;
+        // The data of the raw chunks in the splits is consecutive in the input, so it can just be
+        // read in order
+        for chunk in &split.chunks {
+            sender.extend_from_slice(&chunk.header.to_bytes()).await?;
+            let mut left = chunk.size;
+            while left > 0 {
+                let buf = sender.get_mut_data(left).await?;
+                left -= read_exact_padded(&mut reader, buf).await?;
+                part_progress(&mut sender, i, Some(total));
+            }
+        }
+        sender.finish().await?;
+        info!("Flashing Part {i}");
+        fb.flash(target).await?;
+    }
+
+    Ok(())
+}
+
+/// Stream a split from its source to the device and finish the download
+///
+/// The download should be started for the sparse size of the split (see [Split::sparse_size]).
+/// The chunk data is read from `source` at the offsets recorded in the split; For raw sources
+/// which don't end at a block boundary the last chunk is padded with zeroes.
+pub async fn stream_split<R>(
+    split: &Split,
+    source: &mut R,
+    sender: DataDownload<'_>,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    send_split(split, source, sender, None).await
+}
+
+// Stream a split, reporting progress for it as part (index, total) if given
+async fn send_split<R>(
+    split: &Split,
+    source: &mut R,
+    mut sender: DataDownload<'_>,
+    part: Option<(usize, usize)>,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let progress = |sender: &mut DataDownload<'_>| {
+        if let Some((index, total)) = part {
+            part_progress(sender, index, Some(total));
+        }
+    };
+    progress(&mut sender);
+    sender.extend_from_slice(&split.header.to_bytes()).await?;
+    for chunk in &split.chunks {
+        sender.extend_from_slice(&chunk.header.to_bytes()).await?;
+        source.seek(SeekFrom::Start(chunk.offset as u64)).await?;
+        let mut left = chunk.size;
+        while left > 0 {
+            let buf = sender.get_mut_data(left).await?;
+            left -= read_exact_padded(source, buf).await?;
+            progress(&mut sender);
+        }
+    }
+    sender.finish().await?;
+    Ok(())
+}
+
+// Download a single split, reading the chunk data from the source; The split has to fit the max
+// download size of the device
+async fn download_split<R>(
+    fb: &mut NusbFastBoot,
+    split: &Split,
+    source: &mut R,
+    max_download: u32,
+    index: usize,
+    total: usize,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let sender = fb.download(split.download_size(max_download)?).await?;
+    send_split(split, source, sender, Some((index, total))).await
+}
+
+/// Description of how an image gets flashed
+#[derive(Debug, Clone)]
+pub struct ImagePlan {
+    /// Size of the image once written to the target
+    pub expanded_size: u64,
+    /// Sparse images the image gets split into; Empty if the image is small enough to be
+    /// downloaded as-is
+    pub splits: Vec<Split>,
+    /// Total number of bytes downloaded to the device
+    pub download_size: u64,
+}
+
+impl ImagePlan {
+    /// Number of downloads needed to flash the image
+    pub fn downloads(&self) -> usize {
+        self.splits.len().max(1)
+    }
+}
+
+// Plan for flashing a raw image of `size` bytes
+pub(crate) fn raw_plan(size: u64, max_download: u32) -> Result<ImagePlan, FlashError> {
+    let splits = if size < max_download.into() {
+        vec![]
+    } else {
+        split_raw(size as usize, max_download)?
+    };
+    Ok(ImagePlan {
+        expanded_size: size,
+        download_size: splits_size(&splits).unwrap_or(size),
+        splits,
+    })
+}
+
+// Plan for flashing a sparse image with the given chunks, limiting the number of chunks of each
+// split if given
+fn sparse_plan(
+    header: &FileHeader,
+    chunks: &[ChunkHeader],
+    max_download: u32,
+    max_chunks: Option<u32>,
+) -> Result<ImagePlan, FlashError> {
+    let splits = match max_chunks {
+        Some(max_chunks) => split_image_with_max_chunks(header, chunks, max_download, max_chunks)?,
+        None => split_image(header, chunks, max_download)?,
+    };
+    let size =
+        FILE_HEADER_BYTES_LEN as u64 + chunks.iter().map(|c| c.total_size as u64).sum::<u64>();
+    Ok(ImagePlan {
+        expanded_size: header.total_size(),
+        download_size: splits_size(&splits).unwrap_or(size),
+        splits,
+    })
+}
+
+// Total size of the sparse images generated for the splits; None if there are none
+fn splits_size(splits: &[Split]) -> Option<u64> {
+    (!splits.is_empty()).then(|| splits.iter().map(Split::sparse_size).sum())
+}
+
+/// Determine how an image from a seekable source would be flashed, without downloading anything
+///
+/// See [flash_image] for details
+pub async fn plan_image<R>(fb: &mut NusbFastBoot, source: &mut R) -> Result<ImagePlan, FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let max_download = max_download_size(fb).await?;
+    info!("Max download size: {max_download}");
+    plan_image_for(max_download, fb.quirks().max_sparse_chunks, source).await
+}
+
+// Determine how an image would be flashed to a device with the given max download size and
+// sparse chunk limit
+pub(crate) async fn plan_image_for<R>(
+    max_download: u32,
+    max_chunks: Option<u32>,
+    source: &mut R,
+) -> Result<ImagePlan, FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    source.seek(SeekFrom::Start(0)).await?;
+    let mut header_bytes = FileHeaderBytes::default();
+    source.read_exact(&mut header_bytes).await?;
+    match FileHeader::from_bytes(&header_bytes) {
+        Ok(header) => {
+            info!("Preparing to flash android sparse image");
+            let mut chunks = vec![];
+            let mut position = FILE_HEADER_BYTES_LEN as u64;
+            for index in 0..header.chunks {
+                let mut chunk_bytes = [0; CHUNK_HEADER_BYTES_LEN];
+                source.read_exact(&mut chunk_bytes).await?;
+                let chunk = ChunkHeader::from_bytes(&chunk_bytes)
+                    .map_err(|e| e.in_chunk(index, position))?;
+                position += chunk.total_size as u64;
+
+                source
+                    .seek(SeekFrom::Current(chunk.data_size() as i64))
+                    .await?;
+                chunks.push(chunk);
+            }
+            sparse_plan(&header, &chunks, max_download, max_chunks)
+        }
+        Err(ParseError::UnknownMagic) => {
+            let size = source.seek(SeekFrom::End(0)).await?;
+            raw_plan(size, max_download)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Read the complete data of a single split from the source
+async fn read_split<R>(split: &Split, source: &mut R) -> Result<Vec<u8>, FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let mut data = Vec::with_capacity(split.sparse_size() as usize);
+    data.extend_from_slice(&split.header.to_bytes());
+    for chunk in &split.chunks {
+        data.extend_from_slice(&chunk.header.to_bytes());
+        source.seek(SeekFrom::Start(chunk.offset as u64)).await?;
+        let start = data.len();
+        data.resize(start + chunk.size, 0);
+        read_exact_padded(source, &mut data[start..]).await?;
+    }
+    Ok(data)
+}
+
+/// Turn an image plan into a stream of ready-to-send download payloads
+///
+/// Each item is the complete data of one download; Only a single payload is read into memory at
+/// a time. See [download_payloads] for details.
+pub fn plan_payloads<R>(plan: ImagePlan, source: R) -> impl Stream<Item = Result<Bytes, FlashError>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    futures::stream::try_unfold((plan, source, 0), |(plan, mut source, i)| async move {
+        if plan.splits.is_empty() {
+            if i > 0 {
+                return Ok(None);
+            }
+            source.seek(SeekFrom::Start(0)).await?;
+            let mut data = vec![0; plan.expanded_size as usize];
+            source.read_exact(&mut data).await?;
+            return Ok(Some((Bytes::from(data), (plan, source, 1))));
+        }
+        let Some(split) = plan.splits.get(i) else {
+            return Ok(None);
+        };
+        let data = read_split(split, &mut source).await?;
+        Ok(Some((Bytes::from(data), (plan, source, i + 1))))
+    })
+}
+
+/// Split an image from a seekable source into a stream of ready-to-send download payloads
+///
+/// Whether the image is an android sparse image or a raw image is detected from its header, and
+/// the device is queried for its max download size to decide how it gets split (see
+/// [plan_image]). Each payload should be downloaded to the device followed by a flash of the
+/// target partition.
+///
+/// ```no_run
+/// # async fn example(fb: &mut fastboot_protocol::nusb::NusbFastBoot) -> anyhow::Result<()> {
+/// use fastboot_protocol::flash::download_payloads;
+/// use futures::TryStreamExt;
+///
+/// let file = tokio::fs::File::open("system.img").await?;
+/// let payloads = download_payloads(fb, file).await?;
+/// futures::pin_mut!(payloads);
+/// while let Some(payload) = payloads.try_next().await? {
+///     let mut sender = fb.download(payload.len() as u32).await?;
+///     sender.extend_from_slice(&payload).await?;
+///     sender.finish().await?;
+///     fb.flash("system").await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn download_payloads<R>(
+    fb: &mut NusbFastBoot,
+    mut source: R,
+) -> Result<impl Stream<Item = Result<Bytes, FlashError>>, FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let plan = plan_image(fb, &mut source).await?;
+    Ok(plan_payloads(plan, source))
+}
+
+/// Flash an image from a seekable source to the given target
+///
+/// The image can either be an android sparse image or a raw image. If the image doesn't fit in a
+/// single download (based on the devices max download size) it gets split up into multiple sparse
+/// images which are flashed one after the other
+pub async fn flash_image<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    source: R,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    flash_image_resumable(fb, target, source, None)
+        .await
+        .map_err(|e| e.source)
+}
+
+/// Progress of flashing an image in multiple parts, used to resume after a failure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResumeToken {
+    /// Size of the image once written to the target
+    pub expanded_size: u64,
+    /// Total number of parts
+    pub parts: usize,
+    /// Number of parts flashed successfully
+    pub completed: usize,
+}
+
+/// Failure while flashing an image in multiple parts
+///
+/// The token can be passed to [flash_image_resumable] to continue at the failed part
+#[derive(Debug, Error)]
+#[error("Flashing failed after {} of {} parts: {source}", token.completed, token.parts)]
+pub struct ResumableError {
+    /// Token to resume flashing
+    pub token: ResumeToken,
+    /// Underlying error
+    #[source]
+    pub source: FlashError,
+}
+
+/// Flash an image from a seekable source to the given target, optionally resuming a previous
+/// attempt
+///
+/// When `resume` is given the parts the token marks as completed are skipped; The token must
+/// stem from flashing the same image to a device with the same max download size. See
+/// [flash_image] for details on flashing.
+pub async fn flash_image_resumable<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut source: R,
+    resume: Option<ResumeToken>,
+) -> Result<(), ResumableError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let plan = match plan_image(fb, &mut source).await {
+        Ok(plan) => plan,
+        Err(source) => {
+            return Err(ResumableError {
+                token: resume.unwrap_or_default(),
+                source,
+            })
+        }
+    };
+    let mut token = ResumeToken {
+        expanded_size: plan.expanded_size,
+        parts: plan.downloads(),
+        completed: 0,
+    };
+    if let Err(source) = validate_target(fb, target, plan.expanded_size).await {
+        return Err(ResumableError { token, source });
+    }
+    if let Some(resume) = resume {
+        if resume.expanded_size != token.expanded_size
+            || resume.parts != token.parts
+            || resume.completed > token.parts
+        {
+            return Err(ResumableError {
+                token,
+                source: FlashError::ResumeMismatch,
+            });
+        }
+        token.completed = resume.completed;
+        info!("Resuming at part {}", token.completed);
+    }
+
+    let r = flash_plan(fb, target, &mut source, &plan, &mut token).await;
+    r.map_err(|source| ResumableError { token, source })
+}
+
+// Flash the remaining parts of an image plan, updating the token as parts complete
+async fn flash_plan<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    source: &mut R,
+    plan: &ImagePlan,
+    token: &mut ResumeToken,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    if plan.splits.is_empty() {
+        if token.completed == 0 {
+            source.seek(SeekFrom::Start(0)).await?;
+            flash_raw(fb, target, source, plan.expanded_size as u32).await?;
+            token.completed = 1;
+        }
+        return Ok(());
+    }
+
+    info!("Flashing in {} parts", plan.splits.len());
+    let max_download = max_download_size(fb).await?;
+    for (i, split) in plan.splits.iter().enumerate().skip(token.completed) {
+        info!("Downloading part {i}");
+        download_split(fb, split, source, max_download, i, plan.splits.len()).await?;
+        info!("Flashing Part {i}");
+        fb.flash(target).await?;
+        token.completed = i + 1;
+    }
+
+    Ok(())
+}
+
+// Sparse image part being assembled in memory while streaming
+struct StreamPart {
+    block_size: u32,
+    // Space left in the part
+    space: u32,
+    // Blocks covered by the part
+    blocks: u32,
+    chunks: u32,
+    // Number of chunks which can still be added
+    chunks_left: u32,
+    data: Vec<u8>,
+}
+
+impl StreamPart {
+    fn new(block_size: u32, size: u32, max_chunks: Option<u32>, blocks_offset: u32) -> Self {
+        let mut part = Self {
+            block_size,
+            space: size - FILE_HEADER_BYTES_LEN as u32,
+            blocks: 0,
+            chunks: 0,
+            chunks_left: max_chunks.unwrap_or(u32::MAX),
+            data: vec![],
+        };
+        if blocks_offset > 0 {
+            // Skip to the output offset of the part
+            part.push(&ChunkHeader::new_dontcare(blocks_offset), &[]);
+        }
+        part
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn fits(&self, chunk: &ChunkHeader) -> bool {
+        self.space >= chunk.total_size && self.chunks_left > 0
+    }
+
+    fn push(&mut self, chunk: &ChunkHeader, data: &[u8]) {
+        self.data.extend_from_slice(&chunk.to_bytes());
+        self.data.extend_from_slice(data);
+        self.space -= chunk.total_size;
+        self.blocks += chunk.chunk_size;
+        self.chunks += 1;
+        self.chunks_left -= 1;
+    }
+
+    // Amount of whole raw blocks which still fit in this part
+    fn raw_blocks_left(&self) -> u32 {
+        if self.chunks_left == 0 {
+            return 0;
+        }
+        self.space.saturating_sub(CHUNK_HEADER_BYTES_LEN as u32) / self.block_size
+    }
+
+    fn header(&self) -> FileHeader {
+        FileHeader {
+            block_size: self.block_size,
+            blocks: self.blocks,
+            chunks: self.chunks,
+            checksum: 0,
+        }
+    }
+}
+
+// Amount of assembled data sent between progress reports while streaming
+const STREAM_PROGRESS_INTERVAL: usize = 1024 * 1024;
+
+// Download and flash a part assembled while streaming
+async fn flash_stream_part(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    part: &StreamPart,
+    index: usize,
+) -> Result<(), FlashError> {
+    info!("Downloading part {index}");
+    let size = (FILE_HEADER_BYTES_LEN + part.data.len()) as u32;
+    let mut sender = fb.download(size).await?;
+    part_progress(&mut sender, index, None);
+    sender.extend_from_slice(&part.header().to_bytes()).await?;
+    for data in part.data.chunks(STREAM_PROGRESS_INTERVAL) {
+        sender.extend_from_slice(data).await?;
+        part_progress(&mut sender, index, None);
+    }
+    sender.finish().await?;
+    info!("Flashing Part {index}");
+    fb.flash(target).await?;
+    Ok(())
+}
+
+/// Flash an android sparse image read sequentially from a non-seekable source (e.g. a pipe or a
+/// network stream) to the given target
+///
+/// As the source can't be seeked, the image is split on the fly based on the max download size of
+/// the device; Each part is assembled in memory before being downloaded, so memory usage is
+/// bounded by the max download size. Crc32 chunks are dropped as their checksum doesn't apply to
+/// the individual parts.
+pub async fn flash_sparse_stream<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut source: R,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    let max_download = max_download_size(fb).await?;
+    info!("Max download size: {max_download}");
+
+    let mut header_bytes = FileHeaderBytes::default();
+    source.read_exact(&mut header_bytes).await?;
+    let header = FileHeader::from_bytes(&header_bytes)?;
+    validate_target(fb, target, header.total_size()).await?;
+    let block_size = header.block_size;
+    if max_download < FILE_HEADER_BYTES_LEN as u32 + 2 * CHUNK_HEADER_BYTES_LEN as u32 + block_size
+    {
+        return Err(SplitError::TooSmall.into());
+    }
+    // Each part needs room for a DontCare chunk skipping ahead and a chunk with data
+    let max_chunks = fb.quirks().max_sparse_chunks;
+    if max_chunks.is_some_and(|max| max < 2) {
+        return Err(SplitError::TooFewChunks.into());
+    }
+
+    let mut part = StreamPart::new(block_size, max_download, max_chunks, 0);
+    let mut parts = 0;
+    // Output offset in blocks
+    let mut offset = 0;
+    let mut position = FILE_HEADER_BYTES_LEN as u64;
+    for index in 0..header.chunks {
+        let mut chunk_bytes = ChunkHeaderBytes::default();
+        source.read_exact(&mut chunk_bytes).await?;
+        let chunk =
+            ChunkHeader::from_bytes(&chunk_bytes).map_err(|e| e.in_chunk(index, position))?;
+        position += chunk.total_size as u64;
+
+        match chunk.chunk_type {
+            ChunkType::Raw => {
+                let mut blocks = 0;
+                while blocks < chunk.chunk_size {
+                    let fit = part.raw_blocks_left().min(chunk.chunk_size - blocks);
+                    if fit == 0 {
+                        flash_stream_part(fb, target, &part, parts).await?;
+                        parts += 1;
+                        part =
+                            StreamPart::new(block_size, max_download, max_chunks, offset + blocks);
+                        continue;
+                    }
+                    let mut data = vec![0; (fit * block_size) as usize];
+                    source.read_exact(&mut data).await?;
+                    part.push(&ChunkHeader::new_raw(fit, block_size), &data);
+                    blocks += fit;
+                }
+            }
+            ChunkType::Crc32 => {
+                let mut data = vec![0; chunk.data_size()];
+                source.read_exact(&mut data).await?;
+            }
+            ChunkType::Fill | ChunkType::DontCare => {
+                let mut data = vec![0; chunk.data_size()];
+                source.read_exact(&mut data).await?;
+                if !part.fits(&chunk) {
+                    flash_stream_part(fb, target, &part, parts).await?;
+                    parts += 1;
+                    part = StreamPart::new(block_size, max_download, max_chunks, offset);
+                    if !part.fits(&chunk) {
+                        return Err(SplitError::TooSmall.into());
+                    }
+                }
+                part.push(&chunk, &data);
+            }
+        }
+        offset += chunk.chunk_size;
+    }
+
+    if !part.is_empty() {
+        flash_stream_part(fb, target, &part, parts).await?;
+    }
+
+    Ok(())
+}
+
+// Flash a raw image of unknown size read sequentially from `reader` to the given target
+//
+// The data is wrapped into sparse images of up to the max download size as it is read, so the
+// image doesn't have to be read an additional time to determine its size; Images smaller than
+// the max download size are flashed as-is. The end of the image is padded with zeroes to a
+// multiple of the block size. The size of the target is validated against the data read so far
+// before each part is flashed.
+async fn flash_raw_stream_unsized<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut reader: R,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    let max_download = max_download_size(fb).await?;
+    info!("Max download size: {max_download}");
+    let block_size = DEFAULT_BLOCKSIZE;
+    if max_download < FILE_HEADER_BYTES_LEN as u32 + 2 * CHUNK_HEADER_BYTES_LEN as u32 + block_size
+    {
+        return Err(SplitError::TooSmall.into());
+    }
+
+    // Each part holds a single raw chunk after skipping ahead, so no chunk limit applies
+    let mut part = StreamPart::new(block_size, max_download, None, 0);
+    let mut parts = 0;
+    // Output offset in blocks
+    let mut offset = 0;
+    // Whether the part holds data which wasn't flashed yet
+    let mut pending = false;
+    loop {
+        let fit = part.raw_blocks_left();
+        if fit == 0 {
+            validate_target(fb, target, offset as u64 * block_size as u64).await?;
+            flash_stream_part(fb, target, &part, parts).await?;
+            parts += 1;
+            part = StreamPart::new(block_size, max_download, None, offset);
+            pending = false;
+            continue;
+        }
+        let mut data = vec![0; (fit * block_size) as usize];
+        let read = read_full(&mut reader, &mut data).await?;
+        if parts == 0 && part.is_empty() && read < data.len() {
+            validate_target(fb, target, read as u64).await?;
+            return flash_raw(fb, target, &data[..read], read as u32).await;
+        }
+        let blocks = read.div_ceil(block_size as usize) as u32;
+        if blocks > 0 {
+            data.truncate((blocks * block_size) as usize);
+            part.push(&ChunkHeader::new_raw(blocks, block_size), &data);
+            offset += blocks;
+            pending = true;
+        }
+        if read < (fit * block_size) as usize {
+            break;
+        }
+    }
+
+    if pending {
+        validate_target(fb, target, offset as u64 * block_size as u64).await?;
+        flash_stream_part(fb, target, &part, parts).await?;
+    }
+
+    Ok(())
+}
+
+/// Flash an image read sequentially from a non-seekable source to the given target
+///
+/// The image can either be an android sparse image or a raw image of `size` bytes, see
+/// [flash_sparse_stream] and [flash_raw_stream] for details. Raw images of unknown size are
+/// wrapped into sparse images while they are read. Images with the sparse magic but an invalid
+/// header are rejected rather than flashed raw.
+pub async fn flash_stream<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut source: R,
+    size: Option<u64>,
+    name: &str,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Some(size) = size.filter(|&s| s < FILE_HEADER_BYTES_LEN as u64) {
+        return flash_raw_stream(fb, target, source, size).await;
+    }
+
+    let mut header = FileHeaderBytes::default();
+    source.read_exact(&mut header).await?;
+    let source = Cursor::new(header).chain(source);
+    match FileHeader::from_bytes(&header) {
+        Ok(_) => flash_sparse_stream(fb, target, source).await,
+        Err(ParseError::UnknownMagic) => match size {
+            Some(size) => flash_raw_stream(fb, target, source, size).await,
+            None => {
+                info!("Size of raw image from {name} is unknown, flashing it as it is read");
+                flash_raw_stream_unsized(fb, target, source).await
+            }
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Determine how an image read sequentially from a non-seekable source would be flashed
+///
+/// For sparse images the whole image gets read to determine the chunk layout; The size is only
+/// required for raw images. See [plan_image] for details.
+pub(crate) async fn plan_stream<R>(
+    fb: &mut NusbFastBoot,
+    mut reader: R,
+    size: Option<u64>,
+    name: &str,
+) -> Result<ImagePlan, FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    let max_download = max_download_size(fb).await?;
+
+    let mut header_bytes = FileHeaderBytes::default();
+    reader.read_exact(&mut header_bytes).await?;
+    let header = match FileHeader::from_bytes(&header_bytes) {
+        Ok(header) => header,
+        Err(ParseError::UnknownMagic) => {
+            let size = size.ok_or_else(|| FlashError::UnknownSize(name.to_string()))?;
+            return raw_plan(size, max_download);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut chunks = vec![];
+    let mut position = FILE_HEADER_BYTES_LEN as u64;
+    for index in 0..header.chunks {
+        let mut chunk_bytes = ChunkHeaderBytes::default();
+        reader.read_exact(&mut chunk_bytes).await?;
+        let chunk =
+            ChunkHeader::from_bytes(&chunk_bytes).map_err(|e| e.in_chunk(index, position))?;
+        position += chunk.total_size as u64;
+        let data = chunk.data_size() as u64;
+        let skipped =
+            tokio::io::copy(&mut (&mut reader).take(data), &mut tokio::io::sink()).await?;
+        if skipped != data {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        chunks.push(chunk);
+    }
+    sparse_plan(
+        &header,
+        &chunks,
+        max_download,
+        fb.quirks().max_sparse_chunks,
+    )
+}
+
+/// Flash an image from a length-prefixed stream to the given target
+///
+/// The stream starts with the size of the image as a big endian 64 bit integer followed by the
+/// image itself, so raw images can be piped in from non-seekable sources (e.g. stdin or a
+/// socket); See [flash_stream] for details. Data following the image is left unread.
+pub async fn flash_length_prefixed<R>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    mut source: R,
+    name: &str,
+) -> Result<(), FlashError>
+where
+    R: AsyncRead + Unpin,
+{
+    let size = source.read_u64().await?;
+    info!("Flashing {size} bytes from {name}");
+    flash_stream(fb, target, source.take(size), Some(size), name).await
+}
+
+// Open a block device, along with its size
+pub(crate) async fn open_block_device(path: &Path) -> Result<(tokio::fs::File, u64), FlashError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    // Block devices report a length of 0 in their metadata, but can be seeked to their end
+    let size = file.seek(SeekFrom::End(0)).await?;
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok((file, size))
+}
+
+/// Flash the content of a block device (e.g. a partition of a disk attached to the host) to the
+/// given target
+///
+/// The content is always flashed as a raw image of the size of the block device, see
+/// [flash_raw_stream]
+pub async fn flash_block_device<P: AsRef<Path>>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    path: P,
+) -> Result<(), FlashError> {
+    let (file, size) = open_block_device(path.as_ref()).await?;
+    flash_raw_stream(fb, target, file, size).await
+}
+
+/// Flash the image file at `path` to the given target
+///
+/// See [flash_image] for details
+pub async fn flash_file<P: AsRef<Path>>(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    path: P,
+) -> Result<(), FlashError> {
+    let file = tokio::fs::File::open(path).await?;
+    flash_image(fb, target, file).await
+}
+
+/// Source of an image to flash
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageSource {
+    /// Image file on the local filesystem
+    File(PathBuf),
+    /// Block device on the local system (e.g. /dev/sdb1), flashed as a raw image
+    BlockDevice(PathBuf),
+    /// Image data in memory
+    Bytes(Bytes),
+    /// Compressed image file on the local filesystem, decompressed while flashing
+    Compressed {
+        path: PathBuf,
+        compression: Compression,
+    },
+    /// Image streamed from an HTTP(S) URL
+    #[cfg(feature = "http")]
+    Url(String),
+}
+
+impl ImageSource {
+    /// Source for a location given by a user; HTTP(S) URLs are turned into URL sources when
+    /// supported, anything else is a path to a block device or a file which is compressed if its
+    /// extension says so
+    pub fn from_location(location: &str) -> Self {
+        #[cfg(feature = "http")]
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return ImageSource::Url(location.to_string());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::metadata(location).is_ok_and(|m| m.file_type().is_block_device()) {
+                return ImageSource::BlockDevice(location.into());
+            }
+        }
+        match Compression::from_path(location) {
+            Some(compression) => ImageSource::Compressed {
+                path: location.into(),
+                compression,
+            },
+            None => ImageSource::File(location.into()),
+        }
+    }
+}
+
+impl Display for ImageSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageSource::File(path) | ImageSource::BlockDevice(path) => {
+                write!(f, "{}", path.display())
+            }
+            ImageSource::Bytes(bytes) => write!(f, "<{} bytes in memory>", bytes.len()),
+            ImageSource::Compressed { path, .. } => write!(f, "{}", path.display()),
+            #[cfg(feature = "http")]
+            ImageSource::Url(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+impl From<PathBuf> for ImageSource {
+    fn from(path: PathBuf) -> Self {
+        ImageSource::File(path)
+    }
+}
+
+impl From<&Path> for ImageSource {
+    fn from(path: &Path) -> Self {
+        ImageSource::File(path.to_path_buf())
+    }
+}
+
+impl From<Bytes> for ImageSource {
+    fn from(bytes: Bytes) -> Self {
+        ImageSource::Bytes(bytes)
+    }
+}
+
+impl From<Vec<u8>> for ImageSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        ImageSource::Bytes(bytes.into())
+    }
+}
+
+/// Flash an image from the given source to the given target
+///
+/// See [flash_image] for details
+pub async fn flash_source(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    source: &ImageSource,
+) -> Result<(), FlashError> {
+    match source {
+        ImageSource::File(path) => flash_file(fb, target, path).await,
+        ImageSource::BlockDevice(path) => flash_block_device(fb, target, path).await,
+        ImageSource::Bytes(bytes) => flash_image(fb, target, Cursor::new(bytes.clone())).await,
+        ImageSource::Compressed { path, compression } => {
+            crate::compress::flash_compressed(fb, target, path, *compression).await
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => crate::http::flash_url(fb, target, url).await,
+    }
+}
+
+/// Flash an image from the given source to the given target, optionally resuming a previous
+/// attempt
+///
+/// See [flash_image_resumable] for details
+pub async fn flash_source_resumable(
+    fb: &mut NusbFastBoot,
+    target: &str,
+    source: &ImageSource,
+    resume: Option<ResumeToken>,
+) -> Result<(), ResumableError> {
+    match source {
+        ImageSource::File(path) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .map_err(|e| ResumableError {
+                    token: resume.unwrap_or_default(),
+                    source: e.into(),
+                })?;
+            flash_image_resumable(fb, target, file, resume).await
+        }
+        ImageSource::Bytes(bytes) => {
+            flash_image_resumable(fb, target, Cursor::new(bytes.clone()), resume).await
+        }
+        // Block devices are flashed as raw streams regardless of their content
+        ImageSource::BlockDevice(path) => {
+            flash_block_device(fb, target, path)
+                .await
+                .map_err(|source| ResumableError {
+                    token: ResumeToken::default(),
+                    source,
+                })
+        }
+        // Streamed sources can't skip parts, so they are always flashed from the start
+        ImageSource::Compressed { path, compression } => {
+            crate::compress::flash_compressed(fb, target, path, *compression)
+                .await
+                .map_err(|source| ResumableError {
+                    token: ResumeToken::default(),
+                    source,
+                })
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => crate::http::flash_url(fb, target, url)
+            .await
+            .map_err(|source| ResumableError {
+                token: ResumeToken::default(),
+                source,
+            }),
+    }
+}
+
+/// Result of flashing a single partition with [flash_many]
+#[derive(Debug)]
+pub struct PartitionResult {
+    /// Target partition
+    pub target: String,
+    /// Outcome for the partition
+    pub result: Result<(), FlashError>,
+}
+
+/// Aggregated results of [flash_many]
+#[derive(Debug, Default)]
+pub struct FlashManyReport {
+    /// Results of the partitions which were validated or flashed, in flashing order
+    pub results: Vec<PartitionResult>,
+    /// Partitions which weren't flashed due to an earlier failure
+    pub skipped: Vec<String>,
+}
+
+impl FlashManyReport {
+    /// Whether all partitions were flashed successfully
+    pub fn is_ok(&self) -> bool {
+        self.skipped.is_empty() && self.results.iter().all(|r| r.result.is_ok())
+    }
+
+    /// Partitions which were flashed successfully
+    pub fn succeeded(&self) -> impl Iterator<Item = &str> {
+        self.results
+            .iter()
+            .filter(|r| r.result.is_ok())
+            .map(|r| r.target.as_str())
+    }
+
+    /// Partitions which failed, with their error
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &FlashError)> {
+        self.results
+            .iter()
+            .filter_map(|r| Some((r.target.as_str(), r.result.as_ref().err()?)))
+    }
+}
+
+// Firmware partitions, which [flash_many] flashes before any other partition
+const FIRMWARE_PARTITIONS: &[&str] = &["bootloader", "radio"];
+
+// Flashing priority of a partition; Firmware goes first, operating system partitions last
+fn flash_priority(target: &str) -> usize {
+    let base = match target.rsplit_once('_') {
+        Some((base, slot)) if slot.len() == 1 => base,
+        _ => target,
+    };
+    if FIRMWARE_PARTITIONS.contains(&base) {
+        0
+    } else if BOOT_PARTITIONS.contains(&base) {
+        1
+    } else if OS_PARTITIONS.contains(&base) {
+        3
+    } else {
+        2
+    }
+}
+
+// Order images for flashing, keeping the given order within the same priority
+fn flash_order<S: AsRef<str>>(images: &[(S, ImageSource)]) -> Vec<&(S, ImageSource)> {
+    let mut ordered: Vec<_> = images.iter().collect();
+    ordered.sort_by_key(|(target, _)| flash_priority(target.as_ref()));
+    ordered
+}
+
+/// Flash multiple images in one go, given as (target, source) pairs
+///
+/// All sources are validated against their target partitions before anything gets flashed; If
+/// any of them is invalid nothing is flashed and the report contains the validation failures.
+/// Images are flashed firmware (e.g. bootloader, radio) first, followed by the partitions needed
+/// to boot and finally the operating system partitions. Flashing stops at the first failure, the
+/// remaining partitions are reported as skipped.
+pub async fn flash_many<S: AsRef<str>>(
+    fb: &mut NusbFastBoot,
+    images: &[(S, ImageSource)],
+) -> FlashManyReport {
+    let ordered = flash_order(images);
+    let mut report = FlashManyReport::default();
+
+    for (target, source) in &ordered {
+        let target = target.as_ref();
+        let result = match plan_source(fb, source).await {
+            Ok(plan) => validate_target(fb, target, plan.expanded_size).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            report.results.push(PartitionResult {
+                target: target.to_string(),
+                result: Err(e),
+            });
+        }
+    }
+    if !report.results.is_empty() {
+        report.skipped = ordered
+            .iter()
+            .map(|(t, _)| t.as_ref())
+            .filter(|t| !report.results.iter().any(|r| r.target == *t))
+            .map(str::to_string)
+            .collect();
+        return report;
+    }
+
+    let mut ordered = ordered.into_iter();
+    for (target, source) in ordered.by_ref() {
+        let target = target.as_ref();
+        info!("Flashing {source} to {target}");
+        let result = flash_source(fb, target, source).await;
+        let failed = result.is_err();
+        report.results.push(PartitionResult {
+            target: target.to_string(),
+            result,
+        });
+        if failed {
+            break;
+        }
+    }
+    report.skipped = ordered.map(|(t, _)| t.as_ref().to_string()).collect();
+    report
+}
+
+/// Determine how an image from the given source would be flashed
+///
+/// See [plan_image] for details
+pub async fn plan_source(
+    fb: &mut NusbFastBoot,
+    source: &ImageSource,
+) -> Result<ImagePlan, FlashError> {
+    match source {
+        ImageSource::File(path) => {
+            let mut file = tokio::fs::File::open(path).await?;
+            plan_image(fb, &mut file).await
+        }
+        ImageSource::BlockDevice(path) => {
+            let (_, size) = open_block_device(path).await?;
+            raw_plan(size, max_download_size(fb).await?)
+        }
+        ImageSource::Bytes(bytes) => plan_image(fb, &mut Cursor::new(bytes.clone())).await,
+        ImageSource::Compressed { path, compression } => {
+            crate::compress::plan_compressed(fb, path, *compression).await
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => crate::http::plan_url(fb, url).await,
+    }
+}
+
+/// Download an image from the given source as-is in a single download, without flashing it
+///
+/// Used for commands operating on downloaded data such as `update-super`; The image must fit in
+/// the max download size of the device
+pub async fn download_source(
+    fb: &mut NusbFastBoot,
+    source: &ImageSource,
+) -> Result<(), FlashError> {
+    let data = match source {
+        ImageSource::File(path) | ImageSource::BlockDevice(path) => {
+            Bytes::from(tokio::fs::read(path).await?)
+        }
+        ImageSource::Bytes(bytes) => bytes.clone(),
+        ImageSource::Compressed { path, compression } => {
+            let mut data = vec![];
+            crate::compress::open_compressed(path, *compression)
+                .await?
+                .read_to_end(&mut data)
+                .await?;
+            Bytes::from(data)
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => crate::http::get(url).await?.bytes().await?,
+    };
+    let mut sender = fb.download(data.len() as u32).await?;
+    sender.extend_from_slice(&data).await?;
+    sender.finish().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn payloads() {
+        let image: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+
+        let plan = ImagePlan {
+            expanded_size: image.len() as u64,
+            splits: vec![],
+            download_size: image.len() as u64,
+        };
+        let payloads: Vec<_> = plan_payloads(plan, Cursor::new(image.clone()))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(payloads, vec![Bytes::from(image.clone())]);
+
+        let plan = raw_plan(image.len() as u64, 8192).unwrap();
+        let payloads: Vec<_> = plan_payloads(plan.clone(), Cursor::new(image.clone()))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(payloads.len(), plan.splits.len());
+        assert_eq!(
+            plan.download_size,
+            payloads.iter().map(|p| p.len() as u64).sum::<u64>()
+        );
+        let mut raw = vec![];
+        for (payload, split) in payloads.iter().zip(&plan.splits) {
+            assert_eq!(payload.len() as u64, split.sparse_size());
+            let header =
+                FileHeader::from_bytes(payload[..FILE_HEADER_BYTES_LEN].try_into().unwrap())
+                    .unwrap();
+            assert_eq!(header, split.header);
+            let mut offset = FILE_HEADER_BYTES_LEN;
+            for chunk in &split.chunks {
+                offset += CHUNK_HEADER_BYTES_LEN;
+                if chunk.header.chunk_type == ChunkType::Raw {
+                    raw.extend_from_slice(&payload[offset..offset + chunk.size]);
+                }
+                offset += chunk.size;
+            }
+        }
+        // The end is padded with zeroes up to the block size
+        assert_eq!(&raw[..image.len()], &image[..]);
+        assert!(raw[image.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn sparse_chunk_limit() {
+        let chunks = [
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_fill(2),
+            ChunkHeader::new_raw(2, 4096),
+            ChunkHeader::new_dontcare(2),
+            ChunkHeader::new_raw(2, 4096),
+        ];
+        let header = FileHeader {
+            block_size: 4096,
+            blocks: 10,
+            chunks: chunks.len() as u32,
+            checksum: 0,
+        };
+        let plan = sparse_plan(&header, &chunks, 1024 * 1024, None).unwrap();
+        assert_eq!(plan.downloads(), 1);
+        let plan = sparse_plan(&header, &chunks, 1024 * 1024, Some(3)).unwrap();
+        assert_eq!(plan.downloads(), 2);
+        assert!(plan.splits.iter().all(|split| split.header.chunks <= 3));
+
+        let mut part = StreamPart::new(4096, 1024 * 1024, Some(2), 4);
+        assert!(part.fits(&chunks[1]));
+        part.push(&chunks[1], &[0; 4]);
+        assert!(!part.fits(&chunks[3]));
+        assert_eq!(part.raw_blocks_left(), 0);
+        assert_eq!(part.header().chunks, 2);
+    }
+
+    #[test]
+    fn many_order() {
+        let images: Vec<(&str, ImageSource)> = [
+            "system_a",
+            "boot_a",
+            "userdata",
+            "radio",
+            "vbmeta",
+            "bootloader_b",
+        ]
+        .into_iter()
+        .map(|t| (t, ImageSource::from(vec![])))
+        .collect();
+        let order: Vec<_> = flash_order(&images).iter().map(|(t, _)| *t).collect();
+        assert_eq!(
+            order,
+            [
+                "radio",
+                "bootloader_b",
+                "boot_a",
+                "vbmeta",
+                "userdata",
+                "system_a"
+            ]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    flash::{plan_source, FlashError, ImageSource},
+    mode::{ensure_mode, FastbootMode, ModeError, DEFAULT_MODE_TIMEOUT},
+    nusb::{NusbFastBoot, NusbFastBootError},
+    session::{FlashSession, SessionError},
+    slot::{current_slot, has_slot},
+};
+
+/// Partitions needed to boot, flashed first
+pub(crate) const BOOT_PARTITIONS: &[&str] = &[
+    "boot",
+    "init_boot",
+    "dtbo",
+    "pvmfw",
+    "recovery",
+    "vbmeta",
+    "vbmeta_system",
+    "vbmeta_vendor",
+    "vendor_boot",
+    "vendor_kernel_boot",
+];
+
+/// Operating system partitions; Logical partitions inside super on devices with dynamic
+/// partitions
+pub(crate) const OS_PARTITIONS: &[&str] = &[
+    "odm",
+    "odm_dlkm",
+    "product",
+    "system",
+    "system_dlkm",
+    "system_ext",
+    "vendor",
+    "vendor_dlkm",
+];
+
+pub(crate) const SUPER_EMPTY: &str = "super_empty.img";
+
+/// Errors while flashing a factory image directory
+#[derive(Debug, Error)]
+pub enum FlashAllError {
+    #[error("No images found in {0}")]
+    NoImages(PathBuf),
+    #[error(transparent)]
+    Mode(#[from] ModeError),
+    #[error(transparent)]
+    Flash(#[from] FlashError),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+    #[error(transparent)]
+    Session(#[from] SessionError),
+}
+
+// Image to flash as (partition, path)
+type Image = (&'static str, PathBuf);
+
+// Boot and operating system images in the directory, in flashing order
+fn factory_images(dir: &Path) -> (Vec<Image>, Vec<Image>) {
+    let present = |partitions: &[&'static str]| {
+        partitions
+            .iter()
+            .map(|&p| (p, dir.join(format!("{p}.img"))))
+            .filter(|(_, path)| path.is_file())
+            .collect::<Vec<_>>()
+    };
+    let boot = present(BOOT_PARTITIONS);
+    let mut os = present(OS_PARTITIONS);
+    // Without dynamic partitions a full super image can be flashed directly
+    if !dir.join(SUPER_EMPTY).is_file() {
+        os.extend(present(&["super"]));
+    }
+    (boot, os)
+}
+
+// Partition name to flash to, adding the slot suffix if the partition has slots
+pub(crate) async fn slot_target(
+    fb: &mut NusbFastBoot,
+    partition: &str,
+    slot: Option<&str>,
+) -> Result<String, NusbFastBootError> {
+    match slot {
+        Some(slot) if has_slot(fb, partition).await? => Ok(format!("{partition}_{slot}")),
+        _ => Ok(partition.to_string()),
+    }
+}
+
+pub(crate) async fn is_userspace(fb: &mut NusbFastBoot) -> bool {
+    fb.get_var_cached("is-userspace").await.ok().as_deref() == Some("yes")
+}
+
+pub(crate) async fn super_partition_name(fb: &mut NusbFastBoot) -> String {
+    fb.get_var_cached("super-partition-name")
+        .await
+        .unwrap_or_else(|_| "super".to_string())
+}
+
+/// Plan flashing a factory image directory (as produced by an android build), equivalent to
+/// `fastboot flashall`
+///
+/// Boot critical images (boot, dtbo, vbmeta, ...) are flashed first, followed by the operating
+/// system images. Partitions with slots are flashed to the current slot. If the directory
+/// contains a super_empty.img the device is rebooted into userspace fastboot (fastbootd) if
+/// needed; The super partition metadata is then updated and each logical partition resized to
+/// fit its image before flashing it. Userdata and cache images are not flashed. As the device may
+/// get rebooted the client is consumed; The client to run the session with is returned.
+pub async fn plan_flash_all<P: AsRef<Path>>(
+    fb: NusbFastBoot,
+    dir: P,
+) -> Result<(NusbFastBoot, FlashSession), FlashAllError> {
+    let dir = dir.as_ref();
+    let (boot, os) = factory_images(dir);
+    let super_empty = dir.join(SUPER_EMPTY);
+    let dynamic = super_empty.is_file();
+    if boot.is_empty() && os.is_empty() && !dynamic {
+        return Err(FlashAllError::NoImages(dir.to_path_buf()));
+    }
+
+    let mut fb = if dynamic {
+        ensure_mode(fb, FastbootMode::Fastbootd, DEFAULT_MODE_TIMEOUT).await?
+    } else {
+        fb
+    };
+    let slot = current_slot(&mut fb).await?;
+    info!("Flashing to slot: {slot:?}");
+
+    let mut session = FlashSession::new();
+    for (partition, path) in boot {
+        let target = slot_target(&mut fb, partition, slot.as_deref()).await?;
+        session = session.flash(target, path);
+    }
+
+    if dynamic {
+        let super_name = super_partition_name(&mut fb).await;
+        session = session.update_super(super_name, super_empty, false);
+    }
+
+    for (partition, path) in os {
+        let target = slot_target(&mut fb, partition, slot.as_deref()).await?;
+        if dynamic {
+            // Logical partitions are created empty by super_empty.img
+            let source = ImageSource::from(path.as_path());
+            let size = plan_source(&mut fb, &source).await?.expanded_size;
+            session = session.resize_logical(target.clone(), size);
+        }
+        session = session.flash(target, path);
+    }
+
+    Ok((fb, session))
+}
+
+/// Flash a factory image directory, equivalent to `fastboot flashall`
+///
+/// See [plan_flash_all] for details; Returns the client connected to the device
+pub async fn flash_all<P: AsRef<Path>>(
+    fb: NusbFastBoot,
+    dir: P,
+) -> Result<NusbFastBoot, FlashAllError> {
+    let (mut fb, session) = plan_flash_all(fb, dir).await?;
+    session.run(&mut fb).await?;
+    Ok(fb)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_order() {
+        let dir = std::env::temp_dir().join(format!("fastboot-flashall-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for image in [
+            "system.img",
+            "vbmeta.img",
+            "boot.img",
+            "super.img",
+            "userdata.img",
+            "android-info.txt",
+        ] {
+            std::fs::write(dir.join(image), b"").unwrap();
+        }
+
+        let (boot, os) = factory_images(&dir);
+        let names = |images: Vec<Image>| images.into_iter().map(|(p, _)| p).collect();
+        let boot: Vec<_> = names(boot);
+        let os: Vec<_> = names(os);
+        assert_eq!(boot, vec!["boot", "vbmeta"]);
+        assert_eq!(os, vec!["system", "super"]);
+
+        // With dynamic partitions super gets updated from super_empty.img instead
+        std::fs::write(dir.join(SUPER_EMPTY), b"").unwrap();
+        let (_, os) = factory_images(&dir);
+        assert_eq!(names(os), vec!["system"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use std::error::Error;
+
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncSeek};
+use tracing::info;
+
+use crate::{
+    flash::{plan_image_for, plan_payloads, FlashError},
+    nusb::{DownloadError, NusbFastBoot, NusbFastBootError},
+    progress::ProgressCallback,
+    protocol::parse_u32,
+    tcp::{TcpFastBoot, TcpFastBootError},
+};
+
+/// Errors reported by a [Flasher]
+#[derive(Debug, Error)]
+pub enum FlasherError {
+    #[error("Fastboot failure: {0}")]
+    Failed(String),
+    #[error("Download of {0} bytes is too large")]
+    DownloadTooLarge(usize),
+    #[error(transparent)]
+    Transport(Box<dyn Error + Send + Sync>),
+}
+
+impl From<NusbFastBootError> for FlasherError {
+    fn from(e: NusbFastBootError) -> Self {
+        match e {
+            NusbFastBootError::FastbootFailed(fail) => FlasherError::Failed(fail),
+            e => FlasherError::Transport(Box::new(e)),
+        }
+    }
+}
+
+impl From<DownloadError> for FlasherError {
+    fn from(e: DownloadError) -> Self {
+        match e {
+            DownloadError::Nusb(e) => e.into(),
+            e => FlasherError::Transport(Box::new(e)),
+        }
+    }
+}
+
+impl From<TcpFastBootError> for FlasherError {
+    fn from(e: TcpFastBootError) -> Self {
+        match e {
+            TcpFastBootError::FastbootFailed(fail) => FlasherError::Failed(fail),
+            TcpFastBootError::DownloadTooLarge(size) => FlasherError::DownloadTooLarge(size),
+            e => FlasherError::Transport(Box::new(e)),
+        }
+    }
+}
+
+/// Object safe interface to a fastboot device, independent of the transport
+///
+/// Implemented by [NusbFastBoot] and [TcpFastBoot]; Frameworks can accept a `&mut dyn Flasher`
+/// to support any backend, including mocks for testing.
+pub trait Flasher: Send {
+    /// Get the named variable
+    fn get_var<'a>(&'a mut self, var: &'a str) -> BoxFuture<'a, Result<String, FlasherError>>;
+    /// Download data to the device
+    fn download<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), FlasherError>>;
+    /// Flash downloaded data to the given target partition
+    fn flash<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>>;
+    /// Erase the given target partition
+    fn erase<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>>;
+    /// Reboot the device
+    fn reboot(&mut self) -> BoxFuture<'_, Result<(), FlasherError>>;
+    /// Reboot the device into a specific mode (e.g. bootloader)
+    fn reboot_to<'a>(&'a mut self, mode: &'a str) -> BoxFuture<'a, Result<(), FlasherError>>;
+    /// Set the callback receiving progress events, returning the previous callback
+    fn set_progress(&mut self, progress: Option<ProgressCallback>) -> Option<ProgressCallback>;
+}
+
+impl Flasher for NusbFastBoot {
+    fn get_var<'a>(&'a mut self, var: &'a str) -> BoxFuture<'a, Result<String, FlasherError>> {
+        async move { Ok(NusbFastBoot::get_var(self, var).await?) }.boxed()
+    }
+
+    fn download<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move {
+            let size = u32::try_from(data.len())
+                .map_err(|_| FlasherError::DownloadTooLarge(data.len()))?;
+            let mut sender = NusbFastBoot::download(self, size).await?;
+            sender.extend_from_slice(data).await?;
+            sender.finish().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn flash<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(NusbFastBoot::flash(self, target).await?) }.boxed()
+    }
+
+    fn erase<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(NusbFastBoot::erase(self, target).await?) }.boxed()
+    }
+
+    fn reboot(&mut self) -> BoxFuture<'_, Result<(), FlasherError>> {
+        async move { Ok(NusbFastBoot::reboot(self).await?) }.boxed()
+    }
+
+    fn reboot_to<'a>(&'a mut self, mode: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(NusbFastBoot::reboot_to(self, mode).await?) }.boxed()
+    }
+
+    fn set_progress(&mut self, progress: Option<ProgressCallback>) -> Option<ProgressCallback> {
+        self.replace_progress(progress)
+    }
+}
+
+impl Flasher for TcpFastBoot {
+    fn get_var<'a>(&'a mut self, var: &'a str) -> BoxFuture<'a, Result<String, FlasherError>> {
+        async move { Ok(TcpFastBoot::get_var(self, var).await?) }.boxed()
+    }
+
+    fn download<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(TcpFastBoot::download(self, data).await?) }.boxed()
+    }
+
+    fn flash<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(TcpFastBoot::flash(self, target).await?) }.boxed()
+    }
+
+    fn erase<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(TcpFastBoot::erase(self, target).await?) }.boxed()
+    }
+
+    fn reboot(&mut self) -> BoxFuture<'_, Result<(), FlasherError>> {
+        async move { Ok(TcpFastBoot::reboot(self).await?) }.boxed()
+    }
+
+    fn reboot_to<'a>(&'a mut self, mode: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        async move { Ok(TcpFastBoot::reboot_to(self, mode).await?) }.boxed()
+    }
+
+    fn set_progress(&mut self, progress: Option<ProgressCallback>) -> Option<ProgressCallback> {
+        self.replace_progress(progress)
+    }
+}
+
+/// Errors while flashing through a [Flasher]
+#[derive(Debug, Error)]
+pub enum FlashWithError {
+    #[error("Failed to parse max download size: {0}")]
+    MaxDownloadSize(String),
+    #[error(transparent)]
+    Image(#[from] FlashError),
+    #[error(transparent)]
+    Flasher(#[from] FlasherError),
+}
+
+/// Flash an image from a seekable source to the given target using any [Flasher]
+///
+/// See [crate::flash::flash_image] for details on how the image gets split
+pub async fn flash_with<R>(
+    flasher: &mut dyn Flasher,
+    target: &str,
+    mut source: R,
+) -> Result<(), FlashWithError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let max_download = flasher.get_var("max-download-size").await?;
+    let max_download =
+        parse_u32(&max_download).map_err(|_| FlashWithError::MaxDownloadSize(max_download))?;
+    let plan = plan_image_for(max_download, None, &mut source).await?;
+    let parts = plan.downloads();
+    let payloads = plan_payloads(plan, source);
+    futures::pin_mut!(payloads);
+    let mut part = 0;
+    while let Some(payload) = payloads.try_next().await? {
+        info!("Flashing part {part}/{parts} to {target}");
+        flasher.download(&payload).await?;
+        flasher.flash(target).await?;
+        part += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // Flasher recording the commands it receives
+    #[derive(Default)]
+    struct MockFlasher {
+        commands: Vec<String>,
+    }
+
+    impl Flasher for MockFlasher {
+        fn get_var<'a>(&'a mut self, var: &'a str) -> BoxFuture<'a, Result<String, FlasherError>> {
+            self.commands.push(format!("getvar:{var}"));
+            let r = match var {
+                "max-download-size" => Ok("0x2000".to_string()),
+                _ => Err(FlasherError::Failed("unknown variable".to_string())),
+            };
+            async move { r }.boxed()
+        }
+
+        fn download<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), FlasherError>> {
+            self.commands.push(format!("download:{}", data.len()));
+            async { Ok(()) }.boxed()
+        }
+
+        fn flash<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+            self.commands.push(format!("flash:{target}"));
+            async { Ok(()) }.boxed()
+        }
+
+        fn erase<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+            self.commands.push(format!("erase:{target}"));
+            async { Ok(()) }.boxed()
+        }
+
+        fn reboot(&mut self) -> BoxFuture<'_, Result<(), FlasherError>> {
+            self.commands.push("reboot".to_string());
+            async { Ok(()) }.boxed()
+        }
+
+        fn reboot_to<'a>(&'a mut self, mode: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+            self.commands.push(format!("reboot-{mode}"));
+            async { Ok(()) }.boxed()
+        }
+
+        fn set_progress(
+            &mut self,
+            _progress: Option<ProgressCallback>,
+        ) -> Option<ProgressCallback> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn flash_mock() {
+        let mut flasher = MockFlasher::default();
+        flash_with(&mut flasher, "boot", Cursor::new(vec![0xaa; 0x1000]))
+            .await
+            .unwrap();
+        assert_eq!(
+            flasher.commands,
+            ["getvar:max-download-size", "download:4096", "flash:boot"]
+        );
+
+        let mut flasher = MockFlasher::default();
+        flash_with(&mut flasher, "system", Cursor::new(vec![0xaa; 0x3000]))
+            .await
+            .unwrap();
+        let flashes = flasher
+            .commands
+            .iter()
+            .filter(|c| *c == "flash:system")
+            .count();
+        assert!(flashes > 1);
+        assert_eq!(flasher.commands.len(), 1 + 2 * flashes);
+    }
+}
@@ -0,0 +1,40 @@
+use futures::TryStreamExt;
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+use tracing::info;
+
+use crate::{
+    flash::{flash_stream, plan_stream, FlashError, ImagePlan},
+    nusb::NusbFastBoot,
+};
+
+/// Request the given URL, failing on HTTP error statuses
+pub async fn get(url: &str) -> Result<reqwest::Response, FlashError> {
+    info!("Requesting {url}");
+    Ok(reqwest::get(url).await?.error_for_status()?)
+}
+
+// Body of a response as a reader, along with its size if known
+pub(crate) fn body(response: reqwest::Response) -> (impl AsyncRead + Unpin, Option<u64>) {
+    let size = response.content_length();
+    let stream = response.bytes_stream().map_err(std::io::Error::other);
+    (StreamReader::new(stream), size)
+}
+
+/// Flash an image from an HTTP(S) URL to the given target
+///
+/// The response body is streamed through the sparse or raw image splitting without storing the
+/// image on the host; See [flash_stream] for details.
+pub async fn flash_url(fb: &mut NusbFastBoot, target: &str, url: &str) -> Result<(), FlashError> {
+    let (reader, size) = body(get(url).await?);
+    flash_stream(fb, target, reader, size, url).await
+}
+
+/// Determine how an image from an HTTP(S) URL would be flashed
+///
+/// For sparse images the whole image gets downloaded (but not stored) to determine the chunk
+/// layout; See [crate::flash::plan_image] for details.
+pub async fn plan_url(fb: &mut NusbFastBoot, url: &str) -> Result<ImagePlan, FlashError> {
+    let (reader, size) = body(get(url).await?);
+    plan_stream(fb, reader, size, url).await
+}
@@ -1,6 +1,51 @@
 #![doc = include_str!("../README.md")]
 
+/// Android boot image creation and booting
+pub mod bootimg;
+/// Checksum verification of image sources
+pub mod checksum;
+/// Decompression of compressed images
+pub mod compress;
+/// Flashing of dynamic partitions
+pub mod dynamic;
+/// High-level helpers for flashing images
+pub mod flash;
+/// Flashing of factory image directories
+pub mod flashall;
+/// Transport independent device interface
+pub mod flasher;
+/// Flashing of images from HTTP(S) sources
+#[cfg(feature = "http")]
+pub mod http;
+/// Switching between bootloader fastboot and fastbootd
+pub mod mode;
+/// Running sessions on multiple devices concurrently
+pub mod multi;
 /// Nusb based fastboot client implementation
 pub mod nusb;
+/// Partition layout readback
+pub mod partitions;
+/// Declarative flash plans
+#[cfg(feature = "serde")]
+pub mod plan;
+/// Progress reporting
+pub mod progress;
 /// Lowlevel protocol types and helpers
 pub mod protocol;
+/// Per device workarounds
+pub mod quirks;
+/// Session event recording and replay
+pub mod recorder;
+/// Sequences of flashing operations
+pub mod session;
+/// A/B slot handling
+pub mod slot;
+/// TCP fastboot device discovery and client
+pub mod tcp;
+/// Flashing of update packages
+#[cfg(feature = "zip")]
+pub mod update;
+/// Read-back verification of flashed partitions
+pub mod verify;
+/// Wiping, formatting and erasing of device data
+pub mod wipe;
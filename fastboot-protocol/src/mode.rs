@@ -0,0 +1,108 @@
+use std::{fmt::Display, time::Duration};
+
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    flashall::is_userspace,
+    nusb::{wait_for_device, NusbFastBoot, NusbFastBootError, NusbFastBootOpenError},
+};
+
+/// Default time to wait for the device to come back after rebooting into another mode
+pub const DEFAULT_MODE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Errors while switching fastboot modes
+#[derive(Debug, Error)]
+pub enum ModeError {
+    #[error("Device serial number unknown, can't reconnect after reboot")]
+    NoSerial,
+    #[error("Device didn't come back after rebooting")]
+    Timeout,
+    #[error("Device is in {actual} mode after rebooting, expected {expected}")]
+    WrongMode {
+        expected: FastbootMode,
+        actual: FastbootMode,
+    },
+    #[error("Failed to list devices: {0}")]
+    List(#[from] nusb::Error),
+    #[error("Failed to reopen device: {0}")]
+    Open(#[from] NusbFastBootOpenError),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+}
+
+/// Mode a fastboot device is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastbootMode {
+    /// Fastboot implemented by the bootloader
+    Bootloader,
+    /// Userspace fastboot implemented by the recovery image (fastbootd)
+    Fastbootd,
+}
+
+impl FastbootMode {
+    // Mode argument for the reboot command to enter this mode
+    fn reboot_target(self) -> &'static str {
+        match self {
+            FastbootMode::Bootloader => "bootloader",
+            FastbootMode::Fastbootd => "fastboot",
+        }
+    }
+}
+
+impl Display for FastbootMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FastbootMode::Bootloader => write!(f, "bootloader"),
+            FastbootMode::Fastbootd => write!(f, "fastbootd"),
+        }
+    }
+}
+
+/// Mode the device is currently in
+pub async fn current_mode(fb: &mut NusbFastBoot) -> FastbootMode {
+    if is_userspace(fb).await {
+        FastbootMode::Fastbootd
+    } else {
+        FastbootMode::Bootloader
+    }
+}
+
+/// Make sure the device is in the given mode, rebooting it and reconnecting to it if needed
+///
+/// If the device already is in the requested mode the client is returned as-is. Otherwise the
+/// device is rebooted into the mode and the client is reopened once it re-enumerates, waiting up
+/// to `timeout`. Reconnecting relies on the USB serial number of the device, so the client must
+/// have been created from device info (e.g. [NusbFastBoot::from_info]).
+pub async fn ensure_mode(
+    mut fb: NusbFastBoot,
+    mode: FastbootMode,
+    timeout: Duration,
+) -> Result<NusbFastBoot, ModeError> {
+    if current_mode(&mut fb).await == mode {
+        return Ok(fb);
+    }
+    let serial = fb
+        .usb_details()
+        .and_then(|usb| usb.serial_number.clone())
+        .ok_or(ModeError::NoSerial)?;
+
+    info!("Rebooting {serial} into {mode}");
+    fb.reboot_to(mode.reboot_target()).await?;
+    drop(fb);
+    // Give the device time to drop off the bus before looking for it again
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let info = wait_for_device(&serial, timeout)
+        .await?
+        .ok_or(ModeError::Timeout)?;
+    let mut fb = NusbFastBoot::from_info(&info).await?;
+    let actual = current_mode(&mut fb).await;
+    if actual != mode {
+        return Err(ModeError::WrongMode {
+            expected: mode,
+            actual,
+        });
+    }
+    Ok(fb)
+}
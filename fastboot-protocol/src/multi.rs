@@ -0,0 +1,116 @@
+use futures::future::join_all;
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    nusb::{find_device, NusbFastBoot, NusbFastBootOpenError},
+    session::{FlashSession, SessionError},
+};
+
+/// Errors while running a session on one of multiple devices
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("Failed to list devices: {0}")]
+    List(#[from] nusb::Error),
+    #[error("Device not found")]
+    NotFound,
+    #[error("Failed to open device: {0}")]
+    Open(#[from] NusbFastBootOpenError),
+    #[error(transparent)]
+    Session(#[from] SessionError),
+}
+
+/// Result of running a session on a single device
+#[derive(Debug)]
+pub struct DeviceResult {
+    /// Serial number of the device
+    pub serial: String,
+    /// Outcome for the device
+    pub result: Result<(), DeviceError>,
+}
+
+/// Aggregated results of running a session on multiple devices
+#[derive(Debug, Default)]
+pub struct MultiDeviceReport {
+    /// Results per device, in the order the devices were given
+    pub results: Vec<DeviceResult>,
+}
+
+impl MultiDeviceReport {
+    /// Whether the session succeeded on all devices
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|r| r.result.is_ok())
+    }
+
+    /// Serial numbers of the devices the session succeeded on
+    pub fn succeeded(&self) -> impl Iterator<Item = &str> {
+        self.results
+            .iter()
+            .filter(|r| r.result.is_ok())
+            .map(|r| r.serial.as_str())
+    }
+
+    /// Devices the session failed on, with their error
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &DeviceError)> {
+        self.results
+            .iter()
+            .filter_map(|r| Some((r.serial.as_str(), r.result.as_ref().err()?)))
+    }
+}
+
+async fn run_device(serial: &str, session: FlashSession) -> Result<(), DeviceError> {
+    let info = find_device(serial).await?.ok_or(DeviceError::NotFound)?;
+    let mut fb = NusbFastBoot::from_info(&info).await?;
+    info!("Running session on {serial}");
+    session.run(&mut fb).await?;
+    Ok(())
+}
+
+/// Run a session on multiple devices, identified by their serial number, concurrently
+///
+/// `session` is called once per device to build the session for it; This allows e.g. setting a
+/// per device progress callback with [FlashSession::progress]. A failure on one device doesn't
+/// affect the other devices; The report contains the outcome for each of them.
+pub async fn run_on_devices<I, S, F>(serials: I, session: F) -> MultiDeviceReport
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+    F: Fn(&str) -> FlashSession,
+{
+    let runs = serials.into_iter().map(|serial| {
+        let serial = serial.into();
+        let session = session(&serial);
+        async move {
+            let result = run_device(&serial, session).await;
+            DeviceResult { serial, result }
+        }
+    });
+    MultiDeviceReport {
+        results: join_all(runs).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let report = MultiDeviceReport {
+            results: vec![
+                DeviceResult {
+                    serial: "1234".to_string(),
+                    result: Ok(()),
+                },
+                DeviceResult {
+                    serial: "5678".to_string(),
+                    result: Err(DeviceError::NotFound),
+                },
+            ],
+        };
+        assert!(!report.is_ok());
+        assert_eq!(report.succeeded().collect::<Vec<_>>(), vec!["1234"]);
+        let failed: Vec<_> = report.failed().map(|(s, _)| s).collect();
+        assert_eq!(failed, vec!["5678"]);
+    }
+}
@@ -1,16 +1,25 @@
 use nusb::descriptors::TransferType;
-use nusb::transfer::Bulk;
 use nusb::transfer::Direction;
-use nusb::transfer::{Buffer, In, Out};
-use nusb::Endpoint;
-pub use nusb::{transfer::TransferError, Device, DeviceInfo, Interface};
-use std::{collections::HashMap, fmt::Display, io::Write};
+pub use nusb::{
+    transfer::{Buffer, Bulk, In, Out, TransferError},
+    Device, DeviceInfo, Endpoint, Interface,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    future::Future,
+    io::Write,
+    time::Duration,
+};
 use thiserror::Error;
 use tracing::{info, warn};
 use tracing::{instrument, trace};
 
+use crate::progress::{ProgressCallback, ProgressEvent};
 use crate::protocol::FastBootResponse;
 use crate::protocol::{FastBootCommand, FastBootResponseParseError};
+use crate::quirks::{Quirks, QuirksTable};
+use crate::recorder::{SessionEvent, SessionRecorder};
 
 /// List fastboot devices
 pub async fn devices() -> Result<impl Iterator<Item = DeviceInfo>, nusb::Error> {
@@ -19,17 +28,57 @@ pub async fn devices() -> Result<impl Iterator<Item = DeviceInfo>, nusb::Error>
         .filter(|d| NusbFastBoot::find_fastboot_interface(d).is_some()))
 }
 
+/// Find the fastboot device with the given serial number
+pub async fn find_device(serial: &str) -> Result<Option<DeviceInfo>, nusb::Error> {
+    Ok(devices().await?.find(|d| d.serial_number() == Some(serial)))
+}
+
+/// Wait up to `timeout` for a fastboot device with the given serial number to appear, e.g. after
+/// a reboot
+pub async fn wait_for_device(
+    serial: &str,
+    timeout: Duration,
+) -> Result<Option<DeviceInfo>, nusb::Error> {
+    let start = tokio::time::Instant::now();
+    loop {
+        if let Some(info) = find_device(serial).await? {
+            return Ok(Some(info));
+        }
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
 /// Fastboot communication errors
 #[derive(Debug, Error)]
 pub enum NusbFastBootError {
     #[error("Transfer error: {0}")]
-    Transfer(#[from] TransferError),
+    Transfer(TransferError),
+    #[error("Device disconnected")]
+    DeviceGone,
     #[error("Fastboot client failure: {0}")]
     FastbootFailed(String),
     #[error("Unexpected fastboot response")]
     FastbootUnexpectedReply,
     #[error("Unknown fastboot response: {0}")]
     FastbootParseError(#[from] FastBootResponseParseError),
+    #[error("Timeout waiting for fastboot response")]
+    Timeout,
+    #[error("Command too long for the device: {0}")]
+    CommandTooLong(String),
+    #[error("Not supported by the device: {0}")]
+    Unsupported(&'static str),
+}
+
+impl From<TransferError> for NusbFastBootError {
+    fn from(e: TransferError) -> Self {
+        match e {
+            TransferError::Disconnected => NusbFastBootError::DeviceGone,
+            e => NusbFastBootError::Transfer(e),
+        }
+    }
 }
 
 /// Errors when opening the fastboot device
@@ -47,30 +96,142 @@ pub enum NusbFastBootOpenError {
     FastbootParseError(#[from] FastBootResponseParseError),
 }
 
+/// Retry policy for claiming the fastboot interface
+///
+/// Right after enumeration other software on the host (e.g. udev rules or MTP probing) may still
+/// hold the interface, in which case claiming it fails with a busy error. Claiming is retried up
+/// to `retries` times, waiting `delay` in between attempts. Other errors are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClaimRetry {
+    /// Number of retries after the initial attempt
+    pub retries: u32,
+    /// Delay between attempts
+    pub delay: Duration,
+}
+
+impl ClaimRetry {
+    /// Never retry claiming the interface
+    pub const NONE: ClaimRetry = ClaimRetry {
+        retries: 0,
+        delay: Duration::ZERO,
+    };
+}
+
+impl Default for ClaimRetry {
+    fn default() -> Self {
+        Self {
+            retries: 10,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Lock state changes which can be requested using the `flashing` commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashingLockState {
+    /// Lock the device
+    Lock,
+    /// Unlock the device
+    Unlock,
+    /// Lock critical partitions
+    LockCritical,
+    /// Unlock critical partitions
+    UnlockCritical,
+}
+
+impl FlashingLockState {
+    fn command(self) -> FastBootCommand<&'static str> {
+        match self {
+            FlashingLockState::Lock => FastBootCommand::FlashingLock,
+            FlashingLockState::Unlock => FastBootCommand::FlashingUnlock,
+            FlashingLockState::LockCritical => FastBootCommand::FlashingLockCritical,
+            FlashingLockState::UnlockCritical => FastBootCommand::FlashingUnlockCritical,
+        }
+    }
+}
+
+/// USB level details of a fastboot device
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UsbDetails {
+    /// USB vendor id
+    pub vendor_id: u16,
+    /// USB product id
+    pub product_id: u16,
+    /// Identifier of the bus the device is connected to
+    pub bus_id: String,
+    /// Address of the device on its bus
+    pub device_address: u8,
+    /// Manufacturer string descriptor
+    pub manufacturer: Option<String>,
+    /// Product string descriptor
+    pub product: Option<String>,
+    /// Serial number string descriptor
+    pub serial_number: Option<String>,
+}
+
+impl From<&DeviceInfo> for UsbDetails {
+    fn from(info: &DeviceInfo) -> Self {
+        Self {
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            bus_id: info.bus_id().to_string(),
+            device_address: info.device_address(),
+            manufacturer: info.manufacturer_string().map(str::to_string),
+            product: info.product_string().map(str::to_string),
+            serial_number: info.serial_number().map(str::to_string),
+        }
+    }
+}
+
+/// Summary of a fastboot device as returned by [NusbFastBoot::describe]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceSummary {
+    /// USB details; Only known if the client was created from a [DeviceInfo]
+    pub usb: Option<UsbDetails>,
+    /// Fastboot variables of the device
+    pub vars: HashMap<String, String>,
+}
+
+// Variables queried by describe when the device doesn't support getvar all
+const SUMMARY_VARS: &[&str] = &[
+    "product",
+    "serialno",
+    "version",
+    "version-bootloader",
+    "version-baseband",
+    "secure",
+    "unlocked",
+    "current-slot",
+    "max-download-size",
+];
+
 /// Nusb fastboot client
 pub struct NusbFastBoot {
     ep_out: Endpoint<Bulk, Out>,
     max_out: usize,
     ep_in: Endpoint<Bulk, In>,
     max_in: usize,
+    quirks: Quirks,
+    recorder: Option<SessionRecorder>,
+    vars: HashMap<String, String>,
+    usb: Option<UsbDetails>,
+    response_timeout: Option<Duration>,
+    progress: Option<ProgressCallback>,
+    interface: Option<Interface>,
 }
 
-impl NusbFastBoot {
-    /// Find fastboot interface within a USB device
-    pub fn find_fastboot_interface(info: &DeviceInfo) -> Option<u8> {
-        info.interfaces().find_map(|i| {
-            if i.class() == 0xff && i.subclass() == 0x42 && i.protocol() == 0x3 {
-                Some(i.interface_number())
-            } else {
-                None
-            }
-        })
-    }
+// Endpoints used for fastboot communication
+struct Endpoints {
+    ep_out: Endpoint<Bulk, Out>,
+    max_out: usize,
+    ep_in: Endpoint<Bulk, In>,
+    max_in: usize,
+}
 
-    /// Create a fastboot client based on a USB interface. Interface is assumed to be a fastboot
-    /// interface
-    #[tracing::instrument(skip_all, err)]
-    pub fn from_interface(interface: Interface) -> Result<Self, NusbFastBootOpenError> {
+impl Endpoints {
+    fn open(interface: &Interface) -> Result<Self, NusbFastBootOpenError> {
         let (ep_out, max_out, ep_in, max_in) = interface
             .descriptors()
             .find_map(|alt| {
@@ -116,26 +277,213 @@ impl NusbFastBoot {
             max_in,
         })
     }
+}
+
+impl NusbFastBoot {
+    /// Find fastboot interface within a USB device
+    pub fn find_fastboot_interface(info: &DeviceInfo) -> Option<u8> {
+        info.interfaces().find_map(|i| {
+            if i.class() == 0xff && i.subclass() == 0x42 && i.protocol() == 0x3 {
+                Some(i.interface_number())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Create a fastboot client based on a USB interface. Interface is assumed to be a fastboot
+    /// interface
+    #[tracing::instrument(skip_all, err)]
+    pub fn from_interface(interface: Interface) -> Result<Self, NusbFastBootOpenError> {
+        let Endpoints {
+            ep_out,
+            max_out,
+            ep_in,
+            max_in,
+        } = Endpoints::open(&interface)?;
+        let mut fb = Self::from_endpoints(ep_out, max_out, ep_in, max_in);
+        fb.interface = Some(interface);
+        Ok(fb)
+    }
+
+    /// Create a fastboot client based on already opened bulk endpoints of a fastboot interface
+    ///
+    /// `max_out` and `max_in` should be the max packet sizes of respectively the OUT and IN
+    /// endpoint
+    pub fn from_endpoints(
+        ep_out: Endpoint<Bulk, Out>,
+        max_out: usize,
+        ep_in: Endpoint<Bulk, In>,
+        max_in: usize,
+    ) -> Self {
+        Self {
+            ep_out,
+            max_out,
+            ep_in,
+            max_in,
+            quirks: Quirks::default(),
+            recorder: None,
+            vars: HashMap::new(),
+            usb: None,
+            response_timeout: None,
+            progress: None,
+            interface: None,
+        }
+    }
 
     /// Create a fastboot client based on a USB device. Interface number must be the fastboot
     /// interface
+    ///
+    /// Claiming the interface is retried using the default [ClaimRetry] policy
     #[tracing::instrument(skip_all, err)]
     pub async fn from_device(device: Device, interface: u8) -> Result<Self, NusbFastBootOpenError> {
-        let interface = device
-            .claim_interface(interface)
-            .await
-            .map_err(NusbFastBootOpenError::Interface)?;
+        Self::from_device_with_retry(device, interface, ClaimRetry::default()).await
+    }
+
+    /// Create a fastboot client based on a USB device, using the given policy for retrying
+    /// claiming the interface while it is busy. Interface number must be the fastboot interface
+    #[tracing::instrument(skip_all, err)]
+    pub async fn from_device_with_retry(
+        device: Device,
+        interface: u8,
+        retry: ClaimRetry,
+    ) -> Result<Self, NusbFastBootOpenError> {
+        let mut attempt = 0;
+        let interface = loop {
+            match device.claim_interface(interface).await {
+                Ok(interface) => break interface,
+                Err(e) if e.kind() == nusb::ErrorKind::Busy && attempt < retry.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Interface busy, retrying claim ({attempt}/{}): {e}",
+                        retry.retries
+                    );
+                    tokio::time::sleep(retry.delay).await;
+                }
+                Err(e) => return Err(NusbFastBootOpenError::Interface(e)),
+            }
+        };
         Self::from_interface(interface)
     }
 
     /// Create a fastboot client based on device info. The correct interface will automatically be
     /// determined
+    ///
+    /// Claiming the interface is retried using the default [ClaimRetry] policy
     #[tracing::instrument(skip_all, err)]
     pub async fn from_info(info: &DeviceInfo) -> Result<Self, NusbFastBootOpenError> {
+        Self::from_info_with_retry(info, ClaimRetry::default()).await
+    }
+
+    /// Create a fastboot client based on device info, using the given policy for retrying
+    /// claiming the interface while it is busy
+    #[tracing::instrument(skip_all, err)]
+    pub async fn from_info_with_retry(
+        info: &DeviceInfo,
+        retry: ClaimRetry,
+    ) -> Result<Self, NusbFastBootOpenError> {
+        Self::from_info_with_quirks(info, retry, &QuirksTable::default()).await
+    }
+
+    /// Create a fastboot client based on device info, applying the quirks for the device from
+    /// the given table
+    #[tracing::instrument(skip_all, err)]
+    pub async fn from_info_with_quirks(
+        info: &DeviceInfo,
+        retry: ClaimRetry,
+        quirks: &QuirksTable,
+    ) -> Result<Self, NusbFastBootOpenError> {
         let interface =
             Self::find_fastboot_interface(info).ok_or(NusbFastBootOpenError::MissingInterface)?;
         let device = info.open().await.map_err(NusbFastBootOpenError::Device)?;
-        Self::from_device(device, interface).await
+        let mut fb = Self::from_device_with_retry(device, interface, retry).await?;
+        fb.quirks = quirks.lookup(info.vendor_id(), info.product_id());
+        trace!("Device quirks: {:?}", fb.quirks);
+        fb.usb = Some(UsbDetails::from(info));
+        Ok(fb)
+    }
+
+    /// USB details of the device; Only known if the client was created from a [DeviceInfo]
+    pub fn usb_details(&self) -> Option<&UsbDetails> {
+        self.usb.as_ref()
+    }
+
+    /// Describe the device, combining its USB details with a snapshot of all its variables
+    ///
+    /// If the device doesn't support `getvar all` a selection of common variables is queried
+    /// instead
+    pub async fn describe(&mut self) -> Result<DeviceSummary, NusbFastBootError> {
+        let vars = match self.get_all_vars().await {
+            Ok(vars) => vars,
+            Err(NusbFastBootError::Unsupported(_)) | Err(NusbFastBootError::FastbootFailed(_)) => {
+                let mut vars = HashMap::new();
+                for var in SUMMARY_VARS {
+                    match self.get_var(var).await {
+                        Ok(value) => {
+                            vars.insert(var.to_string(), value);
+                        }
+                        Err(NusbFastBootError::FastbootFailed(e)) => {
+                            trace!("Variable {var} not available: {e}")
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                vars
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(DeviceSummary {
+            usb: self.usb.clone(),
+            vars,
+        })
+    }
+
+    /// Quirks applied to the communication with the device
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Override the quirks applied to the communication with the device
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Resume the session on a new interface
+    ///
+    /// When the device was reset or re-enumerated (indicated by [NusbFastBootError::DeviceGone])
+    /// the endpoints of the client are no longer usable. This rebuilds the endpoint state from the
+    /// newly claimed fastboot interface, while keeping the session state such as quirks, the
+    /// recorder and cached variables.
+    #[tracing::instrument(skip_all, err)]
+    pub fn resume_with(&mut self, interface: Interface) -> Result<(), NusbFastBootOpenError> {
+        let Endpoints {
+            ep_out,
+            max_out,
+            ep_in,
+            max_in,
+        } = Endpoints::open(&interface)?;
+        self.ep_out = ep_out;
+        self.max_out = max_out;
+        self.ep_in = ep_in;
+        self.max_in = max_in;
+        self.interface = Some(interface);
+        Ok(())
+    }
+
+    /// Close the client, handing back the claimed interface
+    ///
+    /// Pending data transfers to the device are completed and outstanding reads are cancelled
+    /// first, such that the interface can be used for another protocol (e.g. ADB after
+    /// [Self::continue_boot]) without reopening the device. The interface is only known if the
+    /// client wasn't created using [Self::from_endpoints].
+    #[tracing::instrument(skip_all, err)]
+    pub async fn close(mut self) -> Result<Option<Interface>, NusbFastBootError> {
+        while self.ep_out.pending() > 0 {
+            let completion = self.ep_out.next_complete().await;
+            completion.status.map_err(NusbFastBootError::from)?;
+        }
+        self.cancel_responses().await;
+        Ok(self.interface.take())
     }
 
     #[tracing::instrument(skip_all, err)]
@@ -156,19 +504,105 @@ impl NusbFastBoot {
             "Sending command: {}",
             std::str::from_utf8(&out).unwrap_or("Invalid utf-8")
         );
+        if self
+            .quirks
+            .max_command_len
+            .is_some_and(|max| out.len() > max)
+        {
+            return Err(NusbFastBootError::CommandTooLong(
+                String::from_utf8_lossy(&out).into_owned(),
+            ));
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(SessionEvent::Command {
+                command: String::from_utf8_lossy(&out).into_owned(),
+            });
+        }
         self.send_data(out).await
     }
 
     #[tracing::instrument(skip_all, err)]
     async fn read_response(&mut self) -> Result<FastBootResponse, NusbFastBootError> {
         self.ep_in.submit(Buffer::new(self.max_in));
-        let resp = self
-            .ep_in
-            .next_complete()
-            .await
-            .into_result()
-            .map_err(NusbFastBootError::Transfer)?;
-        Ok(FastBootResponse::from_bytes(&resp)?)
+        let completion = match self.response_timeout {
+            Some(timeout) => {
+                match tokio::time::timeout(timeout, self.ep_in.next_complete()).await {
+                    Ok(completion) => completion,
+                    Err(_) => {
+                        self.cancel_responses().await;
+                        return Err(NusbFastBootError::Timeout);
+                    }
+                }
+            }
+            None => self.ep_in.next_complete().await,
+        };
+        let resp = completion.into_result().map_err(NusbFastBootError::from)?;
+        let resp = FastBootResponse::from_bytes(&resp)?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(SessionEvent::Response {
+                response: resp.clone(),
+            });
+        }
+        Ok(resp)
+    }
+
+    pub(crate) fn progress(&mut self, event: ProgressEvent) {
+        if let Some(progress) = &mut self.progress {
+            progress(&event);
+        }
+    }
+
+    /// Set the callback receiving progress events, replacing any previous callback
+    pub fn set_progress<F>(&mut self, callback: F)
+    where
+        F: FnMut(&ProgressEvent) + Send + 'static,
+    {
+        self.progress = Some(Box::new(callback));
+    }
+
+    /// Remove the progress callback
+    pub fn clear_progress(&mut self) {
+        self.progress = None;
+    }
+
+    // Replace the progress callback, returning the previous one
+    pub(crate) fn replace_progress(
+        &mut self,
+        progress: Option<ProgressCallback>,
+    ) -> Option<ProgressCallback> {
+        std::mem::replace(&mut self.progress, progress)
+    }
+
+    /// Set the maximum time to wait for each response from the device; `None` (the default) waits
+    /// indefinitely
+    ///
+    /// As INFO messages are responses as well, long running operations (e.g. erasing a big
+    /// partition) don't time out as long as the device keeps sending them. Every INFO message is
+    /// reported as a [ProgressEvent::Heartbeat].
+    pub fn set_response_timeout(&mut self, timeout: Option<Duration>) {
+        self.response_timeout = timeout;
+    }
+
+    fn record(&mut self, event: SessionEvent) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(event);
+        }
+    }
+
+    /// Set a recorder to capture all further interactions with the device, replacing any previous
+    /// recorder. Passing `None` stops recording
+    pub fn set_recorder(&mut self, recorder: Option<SessionRecorder>) {
+        self.recorder = recorder;
+    }
+
+    /// The current session recorder, if any
+    pub fn recorder(&self) -> Option<&SessionRecorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Take the current session recorder out of the client, stopping the recording
+    pub fn take_recorder(&mut self) -> Option<SessionRecorder> {
+        self.recorder.take()
     }
 
     #[tracing::instrument(skip_all, err)]
@@ -177,7 +611,9 @@ impl NusbFastBoot {
             let resp = self.read_response().await?;
             trace!("Response: {:?}", resp);
             match resp {
-                FastBootResponse::Info(_) => (),
+                FastBootResponse::Info(message) => {
+                    self.progress(ProgressEvent::Heartbeat { message })
+                }
                 FastBootResponse::Text(_) => (),
                 FastBootResponse::Data(_) => {
                     return Err(NusbFastBootError::FastbootUnexpectedReply)
@@ -202,7 +638,10 @@ impl NusbFastBoot {
     fn allocate(&self) -> Buffer {
         // Allocate about 1Mb of buffer ensuring it's always a multiple of the maximum out packet
         // size
-        let size = (1024usize * 1024).next_multiple_of(self.max_out);
+        let size = match self.quirks.max_transfer_size {
+            Some(max) => (max.min(1024 * 1024) / self.max_out).max(1) * self.max_out,
+            None => (1024usize * 1024).next_multiple_of(self.max_out),
+        };
         self.ep_out.allocate(size)
     }
 
@@ -214,20 +653,70 @@ impl NusbFastBoot {
         self.execute(cmd).await
     }
 
+    /// Get the named variable, using a cached value if the variable was retrieved before
+    ///
+    /// This should only be used for variables which don't change during a session (e.g.
+    /// "max-download-size" or "product"). Cached values are kept when resuming the session using
+    /// [Self::resume_with]
+    pub async fn get_var_cached(&mut self, var: &str) -> Result<String, NusbFastBootError> {
+        if let Some(value) = self.vars.get(var) {
+            return Ok(value.clone());
+        }
+        let value = self.get_var(var).await?;
+        self.vars.insert(var.to_string(), value.clone());
+        Ok(value)
+    }
+
+    /// Clear all cached variables
+    pub fn clear_var_cache(&mut self) {
+        self.vars.clear();
+    }
+
     /// Prepare a download of a given size
     ///
     /// When successful the [DataDownload] helper should be used to actually send the data
     pub async fn download(&'_ mut self, size: u32) -> Result<DataDownload<'_>, NusbFastBootError> {
         let cmd = FastBootCommand::<&str>::Download(size);
         self.send_command(cmd).await?;
+        let size = self.handle_data_response().await?;
+        Ok(DataDownload::new(self, size))
+    }
+
+    /// Prepare an upload of data previously staged on the device (e.g. by an oem command)
+    ///
+    /// When successful the [DataUpload] helper should be used to actually receive the data
+    pub async fn upload(&'_ mut self) -> Result<DataUpload<'_>, NusbFastBootError> {
+        let cmd = FastBootCommand::<&str>::Upload;
+        self.send_command(cmd).await?;
+        let size = self.handle_data_response().await?;
+        Ok(DataUpload::new(self, size))
+    }
+
+    /// Prepare fetching `size` bytes starting at `offset` from the given partition
+    ///
+    /// When successful the [DataUpload] helper should be used to actually receive the data. Note
+    /// that the device may limit the amount of data returned by a single fetch, [DataUpload::size]
+    /// indicates how much data will actually be sent.
+    pub async fn fetch(
+        &'_ mut self,
+        partition: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<DataUpload<'_>, NusbFastBootError> {
+        let cmd = FastBootCommand::Fetch(partition, offset, size);
+        self.send_command(cmd).await?;
+        let size = self.handle_data_response().await?;
+        Ok(DataUpload::new(self, size))
+    }
+
+    /// Wait for the DATA response announcing the size of a data phase
+    async fn handle_data_response(&mut self) -> Result<u32, NusbFastBootError> {
         loop {
             let resp = self.read_response().await?;
             match resp {
                 FastBootResponse::Info(i) => info!("info: {i}"),
                 FastBootResponse::Text(t) => info!("Text: {}", t),
-                FastBootResponse::Data(size) => {
-                    return Ok(DataDownload::new(self, size));
-                }
+                FastBootResponse::Data(size) => return Ok(size),
                 FastBootResponse::Okay(_) => {
                     return Err(NusbFastBootError::FastbootUnexpectedReply)
                 }
@@ -246,6 +735,14 @@ impl NusbFastBoot {
         })
     }
 
+    /// Boot downloaded data (e.g. an android boot image)
+    pub async fn boot(&mut self) -> Result<(), NusbFastBootError> {
+        let cmd = FastBootCommand::<&str>::Boot;
+        self.execute(cmd).await.map(|v| {
+            trace!("Boot ok: {v}");
+        })
+    }
+
     /// Continue booting
     pub async fn continue_boot(&mut self) -> Result<(), NusbFastBootError> {
         let cmd = FastBootCommand::<&str>::Continue;
@@ -262,6 +759,79 @@ impl NusbFastBoot {
         })
     }
 
+    /// Mark the given slot (e.g. "a" or "b") as active
+    pub async fn set_active(&mut self, slot: &str) -> Result<(), NusbFastBootError> {
+        let cmd = FastBootCommand::SetActive(slot);
+        self.execute(cmd).await.map(|v| {
+            trace!("Set active ok: {v}");
+        })
+    }
+
+    /// Update the metadata of the given super partition from downloaded data (e.g.
+    /// super_empty.img); When `wipe` is set all existing logical partitions are removed
+    ///
+    /// Only supported by userspace fastboot (fastbootd)
+    pub async fn update_super(
+        &mut self,
+        target: &str,
+        wipe: bool,
+    ) -> Result<(), NusbFastBootError> {
+        let cmd = FastBootCommand::UpdateSuper(target, wipe);
+        self.execute(cmd).await.map(|v| {
+            trace!("Update super ok: {v}");
+        })
+    }
+
+    /// Create a logical partition of `size` bytes in the super partition
+    ///
+    /// Only supported by userspace fastboot (fastbootd)
+    pub async fn create_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<(), NusbFastBootError> {
+        let cmd = FastBootCommand::CreateLogicalPartition(partition, size);
+        self.execute(cmd).await.map(|v| {
+            trace!("Create logical partition ok: {v}");
+        })
+    }
+
+    /// Delete a logical partition from the super partition
+    ///
+    /// Only supported by userspace fastboot (fastbootd)
+    pub async fn delete_logical_partition(
+        &mut self,
+        partition: &str,
+    ) -> Result<(), NusbFastBootError> {
+        let cmd = FastBootCommand::DeleteLogicalPartition(partition);
+        self.execute(cmd).await.map(|v| {
+            trace!("Delete logical partition ok: {v}");
+        })
+    }
+
+    /// Resize a logical partition to `size` bytes
+    ///
+    /// Only supported by userspace fastboot (fastbootd)
+    pub async fn resize_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<(), NusbFastBootError> {
+        let cmd = FastBootCommand::ResizeLogicalPartition(partition, size);
+        self.execute(cmd).await.map(|v| {
+            trace!("Resize logical partition ok: {v}");
+        })
+    }
+
+    /// Run an OEM specific command (e.g. "device-info"), returning the value of the final OKAY
+    ///
+    /// Many OEM commands report their output as INFO messages, which are passed to the progress
+    /// callback as [ProgressEvent::Heartbeat]s
+    pub async fn oem(&mut self, command: &str) -> Result<String, NusbFastBootError> {
+        let cmd = FastBootCommand::Oem(command);
+        self.execute(cmd).await
+    }
+
     /// Reboot the device
     pub async fn reboot(&mut self) -> Result<(), NusbFastBootError> {
         let cmd = FastBootCommand::<&str>::Reboot;
@@ -278,8 +848,74 @@ impl NusbFastBoot {
         })
     }
 
+    /// Change the lock state of the device
+    ///
+    /// Many devices require a physical confirmation (e.g. using the volume keys) before changing
+    /// the lock state, in which case this waits for it indefinitely; Use
+    /// [Self::flashing_with_confirmation] to show the device prompts to the user and to bound the
+    /// time waited.
+    pub async fn flashing(&mut self, state: FlashingLockState) -> Result<(), NusbFastBootError> {
+        self.execute(state.command()).await.map(|v| {
+            trace!("Flashing {state:?} ok: {v}");
+        })
+    }
+
+    /// Change the lock state of the device, calling `on_prompt` for every INFO message the device
+    /// sends while waiting for the user to confirm
+    ///
+    /// If the device doesn't finish the request within `timeout` the request is abandoned and
+    /// [NusbFastBootError::Timeout] is returned
+    pub async fn flashing_with_confirmation<F, Fut>(
+        &mut self,
+        state: FlashingLockState,
+        timeout: Duration,
+        mut on_prompt: F,
+    ) -> Result<(), NusbFastBootError>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.send_command(state.command()).await?;
+        let confirmation = async {
+            loop {
+                let resp = self.read_response().await?;
+                trace!("Response: {:?}", resp);
+                match resp {
+                    FastBootResponse::Info(i) => on_prompt(i).await,
+                    FastBootResponse::Text(t) => info!("Text: {}", t),
+                    FastBootResponse::Data(_) => {
+                        return Err(NusbFastBootError::FastbootUnexpectedReply)
+                    }
+                    FastBootResponse::Okay(_) => return Ok(()),
+                    FastBootResponse::Fail(fail) => {
+                        return Err(NusbFastBootError::FastbootFailed(fail))
+                    }
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, confirmation).await {
+            Ok(r) => r,
+            Err(_) => {
+                warn!("Timeout waiting for {state:?} confirmation");
+                self.cancel_responses().await;
+                Err(NusbFastBootError::Timeout)
+            }
+        }
+    }
+
+    // Cancel and drain any outstanding reads of responses
+    async fn cancel_responses(&mut self) {
+        self.ep_in.cancel_all();
+        while self.ep_in.pending() > 0 {
+            let _ = self.ep_in.next_complete().await;
+        }
+    }
+
     /// Retrieve all variables
     pub async fn get_all_vars(&mut self) -> Result<HashMap<String, String>, NusbFastBootError> {
+        if self.quirks.no_getvar_all {
+            return Err(NusbFastBootError::Unsupported("getvar all"));
+        }
         let cmd = FastBootCommand::GetVar("all");
         self.send_command(cmd).await?;
         let mut vars = HashMap::new();
@@ -359,6 +995,10 @@ impl DataDownload<'_> {
         self.left
     }
 
+    pub(crate) fn progress(&mut self, event: ProgressEvent) {
+        self.fastboot.progress(event);
+    }
+
     /// Extend the streaming from a slice
     ///
     /// This will copy all provided data and send it out if enough is collected. The total amount
@@ -419,6 +1059,8 @@ impl DataDownload<'_> {
         };
 
         std::mem::swap(&mut next, &mut self.current);
+        self.fastboot
+            .record(SessionEvent::DataOut { size: next.len() });
         self.fastboot.ep_out.submit(next);
 
         Ok(())
@@ -437,9 +1079,18 @@ impl DataDownload<'_> {
         }
 
         if !self.current.is_empty() {
+            self.fastboot.record(SessionEvent::DataOut {
+                size: self.current.len(),
+            });
             self.fastboot.ep_out.submit(self.current);
         }
 
+        if self.fastboot.quirks.zero_length_packet
+            && self.size as usize % self.fastboot.max_out == 0
+        {
+            self.fastboot.ep_out.submit(Buffer::new(0));
+        }
+
         while self.fastboot.ep_out.pending() > 0 {
             let completion = self.fastboot.ep_out.next_complete().await;
             completion.status.map_err(NusbFastBootError::from)?;
@@ -449,3 +1100,116 @@ impl DataDownload<'_> {
         Ok(())
     }
 }
+
+/// Error during data upload
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("Incorrect data length: expected {expected}, got {actual}")]
+    IncorrectDataLength { actual: u32, expected: u32 },
+    #[error(transparent)]
+    Nusb(#[from] NusbFastBootError),
+}
+
+/// Data upload helper
+///
+/// Receiving data from the device one USB transfer at a time is bound by the transfer latency. To
+/// keep the bus busy this helper keeps multiple IN transfers queued, each requesting a multiple of
+/// the max endpoint size but never more than the total amount of data indicated in the DATA
+/// response, such that the final response can't get swallowed.
+///
+/// Data should be retrieved using [DataUpload::next_data] until it returns `None`, after which
+/// [DataUpload::finish] should be called to validate and finalize.
+pub struct DataUpload<'s> {
+    fastboot: &'s mut NusbFastBoot,
+    size: u32,
+    left: u32,
+    requested: u32,
+    in_flight: VecDeque<u32>,
+}
+
+impl<'s> DataUpload<'s> {
+    fn new(fastboot: &'s mut NusbFastBoot, size: u32) -> DataUpload<'s> {
+        Self {
+            fastboot,
+            size,
+            left: size,
+            requested: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+}
+
+impl DataUpload<'_> {
+    /// Total size of the data transfer
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Data left to be received
+    pub fn left(&self) -> u32 {
+        self.left
+    }
+
+    fn queue_transfers(&mut self) {
+        while self.fastboot.ep_in.pending() < 3 && self.requested < self.size {
+            // Request about 1Mb at a time; rounding up to the max packet size is fine as the device
+            // will finish the transfer with a short packet
+            let size = (1024 * 1024).min(self.size - self.requested);
+            let buffer = self
+                .fastboot
+                .ep_in
+                .allocate((size as usize).next_multiple_of(self.fastboot.max_in));
+            self.fastboot.ep_in.submit(buffer);
+            self.in_flight.push_back(size);
+            self.requested += size;
+        }
+    }
+
+    /// Receive the next block of data from the device
+    ///
+    /// Returns `None` once all data has been received
+    pub async fn next_data(&mut self) -> Result<Option<Buffer>, UploadError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.queue_transfers();
+
+        let data = self
+            .fastboot
+            .ep_in
+            .next_complete()
+            .await
+            .into_result()
+            .map_err(NusbFastBootError::from)?;
+        self.fastboot
+            .record(SessionEvent::DataIn { size: data.len() });
+        let received = data.len() as u32;
+        let expected = self.in_flight.pop_front().unwrap_or_default();
+        // A short transfer means the remainder still has to be requested
+        self.requested -= expected.saturating_sub(received);
+        if received > self.left {
+            return Err(UploadError::IncorrectDataLength {
+                expected: self.size,
+                actual: self.size - self.left + received,
+            });
+        }
+        self.left -= received;
+        Ok(Some(data))
+    }
+
+    /// Finish the upload
+    ///
+    /// This should only be called once all data has been received
+    #[instrument(skip_all, err)]
+    pub async fn finish(self) -> Result<(), UploadError> {
+        if self.left != 0 {
+            return Err(UploadError::IncorrectDataLength {
+                expected: self.size,
+                actual: self.size - self.left,
+            });
+        }
+
+        self.fastboot.handle_responses().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use thiserror::Error;
+
+use crate::{
+    flash::FlashError,
+    nusb::{NusbFastBoot, NusbFastBootError},
+    protocol::parse_u64,
+    verify::fetch_range,
+};
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: u32 = 128;
+
+/// A partition as reported by the device variables
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartitionInfo {
+    /// Partition name, including the slot suffix if any
+    pub name: String,
+    /// Size in bytes (`partition-size`)
+    pub size: Option<u64>,
+    /// Filesystem type (`partition-type`), e.g. "ext4" or "raw"
+    pub fs_type: Option<String>,
+    /// Whether the partition is a logical partition in super (`is-logical`)
+    pub logical: Option<bool>,
+}
+
+/// Partitions of a device, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartitionTable {
+    pub partitions: Vec<PartitionInfo>,
+}
+
+impl PartitionTable {
+    /// Build the table from device variables as returned by
+    /// [NusbFastBoot::get_all_vars]
+    pub fn from_vars<'a, I>(vars: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a String, &'a String)>,
+    {
+        let mut partitions: BTreeMap<&str, PartitionInfo> = BTreeMap::new();
+        for (key, value) in vars {
+            let Some((var, name)) = key.split_once(':') else {
+                continue;
+            };
+            let entry = || PartitionInfo {
+                name: name.to_string(),
+                ..Default::default()
+            };
+            match var {
+                "partition-size" => {
+                    partitions.entry(name).or_insert_with(entry).size = parse_u64(value).ok()
+                }
+                "partition-type" => {
+                    partitions.entry(name).or_insert_with(entry).fs_type = Some(value.clone())
+                }
+                "is-logical" => {
+                    partitions.entry(name).or_insert_with(entry).logical = Some(value == "yes")
+                }
+                _ => (),
+            }
+        }
+        Self {
+            partitions: partitions.into_values().collect(),
+        }
+    }
+
+    /// Look up a partition by name
+    pub fn get(&self, name: &str) -> Option<&PartitionInfo> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// Total size of all partitions with a known size, excluding logical partitions (which are
+    /// contained in super)
+    pub fn physical_size(&self) -> u64 {
+        self.partitions
+            .iter()
+            .filter(|p| p.logical != Some(true))
+            .filter_map(|p| p.size)
+            .sum()
+    }
+}
+
+/// Read the partition layout as reported by the device variables
+///
+/// Requires the device to support `getvar all`
+pub async fn read_partition_table(
+    fb: &mut NusbFastBoot,
+) -> Result<PartitionTable, NusbFastBootError> {
+    let vars = fb.get_all_vars().await?;
+    Ok(PartitionTable::from_vars(&vars))
+}
+
+/// Errors parsing a GPT
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum GptError {
+    #[error("Data too short")]
+    TooShort,
+    #[error("Invalid GPT signature")]
+    InvalidSignature,
+    #[error("Invalid GPT header size {0}")]
+    InvalidHeaderSize(u32),
+    #[error("GPT header checksum mismatch")]
+    HeaderChecksum,
+    #[error("Invalid partition entry size {0}")]
+    InvalidEntrySize(u32),
+    #[error("GPT partition entries checksum mismatch")]
+    EntriesChecksum,
+}
+
+/// Errors reading the GPT from the device
+#[derive(Debug, Error)]
+pub enum ReadGptError {
+    #[error("Failed to parse GPT: {0}")]
+    Gpt(#[from] GptError),
+    #[error(transparent)]
+    Fetch(#[from] FlashError),
+}
+
+/// A GUID as stored in a GPT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Whether this is the all-zero GUID marking unused entries
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let g = &self.0;
+        // The first three fields are stored little endian
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            g[3], g[2], g[1], g[0], g[5], g[4], g[7], g[6], g[8], g[9]
+        )?;
+        g[10..].iter().try_for_each(|b| write!(f, "{b:02X}"))
+    }
+}
+
+/// GPT header fields needed to locate the partition entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GptHeader {
+    /// First block usable by partitions
+    pub first_usable_lba: u64,
+    /// Last block usable by partitions
+    pub last_usable_lba: u64,
+    /// Block of the first partition entry
+    pub entries_lba: u64,
+    /// Number of partition entries
+    pub entries: u32,
+    /// Size of a single partition entry
+    pub entry_size: u32,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    /// Parse a GPT header (the start of LBA 1), validating its checksum
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, GptError> {
+        if bytes.len() < GPT_HEADER_SIZE {
+            return Err(GptError::TooShort);
+        }
+        if &bytes[0..8] != GPT_SIGNATURE {
+            return Err(GptError::InvalidSignature);
+        }
+        let header_size = u32_at(bytes, 12);
+        if (header_size as usize) < GPT_HEADER_SIZE || header_size as usize > bytes.len() {
+            return Err(GptError::InvalidHeaderSize(header_size));
+        }
+        let mut header = bytes[..header_size as usize].to_vec();
+        // The checksum is calculated with the checksum field zeroed
+        header[16..20].fill(0);
+        if crc32fast::hash(&header) != u32_at(bytes, 16) {
+            return Err(GptError::HeaderChecksum);
+        }
+        let entry_size = u32_at(bytes, 84);
+        if entry_size < GPT_ENTRY_MIN_SIZE || entry_size % 8 != 0 {
+            return Err(GptError::InvalidEntrySize(entry_size));
+        }
+        Ok(Self {
+            first_usable_lba: u64_at(bytes, 40),
+            last_usable_lba: u64_at(bytes, 48),
+            entries_lba: u64_at(bytes, 72),
+            entries: u32_at(bytes, 80),
+            entry_size,
+            entries_crc: u32_at(bytes, 88),
+        })
+    }
+
+    /// Size in bytes of the partition entry array
+    pub fn entries_size(&self) -> u64 {
+        u64::from(self.entries) * u64::from(self.entry_size)
+    }
+
+    /// Parse the partition entry array, validating its checksum; Unused entries are skipped
+    pub fn parse_entries(&self, bytes: &[u8]) -> Result<Vec<GptPartition>, GptError> {
+        let size = self.entries_size() as usize;
+        if bytes.len() < size {
+            return Err(GptError::TooShort);
+        }
+        let bytes = &bytes[..size];
+        if crc32fast::hash(bytes) != self.entries_crc {
+            return Err(GptError::EntriesChecksum);
+        }
+        Ok(bytes
+            .chunks_exact(self.entry_size as usize)
+            .map(GptPartition::from_bytes)
+            .filter(|p| !p.type_guid.is_nil())
+            .collect())
+    }
+}
+
+/// A partition entry of a GPT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// Partition type
+    pub type_guid: Guid,
+    /// Unique partition GUID
+    pub guid: Guid,
+    /// First block of the partition
+    pub first_lba: u64,
+    /// Last block of the partition (inclusive)
+    pub last_lba: u64,
+    /// Attribute flags
+    pub attributes: u64,
+    /// Partition name
+    pub name: String,
+}
+
+impl GptPartition {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let name: Vec<u16> = bytes[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        Self {
+            type_guid: Guid(bytes[0..16].try_into().unwrap()),
+            guid: Guid(bytes[16..32].try_into().unwrap()),
+            first_lba: u64_at(bytes, 32),
+            last_lba: u64_at(bytes, 40),
+            attributes: u64_at(bytes, 48),
+            name: String::from_utf16_lossy(&name),
+        }
+    }
+
+    /// Size of the partition in bytes for the given block size
+    pub fn size(&self, block_size: u64) -> u64 {
+        (self.last_lba + 1).saturating_sub(self.first_lba) * block_size
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Read and parse the GPT of a disk using `fetch`
+///
+/// `disk` is the name under which the device exposes the whole disk (e.g. "mmc0" on some
+/// bootloaders); Requires the device to support fetching from it.
+pub async fn fetch_gpt(
+    fb: &mut NusbFastBoot,
+    disk: &str,
+    block_size: u64,
+) -> Result<(GptHeader, Vec<GptPartition>), ReadGptError> {
+    let mut data = vec![];
+    fetch_range(fb, disk, block_size, &mut data, block_size).await?;
+    let header = GptHeader::from_bytes(&data)?;
+    fetch_range(
+        fb,
+        disk,
+        header.entries_lba * block_size,
+        &mut data,
+        header.entries_size(),
+    )
+    .await?;
+    let partitions = header.parse_entries(&data)?;
+    Ok((header, partitions))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn table_from_vars() {
+        let vars: HashMap<String, String> = [
+            ("partition-size:boot_a", "0x4000000"),
+            ("partition-type:boot_a", "raw"),
+            ("is-logical:boot_a", "no"),
+            ("partition-size:system_a", "0x10000000"),
+            ("is-logical:system_a", "yes"),
+            ("partition-size:super", "0x20000000"),
+            ("version", "0.4"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let table = PartitionTable::from_vars(&vars);
+        let names: Vec<_> = table.partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["boot_a", "super", "system_a"]);
+        assert_eq!(
+            table.get("boot_a"),
+            Some(&PartitionInfo {
+                name: "boot_a".to_string(),
+                size: Some(0x4000000),
+                fs_type: Some("raw".to_string()),
+                logical: Some(false),
+            })
+        );
+        assert_eq!(table.physical_size(), 0x24000000);
+    }
+
+    fn gpt(entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; GPT_HEADER_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&1000u64.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&((entries.len() / 128) as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32fast::hash(entries).to_le_bytes());
+        let crc = crc32fast::hash(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn parse_gpt() {
+        let mut entries = vec![0; 2 * 128];
+        entries[0..16].copy_from_slice(&[0xaf; 16]);
+        entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&97u64.to_le_bytes());
+        for (i, c) in "boot".encode_utf16().enumerate() {
+            entries[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+        }
+
+        let header = GptHeader::from_bytes(&gpt(&entries)).unwrap();
+        assert_eq!(header.entries_lba, 2);
+        assert_eq!(header.entries, 2);
+        let partitions = header.parse_entries(&entries).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].name, "boot");
+        assert_eq!(partitions[0].size(512), 64 * 512);
+        assert_eq!(
+            partitions[0].type_guid.to_string(),
+            "AFAFAFAF-AFAF-AFAF-AFAF-AFAFAFAFAFAF"
+        );
+
+        entries[60] = 1;
+        assert_eq!(
+            header.parse_entries(&entries),
+            Err(GptError::EntriesChecksum)
+        );
+        let mut corrupt = gpt(&entries);
+        corrupt[40] = 0;
+        assert_eq!(
+            GptHeader::from_bytes(&corrupt),
+            Err(GptError::HeaderChecksum)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    flash::ImageSource,
+    nusb::NusbFastBoot,
+    session::{FlashSession, SessionError},
+};
+
+/// A single step of a [FlashPlan]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum PlanStep {
+    /// Flash an image file to a partition; Relative paths are resolved against the base
+    /// directory of the plan. With the `http` feature the image can also be an HTTP(S) URL
+    Flash { partition: String, image: PathBuf },
+    /// Erase a partition
+    Erase { partition: String },
+    /// Run an OEM specific command
+    Oem { command: String },
+    /// Mark a slot as active
+    SetActive { slot: String },
+    /// Reboot the device, optionally into a specific mode (e.g. bootloader)
+    Reboot {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<String>,
+    },
+}
+
+/// Declarative description of the steps to flash a device
+///
+/// Plans can be stored in any format supported by serde, e.g. as JSON:
+///
+/// ```json
+/// {
+///   "verify": true,
+///   "steps": [
+///     { "action": "flash", "partition": "boot_a", "image": "boot.img" },
+///     { "action": "erase", "partition": "userdata" },
+///     { "action": "set-active", "slot": "a" },
+///     { "action": "reboot" }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlashPlan {
+    /// Read back and verify every flashed partition
+    #[serde(default)]
+    pub verify: bool,
+    /// Download images flashed to several partitions in a row only once (see
+    /// [FlashSession::dedup_downloads])
+    #[serde(default)]
+    pub dedup_downloads: bool,
+    /// Steps executed in order
+    pub steps: Vec<PlanStep>,
+}
+
+impl FlashPlan {
+    /// Build a session executing the plan, resolving relative image paths against `base`
+    pub fn session<P: AsRef<Path>>(&self, base: P) -> FlashSession {
+        let base = base.as_ref();
+        self.steps.iter().fold(
+            FlashSession::new()
+                .verify(self.verify)
+                .dedup_downloads(self.dedup_downloads),
+            |session, step| match step {
+                PlanStep::Flash { partition, image } => {
+                    let source = match image.to_str() {
+                        Some(location) if location.contains("://") => {
+                            ImageSource::from_location(location)
+                        }
+                        _ => base.join(image).into(),
+                    };
+                    session.flash(partition.as_str(), source)
+                }
+                PlanStep::Erase { partition } => session.erase(partition.as_str()),
+                PlanStep::Oem { command } => session.oem(command.as_str()),
+                PlanStep::SetActive { slot } => session.set_active(slot.as_str()),
+                PlanStep::Reboot { mode: None } => session.reboot(),
+                PlanStep::Reboot { mode: Some(mode) } => session.reboot_to(mode.as_str()),
+            },
+        )
+    }
+
+    /// Execute the plan on a device, resolving relative image paths against `base`
+    pub async fn run<P: AsRef<Path>>(
+        &self,
+        fb: &mut NusbFastBoot,
+        base: P,
+    ) -> Result<(), SessionError> {
+        self.session(base).run(fb).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::session::Operation;
+
+    use super::*;
+
+    #[test]
+    fn plan_session() {
+        let plan: FlashPlan = serde_json::from_str(
+            r#"{
+                "steps": [
+                    { "action": "flash", "partition": "boot_a", "image": "boot.img" },
+                    { "action": "flash", "partition": "dtbo_a", "image": "/images/dtbo.img" },
+                    { "action": "oem", "command": "device-info" },
+                    { "action": "set-active", "slot": "a" },
+                    { "action": "reboot", "mode": "bootloader" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(!plan.verify);
+
+        let session = plan.session("/work");
+        assert_eq!(
+            session.operations(),
+            &[
+                Operation::Flash {
+                    target: "boot_a".to_string(),
+                    source: Path::new("/work/boot.img").into(),
+                },
+                Operation::Flash {
+                    target: "dtbo_a".to_string(),
+                    source: Path::new("/images/dtbo.img").into(),
+                },
+                Operation::Oem {
+                    command: "device-info".to_string()
+                },
+                Operation::SetActive {
+                    slot: "a".to_string()
+                },
+                Operation::RebootTo {
+                    mode: "bootloader".to_string()
+                },
+            ]
+        );
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(serde_json::from_str::<FlashPlan>(&json).unwrap(), plan);
+
+        assert!(serde_json::from_str::<FlashPlan>(
+            r#"{ "steps": [ { "action": "erase", "target": "userdata" } ] }"#
+        )
+        .is_err());
+    }
+}
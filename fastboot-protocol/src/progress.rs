@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+/// Progress events emitted by a fastboot client
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProgressEvent {
+    /// The device sent an INFO message while executing a command, indicating it's still alive
+    Heartbeat { message: String },
+    /// A [FlashSession](crate::session::FlashSession) started executing an operation
+    Step {
+        /// Index of the operation
+        index: usize,
+        /// Total number of operations in the session
+        total: usize,
+        /// Description of the operation
+        operation: String,
+    },
+    /// Data of an image part is being downloaded to the device
+    ///
+    /// Images which don't fit in a single download are flashed in multiple parts; Images which do
+    /// are reported as a single part
+    Part {
+        /// Index of the part
+        index: usize,
+        /// Total number of parts, if known up front
+        total: Option<usize>,
+        /// Bytes of the part downloaded so far
+        downloaded: u32,
+        /// Size of the part in bytes
+        size: u32,
+    },
+    /// Estimate of the remaining transfer time, see [EtaEstimator]
+    Eta {
+        /// Bytes downloaded so far
+        transferred: u64,
+        /// Total bytes to download
+        total: u64,
+        /// Smoothed transfer rate in bytes per second
+        rate: u64,
+        /// Estimated time until all data is downloaded; None until the rate is known
+        remaining: Option<Duration>,
+    },
+}
+
+/// Callback receiving [ProgressEvent]s
+pub type ProgressCallback = Box<dyn FnMut(&ProgressEvent) + Send>;
+
+/// Estimator of the remaining transfer time based on [ProgressEvent::Part] events
+///
+/// Tracks the bytes downloaded over all parts (and images) against the total expected to be
+/// downloaded. The transfer rate is sampled at most every [EtaEstimator::SAMPLE_INTERVAL] and
+/// smoothed with an exponential moving average, so the estimate doesn't jump around with every
+/// USB transfer.
+#[derive(Debug, Clone)]
+pub struct EtaEstimator {
+    total: u64,
+    transferred: u64,
+    // Index and downloaded bytes of the last reported part
+    part: Option<(usize, u32)>,
+    // Time and transferred bytes of the last rate sample
+    sample: Option<(Instant, u64)>,
+    rate: Option<f64>,
+}
+
+impl EtaEstimator {
+    /// Minimal time between rate samples
+    pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+    /// Weight of a new rate sample in the moving average
+    pub const SMOOTHING: f64 = 0.3;
+
+    /// Create an estimator for a transfer of `total` bytes
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            transferred: 0,
+            part: None,
+            sample: None,
+            rate: None,
+        }
+    }
+
+    /// Update the estimate with an event which happened at `now`
+    ///
+    /// Returns the updated estimate for [ProgressEvent::Part] events
+    pub fn update(&mut self, event: &ProgressEvent, now: Instant) -> Option<ProgressEvent> {
+        match *event {
+            ProgressEvent::Step { .. } => {
+                self.part = None;
+                None
+            }
+            ProgressEvent::Part {
+                index, downloaded, ..
+            } => {
+                // Progress of a part restarting (e.g. for the next image) counts as a new part
+                let previous = match self.part {
+                    Some((i, d)) if i == index && d <= downloaded => d,
+                    _ => 0,
+                };
+                self.part = Some((index, downloaded));
+                self.transferred += u64::from(downloaded - previous);
+                self.sample(now);
+                Some(self.eta())
+            }
+            _ => None,
+        }
+    }
+
+    fn sample(&mut self, now: Instant) {
+        let Some((at, transferred)) = self.sample else {
+            self.sample = Some((now, self.transferred));
+            return;
+        };
+        let elapsed = now.saturating_duration_since(at);
+        if elapsed < Self::SAMPLE_INTERVAL {
+            return;
+        }
+        let current = (self.transferred - transferred) as f64 / elapsed.as_secs_f64();
+        self.rate = Some(match self.rate {
+            Some(rate) => Self::SMOOTHING * current + (1.0 - Self::SMOOTHING) * rate,
+            None => current,
+        });
+        self.sample = Some((now, self.transferred));
+    }
+
+    /// The current estimate as a [ProgressEvent::Eta]
+    pub fn eta(&self) -> ProgressEvent {
+        let left = self.total.saturating_sub(self.transferred);
+        ProgressEvent::Eta {
+            transferred: self.transferred,
+            total: self.total,
+            rate: self.rate.unwrap_or_default() as u64,
+            remaining: self
+                .rate
+                .filter(|&rate| rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(left as f64 / rate)),
+        }
+    }
+
+    /// Wrap a progress callback to additionally receive a [ProgressEvent::Eta] after each
+    /// [ProgressEvent::Part]
+    pub fn wrap(mut self, mut callback: ProgressCallback) -> ProgressCallback {
+        Box::new(move |event| {
+            callback(event);
+            if let Some(eta) = self.update(event, Instant::now()) {
+                callback(&eta);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn part(index: usize, downloaded: u32) -> ProgressEvent {
+        ProgressEvent::Part {
+            index,
+            total: None,
+            downloaded,
+            size: 1000,
+        }
+    }
+
+    #[test]
+    fn estimate() {
+        let start = Instant::now();
+        let mut eta = EtaEstimator::new(3000);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        eta.update(&part(0, 0), at(0));
+        assert_eq!(
+            eta.update(&part(0, 100), at(100)),
+            Some(ProgressEvent::Eta {
+                transferred: 100,
+                total: 3000,
+                rate: 0,
+                remaining: None
+            })
+        );
+        // 1000 bytes per second
+        eta.update(&part(0, 1000), at(1000));
+        eta.update(&part(1, 0), at(1000));
+        let Some(ProgressEvent::Eta {
+            transferred,
+            rate,
+            remaining,
+            ..
+        }) = eta.update(&part(1, 500), at(1500))
+        else {
+            panic!("No estimate");
+        };
+        assert_eq!(transferred, 1500);
+        assert_eq!(rate, 1000);
+        assert_eq!(remaining, Some(Duration::from_millis(1500)));
+
+        // A new image restarts at part 0
+        eta.update(&ProgressEvent::Heartbeat { message: "".into() }, at(2000));
+        eta.update(&part(0, 0), at(2000));
+        let Some(ProgressEvent::Eta { transferred, .. }) = eta.update(&part(0, 300), at(2100))
+        else {
+            panic!("No estimate");
+        };
+        assert_eq!(transferred, 1800);
+    }
+}
@@ -18,6 +18,15 @@ pub fn parse_u32(s: &str) -> Result<u32, ParseIntError> {
     }
 }
 
+/// Parses a u64 from a string that can be either hex (0x prefixed) or decimal.
+pub fn parse_u64(s: &str) -> Result<u64, ParseIntError> {
+    if s.starts_with("0x") {
+        parse_u64_hex(s)
+    } else {
+        s.parse()
+    }
+}
+
 /// Parse a hexadecimal 0x prefixed string e.g. 0x1234 into a u32
 pub fn parse_u32_hex(hex: &str) -> Result<u32, ParseIntError> {
     // Can't create a custom ParseIntError; so if there is no 0x prefix, work around it providing
@@ -43,10 +52,26 @@ pub enum FastBootCommand<S> {
     Download(u32),
     /// Verify
     Verify(u32),
+    /// Upload previously staged data from the device
+    Upload,
+    /// Fetch a given range (offset, size) of a partition from the device
+    Fetch(S, u64, u64),
     /// Flash downloaded to a partition
     Flash(S),
     /// Erase a partition
     Erase(S),
+    /// Set the active slot
+    SetActive(S),
+    /// Update the super partition metadata from downloaded data, optionally wiping it
+    UpdateSuper(S, bool),
+    /// Create a logical partition of the given size
+    CreateLogicalPartition(S, u64),
+    /// Delete a logical partition
+    DeleteLogicalPartition(S),
+    /// Resize a logical partition
+    ResizeLogicalPartition(S, u64),
+    /// Run an OEM specific command
+    Oem(S),
     /// Boot the downloaded data
     Boot,
     /// Continue booting
@@ -59,6 +84,14 @@ pub enum FastBootCommand<S> {
     RebootTo(S),
     /// Power off the device
     Powerdown,
+    /// Lock the device
+    FlashingLock,
+    /// Unlock the device
+    FlashingUnlock,
+    /// Lock critical partitions (e.g. the bootloader)
+    FlashingLockCritical,
+    /// Unlock critical partitions (e.g. the bootloader)
+    FlashingUnlockCritical,
 }
 
 impl<S: Display> Display for FastBootCommand<S> {
@@ -67,14 +100,35 @@ impl<S: Display> Display for FastBootCommand<S> {
             FastBootCommand::GetVar(var) => write!(f, "getvar:{var}"),
             FastBootCommand::Download(size) => write!(f, "download:{size:08x}"),
             FastBootCommand::Verify(part) => write!(f, "verity:{part}"),
+            FastBootCommand::Upload => write!(f, "upload"),
+            FastBootCommand::Fetch(part, offset, size) => {
+                write!(f, "fetch:{part}:0x{offset:08x}:0x{size:08x}")
+            }
             FastBootCommand::Flash(part) => write!(f, "flash:{part}"),
             FastBootCommand::Erase(part) => write!(f, "erase:{part}"),
+            FastBootCommand::SetActive(slot) => write!(f, "set_active:{slot}"),
+            FastBootCommand::UpdateSuper(part, false) => write!(f, "update-super:{part}"),
+            FastBootCommand::UpdateSuper(part, true) => write!(f, "update-super:{part}:wipe"),
+            FastBootCommand::CreateLogicalPartition(part, size) => {
+                write!(f, "create-logical-partition:{part}:{size}")
+            }
+            FastBootCommand::DeleteLogicalPartition(part) => {
+                write!(f, "delete-logical-partition:{part}")
+            }
+            FastBootCommand::ResizeLogicalPartition(part, size) => {
+                write!(f, "resize-logical-partition:{part}:{size}")
+            }
+            FastBootCommand::Oem(command) => write!(f, "oem {command}"),
             FastBootCommand::Boot => write!(f, "boot"),
             FastBootCommand::Continue => write!(f, "continue"),
             FastBootCommand::Reboot => write!(f, "reboot"),
             FastBootCommand::RebootBootloader => write!(f, "reboot-bootloader"),
             FastBootCommand::RebootTo(mode) => write!(f, "reboot-{mode}"),
             FastBootCommand::Powerdown => write!(f, "powerdown"),
+            FastBootCommand::FlashingLock => write!(f, "flashing lock"),
+            FastBootCommand::FlashingUnlock => write!(f, "flashing unlock"),
+            FastBootCommand::FlashingLockCritical => write!(f, "flashing lock_critical"),
+            FastBootCommand::FlashingUnlockCritical => write!(f, "flashing unlock_critical"),
         }
     }
 }
@@ -97,7 +151,8 @@ pub enum FastBootResponseParseError {
 }
 
 /// Fastboot response
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FastBootResponse {
     /// Command succeeded with value (depending on command)
     Okay(String),
@@ -177,6 +232,13 @@ mod test {
         assert_eq!(0x134b72400, hex);
     }
 
+    #[test]
+    fn parse_valid_u64() {
+        assert_eq!(parse_u64("0x0000000100000000").unwrap(), 0x1_0000_0000);
+        assert_eq!(parse_u64("4294967296").unwrap(), 0x1_0000_0000);
+        parse_u64("0xhello").unwrap_err();
+    }
+
     #[test]
     fn parse_invalid_u32() {
         parse_u32("12abcd").unwrap_err();
@@ -188,6 +250,36 @@ mod test {
         parse_u32_hex("123456").unwrap_err();
     }
 
+    #[test]
+    fn command_fetch() {
+        let cmd = FastBootCommand::Fetch("boot_a", 0x1000, 0x1_0000_0000);
+        assert_eq!(cmd.to_string(), "fetch:boot_a:0x00001000:0x100000000");
+    }
+
+    #[test]
+    fn command_update_super() {
+        let cmd = FastBootCommand::UpdateSuper("super", false);
+        assert_eq!(cmd.to_string(), "update-super:super");
+        let cmd = FastBootCommand::UpdateSuper("super", true);
+        assert_eq!(cmd.to_string(), "update-super:super:wipe");
+    }
+
+    #[test]
+    fn command_logical_partitions() {
+        let cmd = FastBootCommand::CreateLogicalPartition("system_a", 4096);
+        assert_eq!(cmd.to_string(), "create-logical-partition:system_a:4096");
+        let cmd = FastBootCommand::ResizeLogicalPartition("system_a", 8192);
+        assert_eq!(cmd.to_string(), "resize-logical-partition:system_a:8192");
+        let cmd = FastBootCommand::DeleteLogicalPartition("system_a");
+        assert_eq!(cmd.to_string(), "delete-logical-partition:system_a");
+    }
+
+    #[test]
+    fn command_oem() {
+        let cmd = FastBootCommand::Oem("device-info");
+        assert_eq!(cmd.to_string(), "oem device-info");
+    }
+
     #[test]
     fn response_parse_ok() {
         let r = FastBootResponse::from_bytes(b"OKAYtest").unwrap();
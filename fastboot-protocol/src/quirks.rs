@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+/// Device specific workarounds
+///
+/// The defaults match a device following the fastboot specification
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// Terminate downloads whose size is a multiple of the max packet size with a zero length
+    /// packet
+    pub zero_length_packet: bool,
+    /// Maximum size of a single USB transfer during downloads; Transfers will always be a
+    /// multiple of the max packet size
+    pub max_transfer_size: Option<usize>,
+    /// Maximum length of a command accepted by the device
+    pub max_command_len: Option<usize>,
+    /// The device doesn't support `getvar all`
+    pub no_getvar_all: bool,
+    /// The device doesn't report `partition-size`/`partition-type`, so targets can't be
+    /// validated before flashing
+    pub no_partition_vars: bool,
+    /// Always erase partitions before flashing them, as the device may otherwise leave stale
+    /// data behind
+    pub erase_before_flash: bool,
+    /// Maximum number of chunks in a sparse image accepted by the device; Sparse images with more
+    /// chunks get split into multiple sparse images. Splits of raw images always consist of at
+    /// most two chunks.
+    pub max_sparse_chunks: Option<u32>,
+}
+
+// Built-in quirks as (vendor id, product id, quirks)
+const BUILTIN_QUIRKS: &[(u16, u16, Quirks)] = &[];
+
+/// Table of [Quirks] keyed by USB vendor and product id
+///
+/// The default table contains the built-in quirks; Callers can add or override entries for
+/// their devices before opening them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuirksTable {
+    entries: HashMap<(u16, u16), Quirks>,
+}
+
+impl Default for QuirksTable {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl QuirksTable {
+    /// Table without any entries
+    pub fn empty() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Table with the built-in quirks
+    pub fn builtin() -> Self {
+        Self {
+            entries: BUILTIN_QUIRKS
+                .iter()
+                .map(|&(vid, pid, quirks)| ((vid, pid), quirks))
+                .collect(),
+        }
+    }
+
+    /// Set the quirks for a given vendor and product id, returning the previous entry
+    pub fn insert(&mut self, vendor_id: u16, product_id: u16, quirks: Quirks) -> Option<Quirks> {
+        self.entries.insert((vendor_id, product_id), quirks)
+    }
+
+    /// Remove the quirks for a given vendor and product id
+    pub fn remove(&mut self, vendor_id: u16, product_id: u16) -> Option<Quirks> {
+        self.entries.remove(&(vendor_id, product_id))
+    }
+
+    /// Quirks to use for a given vendor and product id
+    pub fn lookup(&self, vendor_id: u16, product_id: u16) -> Quirks {
+        self.entries
+            .get(&(vendor_id, product_id))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup_override() {
+        let mut table = QuirksTable::empty();
+        assert_eq!(table.lookup(0x18d1, 0x4ee0), Quirks::default());
+
+        let quirks = Quirks {
+            no_getvar_all: true,
+            max_command_len: Some(64),
+            ..Default::default()
+        };
+        assert_eq!(table.insert(0x18d1, 0x4ee0, quirks), None);
+        assert_eq!(table.lookup(0x18d1, 0x4ee0), quirks);
+        assert_eq!(table.lookup(0x18d1, 0x4ee1), Quirks::default());
+
+        assert_eq!(table.remove(0x18d1, 0x4ee0), Some(quirks));
+        assert_eq!(table.lookup(0x18d1, 0x4ee0), Quirks::default());
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use thiserror::Error;
+
+use crate::{
+    flasher::{Flasher, FlasherError},
+    progress::ProgressCallback,
+    protocol::{FastBootCommand, FastBootResponse},
+};
+
+/// A single event in a fastboot session
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum SessionEvent {
+    /// Command sent to the device
+    Command { command: String },
+    /// Response received from the device
+    Response { response: FastBootResponse },
+    /// Data sent to the device during a download (in bytes)
+    DataOut { size: usize },
+    /// Data received from the device during an upload (in bytes)
+    DataIn { size: usize },
+}
+
+/// A recorded [SessionEvent] with the time it happened at
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionRecord {
+    /// Time since the start of the recording
+    pub elapsed: Duration,
+    /// The recorded event
+    pub event: SessionEvent,
+}
+
+/// Recorder for all the interactions with a device
+///
+/// When set on a client (e.g. [crate::nusb::NusbFastBoot::set_recorder]) every command,
+/// response and data transfer is recorded. With the `serde` feature enabled the records can be
+/// serialized, e.g. to attach them to a bug report. [SessionReplay] plays the records back as a
+/// [Flasher] for regression tests.
+#[derive(Debug, Clone)]
+pub struct SessionRecorder {
+    start: Instant,
+    records: Vec<SessionRecord>,
+}
+
+impl Default for SessionRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionRecorder {
+    /// Create a new recorder; Event times are relative to its creation
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            records: vec![],
+        }
+    }
+
+    /// Record an event
+    pub fn record(&mut self, event: SessionEvent) {
+        self.records.push(SessionRecord {
+            elapsed: self.start.elapsed(),
+            event,
+        });
+    }
+
+    /// All records so far
+    pub fn records(&self) -> &[SessionRecord] {
+        &self.records
+    }
+
+    /// Take all records, emptying the recorder
+    pub fn take_records(&mut self) -> Vec<SessionRecord> {
+        std::mem::take(&mut self.records)
+    }
+}
+
+/// Errors while replaying a recorded session
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("Expected {expected} but the recording has {found:?}")]
+    Mismatch {
+        expected: String,
+        found: Option<SessionEvent>,
+    },
+}
+
+/// [Flasher] answering from a recorded session, for regression tests without a device
+///
+/// Every call has to match the next recorded command, and for downloads the amount of data sent;
+/// The recorded responses are then played back. Timings of the records are ignored.
+pub struct SessionReplay {
+    events: VecDeque<SessionEvent>,
+    progress: Option<ProgressCallback>,
+}
+
+impl SessionReplay {
+    /// Create a replay of the given records, e.g. from [SessionRecorder::take_records]
+    pub fn new<I>(records: I) -> Self
+    where
+        I: IntoIterator<Item = SessionRecord>,
+    {
+        Self {
+            events: records.into_iter().map(|r| r.event).collect(),
+            progress: None,
+        }
+    }
+
+    /// Whether all recorded events were replayed
+    pub fn is_done(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn expect(&mut self, expected: SessionEvent) -> Result<(), ReplayError> {
+        match self.events.pop_front() {
+            Some(event) if event == expected => Ok(()),
+            found => Err(ReplayError::Mismatch {
+                expected: format!("{expected:?}"),
+                found,
+            }),
+        }
+    }
+
+    // Next response which isn't informational
+    fn response(&mut self) -> Result<FastBootResponse, ReplayError> {
+        loop {
+            match self.events.pop_front() {
+                Some(SessionEvent::Response {
+                    response: FastBootResponse::Info(_) | FastBootResponse::Text(_),
+                }) => (),
+                Some(SessionEvent::Response { response }) => return Ok(response),
+                found => {
+                    return Err(ReplayError::Mismatch {
+                        expected: "response".to_string(),
+                        found,
+                    })
+                }
+            }
+        }
+    }
+
+    // Replay the final response of a command
+    fn finish(&mut self) -> Result<String, FlasherError> {
+        match self.response()? {
+            FastBootResponse::Okay(value) => Ok(value),
+            FastBootResponse::Fail(reason) => Err(FlasherError::Failed(reason)),
+            response => Err(ReplayError::Mismatch {
+                expected: "OKAY or FAIL".to_string(),
+                found: Some(SessionEvent::Response { response }),
+            }
+            .into()),
+        }
+    }
+
+    fn execute(&mut self, command: FastBootCommand<&str>) -> Result<String, FlasherError> {
+        self.expect(SessionEvent::Command {
+            command: command.to_string(),
+        })?;
+        self.finish()
+    }
+
+    fn download(&mut self, size: usize) -> Result<(), FlasherError> {
+        let expected = u32::try_from(size).map_err(|_| FlasherError::DownloadTooLarge(size))?;
+        self.expect(SessionEvent::Command {
+            command: FastBootCommand::<&str>::Download(expected).to_string(),
+        })?;
+        match self.response()? {
+            FastBootResponse::Data(size) if size == expected => (),
+            FastBootResponse::Fail(reason) => return Err(FlasherError::Failed(reason)),
+            response => {
+                return Err(ReplayError::Mismatch {
+                    expected: format!("DATA {expected:08x}"),
+                    found: Some(SessionEvent::Response { response }),
+                }
+                .into())
+            }
+        }
+        // The data may have been sent in multiple transfers
+        let mut sent = 0;
+        while sent < size {
+            match self.events.pop_front() {
+                Some(SessionEvent::DataOut { size }) => sent += size,
+                found => {
+                    return Err(ReplayError::Mismatch {
+                        expected: format!("{} bytes of data", size - sent),
+                        found,
+                    }
+                    .into())
+                }
+            }
+        }
+        self.finish().map(|_| ())
+    }
+}
+
+impl From<ReplayError> for FlasherError {
+    fn from(e: ReplayError) -> Self {
+        FlasherError::Transport(Box::new(e))
+    }
+}
+
+impl Flasher for SessionReplay {
+    fn get_var<'a>(&'a mut self, var: &'a str) -> BoxFuture<'a, Result<String, FlasherError>> {
+        let r = self.execute(FastBootCommand::GetVar(var));
+        async move { r }.boxed()
+    }
+
+    fn download<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), FlasherError>> {
+        let r = SessionReplay::download(self, data.len());
+        async move { r }.boxed()
+    }
+
+    fn flash<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        let r = self.execute(FastBootCommand::Flash(target)).map(|_| ());
+        async move { r }.boxed()
+    }
+
+    fn erase<'a>(&'a mut self, target: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        let r = self.execute(FastBootCommand::Erase(target)).map(|_| ());
+        async move { r }.boxed()
+    }
+
+    fn reboot(&mut self) -> BoxFuture<'_, Result<(), FlasherError>> {
+        let r = self.execute(FastBootCommand::Reboot).map(|_| ());
+        async move { r }.boxed()
+    }
+
+    fn reboot_to<'a>(&'a mut self, mode: &'a str) -> BoxFuture<'a, Result<(), FlasherError>> {
+        let r = self.execute(FastBootCommand::RebootTo(mode)).map(|_| ());
+        async move { r }.boxed()
+    }
+
+    fn set_progress(&mut self, progress: Option<ProgressCallback>) -> Option<ProgressCallback> {
+        std::mem::replace(&mut self.progress, progress)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::flasher::{flash_with, FlashWithError};
+
+    #[test]
+    fn record_events() {
+        let mut recorder = SessionRecorder::new();
+        recorder.record(SessionEvent::Command {
+            command: "getvar:version".to_string(),
+        });
+        recorder.record(SessionEvent::Response {
+            response: FastBootResponse::Okay("0.4".to_string()),
+        });
+
+        let records = recorder.take_records();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].elapsed <= records[1].elapsed);
+        assert_eq!(
+            records[1].event,
+            SessionEvent::Response {
+                response: FastBootResponse::Okay("0.4".to_string())
+            }
+        );
+        assert!(recorder.records().is_empty());
+    }
+
+    // Session of flashing a 4 KiB image to boot, as recorded by the nusb client
+    fn flash_session() -> Vec<SessionRecord> {
+        let mut recorder = SessionRecorder::new();
+        for event in [
+            SessionEvent::Command {
+                command: "getvar:max-download-size".to_string(),
+            },
+            SessionEvent::Response {
+                response: FastBootResponse::Okay("0x2000".to_string()),
+            },
+            SessionEvent::Command {
+                command: "download:00001000".to_string(),
+            },
+            SessionEvent::Response {
+                response: FastBootResponse::Data(0x1000),
+            },
+            SessionEvent::DataOut { size: 0x800 },
+            SessionEvent::DataOut { size: 0x800 },
+            SessionEvent::Response {
+                response: FastBootResponse::Okay(String::new()),
+            },
+            SessionEvent::Command {
+                command: "flash:boot".to_string(),
+            },
+            SessionEvent::Response {
+                response: FastBootResponse::Info("Writing boot".to_string()),
+            },
+            SessionEvent::Response {
+                response: FastBootResponse::Okay(String::new()),
+            },
+        ] {
+            recorder.record(event);
+        }
+        recorder.take_records()
+    }
+
+    #[tokio::test]
+    async fn replay() {
+        let mut replay = SessionReplay::new(flash_session());
+        flash_with(&mut replay, "boot", Cursor::new(vec![0xaa; 0x1000]))
+            .await
+            .unwrap();
+        assert!(replay.is_done());
+
+        // Flashing a different image diverges from the recording
+        let mut replay = SessionReplay::new(flash_session());
+        let r = flash_with(&mut replay, "boot", Cursor::new(vec![0xaa; 0x800])).await;
+        assert!(matches!(
+            r,
+            Err(FlashWithError::Flasher(FlasherError::Transport(_)))
+        ));
+
+        let mut replay = SessionReplay::new(flash_session());
+        replay.get_var("max-download-size").await.unwrap();
+        assert!(replay.erase("boot").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_failure() {
+        let mut recorder = SessionRecorder::new();
+        recorder.record(SessionEvent::Command {
+            command: "getvar:unknown".to_string(),
+        });
+        recorder.record(SessionEvent::Response {
+            response: FastBootResponse::Fail("Variable not found".to_string()),
+        });
+        let mut replay = SessionReplay::new(recorder.take_records());
+        assert!(matches!(
+            replay.get_var("unknown").await,
+            Err(FlasherError::Failed(reason)) if reason == "Variable not found"
+        ));
+        assert!(replay.is_done());
+    }
+}
@@ -0,0 +1,714 @@
+use std::{fmt::Display, future::Future, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{
+    checksum::{flash_source_checksum, verify_source_checksum, Manifest},
+    dynamic::resize_logical,
+    flash::{
+        download_source, flash_source_resumable, is_raw_partition, plan_source, validate_target,
+        FlashError, ImageSource,
+    },
+    flashall::is_userspace,
+    nusb::{DownloadError, NusbFastBoot, NusbFastBootError},
+    progress::{EtaEstimator, ProgressCallback, ProgressEvent},
+    slot::{resolve_slot, SlotSelection},
+    verify::verify_source,
+    wipe::partition_exists,
+};
+
+/// An operation executed as part of a [FlashSession]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Flash an image to a target partition
+    Flash { target: String, source: ImageSource },
+    /// Erase a partition
+    Erase { target: String },
+    /// Update the metadata of a super partition from an image (e.g. super_empty.img)
+    UpdateSuper {
+        target: String,
+        source: ImageSource,
+        wipe: bool,
+    },
+    /// Create or resize a logical partition to `size` bytes
+    ResizeLogical { target: String, size: u64 },
+    /// Run an OEM specific command
+    Oem { command: String },
+    /// Mark a slot as active
+    SetActive { slot: String },
+    /// Reboot the device
+    Reboot,
+    /// Reboot the device into a specific mode (e.g. bootloader)
+    RebootTo { mode: String },
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Flash { target, source } => write!(f, "flash {target} from {source}"),
+            Operation::Erase { target } => write!(f, "erase {target}"),
+            Operation::UpdateSuper { target, source, .. } => {
+                write!(f, "update super partition {target} from {source}")
+            }
+            Operation::ResizeLogical { target, size } => {
+                write!(f, "resize logical partition {target} to {size} bytes")
+            }
+            Operation::Oem { command } => write!(f, "oem {command}"),
+            Operation::SetActive { slot } => write!(f, "set active slot {slot}"),
+            Operation::Reboot => write!(f, "reboot"),
+            Operation::RebootTo { mode } => write!(f, "reboot to {mode}"),
+        }
+    }
+}
+
+/// Error executing a [FlashSession], indicating which operation failed
+#[derive(Debug, Error)]
+#[error("Step {index} ({operation}) failed: {source}")]
+pub struct SessionError {
+    /// Index of the failed operation
+    pub index: usize,
+    /// The failed operation
+    pub operation: Operation,
+    /// Underlying error
+    #[source]
+    pub source: FlashError,
+}
+
+/// Error returned by a step hook
+pub type HookError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A step of a [FlashSession] as passed to hooks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Index of the operation
+    pub index: usize,
+    /// Total number of operations in the session
+    pub total: usize,
+    /// The operation
+    pub operation: Operation,
+}
+
+/// Outcome of a step as passed to hooks run after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The operation succeeded
+    Success,
+    /// The operation failed with the given error
+    Failure(String),
+}
+
+type BeforeHook = Box<dyn FnMut(Step) -> BoxFuture<'static, Result<(), HookError>> + Send>;
+type AfterHook =
+    Box<dyn FnMut(Step, StepOutcome) -> BoxFuture<'static, Result<(), HookError>> + Send>;
+
+/// Retry policy for flash and erase operations the device failed
+///
+/// Some bootloaders intermittently fail writes (e.g. with "flash write failure") and succeed when
+/// retried. Failed operations are retried up to `retries` times, waiting `delay` in between
+/// attempts; Flashes continue at the failed part of the image. Only FAIL responses of the device
+/// containing one of the `messages` (case insensitive) are retried, or any FAIL response if no
+/// messages are given. Other errors (e.g. USB transfer errors) are never retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the initial attempt
+    pub retries: u32,
+    /// Delay between attempts
+    pub delay: Duration,
+    /// Failure messages which are retried
+    pub messages: Vec<String>,
+}
+
+impl RetryPolicy {
+    /// Never retry failed operations
+    pub const NONE: RetryPolicy = RetryPolicy {
+        retries: 0,
+        delay: Duration::ZERO,
+        messages: Vec::new(),
+    };
+
+    /// Whether an operation failing with the given error should be retried
+    pub fn is_retryable(&self, error: &FlashError) -> bool {
+        let message = match error {
+            FlashError::Fastboot(NusbFastBootError::FastbootFailed(m))
+            | FlashError::Download(DownloadError::Nusb(NusbFastBootError::FastbootFailed(m))) => {
+                m.to_lowercase()
+            }
+            _ => return false,
+        };
+        self.messages.is_empty()
+            || self
+                .messages
+                .iter()
+                .any(|m| message.contains(&m.to_lowercase()))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Builder for a sequence of operations executed on a device
+///
+/// ```no_run
+/// # async fn example(fb: &mut fastboot_protocol::nusb::NusbFastBoot) -> anyhow::Result<()> {
+/// use fastboot_protocol::session::FlashSession;
+/// use std::path::Path;
+///
+/// FlashSession::new()
+///     .flash("boot_a", Path::new("boot.img"))
+///     .erase("userdata")
+///     .set_active("a")
+///     .reboot()
+///     .run(fb)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct FlashSession {
+    operations: Vec<Operation>,
+    // Retry policy of each operation
+    retries: Vec<RetryPolicy>,
+    retry: RetryPolicy,
+    progress: Option<ProgressCallback>,
+    verify: bool,
+    dry_run: bool,
+    manifest: Option<Manifest>,
+    erase_before_flash: bool,
+    dedup_downloads: bool,
+    eta: bool,
+    before: Vec<BeforeHook>,
+    after: Vec<AfterHook>,
+}
+
+impl FlashSession {
+    /// Create an empty session
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an operation
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.operations.push(operation);
+        self.retries.push(self.retry.clone());
+        self
+    }
+
+    /// Flash an image from `source` to the `target` partition
+    pub fn flash<T: Into<String>, S: Into<ImageSource>>(self, target: T, source: S) -> Self {
+        self.operation(Operation::Flash {
+            target: target.into(),
+            source: source.into(),
+        })
+    }
+
+    /// Erase the `target` partition
+    pub fn erase<T: Into<String>>(self, target: T) -> Self {
+        self.operation(Operation::Erase {
+            target: target.into(),
+        })
+    }
+
+    /// Update the metadata of the `target` super partition from `source`
+    pub fn update_super<T: Into<String>, S: Into<ImageSource>>(
+        self,
+        target: T,
+        source: S,
+        wipe: bool,
+    ) -> Self {
+        self.operation(Operation::UpdateSuper {
+            target: target.into(),
+            source: source.into(),
+            wipe,
+        })
+    }
+
+    /// Create the logical partition `target` or resize it if it exists, to `size` bytes
+    pub fn resize_logical<T: Into<String>>(self, target: T, size: u64) -> Self {
+        self.operation(Operation::ResizeLogical {
+            target: target.into(),
+            size,
+        })
+    }
+
+    /// Run an OEM specific command
+    pub fn oem<C: Into<String>>(self, command: C) -> Self {
+        self.operation(Operation::Oem {
+            command: command.into(),
+        })
+    }
+
+    /// Mark `slot` as the active slot
+    pub fn set_active<S: Into<String>>(self, slot: S) -> Self {
+        self.operation(Operation::SetActive { slot: slot.into() })
+    }
+
+    /// Reboot the device
+    pub fn reboot(self) -> Self {
+        self.operation(Operation::Reboot)
+    }
+
+    /// Reboot the device into the given mode
+    pub fn reboot_to<M: Into<String>>(self, mode: M) -> Self {
+        self.operation(Operation::RebootTo { mode: mode.into() })
+    }
+
+    /// Retry flash and erase operations added after this call according to `policy`
+    ///
+    /// Use [RetryPolicy::NONE] to stop retrying for subsequent operations
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Report progress to the given callback while the session runs, instead of the callback set
+    /// on the client
+    pub fn progress<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&ProgressEvent) + Send + 'static,
+    {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Run `hook` before each operation (e.g. to toggle a relay or update a job database)
+    ///
+    /// Hooks run in the order they were added; A failing hook fails the session with
+    /// [FlashError::Hook] before the operation is executed.
+    pub fn before_step<F, Fut>(mut self, mut hook: F) -> Self
+    where
+        F: FnMut(Step) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), HookError>> + Send + 'static,
+    {
+        self.before.push(Box::new(move |step| hook(step).boxed()));
+        self
+    }
+
+    /// Run `hook` after each operation, whether it succeeded or not
+    ///
+    /// Hooks run in the order they were added. If the operation succeeded a failing hook fails
+    /// the session with [FlashError::Hook]; Otherwise the failure of the operation is reported.
+    pub fn after_step<F, Fut>(mut self, mut hook: F) -> Self
+    where
+        F: FnMut(Step, StepOutcome) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), HookError>> + Send + 'static,
+    {
+        self.after
+            .push(Box::new(move |step, outcome| hook(step, outcome).boxed()));
+        self
+    }
+
+    /// Read back every flashed partition and compare it against its image
+    ///
+    /// A mismatch fails the flash operation with [FlashError::Verification]. Requires the device
+    /// to support the `fetch` command.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Verify the checksum of every image listed in `manifest` while flashing it
+    ///
+    /// Images are matched by file name and hashed as they are streamed to the device (see
+    /// [flash_source_checksum]); A mismatch fails the flash operation before the last part of the
+    /// image is flashed. Images not listed in the manifest are flashed unverified. Dry runs
+    /// verify the listed images in a separate pass.
+    pub fn manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Erase partitions with raw flash semantics (see [is_raw_partition]) before flashing them,
+    /// as done by vendor flashing scripts to avoid stale data
+    ///
+    /// Devices with the `erase_before_flash` quirk always get all partitions erased before
+    /// flashing.
+    pub fn erase_before_flash(mut self, erase: bool) -> Self {
+        self.erase_before_flash = erase;
+        self
+    }
+
+    /// Download an image only once when consecutive flash operations use the same source (e.g.
+    /// vbmeta_a and vbmeta_b), flashing the later targets from the data already on the device
+    ///
+    /// Only applies to local images which fit in a single download; Any other operation in
+    /// between, or verifying the flashed partitions, causes the image to be downloaded again.
+    pub fn dedup_downloads(mut self, dedup: bool) -> Self {
+        self.dedup_downloads = dedup;
+        self
+    }
+
+    /// Emit [ProgressEvent::Eta] events to the session progress callback (see
+    /// [FlashSession::progress]), estimating the remaining time over all flash operations
+    ///
+    /// All images are planned before the session starts to determine the total download size;
+    /// Streamed sources (e.g. URLs) get read an additional time for that.
+    pub fn eta(mut self, eta: bool) -> Self {
+        self.eta = eta;
+        self
+    }
+
+    /// Only log what would be done instead of modifying the device
+    ///
+    /// All device queries (variables, image splitting, partition sizes, slots) still happen, so a
+    /// dry run validates the session against the device; Downloads, flashes, erases, slot changes
+    /// and reboots are skipped.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The operations in this session
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Execute all operations in order, stopping at the first failure
+    pub async fn run(mut self, fb: &mut NusbFastBoot) -> Result<(), SessionError> {
+        let mut progress = self.progress.take();
+        if self.eta {
+            if let Some(callback) = progress.take() {
+                let total = self.download_size(fb).await;
+                progress = Some(EtaEstimator::new(total).wrap(callback));
+            }
+        }
+        let previous = progress.map(|p| fb.replace_progress(Some(p)));
+        let mut before = std::mem::take(&mut self.before);
+        let mut after = std::mem::take(&mut self.after);
+        let r = self.run_operations(fb, &mut before, &mut after).await;
+        if let Some(previous) = previous {
+            fb.replace_progress(previous);
+        }
+        r
+    }
+
+    async fn run_operations(
+        &self,
+        fb: &mut NusbFastBoot,
+        before: &mut [BeforeHook],
+        after: &mut [AfterHook],
+    ) -> Result<(), SessionError> {
+        let total = self.operations.len();
+        // Source of the data left on the device by the previous operation, if it can be reused
+        let mut downloaded = None;
+        for (index, operation) in self.operations.iter().enumerate() {
+            let step = Step {
+                index,
+                total,
+                operation: operation.clone(),
+            };
+            let failed = |source| SessionError {
+                index,
+                operation: operation.clone(),
+                source,
+            };
+            for hook in before.iter_mut() {
+                hook(step.clone())
+                    .await
+                    .map_err(|e| failed(FlashError::Hook(e)))?;
+            }
+
+            info!("Step {index}/{total}: {operation}");
+            fb.progress(ProgressEvent::Step {
+                index,
+                total,
+                operation: operation.to_string(),
+            });
+            let r = self
+                .execute(fb, operation, &self.retries[index], &mut downloaded)
+                .await;
+
+            let outcome = match &r {
+                Ok(()) => StepOutcome::Success,
+                Err(e) => StepOutcome::Failure(e.to_string()),
+            };
+            let mut hooks = Ok(());
+            for hook in after.iter_mut() {
+                if let Err(e) = hook(step.clone(), outcome.clone()).await {
+                    warn!("Hook after step {index} failed: {e}");
+                    hooks = hooks.and(Err(e));
+                }
+            }
+            r.map_err(failed)?;
+            hooks.map_err(|e| failed(FlashError::Hook(e)))?;
+        }
+        Ok(())
+    }
+
+    // Total bytes downloaded by the flash operations of the session
+    async fn download_size(&self, fb: &mut NusbFastBoot) -> u64 {
+        let mut total = 0;
+        let mut last = None;
+        for operation in &self.operations {
+            let Operation::Flash { source, .. } = operation else {
+                last = None;
+                continue;
+            };
+            match plan_source(fb, source).await {
+                Ok(plan) => {
+                    let reused = self.dedup_downloads
+                        && !self.verify
+                        && plan.splits.is_empty()
+                        && last == Some(source);
+                    if !reused {
+                        total += plan.download_size;
+                    }
+                }
+                Err(e) => warn!("Failed to determine the download size of {source}: {e}"),
+            }
+            last = Some(source);
+        }
+        total
+    }
+
+    // Whether the target should be erased before flashing it
+    async fn should_erase(
+        &self,
+        fb: &mut NusbFastBoot,
+        target: &str,
+    ) -> Result<bool, NusbFastBootError> {
+        if fb.quirks().erase_before_flash {
+            return Ok(true);
+        }
+        Ok(self.erase_before_flash && is_raw_partition(fb, target).await?)
+    }
+
+    // Flash the data already downloaded to the device to `target`
+    async fn reflash(
+        &self,
+        fb: &mut NusbFastBoot,
+        target: &str,
+        retry: &RetryPolicy,
+    ) -> Result<(), FlashError> {
+        let mut attempt = 0;
+        loop {
+            match fb.flash(target).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let e = FlashError::from(e);
+                    if attempt >= retry.retries || !retry.is_retryable(&e) {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    warn!("Flashing {target} failed, retrying ({attempt}): {e}");
+                    tokio::time::sleep(retry.delay).await;
+                }
+            }
+        }
+    }
+
+    async fn execute(
+        &self,
+        fb: &mut NusbFastBoot,
+        operation: &Operation,
+        retry: &RetryPolicy,
+        downloaded: &mut Option<ImageSource>,
+    ) -> Result<(), FlashError> {
+        let previous = downloaded.take();
+        let checksum = match operation {
+            Operation::Flash { source, .. } => {
+                self.manifest.as_ref().and_then(|m| m.get_source(source))
+            }
+            _ => None,
+        };
+        if self.dry_run {
+            if let Operation::Flash { target, source } = operation {
+                if let Some(expected) = checksum {
+                    verify_source_checksum(source, expected).await?;
+                }
+                if self.should_erase(fb, target).await? {
+                    info!("Dry run: would erase {target} before flashing");
+                }
+            }
+            return dry_run(fb, operation).await;
+        }
+        match operation {
+            Operation::Flash { target, source } => {
+                if self.should_erase(fb, target).await? {
+                    info!("Erasing {target} before flashing");
+                    fb.erase(target).await?;
+                }
+                if let Some(expected) = checksum {
+                    // Verified while streaming, so always flashed in one pass from the start
+                    let mut attempt = 0;
+                    loop {
+                        match flash_source_checksum(fb, target, source, expected).await {
+                            Ok(()) => break,
+                            Err(e) if attempt < retry.retries && retry.is_retryable(&e) => {
+                                attempt += 1;
+                                warn!("Flashing {target} failed, retrying ({attempt}): {e}");
+                                tokio::time::sleep(retry.delay).await;
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                } else if previous.as_ref() == Some(source) {
+                    info!("Flashing {target} from the previous download of {source}");
+                    self.reflash(fb, target, retry).await?;
+                    if !self.verify {
+                        *downloaded = previous;
+                    }
+                } else {
+                    let single = self.dedup_downloads && single_download(fb, source).await?;
+                    let mut resume = None;
+                    let mut attempt = 0;
+                    loop {
+                        match flash_source_resumable(fb, target, source, resume).await {
+                            Ok(()) => break,
+                            Err(e) if attempt < retry.retries && retry.is_retryable(&e.source) => {
+                                attempt += 1;
+                                warn!("Flashing {target} failed, retrying ({attempt}): {e}");
+                                resume = Some(e.token);
+                                tokio::time::sleep(retry.delay).await;
+                            }
+                            Err(e) => return Err(e.source),
+                        }
+                    }
+                    // Reading back the partition for verification overwrites the download
+                    if single && !self.verify {
+                        *downloaded = Some(source.clone());
+                    }
+                }
+                if self.verify {
+                    let report = verify_source(fb, target, source).await?;
+                    if !report.is_ok() {
+                        return Err(FlashError::Verification(report));
+                    }
+                }
+                Ok(())
+            }
+            Operation::Erase { target } => {
+                let mut attempt = 0;
+                loop {
+                    match fb.erase(target).await {
+                        Ok(()) => return Ok(()),
+                        Err(e) => {
+                            let e = FlashError::from(e);
+                            if attempt >= retry.retries || !retry.is_retryable(&e) {
+                                return Err(e);
+                            }
+                            attempt += 1;
+                            warn!("Erasing {target} failed, retrying ({attempt}): {e}");
+                            tokio::time::sleep(retry.delay).await;
+                        }
+                    }
+                }
+            }
+            Operation::UpdateSuper {
+                target,
+                source,
+                wipe,
+            } => {
+                download_source(fb, source).await?;
+                Ok(fb.update_super(target, *wipe).await?)
+            }
+            Operation::ResizeLogical { target, size } => {
+                Ok(resize_logical(fb, target, *size).await?)
+            }
+            Operation::Oem { command } => {
+                let value = fb.oem(command).await?;
+                info!("oem {command}: {value}");
+                Ok(())
+            }
+            Operation::SetActive { slot } => Ok(fb.set_active(slot).await?),
+            Operation::Reboot => Ok(fb.reboot().await?),
+            Operation::RebootTo { mode } => Ok(fb.reboot_to(mode).await?),
+        }
+    }
+}
+
+// Whether the image from `source` is downloaded to the device in one go, so the data on the
+// device can be flashed to further targets
+async fn single_download(fb: &mut NusbFastBoot, source: &ImageSource) -> Result<bool, FlashError> {
+    match source {
+        ImageSource::File(_) | ImageSource::BlockDevice(_) | ImageSource::Bytes(_) => {
+            Ok(plan_source(fb, source).await?.splits.is_empty())
+        }
+        // Planning streams the whole image, which isn't worth it to save a download
+        ImageSource::Compressed { .. } => Ok(false),
+        #[cfg(feature = "http")]
+        ImageSource::Url(_) => Ok(false),
+    }
+}
+
+// Query the device for an operation, logging what would be done
+async fn dry_run(fb: &mut NusbFastBoot, operation: &Operation) -> Result<(), FlashError> {
+    match operation {
+        Operation::Flash { target, source } => {
+            let plan = plan_source(fb, source).await?;
+            validate_target(fb, target, plan.expanded_size).await?;
+            info!(
+                "Dry run: would flash {} bytes to {target} in {} download(s)",
+                plan.expanded_size,
+                plan.downloads()
+            );
+        }
+        Operation::Erase { target } => {
+            if !partition_exists(fb, target).await? {
+                warn!("Partition {target} not found");
+            }
+            info!("Dry run: would erase {target}");
+        }
+        Operation::UpdateSuper { target, .. } => {
+            if !is_userspace(fb).await {
+                warn!("Updating super requires userspace fastboot (fastbootd)");
+            }
+            info!("Dry run: would update super partition {target}");
+        }
+        Operation::ResizeLogical { .. } => {
+            if !is_userspace(fb).await {
+                warn!("Resizing logical partitions requires userspace fastboot (fastbootd)");
+            }
+            info!("Dry run: would {operation}");
+        }
+        Operation::SetActive { slot } => {
+            if let Err(e) = resolve_slot(fb, &SlotSelection::Named(slot.clone())).await {
+                warn!("Invalid slot {slot}: {e}");
+            }
+            info!("Dry run: would set active slot {slot}");
+        }
+        Operation::Oem { .. } | Operation::Reboot | Operation::RebootTo { .. } => {
+            info!("Dry run: would {operation}")
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retryable() {
+        let write_failure = FlashError::Fastboot(NusbFastBootError::FastbootFailed(
+            "Flash write failure".to_string(),
+        ));
+        let download_failure = FlashError::Download(DownloadError::Nusb(
+            NusbFastBootError::FastbootFailed("Flash write failure".to_string()),
+        ));
+        let unknown = FlashError::Fastboot(NusbFastBootError::FastbootFailed(
+            "Unknown partition".to_string(),
+        ));
+
+        assert!(!RetryPolicy::NONE.is_retryable(&FlashError::EmptyFetch));
+        assert!(
+            !RetryPolicy::NONE.is_retryable(&FlashError::Fastboot(NusbFastBootError::DeviceGone))
+        );
+        assert!(RetryPolicy::NONE.is_retryable(&unknown));
+
+        let policy = RetryPolicy {
+            retries: 3,
+            delay: Duration::ZERO,
+            messages: vec!["flash write failure".to_string()],
+        };
+        assert!(policy.is_retryable(&write_failure));
+        assert!(policy.is_retryable(&download_failure));
+        assert!(!policy.is_retryable(&unknown));
+    }
+}
@@ -0,0 +1,279 @@
+use thiserror::Error;
+use tracing::{info, trace};
+
+use crate::{
+    flash::{flash_source, FlashError, ImageSource},
+    nusb::{NusbFastBoot, NusbFastBootError},
+    protocol::parse_u32,
+};
+
+/// Errors while resolving slots
+#[derive(Debug, Error)]
+pub enum SlotError {
+    #[error("Partition {0} doesn't have slots")]
+    NoSlots(String),
+    #[error("Device doesn't have a slot {0:?}")]
+    UnknownSlot(String),
+    #[error("Device reported an invalid current slot {0:?}")]
+    InvalidCurrentSlot(String),
+    #[error("Slot {0} is not bootable after marking it active")]
+    Unbootable(String),
+    #[error(transparent)]
+    Flash(#[from] FlashError),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+}
+
+/// Boot state of a slot as reported by the device
+///
+/// Fields are `None` if the device doesn't report the corresponding variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotInfo {
+    /// Name of the slot (e.g. "a")
+    pub name: String,
+    /// Whether the slot is marked as unbootable
+    pub unbootable: Option<bool>,
+    /// Whether the slot booted successfully
+    pub successful: Option<bool>,
+    /// Number of boot attempts left before the slot is marked unbootable
+    pub retry_count: Option<u32>,
+}
+
+impl SlotInfo {
+    /// Whether the bootloader would try to boot the slot
+    pub fn is_bootable(&self) -> bool {
+        self.unbootable != Some(true) && self.retry_count != Some(0)
+    }
+}
+
+/// Selection of the slot to operate on for partitions with slots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlotSelection {
+    /// The currently active slot
+    Current,
+    /// The slot other than the currently active one; Only valid on devices with two slots
+    Other,
+    /// A named slot (e.g. "a" or "b")
+    Named(String),
+}
+
+// Slot name from a slot or a slot suffix (e.g. "_a" -> "a")
+fn slot_name(slot: &str) -> &str {
+    slot.trim().trim_start_matches('_')
+}
+
+// Index of a single letter slot name
+fn slot_index(slot: &str) -> Option<u32> {
+    match slot.as_bytes() {
+        &[c @ b'a'..=b'z'] => Some(u32::from(c - b'a')),
+        _ => None,
+    }
+}
+
+// Name of the slot following the given slot, wrapping around after `count` slots
+fn other_slot(current: &str, count: u32) -> Option<String> {
+    let index = slot_index(current).filter(|&i| i < count)?;
+    let other = (index + 1) % count;
+    Some(char::from(b'a' + other as u8).to_string())
+}
+
+/// Number of slots of the device; 0 if the device doesn't support slots
+pub async fn slot_count(fb: &mut NusbFastBoot) -> Result<u32, NusbFastBootError> {
+    match fb.get_var_cached("slot-count").await {
+        Ok(count) => Ok(parse_u32(&count).unwrap_or(0)),
+        Err(NusbFastBootError::FastbootFailed(e)) => {
+            trace!("No slot count: {e}");
+            Ok(0)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The currently active slot (e.g. "a"), if the device has slots
+pub async fn current_slot(fb: &mut NusbFastBoot) -> Result<Option<String>, NusbFastBootError> {
+    match fb.get_var_cached("current-slot").await {
+        Ok(slot) if slot_name(&slot).is_empty() => Ok(None),
+        Ok(slot) => Ok(Some(slot_name(&slot).to_string())),
+        Err(NusbFastBootError::FastbootFailed(e)) => {
+            trace!("No current slot: {e}");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the given partition has slots
+pub async fn has_slot(fb: &mut NusbFastBoot, partition: &str) -> Result<bool, NusbFastBootError> {
+    match fb.get_var_cached(&format!("has-slot:{partition}")).await {
+        Ok(v) => Ok(v == "yes"),
+        Err(NusbFastBootError::FastbootFailed(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Value of a slot variable; None if the device doesn't report it
+async fn slot_var(
+    fb: &mut NusbFastBoot,
+    var: &str,
+    slot: &str,
+) -> Result<Option<String>, NusbFastBootError> {
+    // Not cached, as the slot state changes when switching slots
+    match fb.get_var(&format!("{var}:{slot}")).await {
+        Ok(v) => Ok(Some(v)),
+        Err(NusbFastBootError::FastbootFailed(e)) => {
+            trace!("No {var} for slot {slot}: {e}");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Boot state of the given slot (e.g. "a")
+pub async fn slot_info(fb: &mut NusbFastBoot, slot: &str) -> Result<SlotInfo, NusbFastBootError> {
+    let name = slot_name(slot).to_string();
+    let unbootable = slot_var(fb, "slot-unbootable", &name).await?;
+    let successful = slot_var(fb, "slot-successful", &name).await?;
+    let retry_count = slot_var(fb, "slot-retry-count", &name).await?;
+    Ok(SlotInfo {
+        unbootable: unbootable.as_deref().map(|v| v == "yes"),
+        successful: successful.as_deref().map(|v| v == "yes"),
+        retry_count: retry_count.and_then(|v| parse_u32(&v).ok()),
+        name,
+    })
+}
+
+// The current slot, checked to be one of the `count` slots of the device
+async fn current_slot_checked(fb: &mut NusbFastBoot, count: u32) -> Result<String, SlotError> {
+    let current = fb.get_var_cached("current-slot").await?;
+    let name = slot_name(&current);
+    match slot_index(name) {
+        Some(i) if i < count => Ok(name.to_string()),
+        _ => Err(SlotError::InvalidCurrentSlot(current)),
+    }
+}
+
+/// Resolve the selected slot to a slot name (e.g. "a")
+pub async fn resolve_slot(
+    fb: &mut NusbFastBoot,
+    selection: &SlotSelection,
+) -> Result<String, SlotError> {
+    let count = slot_count(fb).await?;
+    match selection {
+        SlotSelection::Current => current_slot_checked(fb, count).await,
+        SlotSelection::Other => {
+            let current = current_slot_checked(fb, count).await?;
+            if count != 2 {
+                return Err(SlotError::UnknownSlot(format!("other than {current}")));
+            }
+            other_slot(&current, count).ok_or(SlotError::InvalidCurrentSlot(current))
+        }
+        SlotSelection::Named(slot) => match slot_index(slot_name(slot)) {
+            Some(i) if i < count => Ok(slot_name(slot).to_string()),
+            _ => Err(SlotError::UnknownSlot(slot.clone())),
+        },
+    }
+}
+
+/// Resolve the partition to operate on for a base partition name (e.g. "boot")
+///
+/// For partitions with slots the suffix of the selected slot is added (e.g. "boot_b"). For
+/// partitions without slots the base name is returned when the current slot is selected;
+/// Selecting any other slot is an error.
+pub async fn slot_partition(
+    fb: &mut NusbFastBoot,
+    base: &str,
+    selection: &SlotSelection,
+) -> Result<String, SlotError> {
+    if has_slot(fb, base).await? {
+        let slot = resolve_slot(fb, selection).await?;
+        Ok(format!("{base}_{slot}"))
+    } else if *selection == SlotSelection::Current {
+        Ok(base.to_string())
+    } else {
+        Err(SlotError::NoSlots(base.to_string()))
+    }
+}
+
+/// Flash an image to the selected slot of a base partition (e.g. "boot")
+///
+/// See [slot_partition] for how the partition is resolved and
+/// [crate::flash::flash_image] for details on flashing
+pub async fn flash_slot(
+    fb: &mut NusbFastBoot,
+    base: &str,
+    source: &ImageSource,
+    selection: SlotSelection,
+) -> Result<(), SlotError> {
+    let target = slot_partition(fb, base, &selection).await?;
+    info!("Flashing {source} to {target}");
+    flash_source(fb, &target, source).await?;
+    Ok(())
+}
+
+/// Flash images to the other slot, mark it active and reboot into it
+///
+/// Each image is flashed to the slot of its base partition (e.g. "boot") which isn't currently
+/// active, as done by A/B OTA updates. After marking the slot active its state is checked to be
+/// bootable before rebooting; Returns the state of the newly active slot.
+pub async fn cycle_slot(
+    fb: &mut NusbFastBoot,
+    images: &[(String, ImageSource)],
+) -> Result<SlotInfo, SlotError> {
+    let slot = resolve_slot(fb, &SlotSelection::Other).await?;
+    info!("Switching to slot {slot}");
+    for (base, source) in images {
+        flash_slot(fb, base, source, SlotSelection::Named(slot.clone())).await?;
+    }
+    fb.set_active(&slot).await?;
+    fb.clear_var_cache();
+
+    let info = slot_info(fb, &slot).await?;
+    if !info.is_bootable() {
+        return Err(SlotError::Unbootable(slot));
+    }
+    info!("Rebooting into slot {slot}");
+    fb.reboot().await?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slot_names() {
+        assert_eq!(slot_name("_a"), "a");
+        assert_eq!(slot_name("b"), "b");
+        assert_eq!(slot_index("a"), Some(0));
+        assert_eq!(slot_index("c"), Some(2));
+        assert_eq!(slot_index("ab"), None);
+        assert_eq!(slot_index(""), None);
+    }
+
+    #[test]
+    fn other_slots() {
+        assert_eq!(other_slot("a", 2).as_deref(), Some("b"));
+        assert_eq!(other_slot("b", 2).as_deref(), Some("a"));
+        assert_eq!(other_slot("c", 2), None);
+        assert_eq!(other_slot("a", 0), None);
+    }
+
+    #[test]
+    fn bootable() {
+        let mut info = SlotInfo {
+            name: "b".to_string(),
+            unbootable: None,
+            successful: None,
+            retry_count: None,
+        };
+        assert!(info.is_bootable());
+        info.retry_count = Some(7);
+        info.unbootable = Some(false);
+        assert!(info.is_bootable());
+        info.retry_count = Some(0);
+        assert!(!info.is_bootable());
+        info.retry_count = Some(7);
+        info.unbootable = Some(true);
+        assert!(!info.is_bootable());
+    }
+}
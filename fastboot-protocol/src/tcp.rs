@@ -0,0 +1,489 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    progress::{ProgressCallback, ProgressEvent},
+    protocol::{FastBootCommand, FastBootResponse, FastBootResponseParseError},
+};
+
+/// Default TCP port used by fastboot
+pub const DEFAULT_PORT: u16 = 5554;
+/// Default time to wait for a device to accept the connection and answer the handshake
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Service type fastboot devices announce themselves with over mDNS
+pub const MDNS_SERVICE_TYPE: &str = "_fastboot._tcp.local.";
+
+// Maximum amount of connections attempted in parallel when probing a subnet
+const MAX_PARALLEL_PROBES: usize = 64;
+// Smallest allowed prefix when probing a subnet; avoids accidentally scanning huge networks
+const MIN_SUBNET_PREFIX: u8 = 16;
+
+/// Errors during TCP device discovery
+#[derive(Debug, Error)]
+pub enum TcpDiscoveryError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Timeout connecting to device")]
+    Timeout,
+    #[error("Invalid fastboot handshake: {0:x?}")]
+    InvalidHandshake([u8; 4]),
+    #[error("Subnet prefix /{0} is invalid or too large to probe")]
+    InvalidSubnet(u8),
+    #[cfg(feature = "mdns")]
+    #[error("mDNS error: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+}
+
+/// Information about a fastboot device reachable over TCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpDeviceInfo {
+    addr: SocketAddr,
+    version: u8,
+    name: Option<String>,
+}
+
+impl TcpDeviceInfo {
+    /// Address the device is reachable on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Fastboot TCP protocol version reported by the device during the handshake
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Service instance name, if the device was discovered via mDNS
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+// Parse the handshake response of a device, e.g. FB01, returning the protocol version
+fn parse_handshake(handshake: &[u8; 4]) -> Option<u8> {
+    let version = handshake.strip_prefix(b"FB")?;
+    std::str::from_utf8(version).ok()?.parse().ok()
+}
+
+/// Probe whether a fastboot device is listening on the given address
+///
+/// This connects to the address and performs the fastboot TCP handshake; The connection is closed
+/// again afterwards.
+#[tracing::instrument(err)]
+pub async fn probe(
+    addr: SocketAddr,
+    timeout: Duration,
+) -> Result<TcpDeviceInfo, TcpDiscoveryError> {
+    let handshake = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"FB01").await?;
+        let mut response = [0u8; 4];
+        stream.read_exact(&mut response).await?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = tokio::time::timeout(timeout, handshake)
+        .await
+        .map_err(|_| TcpDiscoveryError::Timeout)??;
+    trace!("Handshake response from {addr}: {response:x?}");
+    let version =
+        parse_handshake(&response).ok_or(TcpDiscoveryError::InvalidHandshake(response))?;
+
+    Ok(TcpDeviceInfo {
+        addr,
+        version,
+        name: None,
+    })
+}
+
+/// List fastboot devices from a set of `host:port` targets
+///
+/// Each target is probed in parallel; targets without a responding fastboot device are skipped.
+pub async fn devices<I>(targets: I, timeout: Duration) -> impl Iterator<Item = TcpDeviceInfo>
+where
+    I: IntoIterator<Item = SocketAddr>,
+{
+    futures::stream::iter(targets)
+        .map(|addr| async move {
+            probe(addr, timeout)
+                .await
+                .inspect_err(|e| debug!("No fastboot device on {addr}: {e}"))
+                .ok()
+        })
+        .buffer_unordered(MAX_PARALLEL_PROBES)
+        .filter_map(|d| async move { d })
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+}
+
+// All host addresses in an IPv4 subnet, excluding the network and broadcast address if the subnet
+// is big enough to have them
+fn subnet_hosts(network: Ipv4Addr, prefix: u8) -> impl Iterator<Item = Ipv4Addr> {
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    let start = u32::from(network) & mask;
+    let end = start | !mask;
+    let (start, end) = if prefix < 31 {
+        (start + 1, end - 1)
+    } else {
+        (start, end)
+    };
+    (start..=end).map(Ipv4Addr::from)
+}
+
+/// List fastboot devices in an IPv4 subnet (e.g. 192.168.1.0/24) listening on `port`
+///
+/// To avoid accidentally probing huge networks the prefix should be at least /16
+pub async fn probe_subnet(
+    network: Ipv4Addr,
+    prefix: u8,
+    port: u16,
+    timeout: Duration,
+) -> Result<impl Iterator<Item = TcpDeviceInfo>, TcpDiscoveryError> {
+    if !(MIN_SUBNET_PREFIX..=32).contains(&prefix) {
+        return Err(TcpDiscoveryError::InvalidSubnet(prefix));
+    }
+    let targets =
+        subnet_hosts(network, prefix).map(move |host| SocketAddr::new(IpAddr::V4(host), port));
+    Ok(devices(targets, timeout).await)
+}
+
+/// List fastboot devices announcing themselves via mDNS
+///
+/// Browses for [MDNS_SERVICE_TYPE] services for the given duration, returning every resolved
+/// service that completes the fastboot handshake.
+#[cfg(feature = "mdns")]
+pub async fn browse_mdns(
+    duration: Duration,
+    timeout: Duration,
+) -> Result<impl Iterator<Item = TcpDeviceInfo>, TcpDiscoveryError> {
+    let daemon = mdns_sd::ServiceDaemon::new()?;
+    let receiver = daemon.browse(MDNS_SERVICE_TYPE)?;
+    let services = tokio::task::spawn_blocking(move || {
+        let deadline = std::time::Instant::now() + duration;
+        let mut services = vec![];
+        while let Ok(event) = receiver.recv_deadline(deadline) {
+            if let mdns_sd::ServiceEvent::ServiceResolved(info) = event {
+                services.push(info);
+            }
+        }
+        services
+    })
+    .await
+    .map_err(io::Error::other)?;
+    // Shutting down can only fail if the daemon already exited
+    let _ = daemon.shutdown();
+
+    let mut found = vec![];
+    for service in services {
+        for addr in service.get_addresses() {
+            let addr = SocketAddr::new(*addr, service.get_port());
+            match probe(addr, timeout).await {
+                Ok(info) => {
+                    found.push(TcpDeviceInfo {
+                        name: Some(service.get_fullname().to_string()),
+                        ..info
+                    });
+                    break;
+                }
+                Err(e) => debug!("No fastboot device on {addr}: {e}"),
+            }
+        }
+    }
+    Ok(found.into_iter())
+}
+
+/// Errors communicating with a fastboot device over TCP
+#[derive(Debug, Error)]
+pub enum TcpFastBootError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid fastboot handshake: {0:x?}")]
+    InvalidHandshake([u8; 4]),
+    #[error("Fastboot client failure: {0}")]
+    FastbootFailed(String),
+    #[error("Unexpected fastboot response")]
+    FastbootUnexpectedReply,
+    #[error("Unknown fastboot response: {0}")]
+    FastbootParseError(#[from] FastBootResponseParseError),
+    #[error("Response packet of {0} bytes is too large")]
+    PacketTooLarge(u64),
+    #[error("Timeout connecting to device")]
+    Timeout,
+    #[error("Download of {0} bytes is too large")]
+    DownloadTooLarge(usize),
+}
+
+// Maximum size of a response packet; Responses are at most 256 bytes
+const MAX_RESPONSE_LEN: u64 = 4096;
+// Maximum amount of data sent in a single packet during downloads
+const MAX_DATA_PACKET: usize = 1024 * 1024;
+
+/// Fastboot client for devices reachable over TCP (e.g. fastbootd over the network)
+///
+/// Every message is framed as a packet prefixed with its length as a 64 bit big endian integer
+pub struct TcpFastBoot {
+    stream: TcpStream,
+    version: u8,
+    progress: Option<ProgressCallback>,
+}
+
+impl TcpFastBoot {
+    /// Connect to a device and perform the fastboot TCP handshake, waiting up to
+    /// [DEFAULT_CONNECT_TIMEOUT] for it
+    pub async fn connect(addr: SocketAddr) -> Result<Self, TcpFastBootError> {
+        Self::connect_timeout(addr, DEFAULT_CONNECT_TIMEOUT).await
+    }
+
+    /// Connect to a device and perform the fastboot TCP handshake, waiting up to `timeout` for it
+    #[tracing::instrument(err)]
+    pub async fn connect_timeout(
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Self, TcpFastBootError> {
+        let handshake = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(b"FB01").await?;
+            let mut response = [0u8; 4];
+            stream.read_exact(&mut response).await?;
+            Ok::<_, io::Error>((stream, response))
+        };
+        let (stream, response) = tokio::time::timeout(timeout, handshake)
+            .await
+            .map_err(|_| TcpFastBootError::Timeout)??;
+        let version =
+            parse_handshake(&response).ok_or(TcpFastBootError::InvalidHandshake(response))?;
+        Ok(Self {
+            stream,
+            version,
+            progress: None,
+        })
+    }
+
+    /// Fastboot TCP protocol version reported by the device during the handshake
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Set the callback receiving progress events, replacing any previous callback
+    pub fn set_progress<F>(&mut self, callback: F)
+    where
+        F: FnMut(&ProgressEvent) + Send + 'static,
+    {
+        self.progress = Some(Box::new(callback));
+    }
+
+    pub(crate) fn replace_progress(
+        &mut self,
+        progress: Option<ProgressCallback>,
+    ) -> Option<ProgressCallback> {
+        std::mem::replace(&mut self.progress, progress)
+    }
+
+    async fn send_packet(&mut self, data: &[u8]) -> Result<(), TcpFastBootError> {
+        self.stream
+            .write_all(&(data.len() as u64).to_be_bytes())
+            .await?;
+        self.stream.write_all(data).await?;
+        Ok(())
+    }
+
+    async fn read_response(&mut self) -> Result<FastBootResponse, TcpFastBootError> {
+        let mut len = [0u8; 8];
+        self.stream.read_exact(&mut len).await?;
+        let len = u64::from_be_bytes(len);
+        if len > MAX_RESPONSE_LEN {
+            return Err(TcpFastBootError::PacketTooLarge(len));
+        }
+        let mut data = vec![0; len as usize];
+        self.stream.read_exact(&mut data).await?;
+        Ok(FastBootResponse::from_bytes(&data)?)
+    }
+
+    async fn send_command<S: std::fmt::Display>(
+        &mut self,
+        cmd: FastBootCommand<S>,
+    ) -> Result<(), TcpFastBootError> {
+        let cmd = cmd.to_string();
+        trace!("Sending command: {cmd}");
+        self.send_packet(cmd.as_bytes()).await
+    }
+
+    async fn handle_responses(&mut self) -> Result<String, TcpFastBootError> {
+        loop {
+            match self.read_response().await? {
+                FastBootResponse::Info(message) => {
+                    if let Some(progress) = &mut self.progress {
+                        progress(&ProgressEvent::Heartbeat { message });
+                    }
+                }
+                FastBootResponse::Text(_) => (),
+                FastBootResponse::Data(_) => return Err(TcpFastBootError::FastbootUnexpectedReply),
+                FastBootResponse::Okay(value) => return Ok(value),
+                FastBootResponse::Fail(fail) => return Err(TcpFastBootError::FastbootFailed(fail)),
+            }
+        }
+    }
+
+    async fn execute<S: std::fmt::Display>(
+        &mut self,
+        cmd: FastBootCommand<S>,
+    ) -> Result<String, TcpFastBootError> {
+        self.send_command(cmd).await?;
+        self.handle_responses().await
+    }
+
+    /// Get the named variable
+    pub async fn get_var(&mut self, var: &str) -> Result<String, TcpFastBootError> {
+        self.execute(FastBootCommand::GetVar(var)).await
+    }
+
+    /// Download data to the device
+    pub async fn download(&mut self, data: &[u8]) -> Result<(), TcpFastBootError> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| TcpFastBootError::DownloadTooLarge(data.len()))?;
+        self.send_command(FastBootCommand::<&str>::Download(size))
+            .await?;
+        match self.read_response().await? {
+            FastBootResponse::Data(s) if s == size => (),
+            FastBootResponse::Fail(fail) => return Err(TcpFastBootError::FastbootFailed(fail)),
+            _ => return Err(TcpFastBootError::FastbootUnexpectedReply),
+        }
+        for packet in data.chunks(MAX_DATA_PACKET) {
+            self.send_packet(packet).await?;
+        }
+        self.handle_responses().await?;
+        Ok(())
+    }
+
+    /// Flash downloaded data to a given target partition
+    pub async fn flash(&mut self, target: &str) -> Result<(), TcpFastBootError> {
+        self.execute(FastBootCommand::Flash(target)).await?;
+        Ok(())
+    }
+
+    /// Erasing the given target partition
+    pub async fn erase(&mut self, target: &str) -> Result<(), TcpFastBootError> {
+        self.execute(FastBootCommand::Erase(target)).await?;
+        Ok(())
+    }
+
+    /// Reboot the device
+    pub async fn reboot(&mut self) -> Result<(), TcpFastBootError> {
+        self.execute(FastBootCommand::<&str>::Reboot).await?;
+        Ok(())
+    }
+
+    /// Reboot the device into a specific mode (e.g. bootloader)
+    pub async fn reboot_to(&mut self, mode: &str) -> Result<(), TcpFastBootError> {
+        self.execute(FastBootCommand::RebootTo(mode)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        assert_eq!(parse_handshake(b"FB01"), Some(1));
+        assert_eq!(parse_handshake(b"FB42"), Some(42));
+        assert_eq!(parse_handshake(b"FBxx"), None);
+        assert_eq!(parse_handshake(b"HTTP"), None);
+    }
+
+    #[test]
+    fn hosts_in_subnet() {
+        let hosts: Vec<_> = subnet_hosts(Ipv4Addr::new(192, 168, 1, 77), 24).collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+        let hosts: Vec<_> = subnet_hosts(Ipv4Addr::new(10, 0, 0, 5), 32).collect();
+        assert_eq!(hosts, vec![Ipv4Addr::new(10, 0, 0, 5)]);
+    }
+
+    // Read a packet sent by the client to the fake device
+    async fn read_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let mut len = [0u8; 8];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut data = vec![0; u64::from_be_bytes(len) as usize];
+        stream.read_exact(&mut data).await.unwrap();
+        data
+    }
+
+    async fn write_packet(stream: &mut TcpStream, data: &[u8]) {
+        stream
+            .write_all(&(data.len() as u64).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(data).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 4];
+            stream.read_exact(&mut handshake).await.unwrap();
+            assert_eq!(&handshake, b"FB01");
+            stream.write_all(b"FB01").await.unwrap();
+
+            assert_eq!(read_packet(&mut stream).await, b"getvar:product");
+            write_packet(&mut stream, b"INFOlooking").await;
+            write_packet(&mut stream, b"OKAYtest").await;
+
+            assert_eq!(read_packet(&mut stream).await, b"download:00000004");
+            write_packet(&mut stream, b"DATA00000004").await;
+            assert_eq!(read_packet(&mut stream).await, b"data");
+            write_packet(&mut stream, b"OKAY").await;
+
+            assert_eq!(read_packet(&mut stream).await, b"flash:boot");
+            write_packet(&mut stream, b"FAILno such partition").await;
+        });
+
+        let mut fb = TcpFastBoot::connect(addr).await.unwrap();
+        assert_eq!(fb.protocol_version(), 1);
+        let (tx, rx) = std::sync::mpsc::channel();
+        fb.set_progress(move |e| tx.send(e.clone()).unwrap());
+        assert_eq!(fb.get_var("product").await.unwrap(), "test");
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ProgressEvent::Heartbeat {
+                message: "looking".to_string()
+            }
+        );
+        fb.download(b"data").await.unwrap();
+        assert!(matches!(
+            fb.flash("boot").await,
+            Err(TcpFastBootError::FastbootFailed(m)) if m == "no such partition"
+        ));
+        device.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accept the connection but never answer the handshake
+        let device = tokio::spawn(async move { listener.accept().await.unwrap() });
+        assert!(matches!(
+            TcpFastBoot::connect_timeout(addr, Duration::from_millis(100)).await,
+            Err(TcpFastBootError::Timeout)
+        ));
+        device.await.unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use android_sparse_image::{FileHeader, FileHeaderBytes, ParseError, FILE_HEADER_BYTES_LEN};
+use async_zip::{
+    base::read::WithEntry,
+    error::ZipError,
+    tokio::read::{seek::ZipFileReader, ZipEntryReader},
+};
+use futures::{AsyncRead, AsyncReadExt};
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncSeek, BufReader};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::{info, trace};
+
+use crate::{
+    dynamic::resize_logical,
+    flash::{download_source, flash_stream, FlashError, ImageSource},
+    flashall::{slot_target, super_partition_name, BOOT_PARTITIONS, OS_PARTITIONS},
+    mode::{ensure_mode, FastbootMode, ModeError, DEFAULT_MODE_TIMEOUT},
+    nusb::{NusbFastBoot, NusbFastBootError},
+    slot::current_slot,
+};
+
+const ANDROID_INFO: &str = "android-info.txt";
+
+/// Errors while flashing an update package
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read update package: {0}")]
+    Zip(#[from] ZipError),
+    #[error("No images found in update package")]
+    NoImages,
+    #[error("Device {var} is {actual:?}, update requires one of {expected:?}")]
+    Requirement {
+        var: String,
+        actual: String,
+        expected: Vec<String>,
+    },
+    #[error("Device {var} is {actual:?}, which the update rejects")]
+    Rejected { var: String, actual: String },
+    #[error(transparent)]
+    Mode(#[from] ModeError),
+    #[error(transparent)]
+    Flash(#[from] FlashError),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+}
+
+/// A requirement on a device variable from android-info.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    /// Only applies to devices of the given product
+    pub product: Option<String>,
+    /// Variable to check
+    pub var: String,
+    /// Accepted values; A trailing `*` matches any suffix
+    pub values: Vec<String>,
+    /// The variable must not match any of the values instead
+    pub reject: bool,
+}
+
+impl Requirement {
+    /// Whether the given value matches any of the values of the requirement
+    pub fn matches(&self, value: &str) -> bool {
+        self.values.iter().any(|v| match v.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => value == v,
+        })
+    }
+}
+
+/// Parse the requirements in an android-info.txt file
+///
+/// Lines which aren't requirements (e.g. `require partition-exists=`) are ignored
+pub fn parse_android_info(info: &str) -> Vec<Requirement> {
+    info.lines()
+        .filter_map(|line| {
+            let (kind, check) = line.trim().split_once(char::is_whitespace)?;
+            let (product, reject) = match kind {
+                "require" => (None, false),
+                "reject" => (None, true),
+                _ => (Some(kind.strip_prefix("require-for-product:")?), false),
+            };
+            let (var, values) = check.trim().split_once('=')?;
+            let var = match var {
+                "board" => "product",
+                "partition-exists" => return None,
+                var => var,
+            };
+            Some(Requirement {
+                product: product.map(str::to_string),
+                var: var.to_string(),
+                values: values.split('|').map(str::to_string).collect(),
+                reject,
+            })
+        })
+        .collect()
+}
+
+// Check the requirements against the device
+async fn check_requirements(
+    fb: &mut NusbFastBoot,
+    requirements: &[Requirement],
+) -> Result<(), UpdateError> {
+    for requirement in requirements {
+        if let Some(product) = &requirement.product {
+            if fb.get_var_cached("product").await? != *product {
+                continue;
+            }
+        }
+        let actual = match fb.get_var_cached(&requirement.var).await {
+            Ok(actual) => actual,
+            Err(NusbFastBootError::FastbootFailed(e)) => {
+                trace!("Failed to get {}: {e}", requirement.var);
+                String::new()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match (requirement.matches(&actual), requirement.reject) {
+            (true, true) => {
+                return Err(UpdateError::Rejected {
+                    var: requirement.var.clone(),
+                    actual,
+                })
+            }
+            (false, false) => {
+                return Err(UpdateError::Requirement {
+                    var: requirement.var.clone(),
+                    actual,
+                    expected: requirement.values.clone(),
+                })
+            }
+            _ => trace!("Requirement on {} satisfied", requirement.var),
+        }
+    }
+    Ok(())
+}
+
+// Reader of a zip entry checking its CRC32 once all of its data was read; On a mismatch the read
+// completing the data fails, so the end of the image never reaches the device
+struct CheckedEntry<'a, R> {
+    reader: ZipEntryReader<'a, R, WithEntry<'a>>,
+    left: u64,
+}
+
+impl<R> CheckedEntry<'_, R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    fn check(&mut self) -> io::Result<()> {
+        if self.reader.compute_hash() == self.reader.entry().crc32() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ZipError::CRC32CheckError,
+            ))
+        }
+    }
+}
+
+impl<R> AsyncRead for CheckedEntry<'_, R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.left == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let n = ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.left = self.left.saturating_sub(n as u64);
+        if self.left == 0 {
+            self.check()?;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+// Size of the image in a zip entry once flashed; Sparse images get expanded
+async fn image_size<R>(zip: &mut ZipFileReader<R>, index: usize) -> Result<u64, UpdateError>
+where
+    R: AsyncBufRead + AsyncSeek + Unpin,
+{
+    let size = zip.file().entries()[index].uncompressed_size();
+    if size < FILE_HEADER_BYTES_LEN as u64 {
+        return Ok(size);
+    }
+    let mut header = FileHeaderBytes::default();
+    zip.reader_without_entry(index)
+        .await?
+        .read_exact(&mut header)
+        .await?;
+    match FileHeader::from_bytes(&header) {
+        Ok(header) => Ok(header.total_size()),
+        Err(ParseError::UnknownMagic) => Ok(size),
+        Err(e) => Err(FlashError::from(e).into()),
+    }
+}
+
+// Flash a single zip entry, streaming it from the archive
+async fn flash_entry<R>(
+    fb: &mut NusbFastBoot,
+    zip: &mut ZipFileReader<R>,
+    index: usize,
+    target: &str,
+) -> Result<(), UpdateError>
+where
+    R: AsyncBufRead + AsyncSeek + Unpin,
+{
+    let size = zip.file().entries()[index].uncompressed_size();
+    let mut reader = CheckedEntry {
+        reader: zip.reader_with_entry(index).await?,
+        left: size,
+    };
+    info!("Flashing {target}");
+    flash_stream(fb, target, (&mut reader).compat(), Some(size), target).await?;
+    // Data after the end of a sparse image isn't flashed, but still covered by the checksum
+    futures::io::copy(&mut reader, &mut futures::io::sink()).await?;
+    Ok(())
+}
+
+/// Flash an update package (as produced by an android build), equivalent to `fastboot update`
+///
+/// The requirements in android-info.txt are checked against the device first; Images are then
+/// flashed in the same order as [crate::flashall::flash_all], streaming them from the archive
+/// without extracting them and checking the CRC32 of each image before its flash completes. With
+/// a super_empty.img the device is rebooted into userspace fastboot (fastbootd) if needed, see
+/// [ensure_mode]; Each logical partition is then resized to fit its image before flashing it. As
+/// the device may get rebooted the client is consumed; Returns the client connected to the device.
+pub async fn update<P: AsRef<Path>>(
+    mut fb: NusbFastBoot,
+    path: P,
+) -> Result<NusbFastBoot, UpdateError> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut zip = ZipFileReader::with_tokio(BufReader::new(file)).await?;
+
+    let mut images = HashMap::new();
+    let mut android_info = None;
+    for (index, entry) in zip.file().entries().iter().enumerate() {
+        let name = entry.filename().as_str()?;
+        if name == ANDROID_INFO {
+            android_info = Some(index);
+        } else if let Some(image) = name.strip_suffix(".img") {
+            images.insert(image.to_string(), index);
+        }
+    }
+
+    if let Some(index) = android_info {
+        let mut info = String::new();
+        zip.reader_with_entry(index)
+            .await?
+            .read_to_string_checked(&mut info)
+            .await?;
+        check_requirements(&mut fb, &parse_android_info(&info)).await?;
+    }
+
+    let super_empty = images.get("super_empty");
+    let os_partitions = OS_PARTITIONS
+        .iter()
+        .copied()
+        .chain(super_empty.is_none().then_some("super"));
+    let boot: Vec<_> = BOOT_PARTITIONS
+        .iter()
+        .filter_map(|&p| Some((p, *images.get(p)?)))
+        .collect();
+    let os: Vec<_> = os_partitions
+        .filter_map(|p| Some((p, *images.get(p)?)))
+        .collect();
+    if boot.is_empty() && os.is_empty() && super_empty.is_none() {
+        return Err(UpdateError::NoImages);
+    }
+
+    if super_empty.is_some() {
+        fb = ensure_mode(fb, FastbootMode::Fastbootd, DEFAULT_MODE_TIMEOUT).await?;
+    }
+    let slot = current_slot(&mut fb).await?;
+    info!("Flashing to slot: {slot:?}");
+
+    for (partition, index) in boot {
+        let target = slot_target(&mut fb, partition, slot.as_deref()).await?;
+        flash_entry(&mut fb, &mut zip, index, &target).await?;
+    }
+
+    if let Some(&index) = super_empty {
+        let mut data = vec![];
+        zip.reader_with_entry(index)
+            .await?
+            .read_to_end_checked(&mut data)
+            .await?;
+        let target = super_partition_name(&mut fb).await;
+        info!("Updating super partition {target}");
+        download_source(&mut fb, &ImageSource::from(data)).await?;
+        fb.update_super(&target, false).await?;
+    }
+
+    for (partition, index) in os {
+        let target = slot_target(&mut fb, partition, slot.as_deref()).await?;
+        if super_empty.is_some() {
+            // Logical partitions are created empty by super_empty.img
+            let size = image_size(&mut zip, index).await?;
+            resize_logical(&mut fb, &target, size).await?;
+        }
+        flash_entry(&mut fb, &mut zip, index, &target).await?;
+    }
+
+    Ok(fb)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn android_info() {
+        let info = "require board=sargo|bonito\n\
+                    require version-bootloader=b4s4-0.3*\n\
+                    require-for-product:bonito version-baseband=g670\n\
+                    reject version-bootloader=b4s4-0.2\n\
+                    require partition-exists=vendor_dlkm\n\
+                    # comment\n";
+        let requirements = parse_android_info(info);
+        assert_eq!(requirements.len(), 4);
+        assert_eq!(
+            requirements[0],
+            Requirement {
+                product: None,
+                var: "product".to_string(),
+                values: vec!["sargo".to_string(), "bonito".to_string()],
+                reject: false,
+            }
+        );
+        assert!(requirements[0].matches("bonito"));
+        assert!(!requirements[0].matches("bonito2"));
+        assert!(requirements[1].matches("b4s4-0.3-1234"));
+        assert!(!requirements[1].matches("b4s4-0.2-1234"));
+        assert_eq!(requirements[2].product.as_deref(), Some("bonito"));
+        assert!(requirements[3].reject);
+    }
+
+    #[tokio::test]
+    async fn checked_entry() {
+        use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
+
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let mut writer = ZipFileWriter::new(vec![]);
+        writer
+            .write_entry_whole(
+                ZipEntryBuilder::new("system.img".into(), Compression::Stored),
+                &data,
+            )
+            .await
+            .unwrap();
+        let zip = writer.close().await.unwrap();
+
+        let len = data.len() as u64;
+        let read = |zip: Vec<u8>| async move {
+            let mut zip = ZipFileReader::with_tokio(std::io::Cursor::new(zip))
+                .await
+                .unwrap();
+            let mut reader = CheckedEntry {
+                reader: zip.reader_with_entry(0).await.unwrap(),
+                left: len,
+            };
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).await.map(|_| buf)
+        };
+        assert_eq!(read(zip.clone()).await.unwrap(), data);
+
+        // Corrupt the stored data, right after the local file header
+        let mut corrupted = zip;
+        corrupted[30 + "system.img".len() + 100] ^= 0xff;
+        let e = read(corrupted).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{io::SeekFrom, ops::Range};
+
+use android_sparse_image::{
+    ChunkHeader, ChunkHeaderBytes, ChunkType, FileHeader, FileHeaderBytes, ParseError,
+    FILE_HEADER_BYTES_LEN,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::{info, trace};
+
+use crate::{
+    flash::{FlashError, ImageSource},
+    nusb::NusbFastBoot,
+    protocol::parse_u32,
+};
+
+// Maximum amount of data compared in one go
+const WINDOW_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Result of verifying a partition against an image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Amount of bytes compared
+    pub compared: u64,
+    /// Byte ranges in the partition not matching the image
+    pub mismatches: Vec<Range<u64>>,
+}
+
+impl VerifyReport {
+    /// Whether the partition content matched the image
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    fn add_mismatch(&mut self, range: Range<u64>) {
+        match self.mismatches.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.mismatches.push(range),
+        }
+    }
+
+    // Compare actual against expected data at the given partition offset
+    fn compare(&mut self, offset: u64, actual: &[u8], expected: &[u8]) {
+        let mut start = None;
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            match (a == e, start) {
+                (false, None) => start = Some(i),
+                (true, Some(s)) => {
+                    self.add_mismatch(offset + s as u64..offset + i as u64);
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(s) = start {
+            self.add_mismatch(offset + s as u64..offset + actual.len() as u64);
+        }
+        self.compared += actual.len() as u64;
+    }
+}
+
+// Expected content of a region of the partition
+enum Expected {
+    // Data at the given offset in the source
+    Data(u64),
+    Fill([u8; 4]),
+}
+
+struct Region {
+    offset: u64,
+    size: u64,
+    expected: Expected,
+}
+
+// Determine the regions of the partition with defined content
+async fn regions<R>(source: &mut R) -> Result<Vec<Region>, FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let mut header_bytes = FileHeaderBytes::default();
+    source.read_exact(&mut header_bytes).await?;
+    let header = match FileHeader::from_bytes(&header_bytes) {
+        Ok(header) => header,
+        Err(ParseError::UnknownMagic) => {
+            let size = source.seek(SeekFrom::End(0)).await?;
+            return Ok(vec![Region {
+                offset: 0,
+                size,
+                expected: Expected::Data(0),
+            }]);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut regions = vec![];
+    let mut offset = 0;
+    let mut file_offset = FILE_HEADER_BYTES_LEN as u64;
+    for index in 0..header.chunks {
+        let mut chunk_bytes = ChunkHeaderBytes::default();
+        source.read_exact(&mut chunk_bytes).await?;
+        let chunk =
+            ChunkHeader::from_bytes(&chunk_bytes).map_err(|e| e.in_chunk(index, file_offset))?;
+        let size = chunk.out_size(&header);
+        file_offset += chunk_bytes.len() as u64;
+        match chunk.chunk_type {
+            ChunkType::Raw => regions.push(Region {
+                offset,
+                size,
+                expected: Expected::Data(file_offset),
+            }),
+            ChunkType::Fill => {
+                let mut fill = [0u8; 4];
+                source.read_exact(&mut fill).await?;
+                regions.push(Region {
+                    offset,
+                    size,
+                    expected: Expected::Fill(fill),
+                })
+            }
+            ChunkType::DontCare | ChunkType::Crc32 => (),
+        }
+        offset += size;
+        file_offset += chunk.data_size() as u64;
+        source.seek(SeekFrom::Start(file_offset)).await?;
+    }
+    Ok(regions)
+}
+
+// Fetch a range of the partition into buf
+pub(crate) async fn fetch_range(
+    fb: &mut NusbFastBoot,
+    partition: &str,
+    offset: u64,
+    buf: &mut Vec<u8>,
+    size: u64,
+) -> Result<(), FlashError> {
+    buf.clear();
+    while (buf.len() as u64) < size {
+        let fetched = buf.len() as u64;
+        let mut upload = fb
+            .fetch(partition, offset + fetched, size - fetched)
+            .await?;
+        if upload.size() == 0 {
+            return Err(FlashError::EmptyFetch);
+        }
+        while let Some(data) = upload.next_data().await? {
+            buf.extend_from_slice(&data);
+        }
+        upload.finish().await?;
+    }
+    Ok(())
+}
+
+/// Verify the content of a partition against an image by reading it back from the device
+///
+/// The image can either be an android sparse image or a raw image; For sparse images don't care
+/// regions are not verified. Requires the device to support the `fetch` command.
+pub async fn verify_image<R>(
+    fb: &mut NusbFastBoot,
+    partition: &str,
+    mut source: R,
+) -> Result<VerifyReport, FlashError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let window = match fb.get_var_cached("max-fetch-size").await {
+        Ok(size) => parse_u32(&size).map_or(WINDOW_SIZE, |s| u64::from(s).min(WINDOW_SIZE)),
+        Err(e) => {
+            trace!("Failed to get max fetch size: {e}");
+            WINDOW_SIZE
+        }
+    };
+    info!("Verifying {partition}");
+
+    let mut report = VerifyReport::default();
+    let mut actual = vec![];
+    let mut expected = vec![];
+    for region in regions(&mut source).await? {
+        let mut done = 0;
+        while done < region.size {
+            let size = window.min(region.size - done);
+            fetch_range(fb, partition, region.offset + done, &mut actual, size).await?;
+            expected.resize(size as usize, 0);
+            match region.expected {
+                Expected::Data(file_offset) => {
+                    source.seek(SeekFrom::Start(file_offset + done)).await?;
+                    source.read_exact(&mut expected).await?;
+                }
+                Expected::Fill(fill) => {
+                    for (i, b) in expected.iter_mut().enumerate() {
+                        *b = fill[(done as usize + i) % 4];
+                    }
+                }
+            }
+            report.compare(region.offset + done, &actual, &expected);
+            done += size;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Verify the content of a partition against an image from the given source
+///
+/// See [verify_image] for details
+pub async fn verify_source(
+    fb: &mut NusbFastBoot,
+    partition: &str,
+    source: &ImageSource,
+) -> Result<VerifyReport, FlashError> {
+    match source {
+        ImageSource::File(path) | ImageSource::BlockDevice(path) => {
+            let file = tokio::fs::File::open(path).await?;
+            verify_image(fb, partition, file).await
+        }
+        ImageSource::Bytes(bytes) => {
+            verify_image(fb, partition, std::io::Cursor::new(bytes.clone())).await
+        }
+        ImageSource::Compressed { path, .. } => {
+            Err(FlashError::NotSeekable(path.display().to_string()))
+        }
+        #[cfg(feature = "http")]
+        ImageSource::Url(url) => Err(FlashError::NotSeekable(url.clone())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compare_ranges() {
+        let mut report = VerifyReport::default();
+        report.compare(100, &[0, 1, 2, 3, 4, 5], &[0, 9, 9, 3, 4, 9]);
+        assert_eq!(report.compared, 6);
+        assert_eq!(report.mismatches, vec![101..103, 105..106]);
+
+        // Mismatch continuing at the start of the next window gets merged
+        report.compare(106, &[1, 1], &[0, 1]);
+        assert_eq!(report.mismatches, vec![101..103, 105..107]);
+        assert!(!report.is_ok());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeSet;
+
+use thiserror::Error;
+use tracing::{info, trace};
+
+use crate::nusb::{NusbFastBoot, NusbFastBootError};
+
+/// Partitions protected from [erase_all] by default
+///
+/// These hold device specific data (calibration, radio configuration, factory reset protection,
+/// ...) which can't be restored from factory images
+pub const DEFAULT_PROTECTED_PARTITIONS: &[&str] = &[
+    "devinfo",
+    "efs",
+    "frp",
+    "fsc",
+    "fsg",
+    "keymaster",
+    "modemst1",
+    "modemst2",
+    "nvdata",
+    "nvram",
+    "persist",
+    "protect1",
+    "protect2",
+    "sec",
+];
+
+/// Errors while erasing all partitions
+#[derive(Debug, Error)]
+pub enum EraseAllError {
+    #[error("Invalid protected partition name {0:?}")]
+    InvalidName(String),
+    #[error(transparent)]
+    Fastboot(#[from] NusbFastBootError),
+}
+
+/// Partitions holding user data, erased by [erase_userdata] when present
+///
+/// The metadata partition holds the encryption keys for userdata; Erasing only one of the two
+/// leaves a device which fails to decrypt userdata, so they are always erased together.
+pub const USERDATA_PARTITIONS: &[&str] = &["userdata", "metadata", "cache"];
+
+/// Whether the device has the given partition
+pub async fn partition_exists(
+    fb: &mut NusbFastBoot,
+    partition: &str,
+) -> Result<bool, NusbFastBootError> {
+    match fb
+        .get_var_cached(&format!("partition-size:{partition}"))
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(NusbFastBootError::FastbootFailed(e)) => {
+            trace!("No size for {partition}: {e}");
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Erase all user data from the device
+///
+/// Each of the [USERDATA_PARTITIONS] present on the device gets erased; Returns the partitions
+/// which were erased. Unlike `fastboot -w` no filesystem is generated and flashed, Android
+/// formats erased userdata and metadata partitions itself on the next boot.
+pub async fn erase_userdata(fb: &mut NusbFastBoot) -> Result<Vec<String>, NusbFastBootError> {
+    let mut erased = vec![];
+    for &partition in USERDATA_PARTITIONS {
+        if !partition_exists(fb, partition).await? {
+            trace!("Skipping erase of missing {partition}");
+            continue;
+        }
+        info!("Erasing {partition}");
+        fb.erase(partition).await?;
+        erased.push(partition.to_string());
+    }
+    Ok(erased)
+}
+
+/// Set of partitions which must not be erased
+///
+/// Protecting a partition also protects all of its slots (e.g. "modem" protects "modem_a" and
+/// "modem_b")
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtectedPartitions {
+    partitions: BTreeSet<String>,
+}
+
+impl Default for ProtectedPartitions {
+    fn default() -> Self {
+        Self {
+            partitions: DEFAULT_PROTECTED_PARTITIONS
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl ProtectedPartitions {
+    /// Set without any protected partitions
+    pub fn empty() -> Self {
+        Self {
+            partitions: BTreeSet::new(),
+        }
+    }
+
+    /// Set with the given partitions; Fails if any of the names isn't a valid partition name
+    pub fn new<I, S>(partitions: I) -> Result<Self, EraseAllError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut protected = Self::empty();
+        for p in partitions {
+            protected.insert(p)?;
+        }
+        Ok(protected)
+    }
+
+    /// Protect a partition, returning whether it wasn't protected yet
+    pub fn insert<S: Into<String>>(&mut self, partition: S) -> Result<bool, EraseAllError> {
+        let partition = partition.into();
+        if partition.is_empty()
+            || !partition
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        {
+            return Err(EraseAllError::InvalidName(partition));
+        }
+        Ok(self.partitions.insert(partition))
+    }
+
+    /// Stop protecting a partition, returning whether it was protected
+    pub fn remove(&mut self, partition: &str) -> bool {
+        self.partitions.remove(partition)
+    }
+
+    /// Whether the given partition is protected, either directly or as a slot of a protected
+    /// partition
+    pub fn is_protected(&self, partition: &str) -> bool {
+        if self.partitions.contains(partition) {
+            return true;
+        }
+        match partition.rsplit_once('_') {
+            Some((base, slot))
+                if slot.len() == 1 && slot.chars().all(|c| c.is_ascii_lowercase()) =>
+            {
+                self.partitions.contains(base)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Partitions which [erase_all] would erase, based on the partitions reported by the device
+///
+/// Requires the device to support `getvar all`
+pub async fn plan_erase_all(
+    fb: &mut NusbFastBoot,
+    protected: &ProtectedPartitions,
+) -> Result<Vec<String>, EraseAllError> {
+    let vars = fb.get_all_vars().await?;
+    let partitions: BTreeSet<_> = vars
+        .keys()
+        .filter_map(|k| k.strip_prefix("partition-size:"))
+        .filter(|p| {
+            let protected = protected.is_protected(p);
+            if protected {
+                trace!("Not erasing protected {p}");
+            }
+            !protected
+        })
+        .map(str::to_string)
+        .collect();
+    Ok(partitions.into_iter().collect())
+}
+
+/// Erase all partitions of the device except the protected ones, returning the erased
+/// partitions
+///
+/// See [plan_erase_all] for details
+pub async fn erase_all(
+    fb: &mut NusbFastBoot,
+    protected: &ProtectedPartitions,
+) -> Result<Vec<String>, EraseAllError> {
+    let partitions = plan_erase_all(fb, protected).await?;
+    for partition in &partitions {
+        info!("Erasing {partition}");
+        fb.erase(partition).await?;
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protected_slots() {
+        let protected = ProtectedPartitions::default();
+        assert!(protected.is_protected("persist"));
+        assert!(protected.is_protected("modemst1"));
+        assert!(!protected.is_protected("userdata"));
+
+        let protected = ProtectedPartitions::new(["modem", "persist"]).unwrap();
+        assert!(protected.is_protected("modem_a"));
+        assert!(protected.is_protected("modem_b"));
+        assert!(!protected.is_protected("modem_ab"));
+        assert!(!protected.is_protected("modemst1"));
+        assert!(!protected.is_protected("system_ext"));
+    }
+
+    #[test]
+    fn protected_validation() {
+        let mut protected = ProtectedPartitions::empty();
+        assert!(protected.insert("frp").unwrap());
+        assert!(!protected.insert("frp").unwrap());
+        assert!(matches!(
+            protected.insert(""),
+            Err(EraseAllError::InvalidName(_))
+        ));
+        assert!(matches!(
+            ProtectedPartitions::new(["persist", "user data"]),
+            Err(EraseAllError::InvalidName(n)) if n == "user data"
+        ));
+        assert!(protected.remove("frp"));
+        assert!(!protected.is_protected("frp"));
+    }
+}